@@ -0,0 +1,16 @@
+//! Serve a directory of templates, rendered against a database, with live reload.
+//!
+//! Usage: `cargo run --features dev-server --example dev-server -- <templates> <database> [address]`
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (Some(templates), Some(database)) = (args.next(), args.next()) else {
+        eprintln!("usage: dev-server <templates> <database> [address]");
+        std::process::exit(2);
+    };
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    eprintln!("serving {} on http://{}/", templates, addr);
+    htmpl::dev_server::DevServer::new(templates, database)
+        .serve(addr)
+        .unwrap();
+}
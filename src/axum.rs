@@ -0,0 +1,218 @@
+//! Integration with the [axum](https://docs.rs/axum) web framework.
+//!
+//! Put an [`HtmplState`] in the router's state, and extract an [`Htmpl`] in handlers:
+//!
+//! ```no_run
+//! use std::collections::HashMap;
+//! use axum::{extract::Query, response::IntoResponse, routing::get, Router};
+//! use htmpl::axum::{Htmpl, HtmplState};
+//!
+//! async fn page(htmpl: Htmpl, Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+//!     htmpl.render("page.html", params)
+//! }
+//!
+//! let templates = htmpl::Templates::from_dir("templates").unwrap();
+//! let pool = htmpl::Pool::new("site.sqlite3");
+//! let state = HtmplState::new(templates, pool, Default::default());
+//! let app: Router = Router::new().route("/page", get(page)).with_state(state);
+//! ```
+
+use std::{convert::Infallible, sync::Arc};
+
+use ::axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+    response::{IntoResponse, Response},
+};
+
+use crate::{respond::respond, EvaluateOptions, Params, Pool, Renderer, Templates};
+
+/// Templates, a database, and options for evaluating the templates.
+///
+/// Cloning an `HtmplState` is cheap; clones share the same [`Renderer`].
+#[derive(Debug, Clone)]
+pub struct HtmplState(Arc<Renderer>);
+
+impl HtmplState {
+    /// Render the templates against connections from the pool, with the given options.
+    ///
+    /// Parameters set in the options are replaced by the parameters to each render.
+    pub fn new(templates: Templates, pool: Pool, options: EvaluateOptions) -> Self {
+        Renderer::new(templates, pool, options).into()
+    }
+}
+
+impl From<Renderer> for HtmplState {
+    fn from(renderer: Renderer) -> Self {
+        HtmplState(Arc::new(renderer))
+    }
+}
+
+/// Extractor for rendering templates in a handler.
+///
+/// The extractor captures the request headers, to answer conditional requests.
+#[derive(Debug, Clone)]
+pub struct Htmpl {
+    state: HtmplState,
+    headers: HeaderMap,
+}
+
+impl<S> FromRequestParts<S> for Htmpl
+where
+    HtmplState: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Htmpl {
+            state: HtmplState::from_ref(state),
+            headers: parts.headers.clone(),
+        })
+    }
+}
+
+impl Htmpl {
+    /// Render the named template with the given parameters.
+    pub fn render(&self, template: &str, params: impl Into<Params>) -> Rendered {
+        let renderer = &self.state.0;
+        let options = renderer.options().clone().params(params);
+        Rendered(respond(renderer, template, &options, &self.headers))
+    }
+
+    /// Render only the named `htmpl-block` of the template, with the given parameters.
+    ///
+    /// This allows a page and its partial updates (e.g. for [htmx](https://htmx.org) requests)
+    /// to come from the same template; see [`EvaluateOptions::block`].
+    pub fn render_block(&self, template: &str, block: &str, params: impl Into<Params>) -> Rendered {
+        let renderer = &self.state.0;
+        let options = renderer.options().clone().params(params).block(block);
+        Rendered(respond(renderer, template, &options, &self.headers))
+    }
+}
+
+/// The result of rendering a template, as a response.
+///
+/// A successful render is returned with a content type matching the output format,
+/// and `ETag` and `Last-Modified` headers (see [`RenderOutput`](crate::RenderOutput)).
+/// Conditional requests are answered with "304 Not Modified".
+/// Errors are logged, and returned as an error status per [`Error::status_code`](crate::Error::status_code).
+#[derive(Debug)]
+pub struct Rendered(http::Response<String>);
+
+impl IntoResponse for Rendered {
+    fn into_response(self) -> Response {
+        self.0.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ::axum::{
+        body::{to_bytes, Body},
+        extract::Query,
+        http::{Request, StatusCode},
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use rusqlite::Connection;
+    use tempfile::NamedTempFile;
+    use tower::ServiceExt;
+
+    use super::{Htmpl, HtmplState};
+    use crate::{Pool, Templates};
+
+    fn router(db: &NamedTempFile) -> Router {
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE users (name TEXT NOT NULL, greeting TEXT NOT NULL);
+            INSERT INTO users (name, greeting) VALUES ("cceckman", "hello"), ("ddedkman", "hi");
+            "#,
+        )
+        .unwrap();
+        let mut templates = Templates::new();
+        templates.insert(
+            "greet.html",
+            r#"<htmpl-query name="q" :name="params(name)">SELECT greeting FROM users WHERE name = :name;</htmpl-query><p><htmpl-block name="greeting"><htmpl-insert query="q"></htmpl-insert></htmpl-block></p>"#,
+        );
+        let state = HtmplState::new(templates, Pool::new(db.path()), Default::default());
+
+        async fn greet(
+            htmpl: Htmpl,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> impl IntoResponse {
+            htmpl.render("greet.html", params)
+        }
+        async fn greeting(
+            htmpl: Htmpl,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> impl IntoResponse {
+            htmpl.render_block("greet.html", "greeting", params)
+        }
+        async fn missing(htmpl: Htmpl) -> impl IntoResponse {
+            htmpl.render("missing.html", ())
+        }
+
+        Router::new()
+            .route("/greet", get(greet))
+            .route("/greeting", get(greeting))
+            .route("/missing", get(missing))
+            .with_state(state)
+    }
+
+    async fn get_response(router: Router, uri: &str) -> (StatusCode, String, String) {
+        let response = router
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .map(|v| v.to_str().unwrap().to_owned())
+            .unwrap_or_default();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn render_with_params() {
+        let db = NamedTempFile::new().unwrap();
+        let (status, content_type, body) = get_response(router(&db), "/greet?name=ddedkman").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert_eq!(body, "<p>hi</p>");
+    }
+
+    #[tokio::test]
+    async fn render_block() {
+        let db = NamedTempFile::new().unwrap();
+        let (status, _, body) = get_response(router(&db), "/greeting?name=cceckman").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn missing_template_is_not_found() {
+        let db = NamedTempFile::new().unwrap();
+        let (status, _, _) = get_response(router(&db), "/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn evaluation_error_is_server_error() {
+        let db = NamedTempFile::new().unwrap();
+        // No "name" parameter
+        let (status, _, body) = get_response(router(&db), "/greet").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body, "Internal Server Error");
+    }
+}
@@ -0,0 +1,188 @@
+//! Components: reusable fragments of a template, defined with `htmpl-define`.
+//!
+//! ```html
+//! <htmpl-define name="user-card" props="uuid">
+//!     <htmpl-query name="user" :uuid="uuid">SELECT name FROM users WHERE uuid = :uuid;</htmpl-query>
+//!     <div class="card"><htmpl-insert query="user(name)"></htmpl-insert></div>
+//! </htmpl-define>
+//! <user-card :uuid="post(author)"></user-card>
+//! ```
+//!
+//! Each instance of the component is replaced by the body of its definition, evaluated in a scope
+//! of its own: only the [builtin](crate::EvaluateOptions::prelude_query) bindings, and each prop,
+//! bound from the instance's attribute of the same name (with a colon) as by `htmpl-let`.
+//!
+//! Macros are lighter: an `htmpl-macro` is in scope after it is defined, like a query,
+//! and an `htmpl-call` evaluates it in the caller's scope.
+
+use std::collections::HashMap;
+
+use ego_tree::{NodeId, NodeMut};
+use rusqlite::types::Value;
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::{
+    queries,
+    queries::Scope,
+    visit::{is_inert, visit_recurse},
+    Error,
+};
+
+/// Find the `htmpl-define` elements in the template, by the name of the component they define.
+pub(crate) fn definitions(html: &Html) -> Result<HashMap<String, NodeId>, Error> {
+    let selector = Selector::parse("htmpl-define").expect("invalid selector");
+    let mut definitions = HashMap::new();
+    for define in html.select(&selector).filter(|e| !is_inert(**e)) {
+        let name = define
+            .value()
+            .attr("name")
+            .ok_or(Error::MissingAttr("htmpl-define".into(), "name".into()))?;
+        if !name.contains('-') || name.starts_with("htmpl-") {
+            return Err(Error::InvalidParameter(
+                "htmpl-define".into(),
+                format!("name={:?} is not a custom element name", name),
+            ));
+        }
+        if definitions.insert(name.to_owned(), define.id()).is_some() {
+            return Err(Error::InvalidParameter(
+                "htmpl-define".into(),
+                format!("component {} is defined more than once", name),
+            ));
+        }
+    }
+    Ok(definitions)
+}
+
+/// Evaluate an instance of the component defined by `define`, in place of the instance.
+pub(crate) fn instantiate(
+    scope: &mut Scope,
+    define: ElementRef,
+    instance: ElementRef,
+    output: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let mut inner = scope.isolate();
+    inner.enter(instance.value().name())?;
+    inner.set_caller(instance.id(), scope.clone());
+    for prop in define
+        .value()
+        .attr("props")
+        .unwrap_or_default()
+        .split_whitespace()
+    {
+        let attr = format!(":{}", prop);
+        let value = instance
+            .value()
+            .attr(&attr)
+            .ok_or_else(|| Error::MissingParameter("".into(), attr.clone()))?;
+        let value = match queries::literal(value) {
+            Some(value) => value,
+            None => scope.get_single(value)?.into_owned(),
+        };
+        inner.bind_columns(
+            prop,
+            vec![prop.to_owned()],
+            vec![[(prop.to_owned(), value)].into()],
+        );
+    }
+    for child in define.children() {
+        visit_recurse(&mut inner, child, output)?;
+    }
+    Ok(())
+}
+
+/// Evaluate an `htmpl-call` element: the body of the `macro` it names, in the current scope,
+/// with each of the macro's `args` bound from the attribute of the same name:
+/// as text (`label="New"`), or with a colon, as a literal or selector (`:label="post(tag)"`).
+pub(crate) fn call(
+    scope: &mut Scope,
+    element: ElementRef,
+    output: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let name = element
+        .value()
+        .attr("macro")
+        .ok_or(Error::MissingAttr("htmpl-call".into(), "macro".into()))?;
+    let define = scope
+        .get_macro(name)
+        .and_then(|id| ElementRef::wrap(element.tree().get(id)?))
+        .ok_or_else(|| Error::InvalidParameter("htmpl-call".into(), format!("macro={:?}", name)))?;
+    let mut inner = scope.push();
+    inner.enter(name)?;
+    for arg in define
+        .value()
+        .attr("args")
+        .unwrap_or_default()
+        .split_whitespace()
+    {
+        let value = match (
+            element.value().attr(arg),
+            element.value().attr(&format!(":{}", arg)),
+        ) {
+            (Some(text), _) => Value::Text(text.to_owned()),
+            (None, Some(value)) => match queries::literal(value) {
+                Some(value) => value,
+                None => scope
+                    .get_single(value)
+                    .map_err(|e| e.set_element("htmpl-call"))?
+                    .into_owned(),
+            },
+            (None, None) => {
+                return Err(Error::MissingParameter("htmpl-call".into(), arg.to_owned()))
+            }
+        };
+        inner.bind_columns(
+            arg,
+            vec![arg.to_owned()],
+            vec![[(arg.to_owned(), value)].into()],
+        );
+    }
+    for child in define.children() {
+        visit_recurse(&mut inner, child, output)?;
+    }
+    scope.adopt(inner);
+    Ok(())
+}
+
+/// Evaluate an `htmpl-slot` element in a component: insert the children of the instance
+/// for the slot, evaluated in the scope of the instance; or if there are none,
+/// the content of the `htmpl-slot` element.
+///
+/// The children for a slot with a `name` are those with a matching `slot` attribute;
+/// the default slot, without a `name`, gets the rest.
+pub(crate) fn slot(
+    scope: &mut Scope,
+    element: ElementRef,
+    output: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let (instance, caller) = scope
+        .caller()
+        .and_then(|(id, caller)| Some((ElementRef::wrap(element.tree().get(id)?)?, caller)))
+        .ok_or_else(|| {
+            Error::InvalidParameter("htmpl-slot".into(), "outside of a component".to_owned())
+        })?;
+    let name = element.value().attr("name");
+    let content: Vec<_> = instance
+        .children()
+        .filter(|child| match child.value() {
+            Node::Element(e) => e.attr("slot") == name,
+            _ => name.is_none(),
+        })
+        .collect();
+    let empty = content.iter().all(|child| match child.value() {
+        Node::Element(_) => false,
+        Node::Text(t) => t.trim().is_empty(),
+        _ => true,
+    });
+    if empty {
+        let mut inner = scope.push();
+        for child in element.children() {
+            visit_recurse(&mut inner, child, output)?;
+        }
+        return Ok(());
+    }
+    let mut caller = caller.push();
+    for child in content {
+        visit_recurse(&mut caller, child, output)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! CSS output: custom properties set from query values, and scoped class names.
+
+/// The name of the custom property for a column: `--` and the column name,
+/// with underscores as hyphens, e.g. `--primary-color` for `primary_color`.
+pub(crate) fn property_name(column: &str) -> String {
+    let mut name = String::from("--");
+    for c in column.chars() {
+        match c {
+            '_' => name.push('-'),
+            c if c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii() => name.push(c),
+            c => escape_char(&mut name, c),
+        }
+    }
+    name
+}
+
+/// Escape a value for use as the value of a CSS declaration.
+///
+/// Lengths, colors, numbers, and functions like `rgb(...)` pass through;
+/// anything that could end the declaration, rule, or `<style>` element is escaped.
+pub(crate) fn escape_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_alphanumeric() || " #.%-+,()/".contains(c) {
+            out.push(c);
+        } else {
+            escape_char(&mut out, c);
+        }
+    }
+    out
+}
+
+/// Write the CSS escape of the character: a backslash, its hex code point, and a space.
+fn escape_char(out: &mut String, c: char) {
+    out.push_str(&format!("\\{:x} ", c as u32));
+}
+
+/// Returns true if the character can start a CSS identifier.
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '-' || !c.is_ascii()
+}
+
+/// Returns true if the character can continue a CSS identifier.
+fn is_ident(c: char) -> bool {
+    is_ident_start(c) || c.is_ascii_digit()
+}
+
+/// Append the suffix to the class names in the selectors of the stylesheet,
+/// e.g. `.card > .title` to `.card-x > .title-x`.
+///
+/// Declarations, strings, comments, and the preludes of at-rules (e.g. `@media`) are unchanged.
+pub(crate) fn suffix_classes(css: &str, suffix: &str) -> String {
+    // For each open block: true if it holds declarations, false if it holds rules.
+    let mut blocks: Vec<bool> = Vec::new();
+    // Whether the current prelude is of an at-rule; None until its first character.
+    let mut at_rule: Option<bool> = None;
+    let mut out = String::with_capacity(css.len());
+    let mut chars = css.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        match c {
+            '/' if chars.peek() == Some(&'*') => {
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    out.push(c);
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            '"' | '\'' => {
+                while let Some(next) = chars.next() {
+                    out.push(next);
+                    if next == '\\' {
+                        out.extend(chars.next());
+                    } else if next == c {
+                        break;
+                    }
+                }
+            }
+            '\\' => out.extend(chars.next()),
+            '{' => {
+                let declarations = blocks.last() == Some(&true) || at_rule != Some(true);
+                blocks.push(declarations);
+                at_rule = None;
+            }
+            '}' => {
+                blocks.pop();
+                at_rule = None;
+            }
+            ';' => at_rule = None,
+            _ if blocks.last() == Some(&true) || c.is_whitespace() => {}
+            _ => {
+                let at = *at_rule.get_or_insert(c == '@');
+                if c == '.' && !at && chars.peek().is_some_and(|c| is_ident_start(*c)) {
+                    while let Some(c) = chars.next_if(|c| is_ident(*c)) {
+                        out.push(c);
+                    }
+                    out.push('-');
+                    out.push_str(suffix);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_value, property_name, suffix_classes};
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(
+            suffix_classes(".card > p.title { color: red; }", "x1"),
+            ".card-x1 > p.title-x1 { color: red; }"
+        );
+        assert_eq!(
+            suffix_classes(
+                "@media (min-width: 1.5em) { .a { margin: .5em; } } /* .b */ .c::after { content: \".d\" }",
+                "x1"
+            ),
+            "@media (min-width: 1.5em) { .a-x1 { margin: .5em; } } /* .b */ .c-x1::after { content: \".d\" }"
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(property_name("primary_color"), "--primary-color");
+        assert_eq!(property_name("a:b"), "--a\\3a b");
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(escape_value("#ff0000"), "#ff0000");
+        assert_eq!(escape_value("rgb(1, 2, 3)"), "rgb(1, 2, 3)");
+        assert_eq!(escape_value("1.5rem"), "1.5rem");
+        assert_eq!(escape_value("red; } body {"), "red\\3b  \\7d  body \\7b ");
+        assert_eq!(escape_value("</style>"), "\\3c /style\\3e ");
+    }
+}
@@ -0,0 +1,324 @@
+//! A development server for htmpl templates.
+//!
+//! The server renders templates from a directory against a database on every request,
+//! so edits to either show up immediately.
+//! Rendered HTML pages get a small script injected, which reloads the page
+//! when the server sees a template or the database change.
+//!
+//! ```no_run
+//! htmpl::dev_server::DevServer::new("templates", "site.sqlite3")
+//!     .serve("127.0.0.1:8080")
+//!     .unwrap();
+//! ```
+//!
+//! This is meant for iterating on templates, not for production:
+//! it reads templates from disk and opens the database on each request.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::Duration,
+};
+
+use rusqlite::{Connection, OpenFlags};
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::{evaluate_template_with, paths, EvaluateOptions, OutputFormat};
+
+/// Path of the endpoint the reload script polls.
+const RELOAD_PATH: &str = "/_htmpl/reload";
+
+/// How often the watcher checks the templates and database for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a request to the reload endpoint waits for a change before returning.
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A server that renders templates, and reloads the browser when they change.
+#[derive(Debug, Clone)]
+pub struct DevServer {
+    templates: PathBuf,
+    database: PathBuf,
+    options: EvaluateOptions,
+}
+
+impl DevServer {
+    /// Serve templates from the `templates` directory, evaluated against the `database` file.
+    pub fn new(templates: impl Into<PathBuf>, database: impl Into<PathBuf>) -> Self {
+        DevServer {
+            templates: templates.into(),
+            database: database.into(),
+            options: Default::default(),
+        }
+    }
+
+    /// Set the options used to evaluate templates.
+    pub fn options(mut self, options: EvaluateOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Listen on the given address and serve requests, until an error occurs.
+    pub fn serve(self, addr: impl std::net::ToSocketAddrs) -> io::Result<()> {
+        let server = Server::http(addr).map_err(io::Error::other)?;
+        tracing::info!(
+            "htmpl dev server listening on http://{}/",
+            server.server_addr()
+        );
+        let state = Arc::new(State {
+            server: self,
+            generation: Mutex::new(0),
+            changed: Condvar::new(),
+        });
+        {
+            let state = state.clone();
+            thread::spawn(move || state.watch());
+        }
+        for request in server.incoming_requests() {
+            let state = state.clone();
+            thread::spawn(move || {
+                if let Err(e) = state.respond(request) {
+                    tracing::warn!("error responding to request: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// State shared between the request handlers and the watcher.
+struct State {
+    server: DevServer,
+    /// Incremented whenever the watcher sees a change.
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl State {
+    /// Poll the templates and database for changes, and notify waiting reload requests.
+    fn watch(&self) {
+        let mut last = self.fingerprint();
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let current = self.fingerprint();
+            if current != last {
+                last = current;
+                let mut generation = self.generation.lock().unwrap();
+                *generation += 1;
+                tracing::info!("change detected; reloading (generation {})", *generation);
+                self.changed.notify_all();
+            }
+        }
+    }
+
+    /// Summarize the modification state of the templates and database.
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hash_metadata(&self.server.database, &mut hasher);
+        // SQLite in WAL mode writes to a separate file until checkpointing.
+        let mut wal = self.server.database.clone().into_os_string();
+        wal.push("-wal");
+        hash_metadata(Path::new(&wal), &mut hasher);
+        hash_tree(&self.server.templates, &mut hasher);
+        hasher.finish()
+    }
+
+    fn respond(&self, request: Request) -> io::Result<()> {
+        let url = request.url().to_owned();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        if path == RELOAD_PATH {
+            let since = query
+                .split('&')
+                .find_map(|kv| kv.strip_prefix("generation="))
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            let generation = self.wait_for_change(since);
+            return request.respond(Response::from_string(generation.to_string()));
+        }
+
+        let Some(file) = resolve_path(&self.server.templates, path) else {
+            return request.respond(Response::from_string("not found").with_status_code(404));
+        };
+        if file
+            .extension()
+            .is_some_and(|ext| ext == "html" || ext == "xml")
+        {
+            let (status, body) = self.render(&file);
+            return request.respond(
+                Response::from_string(body)
+                    .with_status_code(status)
+                    .with_header(header(
+                        "Content-Type",
+                        self.server.options.format.content_type(),
+                    )),
+            );
+        }
+        match fs::File::open(&file) {
+            Ok(f) => request.respond(Response::from_file(f)),
+            Err(_) => request.respond(Response::from_string("not found").with_status_code(404)),
+        }
+    }
+
+    /// Wait until the generation differs from `since`, or the reload timeout elapses.
+    fn wait_for_change(&self, since: u64) -> u64 {
+        let generation = self.generation.lock().unwrap();
+        let (generation, _) = self
+            .changed
+            .wait_timeout_while(generation, RELOAD_TIMEOUT, |g| *g == since)
+            .unwrap();
+        *generation
+    }
+
+    /// Render the template at the path, with the reload script injected.
+    /// Returns the status code and body.
+    fn render(&self, file: &Path) -> (u16, String) {
+        let generation = *self.generation.lock().unwrap();
+        let result = fs::read_to_string(file)
+            .map_err(|e| format!("could not read template {}: {}", file.display(), e))
+            .and_then(|template| {
+                let conn = Connection::open_with_flags(
+                    &self.server.database,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+                )
+                .map_err(|e| {
+                    format!(
+                        "could not open database {}: {}",
+                        self.server.database.display(),
+                        e
+                    )
+                })?;
+                evaluate_template_with(template, &conn, &self.server.options)
+                    .map_err(|e| e.to_string())
+            });
+        match (result, self.server.options.format) {
+            (Ok(body), OutputFormat::Xml) => (200, body),
+            (Ok(body), OutputFormat::Html | OutputFormat::Xhtml) => {
+                (200, inject_reload_script(body, generation))
+            }
+            (Err(e), _) => {
+                tracing::error!("error rendering {}: {}", file.display(), e);
+                let body = format!(
+                    "<!DOCTYPE html><title>htmpl error</title><pre>{}</pre>",
+                    e.replace('&', "&amp;").replace('<', "&lt;")
+                );
+                (500, inject_reload_script(body, generation))
+            }
+        }
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("invalid static header")
+}
+
+/// Map a request path to a file in the template directory.
+/// Directories map to their `index.html`.
+///
+/// Returns None if the path would escape the template directory.
+fn resolve_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut file = paths::resolve(root, path)?;
+    if path.ends_with('/') || file.is_dir() {
+        file.push("index.html");
+    }
+    Some(file)
+}
+
+/// Add the reload script to the page: at the end of the body, if present,
+/// or at the end of the document.
+fn inject_reload_script(mut body: String, generation: u64) -> String {
+    let script = format!(
+        r#"<script>
+(async () => {{
+  const generation = {generation};
+  for (;;) {{
+    try {{
+      const response = await fetch("{RELOAD_PATH}?generation=" + generation);
+      if (Number(await response.text()) !== generation) {{
+        location.reload();
+        return;
+      }}
+    }} catch (e) {{
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+    }}
+  }}
+}})();
+</script>"#
+    );
+    match body.rfind("</body>") {
+        Some(i) => body.insert_str(i, &script),
+        None => body.push_str(&script),
+    }
+    body
+}
+
+fn hash_metadata(path: &Path, hasher: &mut impl Hasher) {
+    if let Ok(metadata) = fs::metadata(path) {
+        path.hash(hasher);
+        metadata.len().hash(hasher);
+        metadata.modified().ok().hash(hasher);
+    }
+}
+
+/// Hash the metadata of all files under the directory.
+fn hash_tree(dir: &Path, hasher: &mut impl Hasher) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(Result::ok).map(|e| e.path()).collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            hash_tree(&path, hasher);
+        } else {
+            hash_metadata(&path, hasher);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{inject_reload_script, resolve_path};
+
+    #[test]
+    fn resolve_index() {
+        let root = Path::new("/nonexistent/templates");
+        assert_eq!(resolve_path(root, "/").unwrap(), root.join("index.html"));
+        assert_eq!(
+            resolve_path(root, "/posts/").unwrap(),
+            root.join("posts/index.html")
+        );
+        assert_eq!(
+            resolve_path(root, "/posts/first.html").unwrap(),
+            root.join("posts/first.html")
+        );
+    }
+
+    #[test]
+    fn resolve_rejects_traversal() {
+        let root = Path::new("/nonexistent/templates");
+        assert_eq!(resolve_path(root, "/../secrets.db"), None);
+        assert_eq!(resolve_path(root, "/posts/../../secrets.db"), None);
+    }
+
+    #[test]
+    fn inject_before_body_end() {
+        let page = inject_reload_script("<body><p>hi</p></body>".to_owned(), 3);
+        assert!(page.starts_with("<body><p>hi</p><script>"));
+        assert!(page.ends_with("</script></body>"));
+        assert!(page.contains("const generation = 3;"));
+    }
+
+    #[test]
+    fn inject_without_body() {
+        let page = inject_reload_script("<p>hi</p>".to_owned(), 0);
+        assert!(page.starts_with("<p>hi</p><script>"));
+        assert!(page.ends_with("</script>"));
+    }
+}
@@ -0,0 +1,59 @@
+//! Diagnostics about a template and the output of its evaluation.
+//!
+//! Diagnostics don't stop an evaluation; they are returned with the
+//! [output of a render](crate::RenderOutput::diagnostics), and logged as warnings.
+
+use std::{borrow::Cow, fmt};
+
+use scraper::Html;
+
+use crate::{parse, EvaluateOptions};
+
+/// A problem found in the output of an evaluation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// The check that found the problem, e.g. `parse` or `img-alt`.
+    pub rule: &'static str,
+    /// A description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.rule, self.message)
+    }
+}
+
+/// Report the errors recovered from in parsing the template, in [lenient](EvaluateOptions::lenient)
+/// mode; and check the output of its evaluation, as a tree and as serialized, per the options.
+pub(crate) fn check(
+    parse_errors: &[Cow<'static, str>],
+    dom: &Html,
+    output: &str,
+    options: &EvaluateOptions,
+) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<_> = parse_errors
+        .iter()
+        .map(|error| Diagnostic {
+            rule: "parse",
+            message: format!("recovered from error in template: {}", error),
+        })
+        .collect();
+    if options.validate {
+        diagnostics.extend(
+            parse::output_errors(output, options)
+                .into_iter()
+                .map(|message| Diagnostic {
+                    rule: "parse",
+                    message,
+                }),
+        );
+    }
+    for lint in options.lints.iter() {
+        diagnostics.extend(lint(dom));
+    }
+    for diagnostic in &diagnostics {
+        tracing::warn!("{}", diagnostic);
+    }
+    diagnostics
+}
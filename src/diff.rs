@@ -0,0 +1,251 @@
+//! Structural comparison of HTML.
+
+use std::fmt;
+
+use ego_tree::NodeRef;
+use scraper::{Html, Node};
+
+/// A difference between two HTML fragments, found by [`html_diff`].
+///
+/// Each difference has the path to the node, from the top of the fragment: the name of each
+/// node (`#text` for text, `#comment` for comments) and its position among its siblings,
+/// counting from 1, e.g. `div[1] > p[2] > #text[1]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// A node that is wanted is missing.
+    Missing { path: String, want: String },
+    /// A node is present that is not wanted.
+    Extra { path: String, got: String },
+    /// Different kinds of node, or elements with different names, are at the same position.
+    Node {
+        path: String,
+        got: String,
+        want: String,
+    },
+    /// An attribute is missing (`got` is None), extra (`want` is None), or has a different value.
+    Attribute {
+        path: String,
+        name: String,
+        got: Option<String>,
+        want: Option<String>,
+    },
+    /// A text node or comment has different content.
+    Text {
+        path: String,
+        got: String,
+        want: String,
+    },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Missing { path, want } => write!(f, "{}: missing {}", path, want),
+            Difference::Extra { path, got } => write!(f, "{}: unexpected {}", path, got),
+            Difference::Node { path, got, want } => {
+                write!(f, "{}: got {}, want {}", path, got, want)
+            }
+            Difference::Attribute {
+                path,
+                name,
+                got,
+                want,
+            } => match (got, want) {
+                (None, Some(want)) => write!(f, "{}: missing attribute {}={:?}", path, name, want),
+                (Some(got), None) => write!(f, "{}: unexpected attribute {}={:?}", path, name, got),
+                _ => write!(
+                    f,
+                    "{}: attribute {} is {:?}, want {:?}",
+                    path,
+                    name,
+                    got.as_deref().unwrap_or_default(),
+                    want.as_deref().unwrap_or_default()
+                ),
+            },
+            Difference::Text { path, got, want } => {
+                write!(f, "{}: text is {:?}, want {:?}", path, got, want)
+            }
+        }
+    }
+}
+
+/// Compare two HTML fragments by structure, and list the differences between them:
+/// missing or extra nodes, differing elements or attributes, and differing text.
+///
+/// Both fragments are parsed, so differences in how the HTML is written
+/// (e.g. quoting of attributes, or the order of attributes) are not reported.
+/// An empty list means the fragments are equivalent.
+///
+/// ```
+/// let diffs = htmpl::html_diff(r#"<p class="a">hi</p>"#, r#"<p class="b">hi</p><hr>"#);
+/// assert_eq!(
+///     diffs.iter().map(ToString::to_string).collect::<Vec<_>>(),
+///     [r#"p[1]: attribute class is "a", want "b""#, "hr[2]: missing <hr>"],
+/// );
+/// ```
+pub fn html_diff(got: &str, want: &str) -> Vec<Difference> {
+    let got = Html::parse_fragment(got);
+    let want = Html::parse_fragment(want);
+    let mut diffs = Vec::new();
+    diff_children(fragment_root(&got), fragment_root(&want), "", &mut diffs);
+    diffs
+}
+
+/// The node whose children are the content of the fragment:
+/// the `html` element that the parser wraps the fragment in.
+fn fragment_root(h: &Html) -> NodeRef<'_, Node> {
+    h.tree
+        .root()
+        .first_child()
+        .filter(|n| n.value().is_element())
+        .unwrap_or(h.tree.root())
+}
+
+/// A name for the node in a path.
+fn node_name(node: &Node) -> &str {
+    match node {
+        Node::Element(e) => e.name(),
+        Node::Text(_) => "#text",
+        Node::Comment(_) => "#comment",
+        _ => "#node",
+    }
+}
+
+/// A short description of the node.
+fn describe(node: &Node) -> String {
+    match node {
+        Node::Element(e) => format!("<{}>", e.name()),
+        Node::Text(t) => format!("text {:?}", &**t),
+        Node::Comment(c) => format!("comment {:?}", &**c),
+        _ => "node".to_owned(),
+    }
+}
+
+fn diff_children(
+    got: NodeRef<Node>,
+    want: NodeRef<Node>,
+    parent: &str,
+    diffs: &mut Vec<Difference>,
+) {
+    let mut got_children = got.children();
+    let mut want_children = want.children();
+    for i in 1.. {
+        let (got, want) = (got_children.next(), want_children.next());
+        let Some(node) = want.or(got) else {
+            break;
+        };
+        let step = format!("{}[{}]", node_name(node.value()), i);
+        let path = match parent {
+            "" => step,
+            parent => format!("{} > {}", parent, step),
+        };
+        match (got, want) {
+            (Some(got), Some(want)) => diff_node(got, want, path, diffs),
+            (None, Some(want)) => diffs.push(Difference::Missing {
+                path,
+                want: describe(want.value()),
+            }),
+            (Some(got), None) => diffs.push(Difference::Extra {
+                path,
+                got: describe(got.value()),
+            }),
+            (None, None) => unreachable!("one of the nodes is present"),
+        }
+    }
+}
+
+fn diff_node(got: NodeRef<Node>, want: NodeRef<Node>, path: String, diffs: &mut Vec<Difference>) {
+    match (got.value(), want.value()) {
+        (Node::Element(g), Node::Element(w)) if g.name == w.name => {
+            let mut names: Vec<_> = g.attrs.keys().chain(w.attrs.keys()).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                let (g, w) = (g.attrs.get(name), w.attrs.get(name));
+                if g != w {
+                    diffs.push(Difference::Attribute {
+                        path: path.clone(),
+                        name: name.local.to_string(),
+                        got: g.map(|v| v.to_string()),
+                        want: w.map(|v| v.to_string()),
+                    });
+                }
+            }
+            diff_children(got, want, &path, diffs);
+        }
+        (Node::Text(g), Node::Text(w)) => {
+            if **g != **w {
+                diffs.push(Difference::Text {
+                    path,
+                    got: g.to_string(),
+                    want: w.to_string(),
+                });
+            }
+        }
+        (Node::Comment(g), Node::Comment(w)) => {
+            if **g != **w {
+                diffs.push(Difference::Text {
+                    path,
+                    got: g.to_string(),
+                    want: w.to_string(),
+                });
+            }
+        }
+        (g, w) => diffs.push(Difference::Node {
+            path,
+            got: describe(g),
+            want: describe(w),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{html_diff, Difference};
+
+    #[test]
+    fn equal() {
+        assert_eq!(
+            html_diff("<p class='a' id=x>hi</p>", r#"<p id="x" class="a">hi</p>"#),
+            []
+        );
+    }
+
+    #[test]
+    fn differences() {
+        let diffs = html_diff(
+            "<div><p>hi</p><b>x</b><!-- c --></div><span></span>",
+            r#"<div><p lang="en">bye</p><i>x</i><!-- d --></div>"#,
+        );
+        assert_eq!(
+            diffs,
+            [
+                Difference::Attribute {
+                    path: "div[1] > p[1]".to_owned(),
+                    name: "lang".to_owned(),
+                    got: None,
+                    want: Some("en".to_owned()),
+                },
+                Difference::Text {
+                    path: "div[1] > p[1] > #text[1]".to_owned(),
+                    got: "hi".to_owned(),
+                    want: "bye".to_owned(),
+                },
+                Difference::Node {
+                    path: "div[1] > i[2]".to_owned(),
+                    got: "<b>".to_owned(),
+                    want: "<i>".to_owned(),
+                },
+                Difference::Text {
+                    path: "div[1] > #comment[3]".to_owned(),
+                    got: " c ".to_owned(),
+                    want: " d ".to_owned(),
+                },
+                Difference::Extra {
+                    path: "span[2]".to_owned(),
+                    got: "<span>".to_owned(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Custom elements, provided by the application.
+
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use ego_tree::NodeMut;
+use scraper::{ElementRef, Node};
+
+use crate::{queries::Scope, visit::visit_recurse, Error};
+
+/// Evaluates an element in a template.
+///
+/// Handlers are registered by element name in the [options](crate::EvaluateOptions::element),
+/// and evaluate those elements in place of htmpl's default of copying them to the output.
+/// Any function with the signature of [`handle`](ElementHandler::handle) is a handler:
+///
+/// ```
+/// use ego_tree::NodeMut;
+/// use scraper::{node::Text, ElementRef, Node};
+///
+/// let shout = |element: ElementRef, _: &mut htmpl::Scope, output: &mut NodeMut<Node>| {
+///     let text = element.text().collect::<String>().to_uppercase();
+///     output.append(Node::Text(Text { text: text.into() }));
+///     Ok(())
+/// };
+/// let options = htmpl::EvaluateOptions::new().element("my-shout", shout);
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// let result = htmpl::evaluate_template_with("<p><my-shout>hi</my-shout></p>", &conn, &options);
+/// assert_eq!(result.unwrap(), "<p>HI</p>");
+/// ```
+pub trait ElementHandler: Send + Sync {
+    /// Evaluate the element, in the given scope.
+    ///
+    /// The handler appends any output to `output`;
+    /// to evaluate the element's children, use [`evaluate_children`].
+    fn handle(
+        &self,
+        element: ElementRef,
+        scope: &mut Scope,
+        output: &mut NodeMut<Node>,
+    ) -> Result<(), Error>;
+}
+
+impl<F> ElementHandler for F
+where
+    F: Fn(ElementRef, &mut Scope, &mut NodeMut<Node>) -> Result<(), Error> + Send + Sync,
+{
+    fn handle(
+        &self,
+        element: ElementRef,
+        scope: &mut Scope,
+        output: &mut NodeMut<Node>,
+    ) -> Result<(), Error> {
+        self(element, scope, output)
+    }
+}
+
+/// Evaluate the children of the element, in a new scope, appending the results to `output`.
+pub fn evaluate_children(
+    element: ElementRef,
+    scope: &mut Scope,
+    output: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let mut inner = scope.push();
+    for child in element.children() {
+        visit_recurse(&mut inner, child, output)?;
+    }
+    scope.adopt(inner);
+    Ok(())
+}
+
+/// Custom element handlers, by element name.
+#[derive(Clone, Default)]
+pub(crate) struct Elements(HashMap<String, Arc<dyn ElementHandler>>);
+
+impl Elements {
+    pub fn insert(&mut self, name: String, handler: Arc<dyn ElementHandler>) {
+        self.0.insert(name, handler);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn ElementHandler> {
+        self.0.get(name).map(Arc::as_ref)
+    }
+}
+
+impl fmt::Debug for Elements {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
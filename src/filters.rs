@@ -0,0 +1,84 @@
+//! Filters on values, as in `<htmpl-let name="title" value="q(name)|upper">`.
+
+use rusqlite::types::Value;
+
+use crate::{functions, Error};
+
+/// Split the filters off the end of the attribute's value: `q(name)|trim|upper` is
+/// the selector `q(name)`, then the filters `trim` and `upper`, in the order they apply.
+///
+/// Only words are taken as filters, so a `|` elsewhere (e.g. in a literal) is left alone.
+pub(crate) fn split(attr: &str) -> (&str, Vec<&str>) {
+    let mut rest = attr;
+    let mut filters = Vec::new();
+    while let Some((head, filter)) = rest.rsplit_once('|') {
+        let filter = filter.trim();
+        if filter.is_empty() || !filter.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            break;
+        }
+        filters.push(filter);
+        rest = head.trim_end();
+    }
+    filters.reverse();
+    (rest, filters)
+}
+
+/// Apply the named filter to the value. Nulls are passed through.
+pub(crate) fn apply(element: &'static str, filter: &str, value: Value) -> Result<Value, Error> {
+    let text = match &value {
+        Value::Null => return Ok(Value::Null),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) => f.to_string(),
+        Value::Text(t) => t.clone(),
+        Value::Blob(_) => {
+            return Err(Error::InvalidParameter(
+                element.into(),
+                format!("filter {} of a blob", filter),
+            ))
+        }
+    };
+    Ok(match filter {
+        "upper" => Value::Text(text.to_uppercase()),
+        "lower" => Value::Text(text.to_lowercase()),
+        "trim" => Value::Text(text.trim().to_owned()),
+        "slug" => Value::Text(functions::slug(&text)),
+        "urlencode" => Value::Text(functions::url_encode(&text)),
+        "length" => Value::Integer(text.chars().count() as i64),
+        _ => {
+            return Err(Error::InvalidParameter(
+                element.into(),
+                format!("unknown filter {:?}", filter),
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+
+    use super::{apply, split};
+
+    #[test]
+    fn splitting() {
+        assert_eq!(split("q(name)"), ("q(name)", vec![]));
+        assert_eq!(
+            split("q(name) | trim|upper"),
+            ("q(name)", vec!["trim", "upper"])
+        );
+        assert_eq!(split("'a|b c'"), ("'a|b c'", vec![]));
+        assert_eq!(split("'a|b'|length"), ("'a|b'", vec!["length"]));
+    }
+
+    #[test]
+    fn filters() {
+        let text = |s: &str| Value::Text(s.to_owned());
+        let apply = |filter, value| apply("htmpl-let", filter, value).unwrap();
+        assert_eq!(apply("upper", text("Hi")), text("HI"));
+        assert_eq!(apply("slug", text("My First Post!")), text("my-first-post"));
+        assert_eq!(apply("urlencode", text("a b")), text("a%20b"));
+        assert_eq!(apply("length", Value::Integer(1234)), Value::Integer(4));
+        assert_eq!(apply("lower", Value::Null), Value::Null);
+        assert!(super::apply("htmpl-let", "reverse", text("x")).is_err());
+    }
+}
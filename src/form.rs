@@ -0,0 +1,205 @@
+//! Validation of submitted form fields, into [parameters](crate::Params) for a template.
+//!
+//! A [`Form`] lists the fields a template expects, with the rules for each.
+//! [Checking](Form::check) submitted data against it gives parameters for every field:
+//!
+//! - `{field}`: the value, as an integer or number if the field is one;
+//!   or, if the value is invalid, the text as submitted, so the form can show it again.
+//!   Missing fields are null.
+//! - `{field}_error`: why the value is invalid, e.g. `required`; or null, if it is valid.
+//! - `errors`: the number of invalid fields.
+//!
+//! Submitted fields that aren't in the form are dropped.
+
+use rusqlite::types::Value;
+
+use crate::Params;
+
+/// The type of a form field's value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum FieldType {
+    #[default]
+    Text,
+    Integer,
+    Real,
+}
+
+/// The rules for a form field.
+#[derive(Debug, Clone, Default)]
+pub struct Field {
+    kind: FieldType,
+    required: bool,
+    max_len: Option<usize>,
+}
+
+impl Field {
+    /// A text field.
+    pub fn text() -> Self {
+        Default::default()
+    }
+
+    /// A field whose value is an integer, e.g. `42`.
+    pub fn integer() -> Self {
+        Field {
+            kind: FieldType::Integer,
+            ..Default::default()
+        }
+    }
+
+    /// A field whose value is a number, e.g. `4.2`.
+    pub fn real() -> Self {
+        Field {
+            kind: FieldType::Real,
+            ..Default::default()
+        }
+    }
+
+    /// Require a value: a missing or empty field is invalid.
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    /// Limit the length of the value, in characters.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    /// The value of the field, or why the submitted text is invalid.
+    fn check(&self, text: Option<&str>) -> Result<Value, String> {
+        let text = match text {
+            Some(text) if !text.trim().is_empty() => text,
+            _ if self.required => return Err("required".to_owned()),
+            _ => return Ok(Value::Null),
+        };
+        if let Some(max) = self.max_len {
+            if text.chars().count() > max {
+                return Err(format!("must be at most {} characters", max));
+            }
+        }
+        match self.kind {
+            FieldType::Text => Ok(Value::Text(text.to_owned())),
+            FieldType::Integer => text
+                .trim()
+                .parse()
+                .map(Value::Integer)
+                .map_err(|_| "must be an integer".to_owned()),
+            FieldType::Real => text
+                .trim()
+                .parse()
+                .map(Value::Real)
+                .map_err(|_| "must be a number".to_owned()),
+        }
+    }
+}
+
+/// The fields of a form, per the [module docs](self).
+///
+/// ```
+/// let form = htmpl::Form::new()
+///     .field("title", htmpl::Field::text().required().max_len(80))
+///     .field("stars", htmpl::Field::integer());
+/// let params = form.check([("title", "Hello"), ("stars", "many")]);
+/// assert_eq!(params.get("errors"), Some(&rusqlite::types::Value::Integer(1)));
+/// assert_eq!(
+///     params.get("stars_error"),
+///     Some(&rusqlite::types::Value::Text("must be an integer".to_owned()))
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Form {
+    fields: Vec<(String, Field)>,
+}
+
+impl Form {
+    /// Create a form without any fields.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a field to the form.
+    pub fn field(mut self, name: impl Into<String>, field: Field) -> Self {
+        self.fields.push((name.into(), field));
+        self
+    }
+
+    /// Check the submitted data, e.g. a parsed form body or query string, against the fields.
+    ///
+    /// If a field is submitted more than once, the last value is used.
+    pub fn check<K: AsRef<str>, V: AsRef<str>>(
+        &self,
+        data: impl IntoIterator<Item = (K, V)>,
+    ) -> Params {
+        let mut submitted: Vec<(String, String)> = Vec::new();
+        for (name, value) in data {
+            let name = name.as_ref();
+            if self.fields.iter().any(|(field, _)| field == name) {
+                submitted.push((name.to_owned(), value.as_ref().to_owned()));
+            }
+        }
+        let mut params = Params::new();
+        let mut errors = 0;
+        for (name, field) in self.fields.iter() {
+            let text = submitted
+                .iter()
+                .rev()
+                .find(|(submitted, _)| submitted == name)
+                .map(|(_, text)| text.as_str());
+            let (value, error) = match field.check(text) {
+                Ok(value) => (value, Value::Null),
+                Err(error) => {
+                    errors += 1;
+                    let text = text.map_or(Value::Null, |text| Value::Text(text.to_owned()));
+                    (text, Value::Text(error))
+                }
+            };
+            params.insert(name.as_str(), value);
+            params.insert(format!("{}_error", name), error);
+        }
+        params.insert("errors", errors);
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+
+    use super::{Field, Form};
+
+    #[test]
+    fn check() {
+        let form = Form::new()
+            .field("name", Field::text().required().max_len(5))
+            .field("age", Field::integer())
+            .field("score", Field::real().required());
+        let params = form.check([
+            ("name", "Ada"),
+            ("age", " 36 "),
+            ("score", "9.5"),
+            ("admin", "1"),
+        ]);
+        assert_eq!(params.get("name"), Some(&Value::Text("Ada".to_owned())));
+        assert_eq!(params.get("age"), Some(&Value::Integer(36)));
+        assert_eq!(params.get("score"), Some(&Value::Real(9.5)));
+        assert_eq!(params.get("name_error"), Some(&Value::Null));
+        assert_eq!(params.get("errors"), Some(&Value::Integer(0)));
+        assert_eq!(params.get("admin"), None);
+
+        let params = form.check([("name", "Barbara"), ("age", ""), ("age", "x")]);
+        let error = |name: &str| params.get(format!("{}_error", name)).cloned();
+        assert_eq!(
+            error("name"),
+            Some(Value::Text("must be at most 5 characters".to_owned()))
+        );
+        assert_eq!(params.get("name"), Some(&Value::Text("Barbara".to_owned())));
+        assert_eq!(
+            error("age"),
+            Some(Value::Text("must be an integer".to_owned()))
+        );
+        assert_eq!(error("score"), Some(Value::Text("required".to_owned())));
+        assert_eq!(params.get("score"), Some(&Value::Null));
+        assert_eq!(params.get("errors"), Some(&Value::Integer(3)));
+    }
+}
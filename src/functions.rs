@@ -0,0 +1,138 @@
+//! SQL functions provided by the application, for use in template queries.
+
+use std::os::raw::c_int;
+
+use rusqlite::{
+    functions::{Context, FunctionFlags, SqlFnOutput},
+    Connection,
+};
+
+use crate::{
+    markdown,
+    queries::{self, DbTable},
+    Error, EvaluateOptions,
+};
+
+/// Registers SQL functions on the connection used to evaluate a template.
+///
+/// Only function registration is available, so setup can't write to the database.
+///
+/// ```
+/// use rusqlite::functions::FunctionFlags;
+///
+/// let options = htmpl::EvaluateOptions::new().sql_functions(|functions| {
+///     functions.create_scalar_function("shout", 1, FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+///         Ok(ctx.get::<String>(0)?.to_uppercase())
+///     })
+/// });
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// let result = htmpl::evaluate_template_with(
+///     r#"<htmpl-query name="q">SELECT shout("hi");</htmpl-query><htmpl-insert query="q"></htmpl-insert>"#,
+///     &conn,
+///     &options,
+/// );
+/// assert_eq!(result.unwrap(), "HI");
+/// ```
+pub struct SqlFunctions<'a>(&'a Connection);
+
+impl SqlFunctions<'_> {
+    /// Register a scalar function, as [`Connection::create_scalar_function`].
+    pub fn create_scalar_function<F, T>(
+        &self,
+        name: &str,
+        n_arg: c_int,
+        flags: FunctionFlags,
+        f: F,
+    ) -> rusqlite::Result<()>
+    where
+        F: FnMut(&Context<'_>) -> rusqlite::Result<T> + Send + 'static,
+        T: SqlFnOutput,
+    {
+        self.0.create_scalar_function(name, n_arg, flags, f)
+    }
+}
+
+/// Register the application's SQL functions on the connection,
+/// and [attach](EvaluateOptions::attach) its databases.
+pub(crate) fn setup(dbs: &DbTable, options: &EvaluateOptions) -> Result<(), Error> {
+    let functions = SqlFunctions(dbs);
+    for register in options.sql_functions.iter() {
+        register(&functions).map_err(|e| Error::Sql("(functions)".to_owned(), e))?;
+    }
+    queries::attach(dbs, options)
+}
+
+/// Register htmpl's helper functions, for use with [`EvaluateOptions::sql_functions`]:
+///
+/// - `HTMPL_SLUG(text)`: a URL-friendly version of the text, e.g. `"hello-world"` for `"Hello, World!"`
+/// - `HTMPL_URLENC(text)`: the text, percent-encoded for use in a URL path segment or query string
+/// - `HTMPL_MARKDOWN(text)`: the text, rendered from (a subset of) Markdown to HTML,
+///   for an [`htmpl-insert` with the `html` attribute](crate#htmpl-insert).
+///   Any HTML in the text is escaped, and links with script-bearing schemes are dropped.
+///
+/// Each function returns NULL if its text argument is NULL.
+///
+/// ```
+/// let options = htmpl::EvaluateOptions::new().sql_functions(htmpl::builtin_functions);
+/// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+/// let result = htmpl::evaluate_template_with(
+///     r#"<htmpl-query name="q">SELECT HTMPL_SLUG("My First Post!");</htmpl-query><htmpl-insert query="q"></htmpl-insert>"#,
+///     &conn,
+///     &options,
+/// );
+/// assert_eq!(result.unwrap(), "my-first-post");
+/// ```
+pub fn builtin_functions(functions: &SqlFunctions) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    functions.create_scalar_function("HTMPL_SLUG", 1, flags, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|s| slug(&s)))
+    })?;
+    functions.create_scalar_function("HTMPL_URLENC", 1, flags, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|s| url_encode(&s)))
+    })?;
+    functions.create_scalar_function("HTMPL_MARKDOWN", 1, flags, |ctx| {
+        Ok(ctx.get::<Option<String>>(0)?.map(|s| markdown::render(&s)))
+    })
+}
+
+/// Lowercase the text, and join its alphanumeric runs with hyphens.
+pub(crate) fn slug(s: &str) -> String {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Percent-encode all but the unreserved characters of RFC 3986.
+pub(crate) fn url_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{slug, url_encode};
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("Hello, World!"), "hello-world");
+        assert_eq!(slug("  already-a-slug "), "already-a-slug");
+        assert_eq!(slug("Ünïcode Wörds"), "ünïcode-wörds");
+        assert_eq!(slug("!!!"), "");
+    }
+
+    #[test]
+    fn url_encoding() {
+        assert_eq!(url_encode("a b&c=d/é"), "a%20b%26c%3Dd%2F%C3%A9");
+        assert_eq!(url_encode("safe-._~"), "safe-._~");
+    }
+}
@@ -0,0 +1,125 @@
+//! Document head management: `htmpl-title` and `htmpl-meta`.
+//!
+//! These elements can appear anywhere in a template, e.g. in the fragment for one page;
+//! they are evaluated in place into markers, which are moved to the head of the document
+//! after evaluation. A marker replaces the document's existing `<title>`, or `<meta>` with the
+//! same `name`, `property`, or `http-equiv`; otherwise it is added to the `<head>` element,
+//! if the output has one, or at the start of the output.
+
+use ego_tree::{NodeId, Tree};
+use html5ever::{local_name, namespace_url, ns, QualName};
+use scraper::Node;
+
+/// The attributes that identify a `meta` element.
+const META_KEYS: &[&str] = &["name", "property", "http-equiv", "charset"];
+
+/// Returns the name of the element at the node, if it is an element.
+fn element_name(tree: &Tree<Node>, id: NodeId) -> Option<&str> {
+    tree.get(id)?.value().as_element().map(|e| e.name())
+}
+
+/// The first element in the tree matching the predicate.
+fn find(tree: &Tree<Node>, f: impl Fn(&scraper::node::Element) -> bool) -> Option<NodeId> {
+    tree.root()
+        .descendants()
+        .find(|n| n.value().as_element().is_some_and(&f))
+        .map(|n| n.id())
+}
+
+/// The identifying attribute of a `meta` element, and its value.
+fn meta_key(element: &scraper::node::Element) -> Option<(&'static str, &str)> {
+    META_KEYS
+        .iter()
+        .find_map(|key| element.attr(key).map(|value| (*key, value)))
+}
+
+/// Move `htmpl-title` and `htmpl-meta` markers to the head of the document.
+pub(crate) fn hoist(tree: &mut Tree<Node>) {
+    let markers: Vec<NodeId> = tree
+        .root()
+        .descendants()
+        .filter(|n| {
+            n.value()
+                .as_element()
+                .is_some_and(|e| matches!(e.name(), "htmpl-title" | "htmpl-meta"))
+        })
+        .map(|n| n.id())
+        .collect();
+    // New head elements go after the last one added, to keep them in order.
+    let mut last: Option<NodeId> = None;
+    for id in markers {
+        let Some(mut marker) = tree.get_mut(id) else {
+            continue;
+        };
+        marker.detach();
+        let is_title = element_name(tree, id) == Some("htmpl-title");
+        let existing = if is_title {
+            find(tree, |e| e.name() == "title")
+        } else {
+            let marker = tree.get(id).and_then(|n| n.value().as_element());
+            let key = marker.and_then(meta_key);
+            key.and_then(|(key, value)| {
+                find(tree, |e| e.name() == "meta" && e.attr(key) == Some(value))
+            })
+        };
+        let target = match existing {
+            Some(existing) => existing,
+            None => {
+                let name = if is_title {
+                    local_name!("title")
+                } else {
+                    local_name!("meta")
+                };
+                let element = Node::Element(scraper::node::Element::new(
+                    QualName::new(None, ns!(html), name),
+                    vec![],
+                ));
+                let new = insert(tree, last, element);
+                last = Some(new);
+                new
+            }
+        };
+        if is_title {
+            let Some(mut title) = tree.get_mut(target) else {
+                continue;
+            };
+            while let Some(mut child) = title.first_child() {
+                child.detach();
+            }
+            title.reparent_from_id_append(id);
+        } else {
+            let attrs = tree
+                .get(id)
+                .and_then(|n| n.value().as_element())
+                .map(|e| e.attrs.clone())
+                .unwrap_or_default();
+            if let Some(mut meta) = tree.get_mut(target) {
+                if let Node::Element(meta) = meta.value() {
+                    meta.attrs.extend(attrs);
+                }
+            }
+        }
+    }
+}
+
+/// Insert the head element: after the last one inserted, or at the end of the `<head>`,
+/// or at the start of the output.
+fn insert(tree: &mut Tree<Node>, last: Option<NodeId>, node: Node) -> NodeId {
+    if let Some(mut last) = last.and_then(|last| tree.get_mut(last)) {
+        return last.insert_after(node).id();
+    }
+    if let Some(mut head) = find(tree, |e| e.name() == "head").and_then(|h| tree.get_mut(h)) {
+        return head.append(node).id();
+    }
+    // The output is either a fragment (under an `html` element)
+    // or a document (under its document element).
+    let top = tree
+        .root()
+        .descendants()
+        .find(|n| n.value().is_element())
+        .map(|n| n.id());
+    match top.and_then(|top| tree.get_mut(top)) {
+        Some(mut top) => top.prepend(node).id(),
+        None => tree.root_mut().prepend(node).id(),
+    }
+}
@@ -0,0 +1,126 @@
+//! Callbacks around the queries in a template.
+
+use std::{collections::HashMap, fmt, sync::Arc, time::Duration};
+
+use rusqlite::types::Value;
+
+use crate::{Diagnostic, Error, SqlFunctions};
+
+/// A query about to be run, or just run, by an `htmpl-query` element.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryInfo<'a> {
+    /// The name the results are bound to.
+    pub name: &'a str,
+    /// The SQL of the query.
+    pub sql: &'a str,
+    /// The parameters bound in the query, by name (including the `:` prefix).
+    pub params: &'a [(&'a str, &'a Value)],
+}
+
+/// The outcome of a query, as reported to [`after_query`](crate::EvaluateOptions::after_query) hooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryStats {
+    /// How long the query took to run, including fetching all rows.
+    pub duration: Duration,
+    /// How many rows the query returned; None if the query failed.
+    pub rows: Option<usize>,
+}
+
+/// A query that was run by an `htmpl-query` element, as reported to
+/// [`on_query`](crate::EvaluateOptions::on_query) callbacks.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryEvent<'a> {
+    /// The name the results are bound to.
+    pub name: &'a str,
+    /// The SQL of the query.
+    pub sql: &'a str,
+    /// The parameters bound in the query, by name (including the `:` prefix).
+    pub params: &'a [(&'a str, &'a Value)],
+    /// How long the query took to run, including fetching all rows.
+    pub duration: Duration,
+    /// How many rows the query returned; None if the query failed.
+    pub rows: Option<usize>,
+    /// The error from the query, if it failed.
+    pub error: Option<&'a rusqlite::Error>,
+}
+
+/// A callback before each query.
+pub(crate) type BeforeQuery = dyn Fn(&QueryInfo) + Send + Sync;
+/// A callback after each query.
+pub(crate) type AfterQuery = dyn Fn(&QueryInfo, &QueryStats) + Send + Sync;
+/// A callback with the outcome of each query.
+pub(crate) type OnQuery = dyn Fn(&QueryEvent) + Send + Sync;
+/// A rewrite of the SQL of a query, given the query's name.
+pub(crate) type RewriteQuery = dyn Fn(&str, &str) -> String + Send + Sync;
+
+/// A transformation of each row of a query's results, given the query's name.
+pub(crate) type TransformRow =
+    dyn Fn(&str, &mut HashMap<String, Value>) -> Result<(), Error> + Send + Sync;
+
+/// Registration of SQL functions.
+pub(crate) type RegisterFunctions = dyn Fn(&SqlFunctions) -> rusqlite::Result<()> + Send + Sync;
+
+/// A transformation of the output tree, before it is serialized.
+pub(crate) type PostProcess = dyn Fn(&mut scraper::Html) + Send + Sync;
+
+/// A check of the output tree.
+pub(crate) type Lint = dyn Fn(&scraper::Html) -> Vec<Diagnostic> + Send + Sync;
+
+/// A generator of CSRF tokens.
+pub(crate) type CsrfToken = dyn Fn() -> String + Send + Sync;
+
+/// A single callback.
+pub(crate) struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Callback<F> {
+    pub fn new(callback: Arc<F>) -> Self {
+        Callback(callback)
+    }
+
+    pub fn get(&self) -> &F {
+        &self.0
+    }
+}
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Callback(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<callback>")
+    }
+}
+
+/// A list of callbacks.
+pub(crate) struct Hooks<F: ?Sized>(Vec<Arc<F>>);
+
+impl<F: ?Sized> Hooks<F> {
+    pub fn push(&mut self, hook: Arc<F>) {
+        self.0.push(hook)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &F> {
+        self.0.iter().map(Arc::as_ref)
+    }
+}
+
+impl<F: ?Sized> Default for Hooks<F> {
+    fn default() -> Self {
+        Hooks(Vec::new())
+    }
+}
+
+impl<F: ?Sized> Clone for Hooks<F> {
+    fn clone(&self) -> Self {
+        Hooks(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hooks<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{} hooks]", self.0.len())
+    }
+}
@@ -0,0 +1,553 @@
+//! Message catalogs, for translating templates with `htmpl-t`.
+//!
+//! Catalogs are written in a subset of [Fluent](https://projectfluent.org/):
+//!
+//! ```ftl
+//! # Comments start with a hash.
+//! greeting = Hello, { $name }!
+//! farewell =
+//!     Goodbye for now;
+//!     see you soon.
+//! ```
+//!
+//! Each message is a key, an `=`, and the message, which may continue on following
+//! indented lines. In a message, `{ $name }` is replaced by the value of the `name` variable,
+//! and `{ "..." }` by the literal text, e.g. `{ "{" }` for a brace.
+//!
+//! A select expression chooses between variants by the value of a variable:
+//!
+//! ```ftl
+//! items =
+//!     { $count ->
+//!         [0] No items
+//!         [one] One item
+//!        *[other] { $count } items
+//!     }
+//! ```
+//!
+//! A variant whose key is exactly the value (e.g. `[0]`, or `[admin]` for text) is chosen first;
+//! then, for numbers, the variant named for the number's
+//! [CLDR plural category](https://cldr.unicode.org/index/cldr-spec/plural-rules)
+//! in the catalog's locale (`zero`, `one`, `two`, `few`, `many`, or `other`);
+//! otherwise, the default variant, marked with `*`.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use rusqlite::types::Value;
+
+use crate::Error;
+
+/// A part of a message.
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Variable(String),
+    /// A choice of patterns, by the value of the variable, per the [module docs](self).
+    Select {
+        variable: String,
+        variants: Vec<(String, Vec<Part>)>,
+        default: usize,
+    },
+}
+
+/// Messages for a single locale, by key.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    locale: String,
+    messages: HashMap<String, Vec<Part>>,
+}
+
+impl Catalog {
+    /// Create an empty catalog for the locale, e.g. `en-US`.
+    pub fn new(locale: impl Into<String>) -> Self {
+        Catalog {
+            locale: locale.into(),
+            messages: HashMap::new(),
+        }
+    }
+
+    /// Parse a catalog for the locale from its source, per the [module docs](self).
+    pub fn parse(locale: impl Into<String>, source: &str) -> Result<Self, Error> {
+        let mut catalog = Catalog::new(locale);
+        let mut current: Option<(String, String, usize)> = None;
+        for (i, line) in source.lines().enumerate() {
+            let n = i + 1;
+            let trimmed = line.trim();
+            if line.starts_with(char::is_whitespace) && !trimmed.is_empty() {
+                match &mut current {
+                    Some((_, message, _)) => {
+                        if !message.is_empty() {
+                            message.push('\n');
+                        }
+                        message.push_str(trimmed);
+                    }
+                    None => {
+                        return Err(Error::CatalogParse(format!(
+                            "line {}: continuation without a message",
+                            n
+                        )))
+                    }
+                }
+                continue;
+            }
+            if let Some((key, message, start)) = current.take() {
+                catalog.insert_at(key, &message, start)?;
+            }
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let Some((key, message)) = line.split_once('=') else {
+                return Err(Error::CatalogParse(format!(
+                    "line {}: expected `key = message`",
+                    n
+                )));
+            };
+            let key = key.trim();
+            if !is_identifier(key) {
+                return Err(Error::CatalogParse(format!(
+                    "line {}: invalid key {:?}",
+                    n, key
+                )));
+            }
+            current = Some((key.to_owned(), message.trim().to_owned(), n));
+        }
+        if let Some((key, message, start)) = current {
+            catalog.insert_at(key, &message, start)?;
+        }
+        Ok(catalog)
+    }
+
+    /// Read and [parse](Self::parse) a catalog file.
+    pub fn load(locale: impl Into<String>, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let source =
+            fs::read_to_string(path).map_err(|e| Error::Asset(path.display().to_string(), e))?;
+        Self::parse(locale, &source)
+    }
+
+    /// Add a message to the catalog, replacing any message with the same key.
+    pub fn insert(&mut self, key: impl Into<String>, message: &str) -> Result<(), Error> {
+        let key = key.into();
+        let parts =
+            parse_message(message).map_err(|e| Error::CatalogParse(format!("{}: {}", key, e)))?;
+        self.messages.insert(key, parts);
+        Ok(())
+    }
+
+    fn insert_at(&mut self, key: String, message: &str, line: usize) -> Result<(), Error> {
+        let parts = parse_message(message)
+            .map_err(|e| Error::CatalogParse(format!("line {}: {}", line, e)))?;
+        self.messages.insert(key, parts);
+        Ok(())
+    }
+
+    /// The locale of the messages.
+    pub fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    /// Whether the catalog has a message with the key.
+    pub fn contains(&self, key: &str) -> bool {
+        self.messages.contains_key(key)
+    }
+
+    /// Format the message with the key, with the given variables;
+    /// None if there is no such message.
+    pub(crate) fn format(
+        &self,
+        key: &str,
+        variables: &HashMap<String, Value>,
+        format_value: impl Fn(&Value) -> String,
+    ) -> Option<Result<String, Error>> {
+        let parts = self.messages.get(key)?;
+        let mut output = String::new();
+        Some(
+            self.format_parts(parts, variables, &format_value, &mut output)
+                .map(|()| output),
+        )
+    }
+
+    fn format_parts(
+        &self,
+        parts: &[Part],
+        variables: &HashMap<String, Value>,
+        format_value: &impl Fn(&Value) -> String,
+        output: &mut String,
+    ) -> Result<(), Error> {
+        let get = |name: &str| {
+            variables
+                .get(name)
+                .ok_or_else(|| Error::MissingParameter("htmpl-t".into(), format!("${}", name)))
+        };
+        for part in parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Variable(name) => output.push_str(&format_value(get(name)?)),
+                Part::Select {
+                    variable,
+                    variants,
+                    default,
+                } => {
+                    let value = get(variable)?;
+                    let category = plural_category(&self.locale, value);
+                    let (_, pattern) = variants
+                        .iter()
+                        .find(|(key, _)| matches_key(key, value))
+                        .or_else(|| {
+                            variants
+                                .iter()
+                                .find(|(key, _)| Some(key.as_str()) == category)
+                        })
+                        .unwrap_or(&variants[*default]);
+                    self.format_parts(pattern, variables, format_value, output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the variant key is (exactly) the value, e.g. `[0]` for 0, or `[admin]` for "admin".
+fn matches_key(key: &str, value: &Value) -> bool {
+    match value {
+        Value::Integer(i) => key.parse::<i64>() == Ok(*i),
+        Value::Real(f) => key.parse::<f64>() == Ok(*f),
+        Value::Text(t) => key == t,
+        Value::Null | Value::Blob(_) => false,
+    }
+}
+
+/// The [CLDR plural category](https://cldr.unicode.org/index/cldr-spec/plural-rules)
+/// of the number in the locale's language: `zero`, `one`, `two`, `few`, `many`, or `other`.
+///
+/// None if the value is not a number. Fractional numbers are `other`.
+pub(crate) fn plural_category(locale: &str, value: &Value) -> Option<&'static str> {
+    let n = match value {
+        Value::Integer(i) => *i,
+        Value::Real(f) if f.fract() == 0.0 => *f as i64,
+        Value::Real(_) => return Some("other"),
+        _ => return None,
+    };
+    let n = n.unsigned_abs();
+    let language = locale
+        .split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let (n10, n100) = (n % 10, n % 100);
+    let category = match language.as_str() {
+        "ja" | "zh" | "ko" | "vi" | "th" | "id" | "ms" | "lo" | "my" | "km" => "other",
+        "fr" | "pt" | "hi" | "bn" | "fa" | "gu" | "kn" | "zu" | "am" => match n {
+            0 | 1 => "one",
+            _ => "other",
+        },
+        "ru" | "uk" | "be" | "sr" | "hr" | "bs" => match (n10, n100) {
+            (1, _) if n100 != 11 => "one",
+            (2..=4, _) if !(12..=14).contains(&n100) => "few",
+            _ if matches!(language.as_str(), "sr" | "hr" | "bs") => "other",
+            _ => "many",
+        },
+        "pl" => match (n, n10, n100) {
+            (1, _, _) => "one",
+            (_, 2..=4, _) if !(12..=14).contains(&n100) => "few",
+            _ => "many",
+        },
+        "cs" | "sk" => match n {
+            1 => "one",
+            2..=4 => "few",
+            _ => "other",
+        },
+        "ar" => match (n, n100) {
+            (0, _) => "zero",
+            (1, _) => "one",
+            (2, _) => "two",
+            (_, 3..=10) => "few",
+            (_, 11..=99) => "many",
+            _ => "other",
+        },
+        "he" | "iw" => match n {
+            1 => "one",
+            2 => "two",
+            _ => "other",
+        },
+        _ => match n {
+            1 => "one",
+            _ => "other",
+        },
+    };
+    Some(category)
+}
+
+/// Whether the string is a valid message or variable name.
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Parse a message into text and placeables.
+fn parse_message(message: &str) -> Result<Vec<Part>, String> {
+    Parser { s: message, pos: 0 }.pattern(false)
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        let found = self.s[self.pos..].starts_with(prefix);
+        if found {
+            self.pos += prefix.len();
+        }
+        found
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Take characters up to (not including) the first that doesn't match.
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &str {
+        let rest = &self.s[self.pos..];
+        let len = rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    /// Parse text and placeables, up to the end of the message;
+    /// or, for the value of a variant, up to the end of the line or the end of the select.
+    fn pattern(&mut self, variant: bool) -> Result<Vec<Part>, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        loop {
+            match self.peek() {
+                None => break,
+                Some('\n' | '}') if variant => break,
+                Some('}') => return Err("unmatched `}`".to_owned()),
+                Some('{') => {
+                    self.pos += 1;
+                    match self.placeable()? {
+                        Part::Text(literal) => text.push_str(&literal),
+                        part => {
+                            if !text.is_empty() {
+                                parts.push(Part::Text(std::mem::take(&mut text)));
+                            }
+                            parts.push(part);
+                        }
+                    }
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.pos += c.len_utf8();
+                }
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(parts)
+    }
+
+    /// Parse a placeable, after its opening brace.
+    fn placeable(&mut self) -> Result<Part, String> {
+        self.skip_whitespace();
+        let part = if self.eat("\"") {
+            let literal = self.take_while(|c| c != '"').to_owned();
+            if !self.eat("\"") {
+                return Err("unterminated string literal".to_owned());
+            }
+            Part::Text(literal)
+        } else if self.eat("$") {
+            let name = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+            if !is_identifier(name) {
+                return Err(format!("invalid variable {:?}", format!("${}", name)));
+            }
+            Part::Variable(name.to_owned())
+        } else {
+            return Err("expected a variable or string literal".to_owned());
+        };
+        self.skip_whitespace();
+        match part {
+            _ if self.eat("}") => Ok(part),
+            Part::Variable(variable) if self.eat("->") => self.select(variable),
+            _ if self.peek().is_none() => Err("unterminated `{`".to_owned()),
+            _ => Err("expected `}`".to_owned()),
+        }
+    }
+
+    /// Parse the variants of a select expression, after its `->`.
+    fn select(&mut self, variable: String) -> Result<Part, String> {
+        let mut variants: Vec<(String, Vec<Part>)> = Vec::new();
+        let mut default = None;
+        loop {
+            self.skip_whitespace();
+            if self.eat("}") {
+                break;
+            }
+            let is_default = self.eat("*");
+            if !self.eat("[") {
+                return Err(format!("expected a variant of ${}", variable));
+            }
+            let key = self.take_while(|c| c != ']' && c != '\n').trim().to_owned();
+            if key.is_empty() || !self.eat("]") {
+                return Err(format!("invalid variant key of ${}", variable));
+            }
+            if is_default {
+                if default.is_some() {
+                    return Err(format!("several default variants of ${}", variable));
+                }
+                default = Some(variants.len());
+            }
+            let mut pattern = self.pattern(true)?;
+            if let Some(Part::Text(text)) = pattern.first_mut() {
+                *text = text.trim_start().to_owned();
+            }
+            if let Some(Part::Text(text)) = pattern.last_mut() {
+                *text = text.trim_end().to_owned();
+            }
+            variants.push((key, pattern));
+        }
+        let default = default.ok_or_else(|| format!("no default variant of ${}", variable))?;
+        Ok(Part::Select {
+            variable,
+            variants,
+            default,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rusqlite::types::Value;
+
+    use super::{plural_category, Catalog};
+    use crate::Error;
+
+    fn format(catalog: &Catalog, key: &str, variables: &[(&str, Value)]) -> String {
+        let variables: HashMap<String, Value> = variables
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        catalog
+            .format(key, &variables, |v| format!("{:?}", v))
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let catalog = Catalog::parse(
+            "en",
+            "# A comment\n\
+            greeting = Hello, { $name }!\n\
+            \n\
+            farewell =\n    Goodbye { \"{\" }for now{ \"}\" };\n    see you soon.\n",
+        )
+        .unwrap();
+        assert_eq!(catalog.locale(), "en");
+        assert_eq!(
+            format(&catalog, "greeting", &[("name", Value::Integer(7))]),
+            "Hello, Integer(7)!"
+        );
+        assert_eq!(
+            format(&catalog, "farewell", &[]),
+            "Goodbye {for now};\nsee you soon."
+        );
+        assert!(!catalog.contains("missing"));
+    }
+
+    #[test]
+    fn errors() {
+        for source in [
+            "greeting",
+            "  continued",
+            "bad key = hi",
+            "a = { $ }",
+            "a = {",
+            "a = }",
+        ] {
+            assert!(
+                matches!(Catalog::parse("en", source), Err(Error::CatalogParse(_))),
+                "{}",
+                source
+            );
+        }
+        for source in [
+            "a = { $n -> [one] x }",
+            "a = { $n ->\n*[one] x\n*[other] y\n}",
+            "a = { $n ->\n[] x\n}",
+            "a = { $n ->\n*[other] x",
+        ] {
+            assert!(
+                matches!(Catalog::parse("en", source), Err(Error::CatalogParse(_))),
+                "{}",
+                source
+            );
+        }
+        let catalog = Catalog::parse("en", "a = { $b }").unwrap();
+        assert_eq!(
+            catalog.format("a", &HashMap::new(), |_| String::new()),
+            Some(Err(Error::MissingParameter(
+                "htmpl-t".into(),
+                "$b".to_owned()
+            )))
+        );
+    }
+
+    #[test]
+    fn plurals() {
+        const ITEMS: &str = "items =\n    { $count ->\n        [0] No items\n        [one] One item\n        [few] { $count } items (few)\n        [many] { $count } items (many)\n       *[other] { $count } items\n    }";
+        let en = Catalog::parse("en-US", ITEMS).unwrap();
+        let ru = Catalog::parse("ru", ITEMS).unwrap();
+        let count = |catalog: &Catalog, n: Value| {
+            let variables = HashMap::from([("count".to_owned(), n)]);
+            catalog
+                .format("items", &variables, |v| match v {
+                    Value::Integer(i) => i.to_string(),
+                    Value::Real(f) => f.to_string(),
+                    _ => String::new(),
+                })
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(count(&en, Value::Integer(0)), "No items");
+        assert_eq!(count(&en, Value::Integer(1)), "One item");
+        assert_eq!(count(&en, Value::Integer(3)), "3 items");
+        assert_eq!(count(&en, Value::Real(1.5)), "1.5 items");
+        assert_eq!(count(&ru, Value::Integer(21)), "One item");
+        assert_eq!(count(&ru, Value::Integer(22)), "22 items (few)");
+        assert_eq!(count(&ru, Value::Integer(12)), "12 items (many)");
+
+        let role = Catalog::parse(
+            "en",
+            "role = { $role ->\n  [admin] Administrator\n *[other] Member\n }",
+        )
+        .unwrap();
+        let variables = HashMap::from([("role".to_owned(), Value::Text("admin".to_owned()))]);
+        assert_eq!(
+            role.format("role", &variables, |_| String::new()),
+            Some(Ok("Administrator".to_owned()))
+        );
+    }
+
+    #[test]
+    fn categories() {
+        let n = Value::Integer;
+        assert_eq!(plural_category("fr", &n(0)), Some("one"));
+        assert_eq!(plural_category("pl", &n(5)), Some("many"));
+        assert_eq!(plural_category("cs", &n(3)), Some("few"));
+        assert_eq!(plural_category("ar", &n(2)), Some("two"));
+        assert_eq!(plural_category("ar", &n(11)), Some("many"));
+        assert_eq!(plural_category("ja", &n(1)), Some("other"));
+        assert_eq!(plural_category("en", &Value::Text("1".to_owned())), None);
+    }
+}
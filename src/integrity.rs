@@ -0,0 +1,88 @@
+//! Subresource integrity: hashes of local scripts and stylesheets.
+//!
+//! When the [options](crate::EvaluateOptions::asset_root) name a directory of assets,
+//! `<script src>` and `<link href>` references to local files get an `integrity` attribute
+//! with the hash of the file, so browsers can check what they load (e.g. from a CDN).
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha384};
+
+use crate::{paths, Error};
+
+/// The file under the asset root that the URL refers to,
+/// or None if the URL is not a local reference.
+fn local_path(root: &Path, url: &str) -> Option<PathBuf> {
+    // Anything with a scheme, or a protocol-relative URL, is not local.
+    let scheme = url.find(':').is_some_and(|colon| {
+        url[..colon]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    if scheme || url.starts_with("//") {
+        return None;
+    }
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    paths::resolve(root, path)
+}
+
+/// Compute the `integrity` attribute for the asset at the URL,
+/// or None if the URL is not a local reference.
+pub(crate) fn integrity(root: &Path, url: &str) -> Result<Option<String>, Error> {
+    let Some(path) = local_path(root, url) else {
+        return Ok(None);
+    };
+    let content = std::fs::read(&path).map_err(|e| Error::Asset(path.display().to_string(), e))?;
+    Ok(Some(format!("sha384-{}", base64(&Sha384::digest(content)))))
+}
+
+/// Encode the bytes as (padded, standard-alphabet) base64.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{base64, local_path};
+
+    #[test]
+    fn encode() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+    }
+
+    #[test]
+    fn local() {
+        let root = Path::new("/srv");
+        assert_eq!(
+            local_path(root, "/js/app.js?v=2"),
+            Some(root.join("js/app.js"))
+        );
+        assert_eq!(local_path(root, "app.css"), Some(root.join("app.css")));
+        assert_eq!(local_path(root, "https://cdn.example/app.js"), None);
+        assert_eq!(local_path(root, "//cdn.example/app.js"), None);
+        assert_eq!(local_path(root, "../secret"), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! JSON serialization of query results.
+
+use rusqlite::types::Value;
+
+use crate::{queries::row_columns, QueryResult};
+
+/// Quote the string as a JSON string.
+///
+/// `<`, `>`, and `&` are escaped too, so the JSON can be embedded in a `<script>` element.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '<' | '>' | '&' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Serialize the value as JSON.
+///
+/// Blobs are arrays of bytes; non-finite reals are null.
+pub fn json_value(v: &Value) -> String {
+    match v {
+        Value::Null => "null".to_owned(),
+        Value::Integer(i) => format!("{}", i),
+        Value::Real(f) if f.is_finite() => format!("{:?}", f),
+        Value::Real(_) => "null".to_owned(),
+        Value::Text(t) => json_string(t),
+        Value::Blob(b) => format!(
+            "[{}]",
+            b.iter()
+                .map(|b| format!("{}", b))
+                .collect::<Vec<_>>()
+                .join(",")
+        ),
+    }
+}
+
+/// Serialize the rows as a JSON array of objects, with keys in the order of the query's columns.
+pub fn json_rows(rows: &QueryResult, columns: &[String]) -> String {
+    let rows: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let fields: Vec<String> = row_columns(row, columns)
+                .into_iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json_value(v)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect();
+    format!("[{}]", rows.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use rusqlite::types::Value;
+
+    use super::{json_rows, json_string, json_value};
+
+    #[test]
+    fn json_escape() {
+        assert_eq!(json_string("hi"), r#""hi""#);
+        assert_eq!(
+            json_string("<a href=\"x\">\\\n\u{1}</a>"),
+            r#""\u003ca href=\"x\"\u003e\\\n\u0001\u003c/a\u003e""#
+        );
+        assert_eq!(json_string("</script>"), r#""\u003c/script\u003e""#);
+    }
+
+    #[test]
+    fn json_values() {
+        assert_eq!(json_value(&Value::Null), "null");
+        assert_eq!(json_value(&Value::Integer(-3)), "-3");
+        assert_eq!(json_value(&Value::Real(1.0)), "1.0");
+        assert_eq!(json_value(&Value::Real(f64::NAN)), "null");
+        assert_eq!(json_value(&Value::Blob(vec![1, 255])), "[1,255]");
+    }
+
+    #[test]
+    fn rows() {
+        let row: HashMap<String, Value> = [
+            ("b".to_owned(), Value::Integer(1)),
+            ("a".to_owned(), Value::Text("x".to_owned())),
+            ("d".to_owned(), Value::Null),
+            ("c".to_owned(), Value::Null),
+        ]
+        .into_iter()
+        .collect();
+        let columns = ["b".to_owned(), "a".to_owned()];
+        // Columns the query didn't select (e.g. from a row transform) follow, by name.
+        assert_eq!(
+            json_rows(&vec![row.clone(), row], &columns),
+            r#"[{"b":1,"a":"x","c":null,"d":null},{"b":1,"a":"x","c":null,"d":null}]"#
+        );
+        assert_eq!(json_rows(&vec![], &columns), "[]");
+    }
+}
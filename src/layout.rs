@@ -0,0 +1,127 @@
+//! Layout inheritance: a page template that extends a layout, overriding its blocks.
+//!
+//! ```html
+//! <htmpl-extends template="base.html">
+//!     <htmpl-query name="post" :id="params(id)">SELECT title FROM posts WHERE id = :id;</htmpl-query>
+//!     <htmpl-block name="content"><h1><htmpl-insert query="post(title)"></htmpl-insert></h1></htmpl-block>
+//! </htmpl-extends>
+//! ```
+//!
+//! The page is evaluated as the layout, with the content of each of the layout's `htmpl-block`
+//! elements replaced by the content of the page's block of the same name, if any.
+//! The rest of the content of `htmpl-extends` (e.g. queries) is evaluated before the layout.
+
+use std::collections::HashMap;
+
+use ego_tree::{NodeId, NodeMut, NodeRef};
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::{parse, visit::is_inert, Error, EvaluateOptions};
+
+/// How many layouts may extend each other, e.g. a page, a section layout, and a site layout.
+const MAX_DEPTH: usize = 16;
+
+/// Replace the template with the layout it extends, if any, with its blocks overridden;
+/// and so on, if the layout extends another.
+pub(crate) fn extend(mut page: Html, options: &EvaluateOptions) -> Result<Html, Error> {
+    let extends = Selector::parse("htmpl-extends").expect("invalid selector");
+    let mut chain: Vec<String> = Vec::new();
+    for _ in 0..MAX_DEPTH {
+        let Some(element) = page.select(&extends).find(|e| !is_inert(**e)) else {
+            return Ok(page);
+        };
+        let name = element.value().attr("template").ok_or(Error::MissingAttr(
+            "htmpl-extends".into(),
+            "template".into(),
+        ))?;
+        if let Some(i) = chain.iter().position(|layout| layout == name) {
+            let mut cycle = chain.split_off(i);
+            cycle.push(name.to_owned());
+            return Err(Error::Cycle(cycle));
+        }
+        chain.push(name.to_owned());
+        let templates = options.templates.as_ref().ok_or(Error::MissingOption(
+            "htmpl-extends".into(),
+            "templates".into(),
+        ))?;
+        let source = templates
+            .get(name)
+            .ok_or_else(|| Error::MissingTemplate(name.to_owned()))?;
+        let mut layout = parse::parse_template(source, options)?;
+        merge(element, &mut layout);
+        // Keep the errors recovered from in lenient parsing, of the page and then the layout.
+        let mut errors = std::mem::take(&mut page.errors);
+        errors.append(&mut layout.errors);
+        layout.errors = errors;
+        page = layout;
+    }
+    Err(Error::Limit(
+        "layout depth".into(),
+        MAX_DEPTH + 1,
+        MAX_DEPTH,
+    ))
+}
+
+/// Override the blocks of the layout with those in the `htmpl-extends` element,
+/// and put the rest of the element's content at the start of the layout.
+fn merge(extends: ElementRef, layout: &mut Html) {
+    let blocks = Selector::parse("htmpl-block").expect("invalid selector");
+    let mut overrides: HashMap<&str, ElementRef> = HashMap::new();
+    for block in extends.select(&blocks).filter(|e| !is_inert(**e)) {
+        if let Some(name) = block.value().attr("name") {
+            overrides.entry(name).or_insert(block);
+        }
+    }
+    let targets: Vec<(NodeId, ElementRef)> = layout
+        .select(&blocks)
+        .filter(|e| !is_inert(**e))
+        .filter_map(|block| Some((block.id(), *overrides.get(block.value().attr("name")?)?)))
+        .collect();
+    for (id, block) in targets {
+        // Blocks within an overridden block may already be gone.
+        let Some(mut target) = layout.tree.get_mut(id) else {
+            continue;
+        };
+        while let Some(mut child) = target.first_child() {
+            child.detach();
+        }
+        for child in block.children() {
+            copy(child, &mut target);
+        }
+    }
+
+    // If the layout extends another, its own preamble carries this one along.
+    let selector = Selector::parse("htmpl-extends").expect("invalid selector");
+    let start = match layout.select(&selector).find(|e| !is_inert(**e)) {
+        Some(element) => element.id(),
+        None => layout
+            .tree
+            .root()
+            .children()
+            .find(|n| n.value().is_element())
+            .map_or(layout.tree.root().id(), |n| n.id()),
+    };
+    let mut start = layout.tree.get_mut(start).expect("node is in the tree");
+    let preamble: Vec<NodeRef<Node>> = extends
+        .children()
+        .filter(|child| match child.value() {
+            Node::Element(e) => e.name() != "htmpl-block",
+            Node::Text(t) => !t.trim().is_empty(),
+            _ => false,
+        })
+        .collect();
+    for child in preamble.into_iter().rev() {
+        let mut new = start.prepend(child.value().clone());
+        for grandchild in child.children() {
+            copy(grandchild, &mut new);
+        }
+    }
+}
+
+/// Append a copy of the node, and its descendants, to the parent.
+fn copy(node: NodeRef<Node>, parent: &mut NodeMut<Node>) {
+    let mut new = parent.append(node.value().clone());
+    for child in node.children() {
+        copy(child, &mut new);
+    }
+}
@@ -1,53 +1,213 @@
 #![doc = include_str!("lib.md")]
 #![allow(dead_code)]
 
-use std::io;
+use std::{borrow::Cow, io};
 
+#[cfg(feature = "axum")]
+pub mod axum;
+mod components;
+mod css;
+#[cfg(feature = "dev-server")]
+pub mod dev_server;
+mod diagnostics;
+mod diff;
+mod elements;
+mod filters;
+mod form;
+mod functions;
+mod head;
+mod hooks;
+mod i18n;
+mod integrity;
+mod json;
+mod layout;
+pub mod lint;
+mod markdown;
+mod names;
+mod options;
+mod output;
+mod params;
+mod parse;
+mod paths;
+mod pool;
+mod postprocess;
+mod prefix;
+mod preview;
 mod queries;
+mod renderer;
+#[cfg(any(feature = "axum", feature = "tower"))]
+mod respond;
+mod selectors;
+mod serialize;
+mod stream;
+mod templates;
+pub mod testing;
 mod tests;
+#[cfg(feature = "tower")]
+pub mod tower;
+mod trace;
+mod tz;
+mod urls;
 mod visit;
 
-pub use visit::evaluate_template;
+pub use diagnostics::Diagnostic;
+pub use diff::{html_diff, Difference};
+pub use elements::{evaluate_children, ElementHandler};
+pub use form::{Field, Form};
+pub use functions::{builtin_functions, SqlFunctions};
+pub use hooks::{QueryEvent, QueryInfo, QueryStats};
+pub use i18n::Catalog;
+pub use options::{Comments, EvaluateOptions, Formatting, Mode, OutputFormat, Truthiness};
+pub use output::{last_modified, render, RenderOutput};
+pub use params::Params;
+pub use pool::{Pool, PooledConnection};
+pub use queries::{QueryResult, Scope};
+pub use renderer::Renderer;
+pub use stream::{stream_template, StreamFormat};
+pub use templates::Templates;
+pub use trace::{Trace, TraceStep};
+pub use visit::{
+    evaluate_parsed, evaluate_parsed_with, evaluate_template, evaluate_template_with,
+    evaluate_to_dom, evaluate_to_dom_with,
+};
 
+/// An error in evaluating a template.
+///
+/// Rather than matching on variants, which may be added to, use the accessors,
+/// e.g. [`kind`](Error::kind), [`element`](Error::element), and [`query_name`](Error::query_name).
+/// Names in errors, e.g. of elements, are [`Cow`]s, so they can be built at runtime,
+/// e.g. with a [custom prefix](EvaluateOptions::prefix).
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
     #[error("failed at template evaluation: {0}")]
     TemplateEval(String),
     #[error("missing attribute: from element {0}, attribute {1}")]
-    MissingAttr(&'static str, &'static str),
+    MissingAttr(Cow<'static, str>, Cow<'static, str>),
     #[error("missing query: from element {0}, query {1} is not in scope")]
-    MissingQuery(&'static str, String),
+    MissingQuery(Cow<'static, str>, String),
     #[error("incorrect cardinality: from element {0}, query {1} returned {2} rows, wanted {3}")]
-    Cardinality(&'static str, String, usize, usize),
+    Cardinality(Cow<'static, str>, String, usize, usize),
     #[error("invalid column: from element {0}, query {1} has columns {2}, wanted {3}")]
-    MissingColumn(&'static str, String, String, String),
+    MissingColumn(Cow<'static, str>, String, String, String),
     #[error("invalid column: from element {0}, query {1} has columns {2}, wanted one column")]
-    NoDefaultColumn(&'static str, String, String),
+    NoDefaultColumn(Cow<'static, str>, String, String),
     #[error("invalid parameter: in element {0}, parameter {1}: has invalid format")]
-    InvalidParameter(&'static str, String),
+    InvalidParameter(Cow<'static, str>, String),
     #[error("invalid parameter: in element {0}, query has parameter {1}, but there is no corresponding attribute")]
-    MissingParameter(&'static str, String),
+    MissingParameter(Cow<'static, str>, String),
+    #[error("not a boolean: in element {0}, {1} is {2}; with strict truthiness, only integers and null are conditions")]
+    NotABoolean(Cow<'static, str>, String, String),
     #[error(
         r#"multiple conditions: in element {0}, both "true" and "false" conditions are specified"#
     )]
     MultipleConditions(String),
-
+    #[error(
+        r#"no match: in element {0}, select="{1}" matched no elements in scope="{2}"; by default, only the element's siblings (and their descendants) are selected"#
+    )]
+    NoMatch(Cow<'static, str>, String, String),
+    #[error("missing option: element {0} requires the {1} option")]
+    MissingOption(Cow<'static, str>, Cow<'static, str>),
+    #[error("missing template: no template named {0}")]
+    MissingTemplate(String),
+    #[error("missing block: no htmpl-block named {0}")]
+    MissingBlock(String),
     #[error("SQL error: in query {0}: {1}")]
     Sql(String, rusqlite::Error),
+    #[error("could not open database {0}: {1}")]
+    Open(String, rusqlite::Error),
+    #[error("could not read asset {0}: {1}")]
+    Asset(String, io::Error),
     #[error("reserializing error: {0}")]
     Serialize(io::Error),
     #[error("error parsing HTML template: {0}")]
     HtmlParse(String),
+    #[error("error parsing XML template: {0}")]
+    XmlParse(String),
+    #[error("limit exceeded: {0} is {1}, over the limit of {2}")]
+    Limit(Cow<'static, str>, usize, usize),
+    #[error("missing message: no message {0} in the catalog")]
+    MissingMessage(String),
+    #[error("error parsing message catalog: {0}")]
+    CatalogParse(String),
+    #[error("circular reference: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("at {0}: {1}")]
+    At(String, Box<Error>),
+    #[error("in template {0}: {1}")]
+    InTemplate(String, Box<Error>),
 }
 
 impl Error {
+    /// The HTTP status code with which to respond, when this error occurs while handling a request.
+    ///
+    /// A missing template is "not found"; anything else is a server error.
+    pub fn status_code(&self) -> u16 {
+        match self.inner() {
+            Error::MissingTemplate(_) => 404,
+            _ => 500,
+        }
+    }
+
+    /// The path to the template element where the error occurred, if known,
+    /// e.g. `ul > htmpl-foreach (row 3) > li > htmpl-insert`.
+    ///
+    /// Each step of the path is an element's name, `id`, and classes, with its position
+    /// among its siblings if it has any; and for `htmpl-foreach` elements,
+    /// the row (counting from 1) that was being evaluated.
+    pub fn location(&self) -> Option<&str> {
+        match self {
+            Error::At(path, _) => Some(path),
+            Error::InTemplate(_, e) => e.location(),
+            _ => None,
+        }
+    }
+
+    /// The name of the template where the error occurred, if known.
+    pub fn template(&self) -> Option<&str> {
+        match self {
+            Error::InTemplate(name, _) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The error, without its template or location.
+    pub fn inner(&self) -> &Error {
+        match self {
+            Error::At(_, e) | Error::InTemplate(_, e) => e.inner(),
+            e => e,
+        }
+    }
+
+    /// Note that the error occurred while evaluating the named template.
+    ///
+    /// Errors that already name a template are unchanged.
+    pub fn in_template(self, name: &str) -> Self {
+        match self {
+            Error::MissingTemplate(_) | Error::InTemplate(_, _) => self,
+            e => Error::InTemplate(name.to_owned(), Box::new(e)),
+        }
+    }
+
     /// Modify the element listed (in some errors).
-    pub fn set_element(self, element: &'static str) -> Self {
+    pub fn set_element(self, element: impl Into<Cow<'static, str>>) -> Self {
+        let element = element.into();
         match self {
             Error::TemplateEval(_)
             | Error::Sql(_, _)
+            | Error::Open(_, _)
+            | Error::MissingTemplate(_)
+            | Error::MissingBlock(_)
+            | Error::Asset(_, _)
             | Error::Serialize(_)
             | Error::HtmlParse(_)
+            | Error::XmlParse(_)
+            | Error::Limit(_, _, _)
+            | Error::MissingMessage(_)
+            | Error::CatalogParse(_)
+            | Error::Cycle(_)
+            | Error::At(_, _)
+            | Error::InTemplate(_, _)
             | Error::MultipleConditions(_) => self,
             Error::MissingAttr(_, attr) => Error::MissingAttr(element, attr),
             Error::MissingQuery(_, a) => Error::MissingQuery(element, a),
@@ -56,6 +216,263 @@ impl Error {
             Error::NoDefaultColumn(_, a, b) => Error::NoDefaultColumn(element, a, b),
             Error::InvalidParameter(_, a) => Error::InvalidParameter(element, a),
             Error::MissingParameter(_, a) => Error::MissingParameter(element, a),
+            Error::MissingOption(_, a) => Error::MissingOption(element, a),
+            Error::NoMatch(_, a, b) => Error::NoMatch(element, a, b),
+            Error::NotABoolean(_, a, b) => Error::NotABoolean(element, a, b),
+        }
+    }
+}
+
+/// The kind of an [`Error`], for matching on categories of errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// An error from a hook, e.g. a row transform.
+    TemplateEval,
+    /// An element is missing a required attribute.
+    MissingAttr,
+    /// An element refers to a query that is not in scope.
+    MissingQuery,
+    /// A query returned a different number of rows than the element requires.
+    Cardinality,
+    /// A query does not have the requested column.
+    MissingColumn,
+    /// A query has several columns, but no column was requested.
+    NoDefaultColumn,
+    /// An attribute or parameter has an invalid value.
+    InvalidParameter,
+    /// A query has a parameter that is not provided.
+    MissingParameter,
+    /// A condition's value is not an integer or null, with [strict truthiness](Truthiness::StrictInteger).
+    NotABoolean,
+    /// An element has both `true` and `false` conditions.
+    MultipleConditions,
+    /// An element's selector matched no elements in its scope.
+    NoMatch,
+    /// An element requires an option that is not set.
+    MissingOption,
+    /// There is no template with the requested name.
+    MissingTemplate,
+    /// There is no `htmpl-block` with the requested name.
+    MissingBlock,
+    /// SQLite reported an error running a query.
+    Sql,
+    /// A database could not be opened.
+    Open,
+    /// An asset could not be read.
+    Asset,
+    /// The output could not be written.
+    Serialize,
+    /// An HTML template could not be parsed.
+    HtmlParse,
+    /// An XML template could not be parsed.
+    XmlParse,
+    /// A template is over one of the configured limits.
+    Limit,
+    /// The message catalog has no message with the requested key.
+    MissingMessage,
+    /// A message catalog could not be parsed.
+    CatalogParse,
+    /// A layout, component, or macro refers back to itself.
+    Cycle,
+}
+
+impl ErrorKind {
+    /// A stable name for the kind of error, e.g. `missing_query`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::TemplateEval => "template_eval",
+            ErrorKind::MissingAttr => "missing_attr",
+            ErrorKind::MissingQuery => "missing_query",
+            ErrorKind::Cardinality => "cardinality",
+            ErrorKind::MissingColumn => "missing_column",
+            ErrorKind::NoDefaultColumn => "no_default_column",
+            ErrorKind::InvalidParameter => "invalid_parameter",
+            ErrorKind::MissingParameter => "missing_parameter",
+            ErrorKind::NotABoolean => "not_a_boolean",
+            ErrorKind::MultipleConditions => "multiple_conditions",
+            ErrorKind::NoMatch => "no_match",
+            ErrorKind::MissingOption => "missing_option",
+            ErrorKind::MissingTemplate => "missing_template",
+            ErrorKind::MissingBlock => "missing_block",
+            ErrorKind::Sql => "sql",
+            ErrorKind::Open => "open",
+            ErrorKind::Asset => "asset",
+            ErrorKind::Serialize => "serialize",
+            ErrorKind::HtmlParse => "html_parse",
+            ErrorKind::XmlParse => "xml_parse",
+            ErrorKind::Limit => "limit",
+            ErrorKind::MissingMessage => "missing_message",
+            ErrorKind::CatalogParse => "catalog_parse",
+            ErrorKind::Cycle => "cycle",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Error {
+    /// The kind of error.
+    ///
+    /// This is the kind of the [inner](Error::inner) error, regardless of its template or location.
+    pub fn kind(&self) -> ErrorKind {
+        match self.inner() {
+            Error::TemplateEval(_) => ErrorKind::TemplateEval,
+            Error::MissingAttr(_, _) => ErrorKind::MissingAttr,
+            Error::MissingQuery(_, _) => ErrorKind::MissingQuery,
+            Error::Cardinality(_, _, _, _) => ErrorKind::Cardinality,
+            Error::MissingColumn(_, _, _, _) => ErrorKind::MissingColumn,
+            Error::NoDefaultColumn(_, _, _) => ErrorKind::NoDefaultColumn,
+            Error::InvalidParameter(_, _) => ErrorKind::InvalidParameter,
+            Error::MissingParameter(_, _) => ErrorKind::MissingParameter,
+            Error::NotABoolean(_, _, _) => ErrorKind::NotABoolean,
+            Error::MultipleConditions(_) => ErrorKind::MultipleConditions,
+            Error::NoMatch(_, _, _) => ErrorKind::NoMatch,
+            Error::MissingOption(_, _) => ErrorKind::MissingOption,
+            Error::MissingTemplate(_) => ErrorKind::MissingTemplate,
+            Error::MissingBlock(_) => ErrorKind::MissingBlock,
+            Error::Sql(_, _) => ErrorKind::Sql,
+            Error::Open(_, _) => ErrorKind::Open,
+            Error::Asset(_, _) => ErrorKind::Asset,
+            Error::Serialize(_) => ErrorKind::Serialize,
+            Error::HtmlParse(_) => ErrorKind::HtmlParse,
+            Error::XmlParse(_) => ErrorKind::XmlParse,
+            Error::Limit(_, _, _) => ErrorKind::Limit,
+            Error::MissingMessage(_) => ErrorKind::MissingMessage,
+            Error::CatalogParse(_) => ErrorKind::CatalogParse,
+            Error::Cycle(_) => ErrorKind::Cycle,
+            Error::At(_, _) | Error::InTemplate(_, _) => unreachable!("inner error is wrapped"),
+        }
+    }
+
+    /// The name of the element that reported the error, if any, e.g. `htmpl-insert`.
+    pub fn element(&self) -> Option<&str> {
+        match self.inner() {
+            Error::MissingAttr(element, _)
+            | Error::MissingQuery(element, _)
+            | Error::Cardinality(element, _, _, _)
+            | Error::MissingColumn(element, _, _, _)
+            | Error::NoDefaultColumn(element, _, _)
+            | Error::InvalidParameter(element, _)
+            | Error::MissingParameter(element, _)
+            | Error::MissingOption(element, _)
+            | Error::NoMatch(element, _, _)
+            | Error::NotABoolean(element, _, _) => Some(element),
+            Error::MultipleConditions(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    /// The name of the query involved in the error, if any.
+    pub fn query_name(&self) -> Option<&str> {
+        match self.inner() {
+            Error::MissingQuery(_, query)
+            | Error::Cardinality(_, query, _, _)
+            | Error::MissingColumn(_, query, _, _)
+            | Error::NoDefaultColumn(_, query, _)
+            | Error::Sql(query, _) => Some(query),
+            _ => None,
+        }
+    }
+}
+
+/// Errors serialize as a map, with:
+/// - `kind`: a stable name for the kind of error, e.g. `missing_query`
+/// - `message`: the error's message
+/// - the fields of the error that apply, of `element`, `attribute`, `query`, `parameter`,
+///   `option`, `column`, `columns`, `selector`, `scope`, `rows`, `wanted`, `template`, `block`,
+///   `database`, `asset`, `limit`, `value`, `max`, `key`, `cycle`, and `location`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry("kind", self.kind().as_str())?;
+        map.serialize_entry("message", &self.to_string())?;
+        self.serialize_fields(&mut map)?;
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Error {
+    fn serialize_fields<M: serde::ser::SerializeMap>(&self, map: &mut M) -> Result<(), M::Error> {
+        match self {
+            Error::TemplateEval(_)
+            | Error::Serialize(_)
+            | Error::HtmlParse(_)
+            | Error::XmlParse(_)
+            | Error::CatalogParse(_) => Ok(()),
+            Error::MissingAttr(element, attribute) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("attribute", attribute)
+            }
+            Error::MissingQuery(element, query) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("query", query)
+            }
+            Error::Cardinality(element, query, rows, wanted) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("query", query)?;
+                map.serialize_entry("rows", rows)?;
+                map.serialize_entry("wanted", wanted)
+            }
+            Error::MissingColumn(element, query, columns, column) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("query", query)?;
+                map.serialize_entry("columns", columns)?;
+                map.serialize_entry("column", column)
+            }
+            Error::NoDefaultColumn(element, query, columns) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("query", query)?;
+                map.serialize_entry("columns", columns)
+            }
+            Error::InvalidParameter(element, parameter)
+            | Error::MissingParameter(element, parameter) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("parameter", parameter)
+            }
+            Error::NotABoolean(element, selector, value) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("selector", selector)?;
+                map.serialize_entry("value", value)
+            }
+            Error::MultipleConditions(element) => map.serialize_entry("element", element),
+            Error::MissingOption(element, option) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("option", option)
+            }
+            Error::NoMatch(element, selector, scope) => {
+                map.serialize_entry("element", element)?;
+                map.serialize_entry("selector", selector)?;
+                map.serialize_entry("scope", scope)
+            }
+            Error::MissingTemplate(template) => map.serialize_entry("template", template),
+            Error::MissingBlock(block) => map.serialize_entry("block", block),
+            Error::MissingMessage(key) => map.serialize_entry("key", key),
+            Error::Cycle(cycle) => map.serialize_entry("cycle", cycle),
+            Error::Sql(query, _) => map.serialize_entry("query", query),
+            Error::Open(database, _) => map.serialize_entry("database", database),
+            Error::Asset(asset, _) => map.serialize_entry("asset", asset),
+            Error::Limit(limit, value, max) => {
+                map.serialize_entry("limit", limit)?;
+                map.serialize_entry("value", value)?;
+                map.serialize_entry("max", max)
+            }
+            Error::At(location, e) => {
+                map.serialize_entry("location", location)?;
+                e.serialize_fields(map)
+            }
+            Error::InTemplate(template, e) => {
+                map.serialize_entry("template", template)?;
+                e.serialize_fields(map)
+            }
         }
     }
 }
@@ -75,10 +492,38 @@ impl PartialEq for Error {
             (Self::NoDefaultColumn(l0, l1, l2), Self::NoDefaultColumn(r0, r1, r2)) => {
                 l0 == r0 && l1 == r1 && l2 == r2
             }
+            (Self::InvalidParameter(l0, l1), Self::InvalidParameter(r0, r1)) => {
+                l0 == r0 && l1 == r1
+            }
+            (Self::MissingParameter(l0, l1), Self::MissingParameter(r0, r1)) => {
+                l0 == r0 && l1 == r1
+            }
+            (Self::MissingOption(l0, l1), Self::MissingOption(r0, r1)) => l0 == r0 && l1 == r1,
+            (Self::NoMatch(l0, l1, l2), Self::NoMatch(r0, r1, r2)) => {
+                l0 == r0 && l1 == r1 && l2 == r2
+            }
+            (Self::NotABoolean(l0, l1, l2), Self::NotABoolean(r0, r1, r2)) => {
+                l0 == r0 && l1 == r1 && l2 == r2
+            }
+            (Self::MultipleConditions(l0), Self::MultipleConditions(r0)) => l0 == r0,
             (Self::Sql(l0, l1), Self::Sql(r0, r1)) => l0 == r0 && l1 == r1,
+            (Self::Open(l0, l1), Self::Open(r0, r1)) => l0 == r0 && l1 == r1,
+            (Self::MissingTemplate(l0), Self::MissingTemplate(r0)) => l0 == r0,
+            (Self::MissingBlock(l0), Self::MissingBlock(r0)) => l0 == r0,
+            (Self::Asset(l0, l1), Self::Asset(r0, r1)) => {
+                l0 == r0 && (l1.kind() == r1.kind()) && l1.to_string() == r1.to_string()
+            }
             (Self::Serialize(l0), Self::Serialize(r0)) => {
                 (l0.kind() == r0.kind()) && l0.to_string() == r0.to_string()
             }
+            (Self::HtmlParse(l0), Self::HtmlParse(r0)) => l0 == r0,
+            (Self::XmlParse(l0), Self::XmlParse(r0)) => l0 == r0,
+            (Self::Limit(l0, l1, l2), Self::Limit(r0, r1, r2)) => l0 == r0 && l1 == r1 && l2 == r2,
+            (Self::MissingMessage(l0), Self::MissingMessage(r0)) => l0 == r0,
+            (Self::CatalogParse(l0), Self::CatalogParse(r0)) => l0 == r0,
+            (Self::Cycle(l0), Self::Cycle(r0)) => l0 == r0,
+            (Self::At(l0, l1), Self::At(r0, r1)) => l0 == r0 && l1 == r1,
+            (Self::InTemplate(l0, l1), Self::InTemplate(r0, r1)) => l0 == r0 && l1 == r1,
             _ => false,
         }
     }
@@ -0,0 +1,161 @@
+//! Lints over the output of an evaluation.
+//!
+//! A lint is a function from the output tree to the [diagnostics](crate::Diagnostic) it finds.
+//! Add lints with [`EvaluateOptions::lint`](crate::EvaluateOptions::lint), or the built-in
+//! accessibility rules with [`EvaluateOptions::accessibility_lints`](crate::EvaluateOptions::accessibility_lints):
+//!
+//! ```
+//! let conn = rusqlite::Connection::open_in_memory().unwrap();
+//! let options = htmpl::EvaluateOptions::new().accessibility_lints();
+//! let output = htmpl::render(r#"<img src="/a.png">"#, &conn, &options).unwrap();
+//! assert_eq!(
+//!     output.diagnostics()[0].to_string(),
+//!     r#"img-alt: <img src="/a.png"> has no alt text"#
+//! );
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+
+use scraper::{node::Element, Html};
+
+use crate::Diagnostic;
+
+/// Images (`img`, and `input type="image"`) must have an `alt` attribute.
+///
+/// An empty `alt` is allowed: it marks the image as decorative.
+pub fn img_alt(html: &Html) -> Vec<Diagnostic> {
+    elements(html)
+        .filter(|e| e.name() == "img" || (e.name() == "input" && input_type(e) == "image"))
+        .filter(|e| e.attr("alt").is_none())
+        .map(|e| Diagnostic {
+            rule: "img-alt",
+            message: format!("{} has no alt text", describe(e)),
+        })
+        .collect()
+}
+
+/// Form controls (`input`, `select`, and `textarea`) must have a label:
+/// an enclosing `label`, a `label` with a matching `for`, or an `aria-label`,
+/// `aria-labelledby`, or `title` attribute.
+///
+/// Hidden inputs, and buttons (which are labeled by their value), are exempt.
+pub fn form_labels(html: &Html) -> Vec<Diagnostic> {
+    let labeled: HashSet<&str> = elements(html)
+        .filter(|e| e.name() == "label")
+        .filter_map(|e| e.attr("for"))
+        .collect();
+    html.tree
+        .root()
+        .descendants()
+        .filter_map(|n| Some((n, n.value().as_element()?)))
+        .filter(|(_, e)| match e.name() {
+            "input" => !matches!(
+                input_type(e).as_str(),
+                "hidden" | "submit" | "reset" | "button" | "image"
+            ),
+            "select" | "textarea" => true,
+            _ => false,
+        })
+        .filter(|(n, e)| {
+            let named = ["aria-label", "aria-labelledby", "title"]
+                .iter()
+                .any(|a| e.attr(a).is_some_and(|v| !v.trim().is_empty()));
+            let enclosed = n
+                .ancestors()
+                .any(|a| a.value().as_element().is_some_and(|a| a.name() == "label"));
+            let referenced = e.id().is_some_and(|id| labeled.contains(id));
+            !(named || enclosed || referenced)
+        })
+        .map(|(_, e)| Diagnostic {
+            rule: "form-label",
+            message: format!("{} has no label", describe(e)),
+        })
+        .collect()
+}
+
+/// Each `id` must be used at most once, e.g. not repeated in each row of an `htmpl-foreach`.
+pub fn duplicate_ids(html: &Html) -> Vec<Diagnostic> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for id in elements(html).filter_map(Element::id) {
+        *counts.entry(id).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(id, count)| Diagnostic {
+            rule: "duplicate-id",
+            message: format!("id {:?} is used by {} elements", id, count),
+        })
+        .collect()
+}
+
+fn elements(html: &Html) -> impl Iterator<Item = &Element> {
+    html.tree
+        .root()
+        .descendants()
+        .filter_map(|n| n.value().as_element())
+}
+
+fn input_type(e: &Element) -> String {
+    e.attr("type").unwrap_or("text").to_ascii_lowercase()
+}
+
+/// A short description of the element, with the attributes most likely to identify it.
+fn describe(e: &Element) -> String {
+    let attrs: String = ["id", "name", "type", "src"]
+        .iter()
+        .filter_map(|&a| Some(format!(" {}={:?}", a, e.attr(a)?)))
+        .collect();
+    format!("<{}{}>", e.name(), attrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use scraper::Html;
+
+    use super::{duplicate_ids, form_labels, img_alt};
+
+    fn messages(diagnostics: Vec<crate::Diagnostic>) -> Vec<String> {
+        diagnostics.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn images() {
+        let html = Html::parse_fragment(
+            r#"<img src="/a.png"><img src="/b.png" alt=""><input type="image" src="/c.png">"#,
+        );
+        assert_eq!(
+            messages(img_alt(&html)),
+            [
+                r#"img-alt: <img src="/a.png"> has no alt text"#,
+                r#"img-alt: <input type="image" src="/c.png"> has no alt text"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let html = Html::parse_fragment(
+            r#"<label>Name <input name="name"></label>
+            <label for="email">Email</label><input id="email" type="email">
+            <input name="q" aria-label="Search"><input type="hidden" name="t"><input type="submit">
+            <input name="phone" placeholder="Phone"><textarea name="bio"></textarea>"#,
+        );
+        assert_eq!(
+            messages(form_labels(&html)),
+            [
+                r#"form-label: <input name="phone"> has no label"#,
+                r#"form-label: <textarea name="bio"> has no label"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn ids() {
+        let html = Html::parse_fragment(r#"<p id="a"></p><p id="b"></p><p id="a"></p>"#);
+        assert_eq!(
+            messages(duplicate_ids(&html)),
+            [r#"duplicate-id: id "a" is used by 2 elements"#]
+        );
+    }
+}
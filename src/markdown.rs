@@ -0,0 +1,187 @@
+//! A small subset of Markdown, rendered to HTML for [`HTMPL_MARKDOWN`](crate::builtin_functions).
+//!
+//! Supported are paragraphs, `#` headings, `-`/`*`/`+` and `1.` lists, fenced code blocks,
+//! and, inline, `` `code` ``, `**strong**`, `*emphasis*`, and `[links](url)`.
+//! Everything else is text: HTML in the input is escaped, not passed through,
+//! and links with script-bearing schemes (e.g. `javascript:`) are rendered as their text alone.
+
+use std::fmt::Write;
+
+use crate::urls;
+
+/// Render the Markdown text as HTML.
+pub(crate) fn render(s: &str) -> String {
+    let mut out = String::new();
+    let mut lines = s.lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with("```") {
+            let mut code = String::new();
+            for line in lines.by_ref() {
+                if line.trim_start().starts_with("```") {
+                    break;
+                }
+                code.push_str(line);
+                code.push('\n');
+            }
+            out.push_str("<pre><code>");
+            escape_into(&mut out, &code);
+            out.push_str("</code></pre>\n");
+        } else if let Some((level, text)) = heading(line) {
+            let _ = writeln!(out, "<h{0}>{1}</h{0}>", level, inline(text));
+        } else if let Some((ordered, _)) = list_item(line) {
+            let tag = if ordered { "ol" } else { "ul" };
+            let _ = writeln!(out, "<{}>", tag);
+            let mut item = Some(line);
+            while let Some(line) = item {
+                if let Some((_, text)) = list_item(line) {
+                    let _ = writeln!(out, "<li>{}</li>", inline(text));
+                }
+                item = lines
+                    .next_if(|l| list_item(l.trim()).is_some_and(|(o, _)| o == ordered))
+                    .map(str::trim);
+            }
+            let _ = writeln!(out, "</{}>", tag);
+        } else {
+            let mut text = line.to_owned();
+            while let Some(line) = lines.next_if(|l| !starts_block(l.trim())) {
+                text.push('\n');
+                text.push_str(line.trim());
+            }
+            let _ = writeln!(out, "<p>{}</p>", inline(&text));
+        }
+    }
+    out
+}
+
+/// Returns true if the line ends a paragraph: it is blank, or starts another block.
+fn starts_block(line: &str) -> bool {
+    line.is_empty()
+        || line.starts_with("```")
+        || heading(line).is_some()
+        || list_item(line).is_some()
+}
+
+/// The level and text of a heading line, e.g. `## Usage`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let text = line[level..].strip_prefix(' ')?;
+    (1..=6).contains(&level).then_some((level, text.trim()))
+}
+
+/// Whether the line is an item of an ordered list, and its text, if it is a list item.
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    if let Some(text) = ["- ", "* ", "+ "].iter().find_map(|p| line.strip_prefix(p)) {
+        return Some((false, text));
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let text = line[digits..].strip_prefix(". ")?;
+    (digits > 0).then_some((true, text))
+}
+
+/// Render the inline elements of the text.
+fn inline(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        let after = &rest[c.len_utf8()..];
+        match c {
+            '`' => {
+                if let Some((code, after)) = after.split_once('`') {
+                    out.push_str("<code>");
+                    escape_into(&mut out, code);
+                    out.push_str("</code>");
+                    rest = after;
+                    continue;
+                }
+            }
+            '*' => {
+                let (tag, delimiter, inner) = match after.strip_prefix('*') {
+                    Some(inner) => ("strong", "**", inner),
+                    None => ("em", "*", after),
+                };
+                if let Some((text, after)) = inner.split_once(delimiter) {
+                    if !text.is_empty() {
+                        let _ = write!(out, "<{0}>{1}</{0}>", tag, inline(text));
+                        rest = after;
+                        continue;
+                    }
+                }
+            }
+            '[' => {
+                if let Some((text, url, after)) = link(after) {
+                    match urls::escape(url, true) {
+                        url if url == urls::INVALID_URL => out.push_str(&inline(text)),
+                        url => {
+                            out.push_str("<a href=\"");
+                            escape_into(&mut out, &url);
+                            let _ = write!(out, "\">{}</a>", inline(text));
+                        }
+                    }
+                    rest = after;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        escape_into(&mut out, &rest[..c.len_utf8()]);
+        rest = after;
+    }
+    out
+}
+
+/// The text and URL of a link, `text](url)`, and the rest of the line after it.
+fn link(s: &str) -> Option<(&str, &str, &str)> {
+    let (text, rest) = s.split_once("](")?;
+    let (url, rest) = rest.split_once(')')?;
+    (!text.contains('[')).then_some((text, url.trim(), rest))
+}
+
+/// Append the text, escaped for HTML text or a quoted attribute value.
+fn escape_into(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn blocks() {
+        assert_eq!(
+            render("# Title\n\nSome *nice*\n**bold** `code`.\n\n- one\n- [two](/two)\n\n1. first\n\n```\nlet x = 1;\n```"),
+            "<h1>Title</h1>\n<p>Some <em>nice</em>\n<strong>bold</strong> <code>code</code>.</p>\n\
+             <ul>\n<li>one</li>\n<li><a href=\"/two\">two</a></li>\n</ul>\n\
+             <ol>\n<li>first</li>\n</ol>\n<pre><code>let x = 1;\n</code></pre>\n"
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            render("<script>alert('hi')</script> & *<b>*"),
+            "<p>&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt; &amp; <em>&lt;b&gt;</em></p>\n"
+        );
+        assert_eq!(
+            render("`<b>`\n\n```\n</code></pre><i>\n```"),
+            "<p><code>&lt;b&gt;</code></p>\n<pre><code>&lt;/code&gt;&lt;/pre&gt;&lt;i&gt;\n</code></pre>\n"
+        );
+        assert_eq!(
+            render(r#"[x](javascript:alert%281%29) [y](/a"b&c) [<i>](/)"#),
+            "<p>x <a href=\"/a%22b&amp;c\">y</a> <a href=\"/\">&lt;i&gt;</a></p>\n"
+        );
+        assert_eq!(render("*not closed"), "<p>*not closed</p>\n");
+    }
+}
@@ -0,0 +1,139 @@
+//! Qualified names for attributes that htmpl adds to elements.
+//!
+//! The HTML parser adjusts the names of attributes on SVG and MathML elements:
+//! `viewbox` becomes `viewBox`, and `xlink:href` becomes `href` in the XLink namespace.
+//! Attributes added by htmpl (e.g. by `htmpl-attr`) get the same adjustments,
+//! so they replace the template's attributes rather than duplicating them.
+
+use html5ever::{namespace_url, ns, LocalName, Namespace, Prefix, QualName};
+
+/// SVG attributes with mixed-case names.
+const SVG_ATTRS: &[&str] = &[
+    "attributeName",
+    "attributeType",
+    "baseFrequency",
+    "baseProfile",
+    "calcMode",
+    "clipPathUnits",
+    "diffuseConstant",
+    "edgeMode",
+    "filterUnits",
+    "glyphRef",
+    "gradientTransform",
+    "gradientUnits",
+    "kernelMatrix",
+    "kernelUnitLength",
+    "keyPoints",
+    "keySplines",
+    "keyTimes",
+    "lengthAdjust",
+    "limitingConeAngle",
+    "markerHeight",
+    "markerUnits",
+    "markerWidth",
+    "maskContentUnits",
+    "maskUnits",
+    "numOctaves",
+    "pathLength",
+    "patternContentUnits",
+    "patternTransform",
+    "patternUnits",
+    "pointsAtX",
+    "pointsAtY",
+    "pointsAtZ",
+    "preserveAlpha",
+    "preserveAspectRatio",
+    "primitiveUnits",
+    "refX",
+    "refY",
+    "repeatCount",
+    "repeatDur",
+    "requiredExtensions",
+    "requiredFeatures",
+    "specularConstant",
+    "specularExponent",
+    "spreadMethod",
+    "startOffset",
+    "stdDeviation",
+    "stitchTiles",
+    "surfaceScale",
+    "systemLanguage",
+    "tableValues",
+    "targetX",
+    "targetY",
+    "textLength",
+    "viewBox",
+    "viewTarget",
+    "xChannelSelector",
+    "yChannelSelector",
+    "zoomAndPan",
+];
+
+/// The qualified name of the attribute, when added to an element with the given name.
+///
+/// Attributes of HTML elements are lowercase and in no namespace. Attributes of SVG and MathML
+/// elements are adjusted as the HTML parser does. Other (i.e. XML) attributes are as written.
+pub(crate) fn attr_name(element: &QualName, name: &str) -> QualName {
+    let plain = |local: &str| QualName::new(None, ns!(), LocalName::from(local));
+    match element.ns {
+        ns!(html) => plain(&name.to_ascii_lowercase()),
+        ns!(svg) | ns!(mathml) => {
+            let lower = name.to_ascii_lowercase();
+            if let Some(name) = foreign_attr(&lower) {
+                return name;
+            }
+            if element.ns == ns!(mathml) && lower == "definitionurl" {
+                return plain("definitionURL");
+            }
+            match SVG_ATTRS.iter().find(|a| a.eq_ignore_ascii_case(&lower)) {
+                Some(adjusted) if element.ns == ns!(svg) => plain(adjusted),
+                _ => plain(&lower),
+            }
+        }
+        _ => plain(name),
+    }
+}
+
+/// The namespaced name of an XLink, XML, or XMLNS attribute on a foreign element.
+fn foreign_attr(name: &str) -> Option<QualName> {
+    let named = |prefix: Option<&str>, ns: Namespace, local: &str| {
+        Some(QualName::new(
+            prefix.map(Prefix::from),
+            ns,
+            LocalName::from(local),
+        ))
+    };
+    match name.split_once(':') {
+        Some((
+            "xlink",
+            local @ ("actuate" | "arcrole" | "href" | "role" | "show" | "title" | "type"),
+        )) => named(Some("xlink"), ns!(xlink), local),
+        Some(("xml", local @ ("lang" | "space"))) => named(Some("xml"), ns!(xml), local),
+        Some(("xmlns", "xlink")) => named(Some("xmlns"), ns!(xmlns), "xlink"),
+        None if name == "xmlns" => named(None, ns!(xmlns), "xmlns"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use html5ever::{local_name, namespace_url, ns, QualName};
+
+    use super::attr_name;
+
+    #[test]
+    fn adjusts() {
+        let svg = QualName::new(None, ns!(svg), local_name!("rect"));
+        let math = QualName::new(None, ns!(mathml), local_name!("mi"));
+        let html = QualName::new(None, ns!(html), local_name!("div"));
+        let xml = QualName::new(None, ns!(), "item".into());
+        assert_eq!(&*attr_name(&svg, "viewbox").local, "viewBox");
+        assert_eq!(&*attr_name(&svg, "Fill").local, "fill");
+        let href = attr_name(&svg, "xlink:href");
+        assert_eq!((href.ns, &*href.local), (ns!(xlink), "href"));
+        assert_eq!(&*attr_name(&math, "definitionurl").local, "definitionURL");
+        assert_eq!(&*attr_name(&math, "viewbox").local, "viewbox");
+        assert_eq!(&*attr_name(&html, "viewBox").local, "viewbox");
+        assert_eq!(&*attr_name(&xml, "isPermaLink").local, "isPermaLink");
+    }
+}
@@ -0,0 +1,754 @@
+//! Options for template evaluation.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use rusqlite::types::Value;
+
+use crate::{
+    elements::Elements,
+    hooks::{
+        AfterQuery, BeforeQuery, Callback, CsrfToken, Hooks, Lint, OnQuery, PostProcess,
+        RegisterFunctions, RewriteQuery, TransformRow,
+    },
+    selectors::Selectors,
+    Catalog, ElementHandler, Error, Params, QueryEvent, QueryInfo, QueryResult, QueryStats,
+    SqlFunctions, Templates,
+};
+
+/// The markup language of a template, and of its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// An HTML fragment, parsed and serialized per HTML5.
+    #[default]
+    Html,
+    /// An XML document, e.g. an RSS or Atom feed.
+    ///
+    /// The template is parsed as XML, so element and attribute names keep their case,
+    /// and the output is serialized as XML: empty elements are self-closing,
+    /// and namespace declarations are preserved.
+    Xml,
+    /// An HTML template, with output that is also well-formed XML (polyglot XHTML),
+    /// e.g. for EPUB.
+    ///
+    /// The template is parsed as HTML. In the output, elements are in the XHTML namespace,
+    /// void elements are self-closing (`<br/>`), attribute values are always quoted,
+    /// and the contents of `<script>` and `<style>` are CDATA sections where they need to be.
+    Xhtml,
+}
+
+impl OutputFormat {
+    /// The media type of output in this format, e.g. for an HTTP `Content-Type` header.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Html => "text/html; charset=utf-8",
+            OutputFormat::Xml => "application/xml; charset=utf-8",
+            OutputFormat::Xhtml => "application/xhtml+xml; charset=utf-8",
+        }
+    }
+
+    /// Returns true if templates in this format are parsed as HTML.
+    pub(crate) fn is_html(&self) -> bool {
+        matches!(self, OutputFormat::Html | OutputFormat::Xhtml)
+    }
+}
+
+/// Which comments in the template are kept in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Comments {
+    /// Keep all comments.
+    #[default]
+    Keep,
+    /// Remove all comments.
+    Strip,
+    /// Remove comments, except Internet Explorer conditional comments
+    /// (`<!--[if IE]> ... <![endif]-->`).
+    KeepConditional,
+}
+
+/// Whether an evaluation may write to the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Queries may only read from the database.
+    #[default]
+    Read,
+    /// Queries marked with the `writes` attribute may also write to the database,
+    /// e.g. `INSERT` or `UPDATE` statements.
+    /// The evaluation is a transaction: its writes are committed if it succeeds,
+    /// and rolled back if it fails.
+    Write,
+}
+
+/// How values are interpreted as conditions, e.g. by `htmpl-if`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Truthiness {
+    /// By the value's type, per [the rules for truthiness](crate#truthiness):
+    /// zero, empty text, and empty blobs are falsy, and everything else is truthy.
+    #[default]
+    ByType,
+    /// As [`ByType`](Self::ByType), but text flags are also falsy:
+    /// `0`, `false`, `no`, and `off`, in any case and ignoring surrounding whitespace.
+    TextFlags,
+    /// Only integers (and null, which is falsy) are conditions; any other value is an error.
+    StrictInteger,
+}
+
+/// The layout of whitespace in the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Formatting {
+    /// Write the output as evaluated, with whatever whitespace the template has.
+    #[default]
+    Preserve,
+    /// Indent the output, with each block element on its own line.
+    ///
+    /// Inline elements (e.g. `<a>` and `<em>`) stay within their lines of text,
+    /// and the contents of `<pre>`, `<textarea>`, `<script>`, and `<style>` are unchanged.
+    Pretty,
+    /// Make the output as small as possible, without changing how it renders.
+    ///
+    /// Runs of whitespace are collapsed to one space, and removed between block elements;
+    /// attribute values are unquoted where possible; and optional end tags
+    /// (e.g. `</li>` before another `<li>`) are left out.
+    /// The contents of `<pre>`, `<textarea>`, `<script>`, and `<style>` are unchanged.
+    Minified,
+}
+
+/// Settings for parsing templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ParseSettings {
+    pub exact_errors: bool,
+    pub scripting: bool,
+    pub max_len: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub lenient: bool,
+}
+
+impl Default for ParseSettings {
+    fn default() -> Self {
+        ParseSettings {
+            exact_errors: true,
+            // Enable "scripting" since we have custom elements
+            scripting: true,
+            max_len: None,
+            max_depth: None,
+            lenient: false,
+        }
+    }
+}
+
+/// Options controlling how a template is evaluated.
+///
+/// ```
+/// let options = htmpl::EvaluateOptions::new().format(htmpl::OutputFormat::Xml);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct EvaluateOptions {
+    pub(crate) format: OutputFormat,
+    pub(crate) params: Params,
+    pub(crate) freshness_queries: Vec<String>,
+    pub(crate) block: Option<String>,
+    pub(crate) inline_errors: bool,
+    pub(crate) partial: bool,
+    pub(crate) elements: Elements,
+    pub(crate) before_query: Hooks<BeforeQuery>,
+    pub(crate) after_query: Hooks<AfterQuery>,
+    pub(crate) rewrite_query: Hooks<RewriteQuery>,
+    pub(crate) transform_row: Hooks<TransformRow>,
+    pub(crate) sql_functions: Hooks<RegisterFunctions>,
+    pub(crate) highlight_delimiters: Option<(String, String)>,
+    pub(crate) csrf_token: Option<Callback<CsrfToken>>,
+    pub(crate) nonce: Option<String>,
+    pub(crate) asset_root: Option<PathBuf>,
+    pub(crate) reject_unsafe_urls: bool,
+    pub(crate) selectors: Selectors,
+    pub(crate) heading_ids: bool,
+    pub(crate) base_url: Option<String>,
+    pub(crate) post_process: Hooks<PostProcess>,
+    pub(crate) comments: Comments,
+    pub(crate) document: bool,
+    pub(crate) formatting: Formatting,
+    pub(crate) ascii_only: bool,
+    pub(crate) escape_quotes: bool,
+    pub(crate) parse: ParseSettings,
+    pub(crate) redact_params: bool,
+    pub(crate) on_query: Hooks<OnQuery>,
+    pub(crate) trace: bool,
+    pub(crate) annotate: bool,
+    pub(crate) preview: Option<usize>,
+    pub(crate) validate: bool,
+    pub(crate) lints: Hooks<Lint>,
+    pub(crate) catalog: Option<Arc<Catalog>>,
+    pub(crate) timezone: Option<jiff::tz::TimeZone>,
+    pub(crate) truthiness: Truthiness,
+    pub(crate) query_root: Option<PathBuf>,
+    pub(crate) named_queries: HashMap<String, Arc<str>>,
+    pub(crate) prelude: Vec<(String, Prelude)>,
+    pub(crate) templates: Option<Arc<Templates>>,
+    pub(crate) prefix: Option<String>,
+    pub(crate) mode: Mode,
+    pub(crate) attachments: Vec<(String, PathBuf)>,
+}
+
+/// A binding in scope for every template, per [`EvaluateOptions::prelude_query`]
+/// or [`EvaluateOptions::prelude_rows`].
+#[derive(Debug, Clone)]
+pub(crate) enum Prelude {
+    Query(Arc<str>),
+    Rows(Arc<QueryResult>),
+}
+
+impl EvaluateOptions {
+    /// Create the default options: evaluate an HTML fragment.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the markup language of the template and output.
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Set the parameters available to the template, as the `params` query.
+    pub fn params(mut self, params: impl Into<Params>) -> Self {
+        self.params = params.into();
+        self
+    }
+
+    /// Add a query reporting when the data behind the template was last modified.
+    ///
+    /// See [`last_modified`](crate::last_modified) for what the query should return.
+    pub fn freshness_query(mut self, query: impl Into<String>) -> Self {
+        self.freshness_queries.push(query.into());
+        self
+    }
+
+    /// Output only the contents of the named `htmpl-block` element.
+    ///
+    /// The whole template is still evaluated, so the block can use queries from outside it.
+    /// If the block is evaluated more than once (e.g. within `htmpl-foreach`),
+    /// the output contains each evaluation in turn.
+    pub fn block(mut self, name: impl Into<String>) -> Self {
+        self.block = Some(name.into());
+        self
+    }
+
+    /// Render evaluation errors in place, rather than failing the whole evaluation.
+    ///
+    /// When an htmpl element fails to evaluate, the error is logged,
+    /// and the element is replaced with a `<div class="htmpl-error">` describing the error.
+    /// This is meant for development; errors in parsing the template still fail the evaluation.
+    /// In [write mode](Mode::Write), an evaluation with any errors shown inline doesn't
+    /// commit its writes.
+    pub fn inline_errors(mut self, inline_errors: bool) -> Self {
+        self.inline_errors = inline_errors;
+        self
+    }
+
+    /// Evaluate only part of the template, leaving the rest for a later pass.
+    ///
+    /// In a partial evaluation, htmpl elements with a `defer` attribute,
+    /// and `htmpl-*` elements that htmpl doesn't recognize,
+    /// are copied to the output verbatim (without the `defer` attribute),
+    /// so the output can be evaluated again to complete them.
+    pub fn partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// Evaluate elements with the given name using the handler.
+    ///
+    /// The built-in htmpl elements take precedence over handlers of the same name.
+    pub fn element(
+        mut self,
+        name: impl Into<String>,
+        handler: impl ElementHandler + 'static,
+    ) -> Self {
+        self.elements.insert(name.into(), Arc::new(handler));
+        self
+    }
+
+    /// Call the function before running each `htmpl-query`.
+    ///
+    /// Queries that fail to prepare (e.g. with a syntax error) or to bind their parameters
+    /// are not run, and are not reported to query hooks.
+    pub fn before_query(mut self, hook: impl Fn(&QueryInfo) + Send + Sync + 'static) -> Self {
+        self.before_query.push(Arc::new(hook));
+        self
+    }
+
+    /// Call the function after running each `htmpl-query`, whether or not it succeeded.
+    ///
+    /// This is called for each query passed to the [`before_query`](Self::before_query) hooks.
+    pub fn after_query(
+        mut self,
+        hook: impl Fn(&QueryInfo, &QueryStats) + Send + Sync + 'static,
+    ) -> Self {
+        self.after_query.push(Arc::new(hook));
+        self
+    }
+
+    /// Rewrite the SQL of each `htmpl-query` before it is prepared.
+    ///
+    /// The function receives the name of the query and its SQL, and returns the SQL to run.
+    /// Rewrites apply in the order they are added; query hooks see the rewritten SQL.
+    ///
+    /// Parameters added by the rewrite (i.e. that are not in the template's SQL) are bound to
+    /// the attribute of the same name, if the element has one, or otherwise to the
+    /// [parameter](Self::params) of the same name (without the `:`, `@`, or `$` prefix).
+    /// For instance, to limit every query to the current tenant's rows:
+    ///
+    /// ```
+    /// let options = htmpl::EvaluateOptions::new().rewrite_query(|_name, sql| {
+    ///     format!("SELECT * FROM ({}) WHERE tenant_id = :tenant", sql.trim_end_matches(';'))
+    /// });
+    /// ```
+    pub fn rewrite_query(
+        mut self,
+        rewrite: impl Fn(&str, &str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.rewrite_query.push(Arc::new(rewrite));
+        self
+    }
+
+    /// Modify each row of each `htmpl-query`'s results before they are bound,
+    /// e.g. to decrypt or redact a column, or to add a column computed in Rust.
+    ///
+    /// The function receives the name of the query and the row, by column name.
+    /// Transformations apply in the order they are added;
+    /// an error from a transformation fails the evaluation.
+    pub fn transform_row(
+        mut self,
+        transform: impl Fn(&str, &mut HashMap<String, Value>) -> Result<(), Error>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.transform_row.push(Arc::new(transform));
+        self
+    }
+
+    /// Register SQL functions for template queries to call.
+    ///
+    /// The function is called before each evaluation, with the connection the template
+    /// is evaluated against; the functions remain registered on the connection afterwards.
+    /// See [`SqlFunctions`].
+    pub fn sql_functions(
+        mut self,
+        register: impl Fn(&SqlFunctions) -> rusqlite::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.sql_functions.push(Arc::new(register));
+        self
+    }
+
+    /// Set the delimiters of highlighted spans, for `htmpl-insert` elements with
+    /// a `highlight` attribute.
+    ///
+    /// By default, the delimiters are the start and end tags of the highlight element,
+    /// e.g. `<mark>` and `</mark>`. Delimiters that can't appear in the data
+    /// (e.g. control characters, passed to FTS5's `snippet()`) are more robust.
+    pub fn highlight_delimiters(
+        mut self,
+        start: impl Into<String>,
+        end: impl Into<String>,
+    ) -> Self {
+        self.highlight_delimiters = Some((start.into(), end.into()));
+        self
+    }
+
+    /// Set the source of CSRF tokens, for `htmpl-csrf` elements.
+    ///
+    /// The function is called for each `htmpl-csrf` element in the template,
+    /// so a host framework can supply (or mint) the token for the current session.
+    ///
+    /// ```
+    /// let token = "0123abcd".to_owned();
+    /// let options = htmpl::EvaluateOptions::new().csrf_token(move || token.clone());
+    /// ```
+    pub fn csrf_token(mut self, token: impl Fn() -> String + Send + Sync + 'static) -> Self {
+        self.csrf_token = Some(Callback::new(Arc::new(token)));
+        self
+    }
+
+    /// Set the Content-Security-Policy nonce for the evaluation.
+    ///
+    /// The nonce is added as the `nonce` attribute of each `<script>` and `<style>` element
+    /// in the output, and is available to the template as `htmpl(nonce)`.
+    /// Use a fresh nonce for each response, and send it in the `Content-Security-Policy` header:
+    /// `script-src 'nonce-...'`.
+    pub fn nonce(mut self, nonce: impl Into<String>) -> Self {
+        self.nonce = Some(nonce.into());
+        self
+    }
+
+    /// Add subresource integrity hashes to references to local assets.
+    ///
+    /// Each `<script src>`, and `<link href>` with `rel` of `stylesheet`, `preload`, or
+    /// `modulepreload`, that refers to a local file (i.e. a URL with no scheme or host) gets an
+    /// `integrity` attribute with the SHA-384 hash of the file, unless it already has one.
+    /// URLs are resolved as paths under the root, whether or not they start with `/`.
+    /// A missing file is an error.
+    pub fn asset_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.asset_root = Some(root.into());
+        self
+    }
+
+    /// Replace URLs that can run script with a harmless URL.
+    ///
+    /// Query values added to URL-valued attributes (e.g. `href` or `src`, by `htmpl-attr`)
+    /// are always percent-encoded where needed. With this option, values with a `javascript:`,
+    /// `vbscript:`, or `data:` scheme are also replaced with `about:invalid#htmpl`,
+    /// so a malicious column can't become a script in a link.
+    pub fn reject_unsafe_urls(mut self, reject: bool) -> Self {
+        self.reject_unsafe_urls = reject;
+        self
+    }
+
+    /// Give each heading (`<h1>` through `<h6>`) in the output an `id`, so it can be linked to.
+    ///
+    /// Headings that already have an `id` are unchanged; others get a slug of their text,
+    /// e.g. `id="getting-started"` for "Getting Started", with a number appended if the
+    /// slug is already in use on the page.
+    pub fn heading_ids(mut self, heading_ids: bool) -> Self {
+        self.heading_ids = heading_ids;
+        self
+    }
+
+    /// Rewrite the URLs in the output for a site deployed under the base URL.
+    ///
+    /// URLs in `href`, `src`, and similar attributes that are absolute paths are moved under
+    /// the base: with a base of `/blog/` or `https://example.com/blog/`, `/img/a.png` becomes
+    /// `/blog/img/a.png` or `https://example.com/blog/img/a.png`. If the base is an absolute
+    /// URL, other relative URLs are resolved against it too.
+    /// URLs with a scheme or host, and fragments (`#top`), are unchanged.
+    pub fn base_url(mut self, base: impl Into<String>) -> Self {
+        self.base_url = Some(base.into());
+        self
+    }
+
+    /// Transform the output tree before it is serialized, e.g. to add analytics attributes or
+    /// `loading="lazy"` to images.
+    ///
+    /// The function receives the evaluated output, after the built-in transformations
+    /// (e.g. [`heading_ids`](Self::heading_ids)); HTML output is a fragment,
+    /// with its content under an `html` element.
+    /// Transformations apply in the order they are added.
+    ///
+    /// ```
+    /// use html5ever::{local_name, namespace_url, ns, QualName};
+    /// use scraper::{Node, Selector};
+    ///
+    /// let images = Selector::parse("img").unwrap();
+    /// let options = htmpl::EvaluateOptions::new().post_process(move |html| {
+    ///     let ids: Vec<_> = html.select(&images).map(|e| e.id()).collect();
+    ///     for id in ids {
+    ///         let mut node = html.tree.get_mut(id).unwrap();
+    ///         if let Node::Element(img) = node.value() {
+    ///             let loading = QualName::new(None, ns!(), local_name!("loading"));
+    ///             img.attrs.insert(loading, "lazy".into());
+    ///         }
+    ///     }
+    /// });
+    /// ```
+    pub fn post_process(
+        mut self,
+        hook: impl Fn(&mut scraper::Html) + Send + Sync + 'static,
+    ) -> Self {
+        self.post_process.push(Arc::new(hook));
+        self
+    }
+
+    /// Set which comments in the template are kept in the output.
+    ///
+    /// By default, comments are kept; strip them so notes in templates aren't published.
+    pub fn comments(mut self, comments: Comments) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    /// Evaluate the template as a whole HTML document, rather than a fragment.
+    ///
+    /// The template is parsed per the HTML5 document rules, so it can have a `<!DOCTYPE html>`
+    /// and an `<html>` element, and the output keeps them, along with comments outside the
+    /// `<html>` element. Without a doctype, browsers render the page in quirks mode.
+    /// XML templates are always documents.
+    pub fn document(mut self, document: bool) -> Self {
+        self.document = document;
+        self
+    }
+
+    /// Set the layout of whitespace in the output, e.g. to pretty-print generated files.
+    pub fn formatting(mut self, formatting: Formatting) -> Self {
+        self.formatting = formatting;
+        self
+    }
+
+    /// Write non-ASCII characters in text and attribute values as numeric character references
+    /// (e.g. `&#xE9;` for `é`), for systems that don't handle UTF-8.
+    ///
+    /// The contents of `<script>` and `<style>` elements, and comments, are written as-is.
+    pub fn ascii_only(mut self, ascii_only: bool) -> Self {
+        self.ascii_only = ascii_only;
+        self
+    }
+
+    /// Write quotes (`"` and `'`) in text and attribute values as character references,
+    /// e.g. to embed the output in a quoted string.
+    ///
+    /// By default, only double quotes in attribute values are escaped.
+    pub fn escape_quotes(mut self, escape_quotes: bool) -> Self {
+        self.escape_quotes = escape_quotes;
+        self
+    }
+
+    /// Report HTML parse errors in detail, e.g. naming the unexpected token.
+    ///
+    /// This is on by default; turning it off makes parsing a little faster.
+    pub fn exact_errors(mut self, exact_errors: bool) -> Self {
+        self.parse.exact_errors = exact_errors;
+        self
+    }
+
+    /// Parse HTML templates as a browser with scripting enabled does.
+    ///
+    /// This is on by default, so the contents of `<noscript>` are parsed as raw text;
+    /// turn it off to parse them as markup, e.g. to evaluate htmpl elements within them.
+    pub fn scripting(mut self, scripting: bool) -> Self {
+        self.parse.scripting = scripting;
+        self
+    }
+
+    /// Reject templates longer than the given number of bytes.
+    ///
+    /// By default, there is no limit; set one when evaluating templates from untrusted sources.
+    pub fn max_template_len(mut self, max: usize) -> Self {
+        self.parse.max_len = Some(max);
+        self
+    }
+
+    /// Reject templates with elements nested more deeply than the given depth.
+    ///
+    /// Evaluation recurses through the template, so a deeply-nested template can exhaust the
+    /// stack. By default, there is no limit; set one when evaluating templates from untrusted
+    /// sources.
+    pub fn max_depth(mut self, max: usize) -> Self {
+        self.parse.max_depth = Some(max);
+        self
+    }
+
+    /// Accept HTML templates with recoverable parse errors, e.g. a stray end tag.
+    ///
+    /// By default, any parse error fails the evaluation. In lenient mode, the template is
+    /// parsed as a browser would parse it, and the errors are [diagnostics](crate::Diagnostic)
+    /// with the rule `parse`: they are logged as warnings, and returned with the output of
+    /// [`render`](crate::render).
+    /// XML templates must be well-formed regardless.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.parse.lenient = lenient;
+        self
+    }
+
+    /// Keep the values of query parameters out of diagnostics, e.g. when they may be emails or tokens.
+    ///
+    /// [Query hooks](Self::before_query) see each value as `[redacted]`, and parameter values
+    /// in the messages of SQL errors are replaced with `[redacted]`;
+    /// so are values in htmpl's own errors, e.g. a parameter that isn't of its declared type.
+    pub fn redact_params(mut self, redact: bool) -> Self {
+        self.redact_params = redact;
+        self
+    }
+
+    /// Call the function with the outcome of each `htmpl-query`: its name, SQL, and parameters;
+    /// how long it took; and how many rows it returned, or the error it failed with.
+    ///
+    /// This reports the same queries as the [`after_query`](Self::after_query) hooks,
+    /// in one event, e.g. for routing query telemetry to an application's own logs or metrics
+    /// without a `tracing` subscriber.
+    pub fn on_query(mut self, callback: impl Fn(&QueryEvent) + Send + Sync + 'static) -> Self {
+        self.on_query.push(Arc::new(callback));
+        self
+    }
+
+    /// Record each step of the evaluation: the elements visited, queries run, bindings made,
+    /// and conditions checked.
+    ///
+    /// The [trace](crate::Trace) is returned with the output of [`render`](crate::render)
+    /// (or [`Renderer::render`](crate::Renderer::render)), as [`RenderOutput::trace`](crate::RenderOutput::trace).
+    pub fn trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Note in the output where its content came from, for debugging.
+    ///
+    /// Each value inserted by `htmpl-insert`, and each row of an `htmpl-foreach`,
+    /// is wrapped in comments naming its query and row (counting from 1), e.g.
+    /// `<!-- htmpl: posts(title), row 3 -->First post<!-- /htmpl -->`.
+    pub fn annotate(mut self, annotate: bool) -> Self {
+        self.annotate = annotate;
+        self
+    }
+
+    /// Preview the template with fake data: rather than running each `htmpl-query`,
+    /// bind it to the given number of rows of plausible values for its columns.
+    ///
+    /// Values are chosen by each column's name (e.g. `email`, `title`, `created_at`) or declared
+    /// type, and are never null. The queries' tables must exist, but needn't have any rows;
+    /// and query parameters needn't be provided. This lets designers see populated loops
+    /// with only the database's schema.
+    pub fn preview(mut self, rows: usize) -> Self {
+        self.preview = Some(rows);
+        self
+    }
+
+    /// Check that the output parses cleanly, e.g. that values inserted as raw HTML didn't
+    /// introduce stray end tags or mis-nested elements.
+    ///
+    /// Problems are [diagnostics](crate::Diagnostic): they are logged as warnings, and returned
+    /// with the output of [`render`](crate::render) as [`RenderOutput::diagnostics`](crate::RenderOutput::diagnostics),
+    /// but don't fail the evaluation.
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Add a [lint](crate::lint) to check the output tree with.
+    ///
+    /// Lints run after [post-processing](Self::post_process); what they find is reported as
+    /// [diagnostics](crate::Diagnostic), which don't fail the evaluation.
+    pub fn lint(
+        mut self,
+        lint: impl Fn(&scraper::Html) -> Vec<crate::Diagnostic> + Send + Sync + 'static,
+    ) -> Self {
+        self.lints.push(Arc::new(lint));
+        self
+    }
+
+    /// Add the built-in accessibility lints: [images without alt text](crate::lint::img_alt),
+    /// [form controls without labels](crate::lint::form_labels), and
+    /// [duplicate ids](crate::lint::duplicate_ids).
+    pub fn accessibility_lints(self) -> Self {
+        self.lint(crate::lint::img_alt)
+            .lint(crate::lint::form_labels)
+            .lint(crate::lint::duplicate_ids)
+    }
+
+    /// Set the message catalog that `htmpl-t` elements look up messages in.
+    ///
+    /// To serve several languages, evaluate with the catalog for the locale of each request.
+    pub fn catalog(mut self, catalog: impl Into<Arc<Catalog>>) -> Self {
+        self.catalog = Some(catalog.into());
+        self
+    }
+
+    /// Set the time zone that `htmpl-insert` elements with an empty `tz` attribute
+    /// convert timestamps into, e.g. the viewer's:
+    ///
+    /// ```
+    /// let zone = jiff::tz::TimeZone::get("Europe/Paris").unwrap();
+    /// let options = htmpl::EvaluateOptions::new().timezone(zone);
+    /// # let conn = rusqlite::Connection::open_in_memory().unwrap();
+    /// let result = htmpl::evaluate_template_with(
+    ///     r#"<htmpl-query name="q">SELECT '2024-07-01 12:00:00' AS at;</htmpl-query><htmpl-insert query="q(at)" tz></htmpl-insert>"#,
+    ///     &conn,
+    ///     &options,
+    /// );
+    /// assert_eq!(result.unwrap(), "2024-07-01 14:00:00 CEST");
+    /// ```
+    pub fn timezone(mut self, timezone: jiff::tz::TimeZone) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Set how values are interpreted as conditions, e.g. to treat text flags
+    /// like `"false"` as falsy.
+    pub fn truthiness(mut self, truthiness: Truthiness) -> Self {
+        self.truthiness = truthiness;
+        self
+    }
+
+    /// Set the directory that `htmpl-query` elements load SQL from, with a `src` attribute:
+    /// `<htmpl-query name="users" src="queries/users.sql"></htmpl-query>`.
+    ///
+    /// Paths are resolved under the root, whether or not they start with `/`,
+    /// and may not refer outside of it.
+    pub fn query_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.query_root = Some(root.into());
+        self
+    }
+
+    /// Register a named SQL statement, which `htmpl-query` elements can run by reference:
+    /// `<htmpl-query name="q" ref="users_by_team" :team="params(team)"></htmpl-query>`.
+    ///
+    /// This keeps the SQL in code (and code review), rather than in templates.
+    /// Registering a statement with the same name replaces the existing one.
+    pub fn named_query(mut self, name: impl Into<String>, sql: impl AsRef<str>) -> Self {
+        self.named_queries
+            .insert(name.into(), sql.as_ref().trim().into());
+        self
+    }
+
+    /// Run the query at the start of each evaluation, binding its results to the name
+    /// in every template, e.g. for site settings or navigation.
+    ///
+    /// Named parameters of the query (e.g. `:user`) are bound from the evaluation's
+    /// [parameters](Self::params). A query of the same name in a template shadows the prelude.
+    pub fn prelude_query(mut self, name: impl Into<String>, sql: impl AsRef<str>) -> Self {
+        self.prelude
+            .push((name.into(), Prelude::Query(sql.as_ref().trim().into())));
+        self
+    }
+
+    /// Bind the rows to the name in every template, e.g. for the current user.
+    pub fn prelude_rows(mut self, name: impl Into<String>, rows: QueryResult) -> Self {
+        self.prelude.push((name.into(), Prelude::Rows(rows.into())));
+        self
+    }
+
+    /// Set the templates that `htmpl-extends` elements find layouts in.
+    ///
+    /// [`Templates::evaluate`] and [`Renderer`](crate::Renderer) set this to their own templates,
+    /// if it isn't already set.
+    pub fn templates(mut self, templates: impl Into<Arc<Templates>>) -> Self {
+        self.templates = Some(templates.into());
+        self
+    }
+
+    /// Recognize htmpl elements by the given prefix, e.g. `x-` for `<x-insert>`,
+    /// rather than `htmpl-`; for toolchains that already use the `htmpl-` prefix.
+    ///
+    /// Errors and traces name elements with the prefix, as written in the template.
+    /// Elements with the `htmpl-` prefix are still recognized.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into()).filter(|p| !p.is_empty() && p != "htmpl-");
+        self
+    }
+
+    /// Set whether the evaluation may write to the database, per the [`Mode`].
+    ///
+    /// By default, a query that writes to the database is an error.
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Attach the database file at the path to the connection before evaluation,
+    /// under the schema name: e.g. `search`, for queries of tables like `search.docs`.
+    ///
+    /// The database is attached to the connection the template is evaluated against,
+    /// and remains attached afterwards; a connection that already has a database
+    /// attached under the name is left as is.
+    /// Templates can't attach (or detach) databases themselves.
+    pub fn attach(mut self, schema: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        self.attachments.push((schema.into(), path.into()));
+        self
+    }
+
+    /// Check that each [named query](Self::named_query) is valid SQL for the database,
+    /// e.g. at startup, rather than on the first request that uses it.
+    pub fn check_named_queries(&self, conn: &rusqlite::Connection) -> Result<(), Error> {
+        for (name, sql) in self.named_queries.iter() {
+            conn.prepare(sql).map_err(|e| Error::Sql(name.clone(), e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! The output of a render, and metadata about it.
+//!
+//! Alongside the evaluated document, a render reports:
+//!
+//! - A hash of the output, for use as an HTTP entity tag (`ETag`).
+//! - The time the underlying data was last modified, per the _freshness queries_
+//!   configured in the [options](crate::EvaluateOptions::freshness_query).
+//!
+//! Freshness queries can be run on their own, via [`last_modified`],
+//! so HTTP layers can answer conditional requests without evaluating the template.
+
+use rusqlite::types::{Value, ValueRef};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    functions, queries::DbTable, visit::evaluate_render, Diagnostic, Error, EvaluateOptions, Trace,
+};
+
+/// The result of rendering a template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOutput {
+    output: String,
+    last_modified: Option<i64>,
+    trace: Option<Trace>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl RenderOutput {
+    pub(crate) fn new(output: String, last_modified: Option<i64>) -> Self {
+        RenderOutput {
+            output,
+            last_modified,
+            trace: None,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_trace(mut self, trace: Option<Trace>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub(crate) fn with_diagnostics(mut self, diagnostics: Vec<Diagnostic>) -> Self {
+        self.diagnostics = diagnostics;
+        self
+    }
+
+    /// The evaluated document.
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    /// The evaluated document.
+    pub fn into_output(self) -> String {
+        self.output
+    }
+
+    /// A strong entity tag for the output: a quoted hash of its content.
+    pub fn etag(&self) -> String {
+        let hash = Sha256::digest(self.output.as_bytes());
+        let hex: String = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("\"{}\"", hex)
+    }
+
+    /// The latest time reported by the freshness queries, in seconds since the Unix epoch.
+    ///
+    /// None if there are no freshness queries, or none of them returned a time.
+    pub fn last_modified(&self) -> Option<i64> {
+        self.last_modified
+    }
+
+    /// The steps of the evaluation, if the options asked for a [trace](EvaluateOptions::trace).
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// Problems found in the output, e.g. by [validation](EvaluateOptions::validate).
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+/// Evaluate the template, and report metadata about the output.
+pub fn render(
+    s: impl AsRef<str>,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+) -> Result<RenderOutput, Error> {
+    let last_modified = last_modified(dbs, options)?;
+    evaluate_render(s, dbs, options, last_modified)
+}
+
+/// Run the freshness queries from the options, and return the latest time they report,
+/// in seconds since the Unix epoch.
+///
+/// Each freshness query should return a single value:
+/// an integer number of seconds since the Unix epoch,
+/// or a string in one of SQLite's [date and time formats](https://sqlite.org/lang_datefunc.html).
+/// Null values and empty results are ignored.
+///
+/// Named parameters in a freshness query are bound to the [parameter](crate::Params)
+/// of the same name (without the `:`, `@`, or `$` prefix).
+pub fn last_modified(dbs: &DbTable, options: &EvaluateOptions) -> Result<Option<i64>, Error> {
+    if options.freshness_queries.is_empty() {
+        return Ok(None);
+    }
+    functions::setup(dbs, options)?;
+    let mut latest = None;
+    for query in &options.freshness_queries {
+        let note_err = |e| Error::Sql(query.clone(), e);
+        let mut st = dbs.prepare(query).map_err(note_err)?;
+        for i in 1..=st.parameter_count() {
+            let name = st
+                .parameter_name(i)
+                .ok_or_else(|| Error::InvalidParameter("freshness".into(), format!("?{}", i)))?;
+            let value = options
+                .params
+                .get(&name[1..])
+                .ok_or_else(|| Error::MissingParameter("freshness".into(), name.to_owned()))?;
+            st.raw_bind_parameter(i, value).map_err(note_err)?;
+        }
+        let mut rows = st.raw_query();
+        let Some(row) = rows.next().map_err(note_err)? else {
+            continue;
+        };
+        let time = match row.get_ref(0).map_err(note_err)? {
+            ValueRef::Null => None,
+            ValueRef::Integer(i) => Some(i),
+            ValueRef::Real(f) => Some(f as i64),
+            ValueRef::Text(_) | ValueRef::Blob(_) => {
+                let value: Value = row.get(0).map_err(note_err)?;
+                dbs.query_row("SELECT unixepoch(?)", [value], |row| row.get(0))
+                    .map_err(note_err)?
+            }
+        };
+        latest = latest.max(time);
+    }
+    Ok(latest)
+}
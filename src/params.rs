@@ -0,0 +1,107 @@
+//! Parameters to a template.
+//!
+//! Parameters are provided by the caller at evaluation time, e.g. from an HTTP request.
+//! In the template, they are bound to the single-row query named `params`,
+//! with one column per parameter:
+//!
+//! ```html
+//! <htmpl-query name="post" :id="params(id)">SELECT title FROM posts WHERE id = :id;</htmpl-query>
+//! ```
+
+use std::collections::HashMap;
+
+use rusqlite::types::Value;
+
+/// Name of the query to which parameters are bound.
+pub const PARAMS_QUERY: &str = "params";
+
+/// Name of the query to which values from the evaluation options are bound:
+/// the CSP `nonce`.
+pub const HTMPL_QUERY: &str = "htmpl";
+
+/// What query parameters are shown as, when [redacted](crate::EvaluateOptions::redact_params).
+pub(crate) const REDACTED: &str = "[redacted]";
+
+/// Describe the value for an error message; as [`REDACTED`], if values are redacted.
+pub(crate) fn describe(value: &Value, redact: bool) -> String {
+    match redact {
+        true => REDACTED.to_owned(),
+        false => format!("{:?}", value),
+    }
+}
+
+/// Replace the text of parameter values in a SQLite error message with [`REDACTED`].
+pub(crate) fn redact_error(e: rusqlite::Error, values: &[&Value]) -> rusqlite::Error {
+    match e {
+        rusqlite::Error::SqliteFailure(code, Some(mut message)) => {
+            for value in values {
+                let text = match value {
+                    Value::Text(s) => s.clone(),
+                    Value::Integer(i) => i.to_string(),
+                    Value::Real(f) => f.to_string(),
+                    Value::Null | Value::Blob(_) => continue,
+                };
+                if !text.is_empty() {
+                    message = message.replace(&text, REDACTED);
+                }
+            }
+            rusqlite::Error::SqliteFailure(code, Some(message))
+        }
+        e => e,
+    }
+}
+
+/// Parameters to a template evaluation.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params(HashMap<String, Value>);
+
+impl Params {
+    /// Create an empty parameter set.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a parameter.
+    pub fn set(mut self, name: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.insert(name, value);
+        self
+    }
+
+    /// Add a parameter.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<Value>) {
+        self.0.insert(name.into(), value.into());
+    }
+
+    /// Look up a parameter.
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&Value> {
+        self.0.get(name.as_ref())
+    }
+
+    /// The parameters, as a row of the `params` query.
+    pub(crate) fn row(&self) -> HashMap<String, Value> {
+        self.0.clone()
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for Params {
+    fn from_iter<T: IntoIterator<Item = (K, V)>>(iter: T) -> Self {
+        Params(
+            iter.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        )
+    }
+}
+
+/// Text parameters, e.g. from a URL query string.
+impl From<HashMap<String, String>> for Params {
+    fn from(value: HashMap<String, String>) -> Self {
+        value.into_iter().collect()
+    }
+}
+
+impl From<()> for Params {
+    fn from(_: ()) -> Self {
+        Default::default()
+    }
+}
@@ -0,0 +1,296 @@
+//! Parsing of templates.
+
+use std::borrow::Cow;
+
+use ego_tree::{iter::Edge, NodeId};
+use html5ever::{
+    local_name, namespace_url, ns,
+    tendril::{StrTendril, TendrilSink},
+    tokenizer::TokenizerOpts,
+    tree_builder::{ElementFlags, NodeOrText, QuirksMode, TreeBuilderOpts, TreeSink},
+    Attribute, ExpandedName, QualName,
+};
+use scraper::{Html, Node};
+
+use crate::{
+    layout,
+    options::{OutputFormat, ParseSettings},
+    prefix,
+    visit::{is_inert, ELEMENTS},
+    Error, EvaluateOptions,
+};
+
+/// Parse the template per the format in the options,
+/// and [merge it into the layout](layout::extend) it extends, if any.
+pub fn parse(s: &str, options: &EvaluateOptions) -> Result<Html, Error> {
+    layout::extend(parse_template(s, options)?, options).map_err(|e| prefix::error(options, e))
+}
+
+/// Parse the template per the format in the options.
+pub(crate) fn parse_template(s: &str, options: &EvaluateOptions) -> Result<Html, Error> {
+    let settings = &options.parse;
+    if let Some(max) = settings.max_len {
+        if s.len() > max {
+            return Err(Error::Limit("template length".into(), s.len(), max));
+        }
+    }
+    let mut h = match options.format {
+        OutputFormat::Html | OutputFormat::Xhtml if options.document => {
+            parse_html_document(s, settings)
+        }
+        OutputFormat::Html | OutputFormat::Xhtml => parse_html(s, settings),
+        OutputFormat::Xml => parse_xml(s),
+    }?;
+    if let Some(max) = settings.max_depth {
+        check_depth(&h, max)?;
+    }
+    prefix::canonicalize(&mut h, options);
+    trim_whitespace(&mut h);
+    Ok(h)
+}
+
+/// Remove the whitespace around htmpl elements with a `trim` attribute,
+/// and at the start and end of the content of those with a `trim-inner` attribute;
+/// so the indentation of control elements doesn't show up in the output.
+fn trim_whitespace(h: &mut Html) {
+    let mut edits: Vec<(NodeId, Trim)> = Vec::new();
+    for node in h.tree.nodes() {
+        let Some(e) = node.value().as_element() else {
+            continue;
+        };
+        if !ELEMENTS.contains(&e.name()) || is_inert(node) {
+            continue;
+        }
+        if e.attr("trim").is_some() {
+            edits.extend(node.prev_sibling().map(|n| (n.id(), Trim::End)));
+            edits.extend(node.next_sibling().map(|n| (n.id(), Trim::Start)));
+        }
+        if e.attr("trim-inner").is_some() {
+            edits.extend(node.first_child().map(|n| (n.id(), Trim::Start)));
+            edits.extend(node.last_child().map(|n| (n.id(), Trim::End)));
+        }
+    }
+    for (id, trim) in edits {
+        let mut node = h.tree.get_mut(id).expect("node is in the tree");
+        let Node::Text(text) = node.value() else {
+            continue;
+        };
+        let trimmed = match trim {
+            Trim::Start => text.trim_start(),
+            Trim::End => text.trim_end(),
+        };
+        if trimmed.is_empty() {
+            node.detach();
+        } else {
+            text.text = trimmed.into();
+        }
+    }
+}
+
+/// Which end of a text node to trim.
+enum Trim {
+    Start,
+    End,
+}
+
+/// Check that elements in the template are nested no more deeply than the limit.
+fn check_depth(h: &Html, max: usize) -> Result<(), Error> {
+    let mut depth = 0;
+    for edge in h.tree.root().traverse() {
+        match edge {
+            Edge::Open(node) if node.value().is_element() => {
+                depth += 1;
+                if depth > max {
+                    return Err(Error::Limit("template depth".into(), depth, max));
+                }
+            }
+            Edge::Close(node) if node.value().is_element() => depth -= 1,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Parse an HTML fragment.
+fn parse_html(s: &str, settings: &ParseSettings) -> Result<Html, Error> {
+    check_html(html_fragment(s, settings), settings)
+}
+
+/// Parse a whole HTML document, keeping its doctype and the comments around its `<html>` element.
+fn parse_html_document(s: &str, settings: &ParseSettings) -> Result<Html, Error> {
+    check_html(html_document(s, settings), settings)
+}
+
+/// Parse an HTML fragment, noting (but not checking) any errors.
+fn html_fragment(s: &str, settings: &ParseSettings) -> Html {
+    // scraper::parse_fragment impugns an <html> element into the root, which isn't necessarily
+    // true for us.
+    // Try to parse without adding an <html>.
+    // ...doesn't work.
+    html5ever::driver::parse_fragment(
+        LineSink::new(Html::new_fragment()),
+        parse_opts(settings),
+        QualName::new(None, ns!(html), local_name!("body")),
+        Vec::new(),
+    )
+    .one(s)
+}
+
+/// Parse an HTML document, noting (but not checking) any errors.
+fn html_document(s: &str, settings: &ParseSettings) -> Html {
+    html5ever::driver::parse_document(LineSink::new(Html::new_document()), parse_opts(settings))
+        .one(s)
+}
+
+/// Parse the output of an evaluation, per the format in the options, and return the errors in it:
+/// e.g. stray end tags, or elements nested where they aren't allowed.
+pub(crate) fn output_errors(s: &str, options: &EvaluateOptions) -> Vec<String> {
+    let h = match options.format {
+        OutputFormat::Html if options.document => html_document(s, &options.parse),
+        OutputFormat::Html => html_fragment(s, &options.parse),
+        OutputFormat::Xml | OutputFormat::Xhtml => {
+            xml5ever::driver::parse_document(Html::new_document(), Default::default()).one(s)
+        }
+    };
+    h.errors.into_iter().map(String::from).collect()
+}
+
+fn parse_opts(settings: &ParseSettings) -> html5ever::ParseOpts {
+    html5ever::ParseOpts {
+        tokenizer: TokenizerOpts {
+            exact_errors: settings.exact_errors,
+            ..TokenizerOpts::default()
+        },
+        tree_builder: TreeBuilderOpts {
+            exact_errors: settings.exact_errors,
+            scripting_enabled: settings.scripting,
+            ..TreeBuilderOpts::default()
+        },
+    }
+}
+
+/// Check the parsed HTML for errors: fail on them, or in lenient mode, keep them in the tree,
+/// to be reported as [diagnostics](crate::Diagnostic).
+fn check_html(h: Html, settings: &ParseSettings) -> Result<Html, Error> {
+    if !settings.lenient && !h.errors.is_empty() {
+        return Err(Error::HtmlParse(h.errors.join("; ")));
+    }
+    tracing::debug!("quirks: {:?}", h.quirks_mode);
+    Ok(h)
+}
+
+/// Parse an XML document.
+fn parse_xml(s: &str) -> Result<Html, Error> {
+    let h = xml5ever::driver::parse_document(Html::new_document(), Default::default()).one(s);
+    if !h.errors.is_empty() {
+        return Err(Error::XmlParse(h.errors.join("; ")));
+    }
+    Ok(h)
+}
+
+/// A tree sink that builds an [`Html`], noting the line of each parse error.
+struct LineSink {
+    html: Html,
+    line: u64,
+}
+
+impl LineSink {
+    fn new(html: Html) -> Self {
+        LineSink { html, line: 1 }
+    }
+}
+
+impl TreeSink for LineSink {
+    type Handle = NodeId;
+    type Output = Html;
+
+    fn finish(self) -> Html {
+        self.html
+    }
+
+    fn parse_error(&mut self, msg: Cow<'static, str>) {
+        let msg = format!("line {}: {}", self.line, msg);
+        self.html.parse_error(msg.into())
+    }
+
+    fn set_current_line(&mut self, line_number: u64) {
+        self.line = line_number;
+    }
+
+    fn get_document(&mut self) -> NodeId {
+        self.html.get_document()
+    }
+
+    fn elem_name<'a>(&'a self, target: &'a NodeId) -> ExpandedName<'a> {
+        self.html.elem_name(target)
+    }
+
+    fn create_element(
+        &mut self,
+        name: QualName,
+        attrs: Vec<Attribute>,
+        flags: ElementFlags,
+    ) -> NodeId {
+        self.html.create_element(name, attrs, flags)
+    }
+
+    fn create_comment(&mut self, text: StrTendril) -> NodeId {
+        self.html.create_comment(text)
+    }
+
+    fn create_pi(&mut self, target: StrTendril, data: StrTendril) -> NodeId {
+        self.html.create_pi(target, data)
+    }
+
+    fn append(&mut self, parent: &NodeId, child: NodeOrText<NodeId>) {
+        self.html.append(parent, child)
+    }
+
+    fn append_based_on_parent_node(
+        &mut self,
+        element: &NodeId,
+        prev_element: &NodeId,
+        child: NodeOrText<NodeId>,
+    ) {
+        self.html
+            .append_based_on_parent_node(element, prev_element, child)
+    }
+
+    fn append_doctype_to_document(
+        &mut self,
+        name: StrTendril,
+        public_id: StrTendril,
+        system_id: StrTendril,
+    ) {
+        self.html
+            .append_doctype_to_document(name, public_id, system_id)
+    }
+
+    fn get_template_contents(&mut self, target: &NodeId) -> NodeId {
+        self.html.get_template_contents(target)
+    }
+
+    fn same_node(&self, x: &NodeId, y: &NodeId) -> bool {
+        self.html.same_node(x, y)
+    }
+
+    fn set_quirks_mode(&mut self, mode: QuirksMode) {
+        self.html.set_quirks_mode(mode)
+    }
+
+    fn append_before_sibling(&mut self, sibling: &NodeId, new_node: NodeOrText<NodeId>) {
+        self.html.append_before_sibling(sibling, new_node)
+    }
+
+    fn add_attrs_if_missing(&mut self, target: &NodeId, attrs: Vec<Attribute>) {
+        self.html.add_attrs_if_missing(target, attrs)
+    }
+
+    fn remove_from_parent(&mut self, target: &NodeId) {
+        self.html.remove_from_parent(target)
+    }
+
+    fn reparent_children(&mut self, node: &NodeId, new_parent: &NodeId) {
+        self.html.reparent_children(node, new_parent)
+    }
+}
@@ -0,0 +1,35 @@
+//! Resolution of paths in templates and requests against a root directory.
+
+use std::path::{Component, Path, PathBuf};
+
+/// The file under the root that the path refers to, with any leading `/` ignored;
+/// or None if the path would leave the root, e.g. with `..`.
+pub(crate) fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path.trim_start_matches('/'));
+    // Don't follow references out of the root.
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return None;
+    }
+    Some(root.join(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::resolve;
+
+    #[test]
+    fn paths() {
+        let root = Path::new("/srv/site");
+        assert_eq!(
+            resolve(root, "/css/./main.css"),
+            Some(root.join("css/./main.css"))
+        );
+        assert_eq!(resolve(root, "a/../../etc/passwd"), None);
+        assert_eq!(resolve(root, "//etc"), Some(root.join("etc")));
+    }
+}
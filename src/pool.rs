@@ -0,0 +1,149 @@
+//! A pool of database connections.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::{types::Value, Connection, OpenFlags};
+
+use crate::{queries::DbTable, Error, Params};
+
+/// A pool of read-only connections to a SQLite database file.
+///
+/// Connections are opened on demand, and returned to the pool when dropped.
+#[derive(Debug)]
+pub struct Pool {
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Pool {
+    /// Create a pool of connections to the database at the path.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Pool {
+            path: path.into(),
+            idle: Default::default(),
+        }
+    }
+
+    /// The path to the database.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get a connection from the pool, opening a new one if none are idle.
+    pub fn get(&self) -> Result<PooledConnection<'_>, Error> {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let conn = match idle {
+            Some(conn) => conn,
+            None => Connection::open_with_flags(
+                &self.path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )
+            .map_err(|e| Error::Open(self.path.display().to_string(), e))?,
+        };
+        Ok(PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        })
+    }
+}
+
+/// A connection borrowed from a [`Pool`].
+#[derive(Debug)]
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = DbTable;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().expect("connection already returned")
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool
+                .idle
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(conn);
+        }
+    }
+}
+
+/// Opens the pool for a tenant's database.
+type OpenTenant = dyn Fn(&str) -> Result<Pool, Error> + Send + Sync;
+
+/// A pool for each tenant's database, chosen by a parameter to the render,
+/// per [`Renderer::route`](crate::Renderer::route).
+pub(crate) struct Router {
+    param: String,
+    open: Box<OpenTenant>,
+    pools: Mutex<HashMap<String, Arc<Pool>>>,
+}
+
+impl Router {
+    pub(crate) fn new(
+        param: String,
+        open: impl Fn(&str) -> Result<Pool, Error> + Send + Sync + 'static,
+    ) -> Self {
+        Router {
+            param,
+            open: Box::new(open),
+            pools: Default::default(),
+        }
+    }
+
+    /// The pool for the tenant named by the parameters, opened on first use.
+    pub(crate) fn pool(&self, params: &Params) -> Result<Arc<Pool>, Error> {
+        let tenant = match params.get(&self.param) {
+            Some(Value::Text(tenant)) => tenant.clone(),
+            Some(Value::Integer(tenant)) => tenant.to_string(),
+            _ => return Err(Error::MissingParameter("".into(), self.param.clone())),
+        };
+        // Tenant names come from requests; keep them from naming other paths.
+        if !is_tenant_name(&tenant) {
+            return Err(Error::InvalidParameter(
+                "".into(),
+                format!("{} is not a valid tenant name", self.param),
+            ));
+        }
+        let mut pools = self.pools.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(pool) = pools.get(&tenant) {
+            return Ok(pool.clone());
+        }
+        let pool = Arc::new((self.open)(&tenant)?);
+        pools.insert(tenant, pool.clone());
+        Ok(pool)
+    }
+}
+
+/// Whether the tenant name is a plain name, usable as a single path component:
+/// ASCII letters, digits, `-`, `_`, and `.`, not starting with `.`.
+fn is_tenant_name(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && !tenant.starts_with('.')
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Router")
+            .field("param", &self.param)
+            .field("pools", &self.pools)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,110 @@
+//! Passes over the output tree, after evaluation.
+
+use std::collections::HashSet;
+
+use ego_tree::{NodeId, Tree};
+use html5ever::{local_name, QualName};
+use scraper::Node;
+
+use crate::{functions::slug, urls, Comments};
+
+/// Give each heading (`h1` through `h6`) without an `id` an `id` derived from its text,
+/// e.g. `getting-started` for "Getting Started".
+///
+/// If the `id` is already in use, a number is appended: `getting-started-1`, and so on.
+pub(crate) fn heading_ids(tree: &mut Tree<Node>) {
+    let mut ids: HashSet<String> = tree
+        .root()
+        .descendants()
+        .filter_map(|n| n.value().as_element()?.id())
+        .map(str::to_owned)
+        .collect();
+    let headings: Vec<(NodeId, String)> = tree
+        .root()
+        .descendants()
+        .filter(|n| {
+            n.value().as_element().is_some_and(|e| {
+                matches!(e.name(), "h1" | "h2" | "h3" | "h4" | "h5" | "h6") && e.id().is_none()
+            })
+        })
+        .map(|n| {
+            let text: String = n
+                .descendants()
+                .filter_map(|d| d.value().as_text())
+                .map(|t| &**t)
+                .collect();
+            (n.id(), slug(&text))
+        })
+        .filter(|(_, slug)| !slug.is_empty())
+        .collect();
+    for (id, slug) in headings {
+        let mut unique = slug.clone();
+        let mut n = 0;
+        while ids.contains(&unique) {
+            n += 1;
+            unique = format!("{}-{}", slug, n);
+        }
+        ids.insert(unique.clone());
+        if let Some(mut node) = tree.get_mut(id) {
+            if let Node::Element(e) = node.value() {
+                e.attrs.insert(
+                    QualName::new(None, "".into(), local_name!("id")),
+                    unique.into(),
+                );
+            }
+        }
+    }
+}
+
+/// Rebase the URLs in URL-valued attributes (`href`, `src`, and the like) per [`urls::rebase`].
+pub(crate) fn rebase_urls(tree: &mut Tree<Node>, base: &str) {
+    let ids: Vec<NodeId> = tree
+        .root()
+        .descendants()
+        .filter(|n| n.value().is_element())
+        .map(|n| n.id())
+        .collect();
+    for id in ids {
+        let Some(mut node) = tree.get_mut(id) else {
+            continue;
+        };
+        let Node::Element(e) = node.value() else {
+            continue;
+        };
+        for (name, value) in e.attrs.iter_mut() {
+            if !urls::is_url_attr(&name.local) {
+                continue;
+            }
+            if let Some(rebased) = urls::rebase(base, value) {
+                *value = rebased.into();
+            }
+        }
+    }
+}
+
+/// Returns true if the comment is (part of) an Internet Explorer conditional comment,
+/// e.g. `<!--[if IE]> ... <![endif]-->`.
+fn is_conditional(comment: &str) -> bool {
+    let comment = comment.trim_start();
+    comment.starts_with("[if ") || comment.starts_with("[endif]")
+}
+
+/// Remove comments from the tree, per the policy.
+pub(crate) fn strip_comments(tree: &mut Tree<Node>, policy: Comments) {
+    let keep = |comment: &str| match policy {
+        Comments::Keep => true,
+        Comments::Strip => false,
+        Comments::KeepConditional => is_conditional(comment),
+    };
+    let comments: Vec<NodeId> = tree
+        .root()
+        .descendants()
+        .filter(|n| n.value().as_comment().is_some_and(|c| !keep(c)))
+        .map(|n| n.id())
+        .collect();
+    for id in comments {
+        if let Some(mut node) = tree.get_mut(id) {
+            node.detach();
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! A custom prefix for htmpl elements, per [`EvaluateOptions::prefix`].
+//!
+//! Templates are parsed with the custom prefix, and the htmpl elements in them renamed
+//! to their `htmpl-` names; so the rest of the evaluation only deals with `htmpl-` names.
+//! Errors and traces name the elements with the custom prefix again, as they were written.
+
+use html5ever::LocalName;
+use scraper::{Html, Node};
+
+use crate::{visit::ELEMENTS, Error, EvaluateOptions};
+
+/// Rename elements with the custom prefix (if any) that name htmpl elements, e.g. `x-insert`,
+/// to their `htmpl-` names; except within `htmpl-verbatim` elements.
+///
+/// Other elements with the prefix, e.g. those with [custom handlers](EvaluateOptions::element),
+/// are unchanged.
+pub(crate) fn canonicalize(h: &mut Html, options: &EvaluateOptions) {
+    let Some(prefix) = &options.prefix else {
+        return;
+    };
+    let renames: Vec<_> = h
+        .tree
+        .nodes()
+        .filter(|node| {
+            !node.ancestors().any(|a| {
+                a.value().as_element().is_some_and(|e| {
+                    e.name() == "htmpl-verbatim"
+                        || e.name().strip_prefix(prefix.as_str()) == Some("verbatim")
+                })
+            })
+        })
+        .filter_map(|node| {
+            let rest = node
+                .value()
+                .as_element()?
+                .name()
+                .strip_prefix(prefix.as_str())?;
+            let name = format!("htmpl-{}", rest);
+            (ELEMENTS.contains(&name.as_str()) || name == "htmpl-extends")
+                .then(|| (node.id(), LocalName::from(name)))
+        })
+        .collect();
+    for (id, name) in renames {
+        if let Some(Node::Element(e)) = h.tree.get_mut(id).as_mut().map(|n| n.value()) {
+            e.name.local = name;
+        }
+    }
+}
+
+/// The name of the element as written in the template, with the custom prefix (if any).
+pub(crate) fn display(options: &EvaluateOptions, name: &str) -> String {
+    match (&options.prefix, name.strip_prefix("htmpl-")) {
+        (Some(prefix), Some(rest)) => format!("{}{}", prefix, rest),
+        _ => name.to_owned(),
+    }
+}
+
+/// Name the element in the error with the custom prefix (if any).
+pub(crate) fn error(options: &EvaluateOptions, error: Error) -> Error {
+    let Some(prefix) = &options.prefix else {
+        return error;
+    };
+    match error.element() {
+        Some(element) if element.starts_with("htmpl-") && !element.starts_with(prefix.as_str()) => {
+            let element = display(options, element);
+            error.set_element(element)
+        }
+        _ => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonicalize, display, error};
+    use crate::{Error, EvaluateOptions};
+
+    #[test]
+    fn rename() {
+        let options = EvaluateOptions::new().prefix("x-");
+        let mut h = scraper::Html::parse_fragment(
+            r#"<x-insert query="q"></x-insert><x-widget></x-widget><htmpl-if></htmpl-if>"#,
+        );
+        canonicalize(&mut h, &options);
+        let names: Vec<_> = h
+            .tree
+            .nodes()
+            .filter_map(|n| Some(n.value().as_element()?.name().to_owned()))
+            .collect();
+        assert_eq!(
+            names,
+            ["body", "html", "htmpl-insert", "x-widget", "htmpl-if"]
+        );
+        assert_eq!(display(&options, "htmpl-insert"), "x-insert");
+        assert_eq!(display(&options, "div"), "div");
+        assert_eq!(
+            error(
+                &options,
+                Error::MissingAttr("htmpl-insert".into(), "query".into())
+            ),
+            Error::MissingAttr("x-insert".into(), "query".into())
+        );
+    }
+}
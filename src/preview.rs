@@ -0,0 +1,138 @@
+//! Fake data, for previewing templates without real data.
+//!
+//! In [preview mode](crate::EvaluateOptions::preview), queries aren't run; instead, each
+//! query's result columns are introspected, and each row is filled in with a plausible value
+//! for the column, per its name (e.g. `email`, `created_at`) or declared type.
+
+use std::collections::HashMap;
+
+use rusqlite::{types::Value, Statement};
+
+use crate::QueryResult;
+
+const WORDS: &[&str] = &[
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+];
+const NAMES: &[&str] = &["Ada", "Grace", "Alan", "Barbara", "Edsger", "Frances"];
+
+/// Generate `count` rows of fake data for the statement's columns.
+///
+/// Values are never null, so `NOT NULL` columns are respected.
+pub(crate) fn fake_rows(st: &Statement, count: usize) -> QueryResult {
+    let columns = st.columns();
+    (0..count)
+        .map(|row| {
+            columns
+                .iter()
+                .map(|column| {
+                    let value = fake_value(column.name(), column.decl_type(), row);
+                    (column.name().to_owned(), value)
+                })
+                .collect::<HashMap<_, _>>()
+        })
+        .collect()
+}
+
+/// A plausible value for the named column, in the given (0-indexed) row.
+fn fake_value(name: &str, decl_type: Option<&str>, row: usize) -> Value {
+    let name = name.to_ascii_lowercase();
+    let n = row + 1;
+    let word = |i: usize| WORDS[i % WORDS.len()];
+    let has = |s: &str| name.contains(s);
+    if name == "id" || name.ends_with("_id") || has("count") || has("num") {
+        return Value::Integer(n as i64);
+    }
+    if has("email") {
+        return Value::Text(format!("user{}@example.com", n));
+    }
+    if has("url") || has("href") || has("link") {
+        return Value::Text(format!("https://example.com/{}", n));
+    }
+    if has("slug") {
+        return Value::Text(format!("{}-{}", word(row), n));
+    }
+    if has("date") || has("time") || name.ends_with("_at") || name.ends_with("_on") {
+        return Value::Text(format!("2024-01-{:02} 12:00:00", n % 28 + 1));
+    }
+    if has("name") || has("author") || has("user") {
+        return Value::Text(NAMES[row % NAMES.len()].to_owned());
+    }
+    if has("title") || has("subject") || has("label") {
+        return Value::Text(format!("{} {} {}", capitalize(word(row)), word(row + 1), n));
+    }
+    if has("body") || has("content") || has("description") || has("summary") || has("text") {
+        let sentence: Vec<&str> = (row..row + 12).map(word).collect();
+        return Value::Text(format!("{}.", capitalize(&sentence.join(" "))));
+    }
+    // Per SQLite's rules for column affinity:
+    // https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+    let decl_type = decl_type.unwrap_or_default().to_ascii_uppercase();
+    if decl_type.contains("INT") {
+        Value::Integer(n as i64)
+    } else if decl_type.contains("CHAR") || decl_type.contains("CLOB") || decl_type.contains("TEXT")
+    {
+        Value::Text(format!("{} {}", word(row), n))
+    } else if decl_type.contains("BLOB") {
+        Value::Blob(vec![n as u8])
+    } else if decl_type.contains("REAL") || decl_type.contains("FLOA") || decl_type.contains("DOUB")
+    {
+        Value::Real(n as f64 * 1.5)
+    } else if decl_type.is_empty() {
+        // An expression, rather than a column: text is the most likely to show up sensibly.
+        Value::Text(format!("{} {}", word(row), n))
+    } else {
+        Value::Integer(n as i64)
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::{types::Value, Connection};
+
+    use super::{fake_rows, fake_value};
+
+    #[test]
+    fn values() {
+        assert_eq!(fake_value("id", Some("INTEGER"), 0), Value::Integer(1));
+        assert_eq!(
+            fake_value("author_email", Some("TEXT"), 1),
+            Value::Text("user2@example.com".to_owned())
+        );
+        assert_eq!(
+            fake_value("created_at", None, 0),
+            Value::Text("2024-01-02 12:00:00".to_owned())
+        );
+        assert_eq!(fake_value("score", Some("REAL"), 1), Value::Real(3.0));
+        assert_eq!(fake_value("views", Some("BIGINT"), 2), Value::Integer(3));
+    }
+
+    #[test]
+    fn rows() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE posts (id INTEGER PRIMARY KEY, title TEXT NOT NULL);")
+            .unwrap();
+        let st = conn
+            .prepare("SELECT id, title, 1 + 1 AS two FROM posts")
+            .unwrap();
+        let rows = fake_rows(&st, 2);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1]["id"], Value::Integer(2));
+        assert_eq!(rows[0]["title"], Value::Text("Lorem ipsum 1".to_owned()));
+        assert_eq!(rows[0]["two"], Value::Text("lorem 1".to_owned()));
+    }
+}
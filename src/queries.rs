@@ -34,50 +34,318 @@
 //! ```
 //!
 
-use std::{collections::HashMap, ops::Deref, rc::Rc};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    time::Instant,
+};
 
 use ego_tree::NodeId;
-use rusqlite::{types::Value, ToSql};
+use rusqlite::{types::Value, Statement};
 use scraper::ElementRef;
 
-use crate::Error;
+use crate::options::Mode;
+use crate::params::{describe, redact_error, REDACTED};
+use crate::paths;
+use crate::preview;
+use crate::trace::{Recorder, Trace, TraceStep};
+use crate::{stream::Emit, Error, EvaluateOptions, QueryEvent, QueryInfo, QueryStats};
 
 /// Result of performing a database query:
 /// Rows, then column name -> values.
-type QueryResult = Vec<HashMap<String, Value>>;
+pub type QueryResult = Vec<HashMap<String, Value>>;
 
 /// Databases available for querying.
 pub type DbTable = rusqlite::Connection;
 
+/// Attach the databases in the options to the connection, under their schema names;
+/// unless the connection already has a database by that name, e.g. from an earlier evaluation.
+pub(crate) fn attach(dbs: &DbTable, options: &EvaluateOptions) -> Result<(), Error> {
+    if options.attachments.is_empty() {
+        return Ok(());
+    }
+    let note_err = |e| Error::Sql("(attach)".to_owned(), e);
+    let attached: Vec<String> = dbs
+        .prepare("SELECT name FROM pragma_database_list")
+        .and_then(|mut st| st.query_map([], |row| row.get(0))?.collect())
+        .map_err(note_err)?;
+    for (schema, path) in options.attachments.iter() {
+        if attached.contains(schema) {
+            continue;
+        }
+        dbs.execute("ATTACH DATABASE ?1 AS ?2", (path.to_string_lossy(), schema))
+            .map_err(|e| Error::Open(path.display().to_string(), e))?;
+    }
+    Ok(())
+}
+
+/// Run the evaluation in a transaction, so all its queries see the same snapshot
+/// of the database, even while other connections write to it.
+/// In [write mode](Mode::Write), its writes are committed if it succeeds,
+/// and rolled back if it fails, or if `evaluate` returns false
+/// (e.g. because errors were shown [inline](EvaluateOptions::inline_errors)).
+///
+/// The transaction is a savepoint, so an evaluation within the caller's own transaction
+/// is rolled back alone.
+pub(crate) fn transaction(
+    dbs: &DbTable,
+    evaluate: impl FnOnce() -> Result<bool, Error>,
+) -> Result<(), Error> {
+    let note_err = |e| Error::Sql("(transaction)".to_owned(), e);
+    let rollback = || {
+        if let Err(rollback) = dbs.execute_batch("ROLLBACK TO htmpl; RELEASE htmpl") {
+            tracing::error!("failed to roll back evaluation: {}", rollback);
+        }
+    };
+    dbs.execute_batch("SAVEPOINT htmpl").map_err(note_err)?;
+    match evaluate() {
+        Ok(true) => dbs.execute_batch("RELEASE htmpl").map_err(note_err),
+        Ok(false) => {
+            rollback();
+            Ok(())
+        }
+        Err(e) => {
+            rollback();
+            Err(e)
+        }
+    }
+}
+
 /// An attribute added with the htmpl-attr element.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Attribute {
     /// TODO: Should this be an Atom or similar? Something from scraper?
     pub name: String,
     pub value: String,
+    pub mode: AttrMode,
+}
+
+/// How an added attribute combines with the element's existing attribute of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrMode {
+    /// Replace the existing value.
+    Set,
+    /// Add the space-separated tokens of the value that aren't already in the existing value,
+    /// e.g. to add classes.
+    Append,
+    /// Remove the existing attribute; the value is ignored.
+    Remove,
+    /// Replace the content of the element with the value, as text; the name is ignored.
+    /// This fills in a `textarea`, for `htmpl-form`.
+    Content,
 }
 
 /// Data local to the current scope.
 #[derive(Debug, Clone)]
 pub struct Scope<'a> {
     dbs: &'a DbTable,
+    options: &'a EvaluateOptions,
+    sink: Option<&'a dyn Emit>,
     bindings: HashMap<String, Rc<QueryResult>>,
+    columns: HashMap<String, Rc<[String]>>,
     attrs: HashMap<NodeId, Vec<Rc<Attribute>>>,
+    outer_attrs: Rc<RefCell<HashMap<NodeId, Vec<Rc<Attribute>>>>>,
+    /// The row being evaluated by each enclosing `htmpl-foreach` element.
+    rows: Vec<(NodeId, usize)>,
+    /// The row (of the full results) that each query is filtered down to, by `htmpl-foreach`.
+    query_rows: HashMap<String, usize>,
+    recorder: Option<Recorder>,
+    /// The query of the innermost `htmpl-with` element, which selectors without a query refer to.
+    with: Option<String>,
+    /// Queries bound in this scope that are also to be bound in the enclosing scope.
+    exports: Vec<Export>,
+    /// The `htmpl-define` element of each component in the template, by name.
+    components: Rc<HashMap<String, NodeId>>,
+    /// The scope with only the builtin bindings, in which components are evaluated.
+    root: Option<Rc<Scope<'a>>>,
+    /// Within a component: the instance of the component, and the scope it appears in,
+    /// for evaluating the content of its slots.
+    caller: Option<Rc<(NodeId, Scope<'a>)>>,
+    /// The `htmpl-macro` elements in scope, by name.
+    macros: HashMap<String, NodeId>,
+    /// The components and macros being evaluated, outermost first.
+    active: Vec<String>,
+    /// The number of errors shown inline in the output, across every scope of the evaluation.
+    inline_errors: Rc<Cell<usize>>,
+}
+
+/// A query result exported from a scope, by an `htmpl-query` element with an `export` attribute.
+#[derive(Debug, Clone)]
+struct Export {
+    name: String,
+    columns: Rc<[String]>,
+    result: Rc<QueryResult>,
+    /// Whether to keep exporting to each enclosing scope, rather than only the parent.
+    global: bool,
 }
 
 impl<'a> Scope<'a> {
-    /// Create a new scope where queries operate on the provided databases.
-    pub fn new(dbs: &'a DbTable) -> Scope<'a> {
+    /// Create a new scope where queries operate on the provided databases,
+    /// and evaluation follows the provided options.
+    pub fn new(dbs: &'a DbTable, options: &'a EvaluateOptions) -> Scope<'a> {
         Scope {
             dbs,
+            options,
+            sink: None,
             bindings: Default::default(),
+            columns: Default::default(),
             attrs: Default::default(),
+            outer_attrs: Default::default(),
+            rows: Default::default(),
+            query_rows: Default::default(),
+            recorder: options.trace.then(Recorder::default),
+            with: None,
+            exports: Default::default(),
+            components: Default::default(),
+            root: None,
+            caller: None,
+            macros: Default::default(),
+            active: Default::default(),
+            inline_errors: Default::default(),
         }
     }
 
+    /// The options for this evaluation.
+    pub fn options(&self) -> &'a EvaluateOptions {
+        self.options
+    }
+
+    /// Where to send iterations of `htmpl-foreach`, in a streaming evaluation.
+    pub(crate) fn sink(&self) -> Option<&'a dyn Emit> {
+        self.sink
+    }
+
+    /// Set where to send iterations of `htmpl-foreach`, or stop streaming in this scope.
+    pub(crate) fn set_sink(&mut self, sink: Option<&'a dyn Emit>) {
+        self.sink = sink;
+    }
+
+    /// Note that this scope is for the given row (counting from 0) of the `htmpl-foreach` element.
+    pub(crate) fn enter_row(&mut self, element: NodeId, row: usize) {
+        self.rows.push((element, row));
+    }
+
+    /// The row of the `htmpl-foreach` element that this scope is within, if any.
+    pub(crate) fn row(&self, element: NodeId) -> Option<usize> {
+        self.rows
+            .iter()
+            .rev()
+            .find(|(id, _)| *id == element)
+            .map(|(_, row)| *row)
+    }
+
+    /// The row (counting from 0) of the named query's results that it is filtered down to
+    /// in this scope, if it is being iterated over.
+    pub(crate) fn query_row(&self, name: &str) -> Option<usize> {
+        self.query_rows.get(name).copied()
+    }
+
+    /// Record a step of the evaluation, if the options ask for a trace.
+    pub(crate) fn record(&self, step: impl FnOnce() -> TraceStep) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(step())
+        }
+    }
+
+    /// The steps of the evaluation so far, if the options ask for a trace.
+    pub(crate) fn trace(&self) -> Option<Trace> {
+        self.recorder.as_ref().map(Recorder::trace)
+    }
+
     /// Create a new scope from the current one.
-    pub fn push(&self) -> Scope {
-        self.clone()
+    pub fn push(&self) -> Scope<'a> {
+        let mut scope = self.clone();
+        scope.exports.clear();
+        scope
+    }
+
+    /// Note that an error was shown inline in the output, in place of an element.
+    pub(crate) fn note_inline_error(&self) {
+        self.inline_errors.set(self.inline_errors.get() + 1);
+    }
+
+    /// Whether any errors were shown inline in the output, rather than failing the evaluation.
+    pub(crate) fn has_inline_errors(&self) -> bool {
+        self.inline_errors.get() > 0
+    }
+
+    /// Note that the current bindings are the builtins, which components can see.
+    pub(crate) fn mark_root(&mut self) {
+        self.root = None;
+        self.root = Some(Rc::new(self.clone()));
+    }
+
+    /// Create a scope with only the builtin bindings, for evaluating a component.
+    pub(crate) fn isolate(&self) -> Scope<'a> {
+        let mut scope = match &self.root {
+            Some(root) => (**root).clone(),
+            None => Scope::new(self.dbs, self.options),
+        };
+        scope.root = self.root.clone();
+        scope.active = self.active.clone();
+        scope.sink = self.sink;
+        scope.rows = self.rows.clone();
+        scope.recorder = self.recorder.clone();
+        scope.inline_errors = self.inline_errors.clone();
+        scope
+    }
+
+    /// Note that this scope is for the body of the component instance,
+    /// which appears in the `caller` scope.
+    pub(crate) fn set_caller(&mut self, instance: NodeId, caller: Scope<'a>) {
+        self.caller = Some(Rc::new((instance, caller)));
+    }
+
+    /// The component instance this scope is within, if any, and the scope it appears in.
+    pub(crate) fn caller(&self) -> Option<(NodeId, &Scope<'a>)> {
+        self.caller.as_deref().map(|(id, scope)| (*id, scope))
+    }
+
+    /// Note that this scope is for the body of the named component or macro;
+    /// or if it is already being evaluated, return the cycle.
+    pub(crate) fn enter(&mut self, name: &str) -> Result<(), Error> {
+        if let Some(i) = self.active.iter().position(|active| active == name) {
+            let mut cycle = self.active[i..].to_vec();
+            cycle.push(name.to_owned());
+            return Err(Error::Cycle(cycle));
+        }
+        self.active.push(name.to_owned());
+        Ok(())
+    }
+
+    /// Define a macro, for the rest of this scope.
+    pub(crate) fn define_macro(&mut self, name: impl Into<String>, element: NodeId) {
+        self.macros.insert(name.into(), element);
+    }
+
+    /// The `htmpl-macro` element of the named macro, if it is in scope.
+    pub(crate) fn get_macro(&self, name: &str) -> Option<NodeId> {
+        self.macros.get(name).copied()
+    }
+
+    /// Set the components defined in the template.
+    pub(crate) fn set_components(&mut self, components: HashMap<String, NodeId>) {
+        self.components = Rc::new(components);
+    }
+
+    /// The `htmpl-define` element of the named component, if there is one.
+    pub(crate) fn component(&self, name: &str) -> Option<NodeId> {
+        self.components.get(name).copied()
+    }
+
+    /// Bind the queries exported from a scope created by [`push`](Self::push), once it is done.
+    pub fn adopt(&mut self, inner: Scope) {
+        for export in inner.exports {
+            self.columns
+                .insert(export.name.clone(), export.columns.clone());
+            self.bindings
+                .insert(export.name.clone(), export.result.clone());
+            if export.global {
+                self.exports.push(export);
+            }
+        }
     }
 
     /// Generate a new scope for each row in the named query.
@@ -93,66 +361,493 @@ impl<'a> Scope<'a> {
         })
     }
 
+    /// Bind the name to the results of a query.
+    ///
+    /// The query's columns are taken to be those of its first row, in sorted order.
+    pub fn bind(&mut self, name: impl Into<String>, result: QueryResult) {
+        let mut columns: Vec<String> = result
+            .first()
+            .map(|row| row.keys().cloned().collect())
+            .unwrap_or_default();
+        columns.sort();
+        self.bind_columns(name, columns, result);
+    }
+
+    /// Bind the name to the results of a query, with the given columns, in order.
+    pub fn bind_columns(
+        &mut self,
+        name: impl Into<String>,
+        columns: Vec<String>,
+        result: QueryResult,
+    ) {
+        let name = name.into();
+        self.record(|| TraceStep::Bind {
+            name: name.clone(),
+            rows: result.len(),
+        });
+        self.columns.insert(name.clone(), columns.into());
+        self.bindings.insert(name, Rc::new(result));
+    }
+
+    /// The names of the columns of the named query, in the order they were selected.
+    pub fn columns(&self, name: impl AsRef<str>) -> Option<&[String]> {
+        let name = self.resolve(name.as_ref()).ok()?;
+        self.columns.get(name).map(|c| &**c)
+    }
+
+    /// Set the query that selectors without a query refer to, for the body of `htmpl-with`.
+    pub(crate) fn set_with(&mut self, query: impl Into<String>) {
+        self.with = Some(query.into());
+    }
+
+    /// The name of the query, or of the `htmpl-with` query if the name is empty.
+    fn resolve<'s>(&'s self, name: &'s str) -> Result<&'s str, Error> {
+        match (name, &self.with) {
+            ("", Some(with)) => Ok(with),
+            ("", None) => Err(Error::InvalidParameter(
+                "".into(),
+                "selector without a query, outside of htmpl-with".to_owned(),
+            )),
+            (name, _) => Ok(name),
+        }
+    }
+
     /// Add an attribute binding.
     pub fn add_attr(&mut self, node: NodeId, attr: Rc<Attribute>) {
         self.attrs.entry(node).or_default().push(attr)
     }
 
+    /// Add an attribute binding for a node outside of the current scope.
+    ///
+    /// Unlike [`add_attr`](Self::add_attr), the binding is shared by all scopes
+    /// for the rest of the evaluation.
+    pub fn add_outer_attr(&mut self, node: NodeId, attr: Rc<Attribute>) {
+        self.outer_attrs
+            .borrow_mut()
+            .entry(node)
+            .or_default()
+            .push(attr)
+    }
+
     /// Get all attributes for a given node.
-    pub fn get_attrs(&self, node: NodeId) -> &[impl Deref<Target = Attribute>] {
-        self.attrs.get(&node).map(Vec::as_slice).unwrap_or(&[])
+    pub fn get_attrs(&self, node: NodeId) -> Vec<Rc<Attribute>> {
+        let outer = self.outer_attrs.borrow();
+        let outer = outer.get(&node).into_iter().flatten();
+        let local = self.attrs.get(&node).into_iter().flatten();
+        outer.chain(local).cloned().collect()
+    }
+}
+
+/// The names of the statement's parameters, in order, by which they are bound from attributes:
+/// named parameters by their own names (e.g. `:id`), and positional parameters
+/// (`?` or `?NNN`) by their index, counting from 1 (e.g. `:1`).
+fn parameter_names(st: &Statement) -> Vec<String> {
+    // Column names are (apparently) zero-indexed;
+    // parameter names are one-indexed.
+    (1..=st.parameter_count())
+        .map(|i| match st.parameter_name(i) {
+            Some(name) if !name.starts_with('?') => name.to_owned(),
+            _ => format!(":{}", i),
+        })
+        .collect()
+}
+
+/// Coerce the value of the parameter to the type given in its `:type` attribute, if any;
+/// e.g. `:age:type="integer"` for the `:age` parameter.
+///
+/// The types are SQLite's storage classes: `integer`, `real`, `text`, and `blob`.
+/// Null values are left as they are.
+fn coerce<'v>(
+    element: ElementRef,
+    param: &str,
+    value: Cow<'v, Value>,
+    redact: bool,
+) -> Result<Cow<'v, Value>, Error> {
+    let Some(ty) = element.attr(&format!("{}:type", param)) else {
+        return Ok(value);
+    };
+    let invalid = || {
+        Error::InvalidParameter(
+            "htmpl-query".into(),
+            format!(
+                "{}={} is not of type {}",
+                param,
+                describe(&value, redact),
+                ty
+            ),
+        )
+    };
+    let coerced = match (ty.to_ascii_lowercase().as_str(), &*value) {
+        (_, Value::Null) => return Ok(value),
+        ("integer", Value::Integer(_))
+        | ("real", Value::Real(_))
+        | ("text", Value::Text(_))
+        | ("blob", Value::Blob(_)) => return Ok(value),
+        ("integer", Value::Real(f)) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => {
+            Value::Integer(*f as i64)
+        }
+        ("integer", Value::Text(t)) => Value::Integer(t.trim().parse().map_err(|_| invalid())?),
+        ("real", Value::Integer(i)) => Value::Real(*i as f64),
+        ("real", Value::Text(t)) => Value::Real(t.trim().parse().map_err(|_| invalid())?),
+        ("text", Value::Integer(i)) => Value::Text(i.to_string()),
+        ("text", Value::Real(f)) => Value::Text(f.to_string()),
+        ("text", Value::Blob(b)) => {
+            Value::Text(String::from_utf8(b.clone()).map_err(|_| invalid())?)
+        }
+        ("blob", Value::Text(t)) => Value::Blob(t.as_bytes().to_vec()),
+        ("integer" | "real" | "blob", _) => return Err(invalid()),
+        _ => {
+            return Err(Error::InvalidParameter(
+                "htmpl-query".into(),
+                format!("{}:type={:?}", param, ty),
+            ))
+        }
+    };
+    Ok(Cow::Owned(coerced))
+}
+
+/// Replace each use of the named parameter in the SQL with the replacement,
+/// outside of string literals, quoted identifiers, and comments.
+///
+/// Returns None if the parameter isn't used.
+fn expand_parameter(sql: &str, param: &str, replacement: &str) -> Option<String> {
+    let is_name = |c: char| c.is_alphanumeric() || matches!(c, '_' | '$' | ':' | '@');
+    let mut out = String::with_capacity(sql.len());
+    let mut found = false;
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        // The end of a quoted or commented section, if one starts here.
+        let end = match c {
+            '\'' | '"' | '`' => rest[1..].find(c).map(|i| i + 2),
+            '[' => rest.find(']').map(|i| i + 1),
+            '-' if rest.starts_with("--") => Some(rest.find('\n').map_or(rest.len(), |i| i + 1)),
+            '/' if rest.starts_with("/*") => {
+                Some(rest[2..].find("*/").map_or(rest.len(), |i| i + 4))
+            }
+            _ => None,
+        };
+        if let Some(end) = end {
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with(param)
+            && !out.ends_with(is_name)
+            && !rest[param.len()..].starts_with(is_name)
+        {
+            out.push_str(replacement);
+            rest = &rest[param.len()..];
+            found = true;
+        } else {
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
     }
+    found.then_some(out)
 }
 
-/// Parse a parameter name into a query_name and optional column.
-fn parse_specifier(s: &str) -> Result<(&str, Option<&str>), Error> {
-    let mk_err = || Error::InvalidParameter("", s.to_owned());
-    let (query_name, tail) = match s.split_once("(") {
-        None => return Ok((s, None)),
-        Some(tail) => tail,
+/// Statements that templates may not run, by first keyword:
+/// databases are attached per the options, and the evaluation controls the transaction.
+/// SQLite considers all of these read-only, so they aren't caught by the `writes` check.
+const FORBIDDEN: &[&str] = &[
+    "ATTACH",
+    "DETACH",
+    "BEGIN",
+    "COMMIT",
+    "END",
+    "ROLLBACK",
+    "SAVEPOINT",
+    "RELEASE",
+];
+
+/// The first keyword of the statement, after any comments, e.g. `SELECT`.
+fn first_keyword(sql: &str) -> &str {
+    let mut rest = sql.trim_start();
+    loop {
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.split_once('\n').map_or("", |(_, r)| r).trim_start();
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, r)| r).trim_start();
+        } else {
+            break;
+        }
+    }
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+/// Parse the value of a parameter attribute as a literal, if it is one:
+/// a quoted string, as in SQL (`'published'`, with `''` for a quote), a number,
+/// or any text after a `literal:` prefix.
+pub(crate) fn literal(attr: &str) -> Option<Value> {
+    if let Some(text) = attr.strip_prefix("literal:") {
+        return Some(Value::Text(text.to_owned()));
+    }
+    if let Some(text) = attr
+        .strip_prefix('\'')
+        .and_then(|a| a.strip_suffix('\''))
+        .filter(|_| attr.len() > 1)
+    {
+        return Some(Value::Text(text.replace("''", "'")));
+    }
+    if let Ok(i) = attr.parse::<i64>() {
+        return Some(Value::Integer(i));
+    }
+    attr.parse::<f64>()
+        .ok()
+        .filter(|f| f.is_finite())
+        .map(Value::Real)
+}
+
+/// A parsed [selector](crate#selector): the query, and optionally a row and a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Specifier<'s> {
+    pub query: &'s str,
+    /// The index of the row, counting from 0, as in `query[0]`.
+    pub row: Option<usize>,
+    pub column: Option<&'s str>,
+    /// Metadata of the results, rather than a value from them, as in `query#count`.
+    pub meta: Option<Meta>,
+}
+
+/// Metadata of the results of a query, as selected by e.g. `query#count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Meta {
+    /// The number of rows.
+    Count,
+    /// 1 if there are no rows, 0 otherwise.
+    Empty,
+    /// 1 if there are any rows, 0 otherwise.
+    Exists,
+}
+
+impl Meta {
+    /// The value of the metadata for the results.
+    fn of(self, rows: &QueryResult) -> Value {
+        let n = rows.len() as i64;
+        Value::Integer(match self {
+            Meta::Count => n,
+            Meta::Empty => (n == 0).into(),
+            Meta::Exists => (n != 0).into(),
+        })
+    }
+}
+
+impl<'s> Specifier<'s> {
+    /// Parse a specifier: `query`, `query(column)`, `query[row]`, `query[row](column)`,
+    /// or `query#meta`; columns may also be written with a dot, as `query.column`
+    /// or `query[row].column`. The query is empty for `(column)`, i.e. the `htmpl-with` row.
+    pub fn parse(s: &'s str) -> Result<Self, Error> {
+        let mk_err = || Error::InvalidParameter("".into(), s.to_owned());
+        if let Some((query, meta)) = s.split_once('#') {
+            let meta = match meta {
+                "count" => Meta::Count,
+                "empty" => Meta::Empty,
+                "exists" => Meta::Exists,
+                _ => return Err(mk_err()),
+            };
+            if query.is_empty() || query.contains(['(', '[']) {
+                return Err(mk_err());
+            }
+            return Ok(Specifier {
+                query,
+                row: None,
+                column: None,
+                meta: Some(meta),
+            });
+        }
+        let (head, column) = match s.split_once('(') {
+            None => match s.split_once('.') {
+                None => (s, None),
+                Some((_, "")) => return Err(mk_err()),
+                Some((head, column)) => (head, Some(column)),
+            },
+            Some((head, tail)) => {
+                let (column, zero) = tail.split_once(')').ok_or_else(mk_err)?;
+                if column.is_empty() || !zero.is_empty() {
+                    return Err(mk_err());
+                }
+                (head, Some(column))
+            }
+        };
+        let (query, row) = match head.split_once('[') {
+            None => (head, None),
+            Some((query, index)) => {
+                let row = index
+                    .strip_suffix(']')
+                    .and_then(|index| index.parse::<usize>().ok())
+                    .ok_or_else(mk_err)?;
+                (query, Some(row))
+            }
+        };
+        // Within `htmpl-with`, the query may be left out: `(column)`, `.(column)`, or `.column`.
+        let query = query.strip_prefix('.').unwrap_or(query);
+        if query.is_empty() && column.is_none() {
+            return Err(mk_err());
+        }
+        Ok(Specifier {
+            query,
+            row,
+            column,
+            meta: None,
+        })
+    }
+}
+
+/// Split a column name into the column and an optional JSON path operator and path,
+/// e.g. `meta->>'$.title'` into `meta` and `("->>", "$.title")`.
+fn parse_json_path(column: &str) -> (&str, Option<(&'static str, &str)>) {
+    let Some((column, path)) = column.split_once("->") else {
+        return (column, None);
     };
-    let (column_name, zero) = tail.split_once(")").ok_or_else(mk_err)?;
-    if query_name.is_empty() || column_name.is_empty() || !zero.is_empty() {
-        return Err(mk_err());
+    let (op, path) = match path.strip_prefix('>') {
+        Some(path) => ("->>", path),
+        None => ("->", path),
+    };
+    let path = path.trim();
+    let path = path
+        .strip_prefix('\'')
+        .and_then(|p| p.strip_suffix('\''))
+        .unwrap_or(path);
+    (column.trim(), Some((op, path)))
+}
+
+impl<'a> Scope<'a> {
+    /// Generate a new scope for each element of the JSON array (or object)
+    /// in the specified value, e.g. `q(tags)` or `q(meta->'$.tags')`.
+    ///
+    /// In each sub-scope, `name` (or, if None, the column name) is bound to a single row,
+    /// with the columns `key` and `value` per SQLite's `json_each`.
+    pub fn for_each_json(
+        &self,
+        specifier: impl AsRef<str>,
+        name: Option<&str>,
+    ) -> Result<RowIterator<'a>, Error> {
+        let specifier = specifier.as_ref();
+        let column = Specifier::parse(specifier)?.column;
+        let name = match (name, column) {
+            (Some(name), _) => name,
+            (None, Some(column)) => parse_json_path(column).0,
+            (None, None) => return Err(Error::InvalidParameter("".into(), specifier.to_owned())),
+        };
+        let value = self.get_single(specifier)?;
+        let note_err = |e| Error::Sql(specifier.to_owned(), e);
+        let mut st = self
+            .dbs
+            .prepare("SELECT key, value FROM json_each(?)")
+            .map_err(note_err)?;
+        let names = ["key".to_owned(), "value".to_owned()];
+        let rows: rusqlite::Result<QueryResult> = st
+            .query([&*value])
+            .and_then(|rows| rows.mapped(|row| row_to_hash(&names, row)).collect());
+        let mut scope = self.clone();
+        scope.bind_columns(name, names.to_vec(), rows.map_err(note_err)?);
+        scope
+            .for_each_row(name)
+            .ok_or(Error::MissingQuery("".into(), name.to_owned()))
     }
-    Ok((query_name, Some(column_name)))
 }
 
 impl Scope<'_> {
     /// Look up the results of the named query.
     pub fn get(&self, name: impl AsRef<str>) -> Result<&QueryResult, Error> {
+        let name = self.resolve(name.as_ref())?;
         self.bindings
-            .get(name.as_ref())
+            .get(name)
             .map(|v| &**v)
-            .ok_or_else(|| Error::MissingQuery("", name.as_ref().to_owned()))
+            .ok_or_else(|| Error::MissingQuery("".into(), name.to_owned()))
     }
 
     /// Gets a single value from a specifier.
     /// The specifier may be of the form:
     /// - query_name, if the query's results are a single row and single column
     /// - query_name(column_name), if the query's results are a single row
-    pub fn get_single(&self, specifier: impl AsRef<str>) -> Result<&Value, Error> {
-        let (query_name, column_name) = parse_specifier(specifier.as_ref())?;
-        let q = self.get(query_name)?;
-        let row = match q.len() {
-            1 => &q[0],
-            _ => return Err(Error::Cardinality("", query_name.to_owned(), q.len(), 1)),
+    /// - query_name(column_name->'path') or query_name(column_name->>'path'),
+    ///   to extract from a JSON column per SQLite's `->` and `->>` operators
+    /// - query_name[n] or query_name[n](column_name), for the `n`th row (counting from 0)
+    ///   of a query with any number of rows
+    /// - query_name#count, query_name#empty, or query_name#exists, for the number of rows
+    ///   of a query, or (as 1 or 0) whether it has none or any
+    pub fn get_single(&self, specifier: impl AsRef<str>) -> Result<Cow<'_, Value>, Error> {
+        let specifier = specifier.as_ref();
+        let parsed = Specifier::parse(specifier)?;
+        let q = self.get(parsed.query)?;
+        if let Some(meta) = parsed.meta {
+            return Ok(Cow::Owned(meta.of(q)));
+        }
+        let row = match parsed.row {
+            Some(n) => q.get(n),
+            None if q.len() == 1 => q.first(),
+            None => None,
         };
+        match row {
+            Some(row) => self.value_in_row(specifier, row),
+            None => Err(Error::Cardinality(
+                "".into(),
+                self.resolve(parsed.query)?.to_owned(),
+                q.len(),
+                parsed.row.map_or(1, |n| n + 1),
+            )),
+        }
+    }
+
+    /// Gets the value from a specifier in each row of the query, in order;
+    /// as [`get_single`](Self::get_single), but for any number of rows.
+    pub fn get_each(&self, specifier: impl AsRef<str>) -> Result<Vec<Cow<'_, Value>>, Error> {
+        let specifier = specifier.as_ref();
+        let parsed = Specifier::parse(specifier)?;
+        let rows = self.get(parsed.query)?;
+        if let Some(meta) = parsed.meta {
+            return Ok(vec![Cow::Owned(meta.of(rows))]);
+        }
+        let rows = match parsed.row {
+            Some(n) => rows.get(n..=n).unwrap_or_default(),
+            None => rows.as_slice(),
+        };
+        rows.iter()
+            .map(|row| self.value_in_row(specifier, row))
+            .collect()
+    }
+
+    /// Gets the value from a specifier in the given row of its query.
+    fn value_in_row<'s>(
+        &'s self,
+        specifier: &str,
+        row: &'s HashMap<String, Value>,
+    ) -> Result<Cow<'s, Value>, Error> {
+        let Specifier {
+            query: query_name,
+            column: column_name,
+            ..
+        } = Specifier::parse(specifier)?;
+        let query_name = self.resolve(query_name)?;
+        let (column_name, json_path) = match column_name.map(parse_json_path) {
+            Some((column, path)) => (Some(column), path),
+            None => (None, None),
+        };
+        // List the columns in the order they were selected, so the message is the same each time.
         let fmt_columns = || {
-            format!(
-                "\"{}\"",
-                row.iter()
-                    .map(|(k, _v)| k.to_owned())
-                    .collect::<Vec<_>>()
-                    .join(",")
-            )
+            let columns = match self.columns(query_name) {
+                Some(columns) => columns.to_vec(),
+                None => {
+                    let mut columns: Vec<String> = row.keys().cloned().collect();
+                    columns.sort();
+                    columns
+                }
+            };
+            format!("\"{}\"", columns.join(","))
         };
 
         // Extract the relevant column: explicit, or implicit single column.
         let value = if let Some(v) = column_name {
             // An explicit column was specified; try it out.
             row.get(v).ok_or_else(|| {
-                Error::MissingColumn("", query_name.to_owned(), fmt_columns(), v.to_owned())
+                Error::MissingColumn(
+                    "".into(),
+                    query_name.to_owned(),
+                    fmt_columns(),
+                    v.to_owned(),
+                )
             })?
         } else {
             (if row.len() == 1 {
@@ -160,9 +855,60 @@ impl Scope<'_> {
             } else {
                 None
             })
-            .ok_or_else(|| Error::NoDefaultColumn("", query_name.to_owned(), fmt_columns()))?
+            .ok_or_else(|| {
+                Error::NoDefaultColumn("".into(), query_name.to_owned(), fmt_columns())
+            })?
+        };
+        let Some((op, path)) = json_path else {
+            return Ok(Cow::Borrowed(value));
         };
-        Ok(value)
+        self.dbs
+            .query_row(&format!("SELECT ?1 {} ?2", op), (value, path), |row| {
+                row.get(0)
+            })
+            .map(Cow::Owned)
+            .map_err(|e| Error::Sql(specifier.to_owned(), e))
+    }
+
+    /// Run a [prelude query](EvaluateOptions::prelude_query), binding its results to the name.
+    pub(crate) fn run_prelude(&mut self, name: &str, sql: &str) -> Result<(), Error> {
+        let note_err = |e| Error::Sql(name.to_owned(), e);
+        let mut st = self.dbs.prepare(sql).map_err(note_err)?;
+        let names: Vec<String> = (0..st.column_count())
+            .filter_map(|i| st.column_name(i).map(str::to_owned).ok())
+            .collect();
+        for (i, param) in parameter_names(&st).iter().enumerate() {
+            let value = self
+                .options
+                .params
+                .get(&param[1..])
+                .ok_or_else(|| Error::MissingParameter("".into(), param.clone()))?;
+            st.raw_bind_parameter(i + 1, value).map_err(note_err)?;
+        }
+        let result: rusqlite::Result<QueryResult> = st
+            .raw_query()
+            .mapped(|row| row_to_hash(&names, row))
+            .collect();
+        self.bind_columns(name, names, result.map_err(note_err)?);
+        Ok(())
+    }
+
+    /// Read the SQL of a query from a file under the [query root](EvaluateOptions::query_root).
+    fn read_query(&self, src: &str) -> Result<String, Error> {
+        let root = self
+            .options
+            .query_root
+            .as_ref()
+            .ok_or(Error::MissingOption(
+                "htmpl-query".into(),
+                "query_root".into(),
+            ))?;
+        let path = paths::resolve(root, src).ok_or_else(|| {
+            Error::InvalidParameter("htmpl-query".into(), format!("src={:?}", src))
+        })?;
+        let sql = std::fs::read_to_string(&path)
+            .map_err(|e| Error::Asset(path.display().to_string(), e))?;
+        Ok(sql.trim().to_owned())
     }
 
     /// Perform the query described in `element`.
@@ -171,13 +917,14 @@ impl Scope<'_> {
     /// TODO: Document parameter usage --
     /// - Use the ":param_name" format for parameter names
     /// - Use attributes named ":parameter_name", which name the variable to use
+    ///
     /// Attributes starting with a colon are valid in XML, i.e. for custom components:
     /// https://www.w3.org/TR/xml/#NT-Name
     /// https://stackoverflow.com/questions/925994/what-characters-are-allowed-in-an-html-attribute-name
     pub fn do_query(&mut self, element: ElementRef) -> Result<(), Error> {
         let name = element
             .attr("name")
-            .ok_or(Error::MissingAttr("htmpl-query", "name"))?;
+            .ok_or(Error::MissingAttr("htmpl-query".into(), "name".into()))?;
         let note_err = |e| Error::Sql(name.to_owned(), e);
         let content = element
             .text()
@@ -185,39 +932,225 @@ impl Scope<'_> {
             .join(" ")
             .trim()
             .to_owned();
-        let mut st = self
-            .dbs
-            .prepare(&content)
-            .map_err(|e| Error::Sql(name.to_owned(), e))?;
+        let content = match (element.attr("src"), element.attr("ref")) {
+            (None, None) => content,
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidParameter(
+                    "htmpl-query".into(),
+                    "both src= and ref=".to_owned(),
+                ))
+            }
+            _ if !content.is_empty() => {
+                return Err(Error::InvalidParameter(
+                    "htmpl-query".into(),
+                    "src= or ref= with a query in the content".to_owned(),
+                ))
+            }
+            (Some(src), None) => self.read_query(src)?,
+            (None, Some(reference)) => self
+                .options
+                .named_queries
+                .get(reference)
+                .ok_or_else(|| {
+                    Error::InvalidParameter("htmpl-query".into(), format!("ref={:?}", reference))
+                })?
+                .to_string(),
+        };
+        let mut sql = content.clone();
+        for rewrite in self.options.rewrite_query.iter() {
+            sql = rewrite(name, &sql);
+        }
+        // Parameters in the template's SQL are bound from attributes;
+        // those added by a rewrite may also be bound from the evaluation's parameters.
+        let template_params: Option<Vec<String>> = if sql != content {
+            let st = self.dbs.prepare(&content).map_err(note_err)?;
+            Some(parameter_names(&st))
+        } else {
+            None
+        };
+        // Expanded parameters are bound to each value of their selector, as a list.
+        let mut expanded: HashMap<String, Cow<Value>> = HashMap::new();
+        if let Some(expand) = element.attr("expand") {
+            let expand: Vec<&str> = match expand.split_whitespace().collect::<Vec<_>>() {
+                names if names.is_empty() => element
+                    .value()
+                    .attrs()
+                    .map(|(attr, _)| attr)
+                    .filter(|attr| attr.starts_with([':', '@', '$']) && !attr.ends_with(":type"))
+                    .collect(),
+                names => names,
+            };
+            for param in expand {
+                let attr = element.attr(param).ok_or_else(|| {
+                    Error::MissingParameter("htmpl-query".into(), param.to_owned())
+                })?;
+                let items = match literal(attr) {
+                    Some(value) => vec![Cow::Owned(value)],
+                    None => self
+                        .get_each(attr)
+                        .map_err(|e| e.set_element("htmpl-query"))?,
+                };
+                let items = items
+                    .into_iter()
+                    .map(|value| coerce(element, param, value, self.options.redact_params))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let names: Vec<String> = (1..=items.len())
+                    .map(|i| format!("{}__{}", param, i))
+                    .collect();
+                sql = expand_parameter(&sql, param, &names.join(", ")).ok_or_else(|| {
+                    Error::InvalidParameter("htmpl-query".into(), format!("expand={:?}", param))
+                })?;
+                expanded.extend(names.into_iter().zip(items));
+            }
+        }
+        let mut st = self.dbs.prepare(&sql).map_err(note_err)?;
+        let keyword = first_keyword(&sql);
+        if FORBIDDEN.iter().any(|k| keyword.eq_ignore_ascii_case(k)) {
+            return Err(Error::InvalidParameter(
+                "htmpl-query".into(),
+                format!(
+                    "{} is not allowed in templates",
+                    keyword.to_ascii_uppercase()
+                ),
+            ));
+        }
+        if !st.readonly() {
+            if element.attr("writes").is_none() {
+                return Err(Error::InvalidParameter(
+                    "htmpl-query".into(),
+                    "the query writes to the database, without the writes attribute".to_owned(),
+                ));
+            }
+            if self.options.mode != Mode::Write {
+                return Err(Error::MissingOption("htmpl-query".into(), "mode".into()));
+            }
+        }
         let names: Vec<String> = (0..st.column_count())
             .filter_map(|i| st.column_name(i).map(str::to_owned).ok())
             .collect();
-        // Column names are (apparently) zero-indexed;
-        // parameter names are one-indexed.
-        let param_names: Vec<String> = (0..st.parameter_count())
-            .filter_map(|i| st.parameter_name(i + 1).map(str::to_owned))
-            .collect();
-        let params: Result<Vec<(&str, &dyn ToSql)>, Error> = param_names
+        if let Some(rows) = self.options.preview {
+            let result = preview::fake_rows(&st, rows);
+            self.bind_columns(name, names, result);
+            return self
+                .export(element, name)
+                .map_err(|e| e.set_element("htmpl-query"));
+        }
+        let param_names = parameter_names(&st);
+        let values: Result<Vec<(&str, Cow<Value>)>, Error> = param_names
             .iter()
             .map(|name| {
-                let query = element
-                    .attr(&name)
-                    .ok_or_else(|| Error::MissingParameter("", name.clone()))?;
-                let value: &dyn ToSql = self.get_single(query)?;
-                Ok((name.as_str(), value))
+                let missing = || Error::MissingParameter("".into(), name.clone());
+                if let Some(value) = expanded.get(name) {
+                    return Ok((name.as_str(), value.clone()));
+                }
+                let value = match element.attr(name) {
+                    Some(attr) => match literal(attr) {
+                        Some(value) => Cow::Owned(value),
+                        None => self.get_single(attr)?,
+                    },
+                    None if template_params.as_ref().is_some_and(|p| !p.contains(name)) => {
+                        Cow::Borrowed(self.options.params.get(&name[1..]).ok_or_else(missing)?)
+                    }
+                    None => return Err(missing()),
+                };
+                Ok((
+                    name.as_str(),
+                    coerce(element, name, value, self.options.redact_params)?,
+                ))
             })
             .collect();
-        let params = params.map_err(|e| e.set_element("htmpl-query"))?;
-
-        // TODO: For some reson, making this Result<QueryResult> is discarding one of the entries of the Vec.
-        // Something about aggregating Vec<HashMap> maybe?
-        let result: rusqlite::Result<QueryResult> = st
-            .query(params.as_slice())
-            .map_err(note_err)?
-            .mapped(|row| row_to_hash(&names, row))
+        let values = values.map_err(|e| e.set_element("htmpl-query"))?;
+        let params: Vec<(&str, &Value)> = values.iter().map(|(name, v)| (*name, &**v)).collect();
+        let redacted_value = Value::Text(REDACTED.to_owned());
+        let redacted: Vec<(&str, &Value)> = params
+            .iter()
+            .map(|(name, _)| (*name, &redacted_value))
             .collect();
-        let result = result.map_err(note_err)?;
-        self.bindings.insert(name.to_owned(), Rc::new(result));
+
+        let _span = tracing::debug_span!("query", name).entered();
+        let info = QueryInfo {
+            name,
+            sql: &sql,
+            params: if self.options.redact_params {
+                &redacted
+            } else {
+                &params
+            },
+        };
+        for hook in self.options.before_query.iter() {
+            hook(&info);
+        }
+        let start = Instant::now();
+        // Parameters are bound by index, which covers both named and positional parameters.
+        let result: rusqlite::Result<QueryResult> = params
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, (_, value))| st.raw_bind_parameter(i + 1, value))
+            .and_then(|()| {
+                st.raw_query()
+                    .mapped(|row| row_to_hash(&names, row))
+                    .collect()
+            });
+        let result = result.map_err(|e| match self.options.redact_params {
+            true => redact_error(e, &params.iter().map(|(_, v)| *v).collect::<Vec<_>>()),
+            false => e,
+        });
+        let stats = QueryStats {
+            duration: start.elapsed(),
+            rows: result.as_ref().ok().map(Vec::len),
+        };
+        for hook in self.options.after_query.iter() {
+            hook(&info, &stats);
+        }
+        let event = QueryEvent {
+            name,
+            sql: &sql,
+            params: info.params,
+            duration: stats.duration,
+            rows: stats.rows,
+            error: result.as_ref().err(),
+        };
+        for callback in self.options.on_query.iter() {
+            callback(&event);
+        }
+        self.record(|| TraceStep::Query {
+            name: name.to_owned(),
+            sql: sql.clone(),
+            duration: stats.duration,
+            rows: stats.rows,
+        });
+        let mut result = result.map_err(note_err)?;
+        for row in result.iter_mut() {
+            for transform in self.options.transform_row.iter() {
+                transform(name, row)?;
+            }
+        }
+        self.bind_columns(name, names, result);
+        self.export(element, name)
+            .map_err(|e| e.set_element("htmpl-query"))
+    }
+
+    /// Export the query bound by the element (e.g. `htmpl-query`), if it has an `export` attribute:
+    /// to the enclosing scope (`export` or `export="parent"`),
+    /// or to every enclosing scope (`export="global"`).
+    pub(crate) fn export(&mut self, element: ElementRef, name: &str) -> Result<(), Error> {
+        let global = match element.attr("export") {
+            None => return Ok(()),
+            Some("" | "parent") => false,
+            Some("global") => true,
+            Some(other) => {
+                return Err(Error::InvalidParameter(
+                    "".into(),
+                    format!("export={:?}", other),
+                ));
+            }
+        };
+        self.exports.push(Export {
+            name: name.to_owned(),
+            columns: self.columns[name].clone(),
+            result: self.bindings[name].clone(),
+            global,
+        });
         Ok(())
     }
 }
@@ -237,13 +1170,34 @@ impl<'a> Iterator for RowIterator<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         let row = self.query.get(self.i)?;
         self.i += 1;
-        let mut new = self.parent_scope.clone();
+        let mut new = self.parent_scope.push();
         new.bindings
             .insert(self.query_name.clone(), Rc::new(vec![row.clone()]));
+        new.query_rows.insert(self.query_name.clone(), self.i - 1);
         Some(new)
     }
 }
 
+/// The columns of a row of a query's results: those the query selected, in order,
+/// then any others (e.g. added by [`EvaluateOptions::transform_row`]) by name.
+pub(crate) fn row_columns<'r>(
+    row: &'r HashMap<String, Value>,
+    columns: &[String],
+) -> Vec<(&'r str, &'r Value)> {
+    let mut others: Vec<_> = row
+        .iter()
+        .filter(|(name, _)| !columns.contains(name))
+        .map(|(name, value)| (name.as_str(), value))
+        .collect();
+    others.sort_by_key(|(name, _)| *name);
+    columns
+        .iter()
+        .filter_map(|column| row.get_key_value(column))
+        .map(|(name, value)| (name.as_str(), value))
+        .chain(others)
+        .collect()
+}
+
 /// Decode a single row into a column->value hashmap.
 fn row_to_hash(
     columns: &[impl AsRef<str>],
@@ -0,0 +1,164 @@
+//! Rendering named templates against a pool of connections.
+
+use std::{io::Write, sync::Arc};
+
+use crate::{
+    last_modified, pool::Router, stream_template, visit::evaluate_render, Error, EvaluateOptions,
+    Params, Pool, RenderOutput, StreamFormat, Templates,
+};
+
+/// Everything needed to render pages: templates, a database, and evaluation options.
+///
+/// A `Renderer` can be shared between threads, e.g. by web server integrations.
+#[derive(Debug)]
+pub struct Renderer {
+    templates: Templates,
+    pool: Pool,
+    options: EvaluateOptions,
+    router: Option<Router>,
+}
+
+impl Renderer {
+    /// Render the templates against connections from the pool, with the given options.
+    ///
+    /// Parameters set in the options are replaced by the parameters to each render.
+    pub fn new(templates: Templates, pool: Pool, options: EvaluateOptions) -> Self {
+        let options = match options.templates {
+            Some(_) => options,
+            None => options.templates(templates.clone()),
+        };
+        Renderer {
+            templates,
+            pool,
+            options,
+            router: None,
+        }
+    }
+
+    /// Render each request against the database of its tenant, for multi-tenant sites:
+    /// the tenant is the value of the named parameter, and `open` creates the pool
+    /// for a tenant's database, on its first render.
+    ///
+    /// The renderer's own pool is not used. A render without the parameter is an error,
+    /// as is a tenant name other than ASCII letters, digits, `-`, `_`, and `.`
+    /// (not at the start); so a name is a single path component, e.g. not `../other`.
+    ///
+    /// The pool for each tenant is kept for the life of the renderer. Since tenant names
+    /// usually come from requests, `open` should check that the tenant exists
+    /// (returning an error otherwise), so requests can't open arbitrarily many pools.
+    ///
+    /// ```no_run
+    /// # let templates = htmpl::Templates::new();
+    /// let renderer = htmpl::Renderer::new(templates, htmpl::Pool::new("unused"), Default::default())
+    ///     .route("tenant", |tenant| {
+    ///         let path = std::path::Path::new("tenants").join(format!("{}.sqlite3", tenant));
+    ///         if !path.is_file() {
+    ///             return Err(htmpl::Error::InvalidParameter("".into(), format!("unknown tenant {}", tenant)));
+    ///         }
+    ///         Ok(htmpl::Pool::new(path))
+    ///     });
+    /// let params = htmpl::Params::new().set("tenant", "acme".to_owned());
+    /// let output = renderer.render("index.html", params);
+    /// ```
+    pub fn route(
+        mut self,
+        param: impl Into<String>,
+        open: impl Fn(&str) -> Result<Pool, Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.router = Some(Router::new(param.into(), open));
+        self
+    }
+
+    /// The pool to render against, per the parameters in the options:
+    /// the tenant's, if the renderer [routes](Self::route) requests.
+    fn pool(&self, options: &EvaluateOptions) -> Result<Option<Arc<Pool>>, Error> {
+        self.router
+            .as_ref()
+            .map(|router| router.pool(&options.params))
+            .transpose()
+    }
+
+    /// The templates available to render.
+    pub fn templates(&self) -> &Templates {
+        &self.templates
+    }
+
+    /// The options used for each render.
+    pub fn options(&self) -> &EvaluateOptions {
+        &self.options
+    }
+
+    /// Render the named template with the given parameters.
+    pub fn render(&self, template: &str, params: impl Into<Params>) -> Result<RenderOutput, Error> {
+        let options = self.options.clone().params(params);
+        let output = self.render_if_modified_since(template, &options, None)?;
+        Ok(output.expect("rendering is unconditional"))
+    }
+
+    /// Render only the named `htmpl-block` of the template, with the given parameters.
+    ///
+    /// See [`EvaluateOptions::block`].
+    pub fn render_block(
+        &self,
+        template: &str,
+        block: &str,
+        params: impl Into<Params>,
+    ) -> Result<RenderOutput, Error> {
+        let options = self.options.clone().params(params).block(block);
+        let output = self.render_if_modified_since(template, &options, None)?;
+        Ok(output.expect("rendering is unconditional"))
+    }
+
+    /// Render the named template with the given options,
+    /// unless the freshness queries report no modifications since `since`
+    /// (in seconds since the Unix epoch).
+    ///
+    /// The options are used in place of the renderer's own [`options`](Renderer::options);
+    /// usually, they are derived from them, e.g. by setting the parameters.
+    ///
+    /// Returns None if the template is unmodified.
+    pub fn render_if_modified_since(
+        &self,
+        template: &str,
+        options: &EvaluateOptions,
+        since: Option<i64>,
+    ) -> Result<Option<RenderOutput>, Error> {
+        let source = self
+            .templates
+            .get(template)
+            .ok_or_else(|| Error::MissingTemplate(template.to_owned()))?;
+        let routed = self.pool(options)?;
+        let conn = routed.as_deref().unwrap_or(&self.pool).get()?;
+        let modified = last_modified(&conn, options)?;
+        if let (Some(since), Some(modified)) = (since, modified) {
+            if modified <= since {
+                return Ok(None);
+            }
+        }
+        let output = evaluate_render(source, &conn, options, modified)
+            .map_err(|e| e.in_template(template))?;
+        Ok(Some(output))
+    }
+
+    /// Stream the named template with the given parameters:
+    /// write each iteration of its outermost `htmpl-foreach` elements to `out`
+    /// as soon as it is rendered.
+    ///
+    /// See [`stream_template`].
+    pub fn stream(
+        &self,
+        template: &str,
+        params: impl Into<Params>,
+        format: StreamFormat,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let source = self
+            .templates
+            .get(template)
+            .ok_or_else(|| Error::MissingTemplate(template.to_owned()))?;
+        let options = self.options.clone().params(params);
+        let routed = self.pool(&options)?;
+        let conn = routed.as_deref().unwrap_or(&self.pool).get()?;
+        stream_template(source, &conn, &options, format, out).map_err(|e| e.in_template(template))
+    }
+}
@@ -0,0 +1,107 @@
+//! HTTP responses for rendered templates, shared by the web framework integrations.
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+
+use crate::{EvaluateOptions, Renderer};
+
+/// Render the template as a response to a request with the given headers.
+///
+/// The options are used in place of the renderer's own; see [`Renderer::render_if_modified_since`].
+///
+/// The response carries `ETag` and `Last-Modified` headers, per the render.
+/// Conditional requests are answered with "304 Not Modified":
+/// if the request has an `If-Modified-Since` header (and no `If-None-Match`),
+/// the freshness queries are checked before evaluating the template.
+pub fn respond(
+    renderer: &Renderer,
+    template: &str,
+    options: &EvaluateOptions,
+    request: &HeaderMap,
+) -> Response<String> {
+    let if_none_match = request
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok());
+    // Per RFC 9110, If-None-Match takes precedence over If-Modified-Since.
+    let since = if if_none_match.is_some() {
+        None
+    } else {
+        request
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+    };
+
+    let output = match renderer.render_if_modified_since(template, options, since) {
+        Ok(Some(output)) => output,
+        Ok(None) => {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .body(String::new())
+                .expect("invalid response")
+        }
+        Err(e) => {
+            tracing::error!("error rendering {}: {}", template, e);
+            return error_response(
+                StatusCode::from_u16(e.status_code()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            );
+        }
+    };
+
+    let etag = output.etag();
+    let mut response = Response::builder();
+    if let Some(last_modified) = output.last_modified() {
+        let time = UNIX_EPOCH + Duration::from_secs(last_modified.max(0) as u64);
+        response = response.header(header::LAST_MODIFIED, httpdate::fmt_http_date(time));
+    }
+    if if_none_match.is_some_and(|tags| etag_matches(tags, &etag)) {
+        return response
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, etag)
+            .body(String::new())
+            .expect("invalid response");
+    }
+    response
+        .header(header::ETAG, etag)
+        .header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(options.format.content_type()),
+        )
+        .body(output.into_output())
+        .expect("invalid response")
+}
+
+/// A plain-text response with the status's reason, e.g. "Not Found".
+pub fn error_response(status: StatusCode) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(status.canonical_reason().unwrap_or_default().to_owned())
+        .expect("invalid response")
+}
+
+/// Returns true if the `If-None-Match` header value matches the entity tag,
+/// using the weak comparison function.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::etag_matches;
+
+    #[test]
+    fn etag_match() {
+        assert!(etag_matches(r#""abc""#, r#""abc""#));
+        assert!(etag_matches(r#""xyz", W/"abc""#, r#""abc""#));
+        assert!(etag_matches("*", r#""abc""#));
+        assert!(!etag_matches(r#""xyz""#, r#""abc""#));
+    }
+}
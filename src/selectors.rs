@@ -0,0 +1,59 @@
+//! Compiled CSS selectors, cached across evaluations.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use scraper::Selector;
+
+/// The most selectors to keep; past this, the cache starts over.
+const MAX_SELECTORS: usize = 1024;
+
+/// A cache of compiled selectors, by source.
+///
+/// Clones share the cache, so every evaluation with (a clone of) the same options
+/// parses each selector once.
+#[derive(Clone, Default)]
+pub(crate) struct Selectors(Arc<Mutex<HashMap<String, Arc<Selector>>>>);
+
+impl Selectors {
+    /// Compile the selector, or get it from the cache.
+    /// Returns None if the selector is invalid.
+    pub fn parse(&self, selector: &str) -> Option<Arc<Selector>> {
+        let mut cache = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(compiled) = cache.get(selector) {
+            return Some(compiled.clone());
+        }
+        let compiled = Arc::new(Selector::parse(selector).ok()?);
+        if cache.len() >= MAX_SELECTORS {
+            cache.clear();
+        }
+        cache.insert(selector.to_owned(), compiled.clone());
+        Some(compiled)
+    }
+}
+
+impl fmt::Debug for Selectors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cache = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        write!(f, "[{} selectors]", cache.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Selectors;
+
+    #[test]
+    fn cached() {
+        let selectors = Selectors::default();
+        let first = selectors.parse("div > p.x").unwrap();
+        let second = selectors.clone().parse("div > p.x").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(selectors.parse("div >").is_none());
+    }
+}
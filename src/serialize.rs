@@ -0,0 +1,558 @@
+//! Serialization of the evaluated tree.
+//!
+//! htmpl has its own serializer, rather than using html5ever's,
+//! so that the same tree can be written out as HTML or as XML.
+
+use ego_tree::NodeRef;
+use html5ever::{local_name, namespace_url, ns, Namespace, Prefix, QualName};
+use scraper::{node::Element, Node};
+
+use crate::options::{EvaluateOptions, Formatting, OutputFormat};
+
+/// Serialize the children of `node` in the given format, per the options.
+pub fn serialize_children(
+    node: NodeRef<Node>,
+    format: OutputFormat,
+    options: &EvaluateOptions,
+) -> String {
+    let mut serializer = Serializer {
+        out: String::new(),
+        format,
+        formatting: options.formatting,
+        ascii_only: options.ascii_only,
+        escape_quotes: options.escape_quotes,
+        scripting: options.parse.scripting,
+        depth: 0,
+        namespaces: Vec::new(),
+    };
+    serializer.container(node);
+    if options.formatting == Formatting::Pretty && !serializer.out.is_empty() {
+        serializer.out.push('\n');
+    }
+    serializer.out
+}
+
+struct Serializer {
+    out: String,
+    format: OutputFormat,
+    formatting: Formatting,
+    /// Write non-ASCII characters as character references.
+    ascii_only: bool,
+    /// Write quotes as character references, in text as well as attribute values.
+    escape_quotes: bool,
+    /// Whether templates are parsed with scripting enabled.
+    scripting: bool,
+    /// The indentation level of the current line, when pretty-printing.
+    depth: usize,
+    /// XML namespace declarations in scope, innermost last.
+    namespaces: Vec<(Option<Prefix>, Namespace)>,
+}
+
+impl Serializer {
+    fn children(&mut self, node: NodeRef<Node>, raw: bool) {
+        for child in node.children() {
+            self.node(child, raw);
+        }
+    }
+
+    /// Serialize the children of a document or fragment.
+    fn container(&mut self, node: NodeRef<Node>) {
+        match self.formatting {
+            Formatting::Preserve => self.children(node, false),
+            Formatting::Pretty => self.indented(node),
+            Formatting::Minified => self.minified(node),
+        }
+    }
+
+    /// Serialize the children of an element, formatted per the options.
+    fn element_children(&mut self, node: NodeRef<Node>, element: &Element) {
+        let name = &element.name;
+        // With scripting enabled, the parser reads <noscript> as raw text too.
+        let noscript =
+            self.scripting && name.ns == ns!(html) && name.local == local_name!("noscript");
+        let raw = self.format == OutputFormat::Html && (is_raw_text(name) || noscript);
+        if self.formatting == Formatting::Preserve || raw {
+            return self.children(node, raw);
+        }
+        if preserves_whitespace(element) {
+            let formatting = std::mem::replace(&mut self.formatting, Formatting::Preserve);
+            self.children(node, false);
+            self.formatting = formatting;
+            return;
+        }
+        // XML elements with text content keep their whitespace, which may be significant.
+        let xml_text = self.format == OutputFormat::Xml
+            && node
+                .children()
+                .any(|c| c.value().as_text().is_some_and(|t| !is_blank(t)));
+        if xml_text {
+            return self.children(node, false);
+        }
+        if self.formatting == Formatting::Minified {
+            return self.minified(node);
+        }
+        if self.is_inline(node) {
+            return self.children(node, false);
+        }
+        if node.children().all(|c| self.is_inline(c)) {
+            let children: Vec<_> = node.children().collect();
+            return self.inline_run(&children);
+        }
+        self.depth += 1;
+        self.indented(node);
+        self.depth -= 1;
+        self.newline();
+    }
+
+    /// Serialize the children of the node each on their own line;
+    /// runs of inline content (text, comments, and inline elements) stay together on a line.
+    fn indented(&mut self, node: NodeRef<Node>) {
+        let mut run = Vec::new();
+        for child in node.children() {
+            if self.is_inline(child) {
+                run.push(child);
+                continue;
+            }
+            self.inline_line(&run);
+            run.clear();
+            self.newline();
+            self.node(child, false);
+        }
+        self.inline_line(&run);
+    }
+
+    /// Serialize a run of inline content on its own line, unless it is all whitespace.
+    fn inline_line(&mut self, run: &[NodeRef<Node>]) {
+        let blank = run
+            .iter()
+            .all(|n| n.value().as_text().is_some_and(|t| is_blank(t)));
+        if !blank {
+            self.newline();
+            self.inline_run(run);
+        }
+    }
+
+    /// Serialize a run of inline content, trimming whitespace from its ends.
+    fn inline_run(&mut self, run: &[NodeRef<Node>]) {
+        for (i, node) in run.iter().enumerate() {
+            match node.value() {
+                Node::Text(text) => {
+                    let mut text: &str = text;
+                    if i == 0 {
+                        text = text.trim_start();
+                    }
+                    if i == run.len() - 1 {
+                        text = text.trim_end();
+                    }
+                    self.escape(text, false);
+                }
+                _ => self.node(*node, false),
+            }
+        }
+    }
+
+    /// Serialize the children of the node, with their whitespace collapsed.
+    fn minified(&mut self, node: NodeRef<Node>) {
+        for child in node.children() {
+            match child.value() {
+                Node::Text(text) => {
+                    let text = self.minified_text(child, text);
+                    self.escape(&text, false);
+                }
+                _ => self.node(child, false),
+            }
+        }
+    }
+
+    /// The text of the node with runs of whitespace collapsed to a single space,
+    /// and trimmed where it is next to the start or end of a block.
+    fn minified_text(&self, node: NodeRef<Node>, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        for c in text.chars() {
+            if !c.is_ascii_whitespace() {
+                out.push(c);
+            } else if !out.ends_with(' ') {
+                out.push(' ');
+            }
+        }
+        let inline_parent = node.parent().is_some_and(|p| self.is_inline(p));
+        let block_before = match node.prev_sibling() {
+            Some(prev) => !self.is_inline(prev),
+            None => !inline_parent,
+        };
+        let block_after = match node.next_sibling() {
+            Some(next) => !self.is_inline(next),
+            None => !inline_parent,
+        };
+        if block_after && out.ends_with(' ') {
+            out.pop();
+        }
+        if block_before && out.starts_with(' ') {
+            out.remove(0);
+        }
+        out
+    }
+
+    /// Returns true if the end tag of the HTML element can be left out when minifying,
+    /// because the next element (or the end of the parent) implies it.
+    fn omit_end_tag(&self, node: NodeRef<Node>, name: &QualName) -> bool {
+        if self.formatting != Formatting::Minified || name.ns != ns!(html) {
+            return false;
+        }
+        // Skip text that is dropped by minification.
+        let next = node.next_siblings().find(|n| match n.value() {
+            Node::Text(text) => !self.minified_text(*n, text).is_empty(),
+            _ => true,
+        });
+        let next = match next {
+            None => None,
+            Some(next) => match next.value().as_element() {
+                Some(e) if e.name.ns == ns!(html) => Some(e.name.local.clone()),
+                // Other content does not imply an end tag.
+                _ => return false,
+            },
+        };
+        match name.local {
+            local_name!("li") => matches!(next, None | Some(local_name!("li"))),
+            local_name!("dt") => matches!(next, Some(local_name!("dt") | local_name!("dd"))),
+            local_name!("dd") => {
+                matches!(next, None | Some(local_name!("dt") | local_name!("dd")))
+            }
+            local_name!("option") => {
+                matches!(
+                    next,
+                    None | Some(local_name!("option") | local_name!("optgroup"))
+                )
+            }
+            local_name!("tr") => matches!(next, None | Some(local_name!("tr"))),
+            local_name!("td") | local_name!("th") => {
+                matches!(next, None | Some(local_name!("td") | local_name!("th")))
+            }
+            _ => false,
+        }
+    }
+
+    /// Start a new line at the current depth, unless this is the start of the output.
+    fn newline(&mut self) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        for _ in 0..self.depth {
+            self.out.push_str("  ");
+        }
+    }
+
+    /// Returns true if the node is laid out inline when pretty-printing:
+    /// text, comments, and HTML phrasing elements like `<a>` and `<em>`.
+    fn is_inline(&self, node: NodeRef<Node>) -> bool {
+        match node.value() {
+            Node::Text(_) | Node::Comment(_) => true,
+            Node::Element(e) => self.format.is_html() && is_phrasing(&e.name),
+            _ => false,
+        }
+    }
+
+    /// Serialize a node.
+    /// If `raw` is set, text is written without escaping (e.g. within an HTML `<script>`).
+    fn node(&mut self, node: NodeRef<Node>, raw: bool) {
+        match node.value() {
+            Node::Document | Node::Fragment => self.container(node),
+            Node::Doctype(doctype) => {
+                self.out.push_str("<!DOCTYPE ");
+                self.out.push_str(doctype.name());
+                if self.format == OutputFormat::Xml {
+                    if !doctype.public_id().is_empty() {
+                        self.out.push_str(" PUBLIC \"");
+                        self.out.push_str(doctype.public_id());
+                        self.out.push('"');
+                    } else if !doctype.system_id().is_empty() {
+                        self.out.push_str(" SYSTEM");
+                    }
+                    if !doctype.system_id().is_empty() {
+                        self.out.push_str(" \"");
+                        self.out.push_str(doctype.system_id());
+                        self.out.push('"');
+                    }
+                }
+                self.out.push('>');
+            }
+            Node::Comment(comment) => {
+                self.out.push_str("<!--");
+                self.out.push_str(comment);
+                self.out.push_str("-->");
+            }
+            Node::Text(text) if raw => self.out.push_str(text),
+            Node::Text(text) => self.escape(text, false),
+            Node::ProcessingInstruction(pi) => {
+                self.out.push_str("<?");
+                self.out.push_str(&pi.target);
+                if !pi.data.is_empty() {
+                    self.out.push(' ');
+                    self.out.push_str(&pi.data);
+                }
+                self.out.push_str(match self.format {
+                    OutputFormat::Html => ">",
+                    OutputFormat::Xml | OutputFormat::Xhtml => "?>",
+                });
+            }
+            Node::Element(element) => match self.format {
+                OutputFormat::Html => self.html_element(node, element),
+                OutputFormat::Xml | OutputFormat::Xhtml => self.xml_element(node, element),
+            },
+        }
+    }
+
+    fn html_element(&mut self, node: NodeRef<Node>, element: &Element) {
+        self.out.push('<');
+        self.out.push_str(&element.name.local);
+        for (name, value) in element.attrs.iter() {
+            self.out.push(' ');
+            match name.ns {
+                ns!(xml) => self.out.push_str("xml:"),
+                ns!(xmlns) if name.local != local_name!("xmlns") => self.out.push_str("xmlns:"),
+                ns!(xlink) => self.out.push_str("xlink:"),
+                _ => (),
+            }
+            self.out.push_str(&name.local);
+            if self.formatting == Formatting::Minified && value.is_empty() {
+                continue;
+            }
+            let quote = self.formatting != Formatting::Minified
+                || value.contains(|c: char| c.is_ascii_whitespace() || "\"'=<>`".contains(c));
+            self.out.push('=');
+            if quote {
+                self.out.push('"');
+            }
+            self.escape(value, true);
+            if quote {
+                self.out.push('"');
+            }
+        }
+        self.out.push('>');
+        if is_void(&element.name) {
+            return;
+        }
+        self.element_children(node, element);
+        if self.omit_end_tag(node, &element.name) {
+            return;
+        }
+        self.out.push_str("</");
+        self.out.push_str(&element.name.local);
+        self.out.push('>');
+    }
+
+    fn xml_element(&mut self, node: NodeRef<Node>, element: &Element) {
+        let scope_start = self.namespaces.len();
+        self.out.push('<');
+        self.qualified_name(&element.name);
+        self.declare(&element.name);
+        for (name, value) in element.attrs.iter() {
+            // Namespace declarations parsed from HTML are redone per the names in use.
+            if self.format == OutputFormat::Xhtml && name.ns == ns!(xmlns) {
+                continue;
+            }
+            // Unprefixed attributes are in no namespace, regardless of the default namespace.
+            if name.prefix.is_some() {
+                self.declare(name);
+            }
+            self.out.push(' ');
+            self.qualified_name(name);
+            self.out.push_str("=\"");
+            self.escape(value, true);
+            self.out.push('"');
+        }
+        let xhtml = self.format == OutputFormat::Xhtml;
+        // In polyglot XHTML, only void elements can be self-closing.
+        if node.has_children() || (xhtml && !is_void(&element.name)) {
+            self.out.push('>');
+            if xhtml && is_raw_text(&element.name) {
+                self.cdata(node);
+            } else {
+                self.element_children(node, element);
+            }
+            self.out.push_str("</");
+            self.qualified_name(&element.name);
+            self.out.push('>');
+        } else {
+            self.out.push_str("/>");
+        }
+        self.namespaces.truncate(scope_start);
+    }
+
+    /// Write the text of a `<script>` or `<style>` element, so that it reads the same as XML
+    /// and as HTML: as-is if it has no markup characters, or else as a CDATA section.
+    fn cdata(&mut self, node: NodeRef<Node>) {
+        let text: String = node
+            .children()
+            .filter_map(|c| c.value().as_text())
+            .map(|t| &**t)
+            .collect();
+        if text.contains(['<', '&']) {
+            self.out.push_str("<![CDATA[");
+            self.out.push_str(&text.replace("]]>", "]]]]><![CDATA[>"));
+            self.out.push_str("]]>");
+        } else {
+            self.out.push_str(&text);
+        }
+    }
+
+    fn qualified_name(&mut self, name: &QualName) {
+        if let Some(prefix) = &name.prefix {
+            self.out.push_str(prefix);
+            self.out.push(':');
+        }
+        self.out.push_str(&name.local);
+    }
+
+    /// Write a namespace declaration for the name, if its prefix isn't already bound to its
+    /// namespace.
+    fn declare(&mut self, name: &QualName) {
+        let bound = self
+            .namespaces
+            .iter()
+            .rev()
+            .find(|(prefix, _)| *prefix == name.prefix)
+            .map(|(_, ns)| ns.clone());
+        let bound = match (&name.prefix, bound) {
+            (_, Some(ns)) => ns,
+            (Some(prefix), None) if &**prefix == "xml" => ns!(xml),
+            (_, None) => ns!(),
+        };
+        if bound == name.ns {
+            return;
+        }
+        self.out.push_str(" xmlns");
+        if let Some(prefix) = &name.prefix {
+            self.out.push(':');
+            self.out.push_str(prefix);
+        }
+        self.out.push_str("=\"");
+        self.escape(&name.ns, true);
+        self.out.push('"');
+        self.namespaces.push((name.prefix.clone(), name.ns.clone()));
+    }
+
+    fn escape(&mut self, text: &str, attr_mode: bool) {
+        for c in text.chars() {
+            match (self.format, c) {
+                (_, '&') => self.out.push_str("&amp;"),
+                (_, '"') if attr_mode || self.escape_quotes => self.out.push_str("&quot;"),
+                (_, '\'') if self.escape_quotes => self.out.push_str("&#39;"),
+                (OutputFormat::Html, '\u{00A0}') => self.out.push_str("&nbsp;"),
+                (OutputFormat::Html, '<' | '>') if attr_mode => self.out.push(c),
+                (_, '<') => self.out.push_str("&lt;"),
+                (_, '>') => self.out.push_str("&gt;"),
+                (_, c) if self.ascii_only && !c.is_ascii() => {
+                    self.out.push_str(&format!("&#x{:X};", c as u32))
+                }
+                (_, c) => self.out.push(c),
+            }
+        }
+    }
+}
+
+/// Returns true if the element is an HTML void element, which has no end tag.
+fn is_void(name: &QualName) -> bool {
+    name.ns == ns!(html)
+        && matches!(
+            name.local,
+            local_name!("area")
+                | local_name!("base")
+                | local_name!("basefont")
+                | local_name!("bgsound")
+                | local_name!("br")
+                | local_name!("col")
+                | local_name!("embed")
+                | local_name!("frame")
+                | local_name!("hr")
+                | local_name!("img")
+                | local_name!("input")
+                | local_name!("keygen")
+                | local_name!("link")
+                | local_name!("meta")
+                | local_name!("param")
+                | local_name!("source")
+                | local_name!("track")
+                | local_name!("wbr")
+        )
+}
+
+/// Returns true if the text content of the HTML element is written without escaping.
+fn is_raw_text(name: &QualName) -> bool {
+    name.ns == ns!(html)
+        && matches!(
+            name.local,
+            local_name!("style")
+                | local_name!("script")
+                | local_name!("xmp")
+                | local_name!("iframe")
+                | local_name!("noembed")
+                | local_name!("noframes")
+                | local_name!("plaintext")
+        )
+}
+
+/// Returns true if the text is empty or all whitespace.
+fn is_blank(text: &str) -> bool {
+    text.chars().all(char::is_whitespace)
+}
+
+/// Returns true if whitespace within the element is significant, i.e. it is an HTML `<pre>`
+/// or `<textarea>` (or the like), or has `xml:space="preserve"`.
+fn preserves_whitespace(element: &Element) -> bool {
+    let name = &element.name;
+    let pre = name.ns == ns!(html)
+        && matches!(
+            name.local,
+            local_name!("pre") | local_name!("textarea") | local_name!("listing")
+        );
+    pre || element.attrs.iter().any(|(name, value)| {
+        name.ns == ns!(xml) && &*name.local == "space" && &**value == "preserve"
+    })
+}
+
+/// Returns true if the element is an HTML phrasing element, laid out within a line of text.
+fn is_phrasing(name: &QualName) -> bool {
+    name.ns == ns!(html)
+        && matches!(
+            name.local,
+            local_name!("a")
+                | local_name!("abbr")
+                | local_name!("b")
+                | local_name!("bdi")
+                | local_name!("bdo")
+                | local_name!("br")
+                | local_name!("button")
+                | local_name!("cite")
+                | local_name!("code")
+                | local_name!("data")
+                | local_name!("del")
+                | local_name!("dfn")
+                | local_name!("em")
+                | local_name!("i")
+                | local_name!("img")
+                | local_name!("input")
+                | local_name!("ins")
+                | local_name!("kbd")
+                | local_name!("label")
+                | local_name!("mark")
+                | local_name!("meter")
+                | local_name!("output")
+                | local_name!("progress")
+                | local_name!("q")
+                | local_name!("ruby")
+                | local_name!("s")
+                | local_name!("samp")
+                | local_name!("select")
+                | local_name!("small")
+                | local_name!("span")
+                | local_name!("strong")
+                | local_name!("sub")
+                | local_name!("sup")
+                | local_name!("textarea")
+                | local_name!("time")
+                | local_name!("u")
+                | local_name!("var")
+                | local_name!("wbr")
+        )
+}
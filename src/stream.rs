@@ -0,0 +1,128 @@
+//! Streaming evaluation: emitting each `htmpl-foreach` iteration as soon as it renders.
+//!
+//! In a streaming evaluation, each iteration of an outermost `htmpl-foreach` element
+//! is serialized and written out as its own event, e.g. to a client awaiting a long list of results.
+//! Content outside of `htmpl-foreach` elements is evaluated (so queries run, and bindings are
+//! available to the iterations) but is not output.
+
+use std::{cell::RefCell, fmt, io::Write};
+
+use crate::{
+    components, functions,
+    json::json_string,
+    options::OutputFormat,
+    parse, prefix,
+    queries::{self, DbTable, Scope},
+    visit::{bind_builtins, visit_recurse},
+    Error, EvaluateOptions,
+};
+
+/// The framing of events in a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamFormat {
+    /// [Server-sent events](https://html.spec.whatwg.org/multipage/server-sent-events.html):
+    /// each iteration is a `data:` event, named per the `event=` attribute of the
+    /// `htmpl-foreach` element, if present.
+    Sse,
+    /// Newline-delimited JSON: each iteration is a line holding an object,
+    /// with the rendered markup in the `"html"` field and the `event=` attribute
+    /// (if present) in the `"event"` field.
+    Ndjson,
+}
+
+impl StreamFormat {
+    /// The media type of a stream in this format, e.g. for an HTTP `Content-Type` header.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StreamFormat::Sse => "text/event-stream",
+            StreamFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// The destination of streamed events, as carried in a [`Scope`].
+pub(crate) trait Emit: fmt::Debug {
+    /// The format in which to serialize each event's markup.
+    fn output_format(&self) -> OutputFormat;
+
+    /// Write out an event, and flush it to the client.
+    fn emit(&self, event: Option<&str>, content: &str) -> Result<(), Error>;
+}
+
+/// Writes events to an output stream.
+struct Sink<'a> {
+    format: StreamFormat,
+    output: OutputFormat,
+    out: RefCell<&'a mut dyn Write>,
+}
+
+impl fmt::Debug for Sink<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sink")
+            .field("format", &self.format)
+            .field("output", &self.output)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Emit for Sink<'_> {
+    fn output_format(&self) -> OutputFormat {
+        self.output
+    }
+
+    fn emit(&self, event: Option<&str>, content: &str) -> Result<(), Error> {
+        let mut buf = String::new();
+        match self.format {
+            StreamFormat::Sse => {
+                if let Some(event) = event {
+                    buf.push_str(&format!("event: {}\n", event));
+                }
+                for line in content.lines() {
+                    buf.push_str(&format!("data: {}\n", line));
+                }
+                if content.is_empty() {
+                    buf.push_str("data: \n");
+                }
+                buf.push('\n');
+            }
+            StreamFormat::Ndjson => {
+                buf.push('{');
+                if let Some(event) = event {
+                    buf.push_str(&format!("\"event\":{},", json_string(event)));
+                }
+                buf.push_str(&format!("\"html\":{}}}\n", json_string(content)));
+            }
+        }
+        let mut out = self.out.borrow_mut();
+        out.write_all(buf.as_bytes()).map_err(Error::Serialize)?;
+        out.flush().map_err(Error::Serialize)
+    }
+}
+
+/// Evaluate the template, writing each iteration of the outermost `htmpl-foreach` elements
+/// to `out` as an event, as soon as it is rendered.
+pub fn stream_template(
+    s: impl AsRef<str>,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+    format: StreamFormat,
+    out: &mut dyn Write,
+) -> Result<(), Error> {
+    let h = parse::parse(s.as_ref(), options)?;
+    functions::setup(dbs, options)?;
+    let sink = Sink {
+        format,
+        output: options.format,
+        out: RefCell::new(out),
+    };
+    let mut scope = Scope::new(dbs, options);
+    scope.set_sink(Some(&sink));
+    scope.set_components(components::definitions(&h).map_err(|e| prefix::error(options, e))?);
+    // The rest of the document is discarded.
+    let mut output = scraper::Html::new_fragment();
+    queries::transaction(dbs, || {
+        bind_builtins(&mut scope, options)?;
+        visit_recurse(&mut scope, h.tree.root(), &mut output.tree.root_mut())?;
+        Ok(!scope.has_inline_errors())
+    })
+}
@@ -0,0 +1,113 @@
+//! Registry of named templates.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{evaluate_template_with, queries::DbTable, Error, EvaluateOptions};
+
+/// A set of templates, by name.
+///
+/// Templates are stored as source, and parsed on each evaluation;
+/// a `Templates` can be shared between threads.
+#[derive(Debug, Clone, Default)]
+pub struct Templates {
+    templates: HashMap<String, Arc<str>>,
+}
+
+impl Templates {
+    /// Create an empty set of templates.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Load all `.html` and `.xml` files under the directory.
+    /// Each template is named by its path relative to the directory, with `/` separators:
+    /// e.g. `index.html`, `posts/page.html`.
+    ///
+    /// Symbolic links are followed only within the directory:
+    /// a link to a file or directory outside of it is an error.
+    pub fn from_dir(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut templates = Templates::new();
+        let dir = dir.as_ref();
+        let root = fs::canonicalize(dir)?;
+        let mut visited: HashSet<PathBuf> = HashSet::new();
+        let mut pending: Vec<PathBuf> = vec![dir.to_owned()];
+        while let Some(next) = pending.pop() {
+            // A link back to an enclosing directory would otherwise be listed forever.
+            if !visited.insert(fs::canonicalize(&next)?) {
+                continue;
+            }
+            for entry in fs::read_dir(next)? {
+                let path = entry?.path();
+                if !fs::canonicalize(&path)?.starts_with(&root) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("{} is outside of {}", path.display(), dir.display()),
+                    ));
+                }
+                if path.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                if !path
+                    .extension()
+                    .is_some_and(|ext| ext == "html" || ext == "xml")
+                {
+                    continue;
+                }
+                let name = path
+                    .strip_prefix(dir)
+                    .expect("listed file is not in directory")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                templates.insert(name, fs::read_to_string(&path)?);
+            }
+        }
+        Ok(templates)
+    }
+
+    /// Add a template, replacing any existing template with the same name.
+    pub fn insert(&mut self, name: impl Into<String>, template: impl AsRef<str>) {
+        self.templates
+            .insert(name.into(), Arc::from(template.as_ref()));
+    }
+
+    /// The names of the templates, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+
+    /// Get the source of the named template.
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&str> {
+        self.templates.get(name.as_ref()).map(|t| &**t)
+    }
+
+    /// Evaluate the named template.
+    ///
+    /// Errors from the evaluation carry the template's [name](Error::template).
+    pub fn evaluate(
+        &self,
+        name: impl AsRef<str>,
+        dbs: &DbTable,
+        options: &EvaluateOptions,
+    ) -> Result<String, Error> {
+        let name = name.as_ref();
+        let template = self
+            .get(name)
+            .ok_or_else(|| Error::MissingTemplate(name.to_owned()))?;
+        let result = match options.templates {
+            Some(_) => evaluate_template_with(template, dbs, options),
+            None => {
+                let options = options.clone().templates(self.clone());
+                evaluate_template_with(template, dbs, &options)
+            }
+        };
+        result.map_err(|e| e.in_template(name))
+    }
+}
@@ -0,0 +1,186 @@
+//! Utilities for testing templates.
+//!
+//! [`assert_html_eq`] compares HTML by structure, rather than by text.
+//! [`Snapshots`] renders templates against a fixture database, and compares the output
+//! against stored "golden" files:
+//!
+//! ```no_run
+//! use htmpl::testing::{fixture_db, Snapshots};
+//!
+//! let conn = fixture_db("CREATE TABLE posts (title TEXT); INSERT INTO posts VALUES ('Hello');");
+//! let templates = htmpl::Templates::from_dir("templates").unwrap();
+//! // Set HTMPL_UPDATE_SNAPSHOTS=1 to (re)write the snapshots.
+//! Snapshots::new("tests/snapshots").check_all(&templates, &conn, &Default::default());
+//! ```
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::Connection;
+
+use crate::{html_diff, EvaluateOptions, Templates};
+
+/// The environment variable that, if set (to anything but `0`), puts [`Snapshots`]
+/// in update mode.
+pub const UPDATE_SNAPSHOTS: &str = "HTMPL_UPDATE_SNAPSHOTS";
+
+/// Assert that two HTML fragments have the same structure:
+/// the same elements, attributes, and text, regardless of how they are written
+/// (e.g. quoting of attributes, or leading and trailing whitespace).
+///
+/// On failure, the message lists the [differences](crate::html_diff) between them.
+#[track_caller]
+pub fn assert_html_eq(got: impl AsRef<str>, want: impl AsRef<str>) {
+    let (got, want) = (got.as_ref().trim(), want.as_ref().trim());
+    let diffs = html_diff(got, want);
+    if !diffs.is_empty() {
+        let diffs: Vec<String> = diffs.iter().map(ToString::to_string).collect();
+        panic!(
+            "HTML differs:\n{}\ngot:\n---\n{}\n---\nwant:\n---\n{}\n---\n",
+            diffs.join("\n"),
+            got,
+            want
+        );
+    }
+}
+
+/// Create an in-memory database, set up by the SQL, e.g. `CREATE TABLE` and `INSERT` statements.
+///
+/// Panics if the SQL fails.
+#[track_caller]
+pub fn fixture_db(sql: &str) -> Connection {
+    let conn = Connection::open_in_memory().expect("could not open in-memory database");
+    conn.execute_batch(sql)
+        .expect("could not set up fixture database");
+    conn
+}
+
+/// Golden-file testing: compare rendered templates against stored snapshots.
+///
+/// The snapshot of a template is the file with the template's name under the snapshot
+/// directory. In update mode, missing or different snapshots are (re)written instead of
+/// failing the test.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    update: bool,
+}
+
+impl Snapshots {
+    /// Compare against snapshots in the directory.
+    ///
+    /// Update mode is set if the [`UPDATE_SNAPSHOTS`] environment variable is set.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Snapshots {
+            dir: dir.into(),
+            update: env::var(UPDATE_SNAPSHOTS).is_ok_and(|v| v != "0"),
+        }
+    }
+
+    /// Set whether to (re)write snapshots, rather than compare against them.
+    pub fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// The directory the snapshots are stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Compare the output against the named snapshot, or (in update mode) write it.
+    ///
+    /// Panics if the snapshot is missing or different, outside of update mode.
+    #[track_caller]
+    pub fn check(&self, name: &str, got: &str) {
+        let path = self.dir.join(name);
+        let want = fs::read_to_string(&path).ok();
+        if want.as_deref() == Some(got) {
+            return;
+        }
+        if self.update {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).expect("could not create snapshot directory");
+            }
+            fs::write(&path, got).expect("could not write snapshot");
+            return;
+        }
+        match want {
+            None => panic!(
+                "missing snapshot {}; set {}=1 to write it",
+                path.display(),
+                UPDATE_SNAPSHOTS
+            ),
+            Some(want) => panic!(
+                "output of {} differs from snapshot {}; set {}=1 to update it\n\
+                got:\n---\n{}\n---\nwant:\n---\n{}\n---\n",
+                name,
+                path.display(),
+                UPDATE_SNAPSHOTS,
+                got,
+                want
+            ),
+        }
+    }
+
+    /// Render each of the templates against the database, and [check](Self::check)
+    /// each output against the snapshot of the same name.
+    ///
+    /// Panics if a template fails to render.
+    #[track_caller]
+    pub fn check_all(&self, templates: &Templates, conn: &Connection, options: &EvaluateOptions) {
+        let mut names: Vec<&str> = templates.names().collect();
+        names.sort();
+        for name in names {
+            let got = templates
+                .evaluate(name, conn, options)
+                .unwrap_or_else(|e| panic!("could not render {}: {}", name, e));
+            self.check(name, &got);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{assert_html_eq, fixture_db, Snapshots};
+    use crate::Templates;
+
+    #[test]
+    fn structural() {
+        assert_html_eq("<p class='a'>hi</p>\n", r#"<p class="a">hi</p>"#);
+    }
+
+    #[test]
+    #[should_panic]
+    fn different() {
+        assert_html_eq("<p>hi</p>", "<p>bye</p>");
+    }
+
+    #[test]
+    fn snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = fixture_db("CREATE TABLE t (x TEXT); INSERT INTO t VALUES ('hi');");
+        let mut templates = Templates::new();
+        templates.insert(
+            "a/index.html",
+            r#"<htmpl-query name="t">SELECT x FROM t;</htmpl-query><p><htmpl-insert query="t"></htmpl-insert></p>"#,
+        );
+        let snapshots = Snapshots::new(dir.path()).update(true);
+        snapshots.check_all(&templates, &conn, &Default::default());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a/index.html")).unwrap(),
+            "<p>hi</p>"
+        );
+
+        let snapshots = snapshots.update(false);
+        snapshots.check_all(&templates, &conn, &Default::default());
+        let missing = std::panic::catch_unwind(|| snapshots.check("b.html", "<p>hi</p>"));
+        assert!(missing.is_err());
+        let changed = std::panic::catch_unwind(|| snapshots.check("a/index.html", "<p>bye</p>"));
+        assert!(changed.is_err());
+    }
+}
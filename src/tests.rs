@@ -1,10 +1,17 @@
 #![cfg(test)]
 
-use std::ops::Deref;
+use std::sync::{Arc, Mutex};
 
-use crate::{evaluate_template, Error};
-use rusqlite::{params, Connection};
-use scraper::Html;
+use crate::{
+    evaluate_children, evaluate_parsed, evaluate_template, evaluate_template_with, evaluate_to_dom,
+    render, stream_template, testing::assert_html_eq, Comments, Error, ErrorKind, EvaluateOptions,
+    Formatting, Mode, OutputFormat, Params, Pool, RenderOutput, Renderer, Scope, StreamFormat,
+    Templates, TraceStep, Truthiness,
+};
+use ego_tree::NodeMut;
+use html5ever::{local_name, namespace_url, ns, QualName};
+use rusqlite::{functions::FunctionFlags, params, types::Value, Connection};
+use scraper::{ElementRef, Html, Node};
 use tempfile::NamedTempFile;
 
 const CCECKMAN_UUID: &str = "18adfb4d-6a38-4c81-b2e8-4d59e6467c9f";
@@ -44,19 +51,6 @@ CREATE TABLE users
     ro
 }
 
-/// Compare HTML for equal structure.
-fn html_equal(got: impl Deref<Target = str>, want: impl Deref<Target = str>) {
-    let got_html = Html::parse_fragment(got.trim());
-    let want_html = Html::parse_fragment(want.trim());
-    assert_eq!(
-        got_html,
-        want_html,
-        "got:\n---\n{}\n---\nwant:\n---\n{}\n---\n",
-        got.trim(),
-        want.trim()
-    );
-}
-
 #[test]
 fn meta_conn_is_ro() {
     let conn = make_test_db();
@@ -73,7 +67,10 @@ fn missing_query() {
         "#;
     let result =
         evaluate_template(TEMPLATE, &db).expect_err("succeeded at evaluating invalid template");
-    assert_eq!(result, Error::MissingQuery("htmpl-insert", "q".to_owned()));
+    assert_eq!(
+        *result.inner(),
+        Error::MissingQuery("htmpl-insert".into(), "q".to_owned())
+    );
 }
 
 #[test]
@@ -86,7 +83,8 @@ fn multi_column_requires_column_selection() {
         <htmpl-insert query="q"></htmpl-insert>
         "#;
     let result = evaluate_template(TEMPLATE, &db).expect_err("unexpected success");
-    if let Error::NoDefaultColumn("htmpl-insert", _, _) = result {
+    if let Error::NoDefaultColumn(element, _, _) = result.inner() {
+        assert_eq!(element, "htmpl-insert");
     } else {
         panic!("unexpected error: {}", result);
     }
@@ -102,7 +100,8 @@ fn error_on_invalid_column() {
         <htmpl-insert query="q(does-not-exist)"></htmpl-insert>
         "#;
     let result = evaluate_template(TEMPLATE, &db).expect_err("unexpected success");
-    if let Error::MissingColumn("htmpl-insert", _, _, _) = result {
+    if let Error::MissingColumn(element, _, _, _) = result.inner() {
+        assert_eq!(element, "htmpl-insert");
     } else {
         panic!("unexpected error: {}", result);
     }
@@ -118,7 +117,7 @@ fn insert_named_column() {
         <htmpl-insert query="q(uuid)"></htmpl-insert>
         "#;
     let result: String = evaluate_template(TEMPLATE, &db).expect("failed to evaluate template");
-    html_equal(result, CCECKMAN_UUID);
+    assert_html_eq(result, CCECKMAN_UUID);
 }
 
 #[test]
@@ -131,7 +130,7 @@ fn insert_default_column() {
         <htmpl-insert query="q"></htmpl-insert>
         "#;
     let result: String = evaluate_template(TEMPLATE, &db).expect("failed to evaluate template");
-    html_equal(result, CCECKMAN_UUID);
+    assert_html_eq(result, CCECKMAN_UUID);
 }
 
 #[test]
@@ -144,7 +143,8 @@ fn insert_requires_single_row() {
         <htmpl-insert query="q"></htmpl-insert>
         "#;
     let result = evaluate_template(TEMPLATE, &db).expect_err("unexpected success");
-    if let Error::Cardinality("htmpl-insert", _, _, _) = result {
+    if let Error::Cardinality(element, _, _, _) = result.inner() {
+        assert_eq!(element, "htmpl-insert");
     } else {
         panic!("unexpected error: {}", result);
     }
@@ -172,7 +172,7 @@ fn shadow_inner_scope() {
         .chars()
         .filter(|v| !char::is_whitespace(*v))
         .collect();
-    html_equal(
+    assert_html_eq(
         trimmed,
         format!("<div><div>{}</div>{}</div>", CCECKMAN_UUID, OTHER_UUID),
     );
@@ -251,7 +251,7 @@ fn constant() {
         <htmpl-insert query="admin_uuid"></htmpl-insert>
         "#;
     let result = evaluate_template(TEMPLATE, &db).expect("unexpected error");
-    html_equal(result, CCECKMAN_UUID);
+    assert_html_eq(result, CCECKMAN_UUID);
 }
 
 #[test_log::test]
@@ -264,12 +264,130 @@ fn single_attr() {
         <div class="name"><htmpl-insert query="q(name)"></htmpl-insert></div>
         "#;
     let result = evaluate_template(TEMPLATE, &conn).unwrap();
-    html_equal(
+    assert_html_eq(
         result,
         r#"<div class="18adfb4d-6a38-4c81-b2e8-4d59e6467c9f name">cceckman</div>"#,
     );
 }
 
+#[test]
+fn attr_append() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+        <htmpl-query name="q">SELECT name, uuid FROM users ORDER BY name ASC LIMIT 1;</htmpl-query>
+        <htmpl-attr select=".name" query="q(uuid)" attr="class" append></htmpl-attr>
+        <htmpl-attr select=".name" query="q(name)" attr="class" append></htmpl-attr>
+        <div class="name"></div><htmpl-attr select="p" query="q(name)" attr="class" append></htmpl-attr><p></p>
+        "#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<div class="name 18adfb4d-6a38-4c81-b2e8-4d59e6467c9f cceckman"></div><p class="cceckman"></p>"#,
+    );
+}
+
+#[test]
+fn attr_conditions() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+        <htmpl-query name="q">SELECT id, name, id = 1 AS current FROM users ORDER BY id;</htmpl-query>
+        <ul><htmpl-foreach query="q"><htmpl-attr select="a" query="q(current)" attr="aria-current" if="q(current)"></htmpl-attr><htmpl-attr select="a" query="q(name)" attr="title" unless="q(current)"></htmpl-attr><a></a></htmpl-foreach></ul>
+        "#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<ul><a aria-current="1"></a><a title="ddedkman"></a></ul>"#,
+    );
+
+    let result = evaluate_template(
+        r#"<htmpl-attr select="a" query="params" attr="x" if="params" unless="params"></htmpl-attr>"#,
+        &conn,
+    );
+    assert!(matches!(
+        result.unwrap_err().inner(),
+        Error::MultipleConditions(_)
+    ));
+}
+
+#[test]
+fn attr_remove() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+        <htmpl-query name="q">SELECT COUNT(*) AS count FROM users;</htmpl-query>
+        <htmpl-attr select="p" attr="hidden" remove if="q(count)"></htmpl-attr>
+        <htmpl-attr select="a" attr="href" remove unless="q(count)"></htmpl-attr>
+        <p hidden><a href="/users">users</a></p>
+        "#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(result, r#"<p><a href="/users">users</a></p>"#);
+}
+
+#[test]
+fn attr_boolean() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+        <htmpl-query name="q">SELECT id, id = 2 AS locked FROM users ORDER BY id;</htmpl-query>
+        <htmpl-foreach query="q"><htmpl-attr select="button" attr="disabled" query="q(locked)" boolean></htmpl-attr><button disabled></button></htmpl-foreach>
+        "#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(result, r#"<button></button><button disabled=""></button>"#);
+}
+
+#[test]
+fn class() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+        <htmpl-query name="q">SELECT id, id = 1 AS active FROM users ORDER BY id;</htmpl-query>
+        <htmpl-foreach query="q"><htmpl-class select=".row" class="active selected" if="q(active)"></htmpl-class><htmpl-class select=".row" class="inactive" unless="q(active)"></htmpl-class><div class="row"></div></htmpl-foreach>
+        "#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<div class="row active selected"></div><div class="row inactive"></div>"#,
+    );
+}
+
+#[test]
+fn attr_scope() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<main><div><htmpl-attr select="p.a" query="params(id)" attr="id" scope="document"></htmpl-attr><htmpl-class select="p" class="inner" scope="descendants"><p></p></htmpl-class><htmpl-class select="p.b" class="up" scope="2"></htmpl-class><p class="a"></p></div><p class="a"></p><p class="b"></p></main>"#;
+    let options = EvaluateOptions::new().params(Params::new().set("id", "x".to_owned()));
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"<main><div><p class="inner"></p><p class="a" id="x"></p></div><p class="a" id="x"></p><p class="b up"></p></main>"#,
+    );
+
+    let result = evaluate_template(
+        r#"<div><htmpl-class select="p" class="x" scope="up"></htmpl-class></div>"#,
+        &conn,
+    );
+    assert_eq!(
+        *result.unwrap_err().inner(),
+        Error::InvalidParameter("htmpl-class".into(), "scope".to_owned())
+    );
+
+    // A selector that matches nothing in scope explains the default scope.
+    let err = evaluate_template(
+        r#"<div><htmpl-class select="p.b" class="x"></htmpl-class></div><p class="b"></p>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::NoMatch(
+            "htmpl-class".into(),
+            "p.b".to_owned(),
+            "siblings".to_owned()
+        )
+    );
+    assert!(
+        err.to_string().contains(r#"select="p.b" matched no elements in scope="siblings"; by default, only the element's siblings (and their descendants) are selected"#),
+        "{}",
+        err
+    );
+}
+
 #[test_log::test]
 fn attr_selector() {
     let conn = make_test_db();
@@ -281,7 +399,7 @@ fn attr_selector() {
         <div><a class="name" href="https://cceckman.com"><htmpl-insert query="q(name)"></htmpl-insert></a></div>"#;
     let result = evaluate_template(TEMPLATE, &conn).unwrap();
     // Don't depend on attribute order:
-    html_equal(
+    assert_html_eq(
         result,
         r#"
         <div class="18adfb4d-6a38-4c81-b2e8-4d59e6467c9f name"><a href="https://cceckman.com">cceckman</a></div>
@@ -309,7 +427,7 @@ fn truthy() {
         CCECKMAN_UUID
     );
     let result = evaluate_template(template, &conn).unwrap();
-    html_equal(
+    assert_html_eq(
         result,
         r#"
         cceckman (hi Charles!)
@@ -330,7 +448,7 @@ fn falsy() {
         CCECKMAN_UUID
     );
     let result = evaluate_template(template, &conn).unwrap();
-    html_equal(
+    assert_html_eq(
         result,
         r#"
         cceckman
@@ -347,5 +465,2519 @@ fn empty_is_falsy() {
         <htmpl-if false="q(name)">No one is here</htmpl-if>
         "#;
     let result = evaluate_template(TEMPLATE, &conn).unwrap();
-    html_equal(result, "No one is here");
+    assert_html_eq(result, "No one is here");
+}
+
+#[test_log::test]
+fn xml_feed() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom"><channel><atom:link href="https://example.com/feed.xml"></atom:link><htmpl-query name="q">SELECT name, uuid FROM users ORDER BY name;</htmpl-query><htmpl-foreach query="q"><item><title><htmpl-insert query="q(name)"></htmpl-insert> &amp; co.</title><link>https://example.com/<htmpl-insert query="q(name)"></htmpl-insert></link><guid isPermaLink="false"><htmpl-insert query="q(uuid)"></htmpl-insert></guid></item></htmpl-foreach></channel></rss>"#;
+    let options = EvaluateOptions::new().format(OutputFormat::Xml);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><rss version="2.0"><channel><atom:link xmlns:atom="http://www.w3.org/2005/Atom" href="https://example.com/feed.xml"/><item><title>cceckman &amp; co.</title><link>https://example.com/cceckman</link><guid isPermaLink="false">{}</guid></item><item><title>ddedkman &amp; co.</title><link>https://example.com/ddedkman</link><guid isPermaLink="false">{}</guid></item></channel></rss>"#,
+            CCECKMAN_UUID, OTHER_UUID
+        )
+    );
+}
+
+#[test_log::test]
+fn xml_default_namespace() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<feed xmlns="http://www.w3.org/2005/Atom"><htmpl-query name="q">SELECT "a &lt; b" AS title;</htmpl-query><title><htmpl-insert query="q"></htmpl-insert></title><link href="https://example.com/"/></feed>"#;
+    let options = EvaluateOptions::new().format(OutputFormat::Xml);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>a &lt; b</title><link href="https://example.com/"/></feed>"#
+    );
+}
+
+#[test_log::test]
+fn invalid_xml() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<feed><title></feed>"#;
+    let options = EvaluateOptions::new().format(OutputFormat::Xml);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap_err();
+    assert!(matches!(result, Error::XmlParse(_)), "{}", result);
+}
+
+#[test_log::test]
+fn params() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+        <htmpl-query name="q" :name="params(name)">SELECT uuid FROM users WHERE name = :name;</htmpl-query>
+        <htmpl-insert query="q"></htmpl-insert>
+        "#;
+    let options = EvaluateOptions::new().params(Params::new().set("name", "ddedkman".to_owned()));
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, OTHER_UUID);
+}
+
+#[test_log::test]
+fn missing_param() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-insert query="params(name)"></htmpl-insert>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap_err();
+    assert!(
+        matches!(
+            result.inner(),
+            Error::MissingColumn(element, _, _, _) if element == "htmpl-insert"
+        ),
+        "{}",
+        result
+    );
+}
+
+#[test]
+fn missing_template() {
+    let conn = make_test_db();
+    let mut templates = Templates::new();
+    templates.insert("index.html", "hello");
+    assert_eq!(
+        templates
+            .evaluate("index.html", &conn, &Default::default())
+            .unwrap(),
+        "hello"
+    );
+    assert_eq!(
+        templates
+            .evaluate("other.html", &conn, &Default::default())
+            .unwrap_err(),
+        Error::MissingTemplate("other.html".to_owned())
+    );
+}
+
+#[test]
+fn template_name_in_errors() {
+    let conn = make_test_db();
+    let mut templates = Templates::new();
+    templates.insert(
+        "posts/index.html",
+        r#"<p><htmpl-insert query="q"></htmpl-insert></p>"#,
+    );
+    let err = templates
+        .evaluate("posts/index.html", &conn, &Default::default())
+        .unwrap_err();
+    assert_eq!(err.template(), Some("posts/index.html"));
+    assert_eq!(err.location(), Some("p > htmpl-insert"));
+    assert_eq!(
+        *err.inner(),
+        Error::MissingQuery("htmpl-insert".into(), "q".to_owned())
+    );
+    assert!(
+        err.to_string()
+            .starts_with("in template posts/index.html: at p"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn render_metadata() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-insert query="params(name)"></htmpl-insert>"#;
+    let options = EvaluateOptions::new()
+        .params(Params::new().set("name", "cceckman".to_owned()))
+        .freshness_query("SELECT 1700000000")
+        .freshness_query(r#"SELECT "2024-01-01 00:00:00""#)
+        .freshness_query("SELECT NULL")
+        .freshness_query("SELECT id FROM users WHERE name = :name AND id < 0");
+    let output = render(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(output.output(), "cceckman");
+    assert_eq!(output.last_modified(), Some(1704067200));
+    assert_eq!(
+        output.etag(),
+        render(TEMPLATE, &conn, &options).unwrap().etag()
+    );
+
+    let other = options.params(Params::new().set("name", "ddedkman".to_owned()));
+    assert_ne!(
+        output.etag(),
+        render(TEMPLATE, &conn, &other).unwrap().etag()
+    );
+}
+
+#[test]
+fn render_without_freshness() {
+    let conn = make_test_db();
+    let output = render("hello", &conn, &Default::default()).unwrap();
+    assert_eq!(output.last_modified(), None);
+    assert!(output.etag().starts_with('"') && output.etag().ends_with('"'));
+}
+
+#[test]
+fn render_block() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<h1>Users</h1>
+<ul id="results">
+<htmpl-block name="results"><htmpl-foreach query="users"><li><htmpl-insert query="users(name)"></htmpl-insert></li></htmpl-foreach></htmpl-block>
+</ul>
+"#;
+    let page = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        page,
+        r#"
+<h1>Users</h1>
+<ul id="results">
+<li>cceckman</li><li>ddedkman</li>
+</ul>
+"#,
+    );
+
+    let block =
+        evaluate_template_with(TEMPLATE, &conn, &EvaluateOptions::new().block("results")).unwrap();
+    assert_eq!(block, "<li>cceckman</li><li>ddedkman</li>");
+}
+
+#[test]
+fn render_repeated_block() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><p><htmpl-block name="name"><htmpl-insert query="users(name)"></htmpl-insert>;</htmpl-block></p></htmpl-foreach>
+"#;
+    let block =
+        evaluate_template_with(TEMPLATE, &conn, &EvaluateOptions::new().block("name")).unwrap();
+    assert_eq!(block, "cceckman;ddedkman;");
+}
+
+#[test]
+fn missing_block() {
+    let conn = make_test_db();
+    let result = evaluate_template_with(
+        r#"<htmpl-block name="results">hi</htmpl-block>"#,
+        &conn,
+        &EvaluateOptions::new().block("other"),
+    );
+    assert_eq!(result.unwrap_err(), Error::MissingBlock("other".to_owned()));
+}
+
+#[test]
+fn htmx_attributes() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r##"
+<htmpl-query name="user">SELECT uuid, "/users/" || uuid AS url FROM users WHERE name = "cceckman";</htmpl-query>
+<div>
+<htmpl-hx select="button" :get="user(url)" target="#results" swap="outerHTML"></htmpl-hx>
+<button>More</button>
+</div>
+"##,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(
+        result,
+        format!(
+            r##"
+<div>
+
+<button hx-get="/users/{CCECKMAN_UUID}" hx-target="#results" hx-swap="outerHTML">More</button>
+</div>
+"##
+        ),
+    );
+}
+
+#[test]
+fn stream_sse() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<h1>Not streamed</h1>
+<htmpl-foreach query="users" event="user"><li><htmpl-insert query="users(name)"></htmpl-insert></li>
+</htmpl-foreach>
+"#;
+    let mut out = Vec::new();
+    stream_template(
+        TEMPLATE,
+        &conn,
+        &Default::default(),
+        StreamFormat::Sse,
+        &mut out,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "event: user\ndata: <li>cceckman</li>\n\nevent: user\ndata: <li>ddedkman</li>\n\n"
+    );
+}
+
+#[test]
+fn stream_ndjson() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><p class="user"><htmpl-query name="letters">SELECT 1 AS n UNION SELECT 2 AS n;</htmpl-query><htmpl-foreach query="letters"><htmpl-insert query="letters(n)"></htmpl-insert></htmpl-foreach>
+<htmpl-insert query="users(name)"></htmpl-insert></p></htmpl-foreach>
+"#;
+    let mut out = Vec::new();
+    stream_template(
+        TEMPLATE,
+        &conn,
+        &Default::default(),
+        StreamFormat::Ndjson,
+        &mut out,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        r#"{"html":"\u003cp class=\"user\"\u003e12\ncceckman\u003c/p\u003e"}
+{"html":"\u003cp class=\"user\"\u003e12\nddedkman\u003c/p\u003e"}
+"#
+    );
+}
+
+#[test]
+fn inline_errors() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<section><htmpl-insert query="nope"></htmpl-insert></section>
+<section><htmpl-insert query="users"></htmpl-insert></section>
+"#;
+    assert!(evaluate_template(TEMPLATE, &conn).is_err());
+    let result =
+        evaluate_template_with(TEMPLATE, &conn, &EvaluateOptions::new().inline_errors(true))
+            .unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<section><div class="htmpl-error">htmpl-insert: missing query: from element htmpl-insert, query nope is not in scope</div></section>
+<section><div class="htmpl-error">htmpl-insert: incorrect cardinality: from element htmpl-insert, query users returned 2 rows, wanted 1</div></section>
+"#,
+    );
+}
+
+#[test]
+fn partial_evaluation() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<ul><htmpl-foreach query="users"><li><htmpl-insert query="users(name)"></htmpl-insert></li></htmpl-foreach></ul>
+<htmpl-query defer name="me" :name="params(name)">SELECT uuid FROM users WHERE name = :name;</htmpl-query>
+<p><htmpl-insert defer query="me"></htmpl-insert></p>
+<htmpl-later><htmpl-insert query="users"></htmpl-insert></htmpl-later>
+"#;
+    let first =
+        evaluate_template_with(TEMPLATE, &conn, &EvaluateOptions::new().partial(true)).unwrap();
+    assert_html_eq(
+        &*first,
+        r#"
+<ul><li>cceckman</li><li>ddedkman</li></ul>
+<htmpl-query name="me" :name="params(name)">SELECT uuid FROM users WHERE name = :name;</htmpl-query>
+<p><htmpl-insert query="me"></htmpl-insert></p>
+<htmpl-later><htmpl-insert query="users"></htmpl-insert></htmpl-later>
+"#,
+    );
+
+    let second = evaluate_template_with(
+        first
+            .replace("<htmpl-later>", "")
+            .replace("</htmpl-later>", ""),
+        &conn,
+        &EvaluateOptions::new().params(Params::new().set("name", "cceckman".to_owned())),
+    );
+    // The non-deferred "users" query isn't available in the second pass.
+    assert_eq!(
+        *second.unwrap_err().inner(),
+        Error::MissingQuery("htmpl-insert".into(), "users".to_owned())
+    );
+    let second = evaluate_template_with(
+        first.replace(
+            r#"<htmpl-later><htmpl-insert query="users"></htmpl-insert></htmpl-later>"#,
+            "",
+        ),
+        &conn,
+        &EvaluateOptions::new().params(Params::new().set("name", "cceckman".to_owned())),
+    )
+    .unwrap();
+    assert_html_eq(
+        second,
+        format!(
+            r#"
+<ul><li>cceckman</li><li>ddedkman</li></ul>
+
+<p>{CCECKMAN_UUID}</p>
+"#
+        ),
+    );
+}
+
+#[test]
+fn custom_element() {
+    let conn = make_test_db();
+    // <user-card user="q"> looks up the user's name, and wraps its children in a card.
+    let card = |element: ElementRef, scope: &mut Scope, output: &mut NodeMut<Node>| {
+        let user = element
+            .attr("user")
+            .ok_or(Error::MissingAttr("user-card".into(), "user".into()))?;
+        let name = match &*scope.get_single(format!("{}(name)", user))? {
+            Value::Text(name) => name.clone(),
+            _ => return Err(Error::TemplateEval("user name is not text".to_owned())),
+        };
+        let mut div = output.append(Node::Element(scraper::node::Element::new(
+            QualName::new(None, ns!(html), local_name!("div")),
+            vec![],
+        )));
+        div.append(Node::Text(scraper::node::Text {
+            text: format!("{}: ", name).into(),
+        }));
+        evaluate_children(element, scope, &mut div)
+    };
+    let options = EvaluateOptions::new().element("user-card", card);
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT id, name FROM users ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><user-card user="users"><htmpl-insert query="users(id)"></htmpl-insert></user-card></htmpl-foreach>
+"#;
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, "<div>cceckman: 1</div><div>ddedkman: 2</div>");
+
+    let result = evaluate_template_with(r#"<user-card></user-card>"#, &conn, &options);
+    assert_eq!(
+        *result.unwrap_err().inner(),
+        Error::MissingAttr("user-card".into(), "user".into())
+    );
+}
+
+#[test]
+fn query_hooks() {
+    let conn = make_test_db();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let before_log = log.clone();
+    let after_log = log.clone();
+    let options = EvaluateOptions::new()
+        .params(Params::new().set("name", "cceckman".to_owned()))
+        .before_query(move |info| {
+            before_log.lock().unwrap().push(format!(
+                "before {}: {} {:?}",
+                info.name, info.sql, info.params
+            ))
+        })
+        .after_query(move |info, stats| {
+            after_log
+                .lock()
+                .unwrap()
+                .push(format!("after {}: {:?} rows", info.name, stats.rows))
+        });
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users;</htmpl-query>
+<htmpl-query name="me" :name="params(name)">SELECT id FROM users WHERE name = :name;</htmpl-query>
+<htmpl-query name="bad">SELECT nope FROM users;</htmpl-query>
+"#;
+    let result = evaluate_template_with(TEMPLATE, &conn, &options);
+    assert!(matches!(result.unwrap_err().inner(), Error::Sql(_, _)));
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            "before users: SELECT name FROM users; []".to_owned(),
+            "after users: Some(2) rows".to_owned(),
+            r#"before me: SELECT id FROM users WHERE name = :name; [(":name", Text("cceckman"))]"#
+                .to_owned(),
+            "after me: Some(1) rows".to_owned(),
+        ]
+    );
+}
+
+#[test]
+fn on_query() {
+    let conn = make_test_db();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let events = log.clone();
+    let options = EvaluateOptions::new().on_query(move |event| {
+        events.lock().unwrap().push(format!(
+            "{}: {} -> {:?} {:?}",
+            event.name,
+            event.sql,
+            event.rows,
+            event.error.map(|e| e.to_string())
+        ))
+    });
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name FROM users;</htmpl-query>
+<htmpl-query name="bad">SELECT json_extract('nope', '$');</htmpl-query>
+"#;
+    let result = evaluate_template_with(TEMPLATE, &conn, &options);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::Sql);
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            "users: SELECT name FROM users; -> Some(2) None".to_owned(),
+            r#"bad: SELECT json_extract('nope', '$'); -> None Some("malformed JSON")"#.to_owned()
+        ]
+    );
+}
+
+#[test]
+fn redact_params() {
+    let conn = make_test_db();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let before_log = log.clone();
+    let options = EvaluateOptions::new()
+        .params(Params::new().set("email", "me@example.com".to_owned()))
+        .redact_params(true)
+        .before_query(move |info| {
+            before_log
+                .lock()
+                .unwrap()
+                .push(format!("{:?}", info.params))
+        })
+        .sql_functions(|functions| {
+            functions.create_scalar_function("check_email", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
+                let email: String = ctx.get(0)?;
+                Err::<bool, _>(rusqlite::Error::UserFunctionError(
+                    format!("unknown user {}", email).into(),
+                ))
+            })
+        });
+    const TEMPLATE: &str = r#"<htmpl-query name="user" :email="params(email)">SELECT check_email(:email);</htmpl-query>"#;
+    let err = evaluate_template_with(TEMPLATE, &conn, &options).unwrap_err();
+    assert!(matches!(err.inner(), Error::Sql(_, _)));
+    assert!(!err.to_string().contains("me@example.com"), "{}", err);
+    assert!(
+        err.to_string().contains("unknown user [redacted]"),
+        "{}",
+        err
+    );
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![r#"[(":email", Text("[redacted]"))]"#.to_owned()]
+    );
+
+    let err = evaluate_template_with(TEMPLATE, &conn, &options.redact_params(false)).unwrap_err();
+    assert!(err.to_string().contains("me@example.com"), "{}", err);
+}
+
+#[test]
+fn rewrite_query() {
+    let conn = make_test_db();
+    let options = EvaluateOptions::new()
+        .rewrite_query(|_, sql| {
+            format!(
+                "SELECT * FROM ({}) WHERE name = :who",
+                sql.trim_end_matches(';')
+            )
+        })
+        .params(Params::new().set("who", "ddedkman".to_owned()));
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users" :min="params(min)">SELECT name, uuid FROM users WHERE id >= :min;</htmpl-query>
+<htmpl-foreach query="users"><htmpl-insert query="users(uuid)"></htmpl-insert></htmpl-foreach>
+"#;
+    let result = evaluate_template_with(
+        TEMPLATE,
+        &conn,
+        &options.clone().params(
+            Params::new()
+                .set("who", "ddedkman".to_owned())
+                .set("min", 0),
+        ),
+    )
+    .unwrap();
+    assert_eq!(result.trim(), OTHER_UUID);
+
+    // Parameters from the template are still bound from attributes only.
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="users">SELECT name FROM users WHERE id >= :min;</htmpl-query>"#,
+        &conn,
+        &options.params(
+            Params::new()
+                .set("who", "ddedkman".to_owned())
+                .set("min", 0),
+        ),
+    );
+    assert_eq!(
+        *result.unwrap_err().inner(),
+        Error::MissingParameter("htmpl-query".into(), ":min".to_owned())
+    );
+}
+
+#[test]
+fn transform_row() {
+    let conn = make_test_db();
+    let options = EvaluateOptions::new().transform_row(|query, row| {
+        if query == "users" {
+            if let Some(Value::Text(name)) = row.get("name") {
+                let shout = name.to_uppercase();
+                row.insert("shout".to_owned(), Value::Text(shout));
+            }
+            row.remove("uuid");
+        }
+        Ok(())
+    });
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name, uuid FROM users ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><p><htmpl-insert query="users(shout)"></htmpl-insert></p></htmpl-foreach>
+"#;
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>CCECKMAN</p><p>DDEDKMAN</p>");
+
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="users">SELECT name, uuid FROM users WHERE id = 1;</htmpl-query><htmpl-insert query="users(uuid)"></htmpl-insert>"#,
+        &conn,
+        &options,
+    );
+    assert!(matches!(
+        result.unwrap_err().inner(),
+        Error::MissingColumn(element, _, _, _) if element == "htmpl-insert"
+    ));
+
+    let failing = EvaluateOptions::new()
+        .transform_row(|_, _| Err(Error::TemplateEval("could not decrypt".to_owned())));
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="users">SELECT name FROM users;</htmpl-query>"#,
+        &conn,
+        &failing,
+    );
+    assert_eq!(
+        *result.unwrap_err().inner(),
+        Error::TemplateEval("could not decrypt".to_owned())
+    );
+}
+
+#[test]
+fn sql_functions() {
+    let conn = make_test_db();
+    let options = EvaluateOptions::new()
+        .sql_functions(|functions| {
+            functions.create_scalar_function(
+                "initials",
+                1,
+                FunctionFlags::SQLITE_DETERMINISTIC | FunctionFlags::SQLITE_UTF8,
+                |ctx| {
+                    let name: String = ctx.get(0)?;
+                    Ok(name.chars().take(2).collect::<String>())
+                },
+            )
+        })
+        .freshness_query("SELECT CAST(initials('1700000000') AS INTEGER)");
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT initials(name) AS i FROM users ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><p><htmpl-insert query="users(i)"></htmpl-insert></p></htmpl-foreach>
+"#;
+    let output = render(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(output.output(), "<p>cc</p><p>dd</p>");
+    assert_eq!(output.last_modified(), Some(17));
+
+    let result = evaluate_template(TEMPLATE, &make_test_db());
+    assert!(matches!(result.unwrap_err().inner(), Error::Sql(_, _)));
+}
+
+#[test]
+fn highlight_snippets() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(
+        r#"
+CREATE VIRTUAL TABLE posts USING fts5(title, body);
+INSERT INTO posts (title, body) VALUES ("First", "a <b>bold</b> claim about rust and rusqlite");
+"#,
+    )
+    .unwrap();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="q">SELECT highlight(posts, 1, '&lt;mark>', '&lt;/mark>') AS body FROM posts WHERE posts MATCH 'rust';</htmpl-query>
+<p><htmpl-insert query="q(body)" highlight></htmpl-insert></p>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        "<p>a &lt;b&gt;bold&lt;/b&gt; claim about <mark>rust</mark> and rusqlite</p>",
+    );
+
+    const DELIMITED: &str = r#"
+<htmpl-query name="q">SELECT snippet(posts, 1, char(2), char(3), '...', 3) AS body FROM posts WHERE posts MATCH 'claim';</htmpl-query>
+<p><htmpl-insert query="q(body)" highlight="b"></htmpl-insert></p>
+"#;
+    let options = EvaluateOptions::new().highlight_delimiters("\u{2}", "\u{3}");
+    let result = evaluate_template_with(DELIMITED, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>...b&gt; <b>claim</b> about...</p>");
+}
+
+#[test]
+fn markdown_insert() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE posts (body TEXT);")
+        .unwrap();
+    conn.execute(
+        "INSERT INTO posts (body) VALUES (?1)",
+        ["Hi *there*, <script>alert(1)</script>\n\n- [home](/)\n- [evil](javascript:x)"],
+    )
+    .unwrap();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="post">SELECT HTMPL_MARKDOWN(body) AS body FROM posts;</htmpl-query>
+<article><htmpl-insert query="post(body)" html></htmpl-insert></article>
+<pre><htmpl-insert query="post(body)"></htmpl-insert></pre>
+"#;
+    let options = EvaluateOptions::new().sql_functions(crate::builtin_functions);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<article><p>Hi <em>there</em>, &lt;script&gt;alert(1)&lt;/script&gt;</p>
+<ul>
+<li><a href="/">home</a></li>
+<li>evil</li>
+</ul>
+</article>
+<pre>&lt;p&gt;Hi &lt;em&gt;there&lt;/em&gt;, &amp;lt;script&amp;gt;alert(1)&amp;lt;/script&amp;gt;&lt;/p&gt;
+&lt;ul&gt;
+&lt;li&gt;&lt;a href="/"&gt;home&lt;/a&gt;&lt;/li&gt;
+&lt;li&gt;evil&lt;/li&gt;
+&lt;/ul&gt;
+</pre>
+"#,
+    );
+}
+
+#[test]
+fn json_columns() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="post">SELECT '{"title": "Hello", "tags": ["a", "b"], "authors": [{"name": "cceckman"}]}' AS meta;</htmpl-query>
+<h1><htmpl-insert query="post(meta->>'$.title')"></htmpl-insert></h1>
+<ul><htmpl-foreach query="post(meta->'$.tags')" as="tag"><li><htmpl-insert query="tag(value)"></htmpl-insert></li></htmpl-foreach></ul>
+<htmpl-foreach query="post(meta->'authors')"><p><htmpl-insert query="meta(key)"></htmpl-insert>: <htmpl-insert query="meta(value->>'name')"></htmpl-insert></p></htmpl-foreach>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<h1>Hello</h1>
+<ul><li>a</li><li>b</li></ul>
+<p>0: cceckman</p>
+"#,
+    );
+
+    let result = evaluate_template(
+        r#"<htmpl-query name="q">SELECT 'nope' AS meta;</htmpl-query><htmpl-insert query="q(meta->>'$.title')"></htmpl-insert>"#,
+        &conn,
+    );
+    assert!(matches!(result.unwrap_err().inner(), Error::Sql(_, _)));
+}
+
+#[test]
+fn json_element() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT id, name, '&lt;/script>' AS evil FROM users ORDER BY id;</htmpl-query>
+<htmpl-json query="users" id="users"></htmpl-json>
+<htmpl-foreach query="users"><htmpl-json query="users(name)" class="name"></htmpl-json></htmpl-foreach>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<script type="application/json" id="users">[{"id":1,"name":"cceckman","evil":"\u003c/script\u003e"},{"id":2,"name":"ddedkman","evil":"\u003c/script\u003e"}]</script>
+<script type="application/json" class="name">"cceckman"</script><script type="application/json" class="name">"ddedkman"</script>
+"#,
+    );
+}
+
+#[test]
+fn dataset() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT id, name, uuid AS userId, NULL AS missing FROM users ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><htmpl-dataset select="li" query="users"></htmpl-dataset><li></li></htmpl-foreach>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        format!(
+            r#"
+<li data-id="1" data-name="cceckman" data-userid="{CCECKMAN_UUID}"></li><li data-id="2" data-name="ddedkman" data-userid="{OTHER_UUID}"></li>
+"#
+        ),
+    );
+
+    // Attributes are added in the order the query selects the columns.
+    let result = evaluate_template(
+        r#"<htmpl-query name="u">SELECT 'b' AS zeta, 'a' AS alpha;</htmpl-query><htmpl-dataset select="p" query="u"></htmpl-dataset><p></p>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_eq!(result, r#"<p data-zeta="b" data-alpha="a"></p>"#);
+
+    let result = evaluate_template(
+        r#"<htmpl-query name="users">SELECT id FROM users;</htmpl-query><htmpl-dataset select="li" query="users"></htmpl-dataset><li></li>"#,
+        &conn,
+    );
+    assert_eq!(
+        *result.unwrap_err().inner(),
+        Error::Cardinality("htmpl-dataset".into(), "users".to_owned(), 2, 1)
+    );
+}
+
+#[test]
+fn table() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT name, id, NULL AS note FROM users ORDER BY id;</htmpl-query>
+<htmpl-table query="users" class="admin"></htmpl-table>
+<htmpl-table query="users" columns="id, name" labels="ID, Name"></htmpl-table>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<table class="admin"><thead><tr><th>name</th><th>id</th><th>note</th></tr></thead><tbody><tr><td>cceckman</td><td>1</td><td></td></tr><tr><td>ddedkman</td><td>2</td><td></td></tr></tbody></table>
+<table><thead><tr><th>ID</th><th>Name</th></tr></thead><tbody><tr><td>1</td><td>cceckman</td></tr><tr><td>2</td><td>ddedkman</td></tr></tbody></table>
+"#,
+    );
+
+    let result = evaluate_template(
+        r#"<htmpl-query name="users">SELECT id FROM users;</htmpl-query><htmpl-table query="users" columns="id,name"></htmpl-table>"#,
+        &conn,
+    );
+    assert!(matches!(
+        result.unwrap_err().inner(),
+        Error::MissingColumn(element, _, _, column) if element == "htmpl-table" && column == "name"
+    ));
+
+    // Only the first row is checked up front; later rows without the column are errors too.
+    let options = EvaluateOptions::new().transform_row(|_, row| {
+        if row.get("id") == Some(&Value::Integer(2)) {
+            row.remove("name");
+        }
+        Ok(())
+    });
+    for template in [
+        r#"<htmpl-table query="users" columns="id,name"></htmpl-table>"#,
+        r#"<htmpl-options query="users" value="id" label="name"></htmpl-options>"#,
+    ] {
+        let template = format!(
+            r#"<htmpl-query name="users">SELECT id, name FROM users ORDER BY id;</htmpl-query>{}"#,
+            template
+        );
+        let result = evaluate_template_with(&template, &conn, &options);
+        assert!(matches!(
+            result.unwrap_err().inner(),
+            Error::MissingColumn(_, _, _, column) if column == "name"
+        ));
+    }
+}
+
+#[test]
+fn options() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT id, name FROM users ORDER BY id;</htmpl-query>
+<htmpl-options query="users" value="id" label="name" selected="params(user)" name="user"><option value="">Nobody</option></htmpl-options>
+"#;
+    let options = EvaluateOptions::new().params(Params::new().set("user", "2".to_owned()));
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<select name="user"><option value="">Nobody</option><option value="1">cceckman</option><option value="2" selected="">ddedkman</option></select>
+"#,
+    );
+}
+
+#[test]
+fn form() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="user">SELECT name, 1 AS admin, 'b' AS team, 'red' AS color, 'hunter2' AS password, NULL AS note FROM users WHERE id = 1;</htmpl-query>
+<htmpl-form select="form" query="user"></htmpl-form>
+<form>
+<input name="name" value="placeholder">
+<input type="checkbox" name="admin">
+<input type="radio" name="team" value="a"><input type="radio" name="team" value="b">
+<select name="color"><option>blue</option><option>red</option></select>
+<input type="password" name="password">
+<input name="note" value="unchanged">
+<input name="other">
+</form>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<form>
+<input name="name" value="cceckman">
+<input type="checkbox" name="admin" checked="">
+<input type="radio" name="team" value="a"><input type="radio" name="team" value="b" checked="">
+<select name="color"><option>blue</option><option selected="">red</option></select>
+<input type="password" name="password">
+<input name="note" value="unchanged">
+<input name="other">
+</form>
+"#,
+    );
+
+    // Textareas get the column as their (escaped) text.
+    const TEXTAREA: &str = r#"
+<htmpl-query name="post">SELECT 'Hi &amp; &lt;/textarea>&lt;script>x()&lt;/script>' AS body, NULL AS note;</htmpl-query>
+<htmpl-form select="form" query="post"></htmpl-form>
+<form><textarea name="body">draft</textarea><textarea name="note">unchanged</textarea></form>
+"#;
+    let result = evaluate_template(TEXTAREA, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<form><textarea name="body">Hi &amp; &lt;/textarea&gt;&lt;script&gt;x()&lt;/script&gt;</textarea><textarea name="note">unchanged</textarea></form>"#,
+    );
+}
+
+#[test]
+fn csrf() {
+    let conn = make_test_db();
+    const TEMPLATE: &str =
+        r#"<form><htmpl-csrf></htmpl-csrf></form><htmpl-csrf meta name="x-csrf"></htmpl-csrf>"#;
+    let options = EvaluateOptions::new().csrf_token(|| "abc123".to_owned());
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"<form><input type="hidden" name="csrf_token" value="abc123"></form><meta name="x-csrf" content="abc123">"#,
+    );
+
+    let result = evaluate_template(TEMPLATE, &conn);
+    assert_eq!(
+        *result.unwrap_err().inner(),
+        Error::MissingOption("htmpl-csrf".into(), "csrf_token".into())
+    );
+}
+
+#[test]
+fn nonce() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<script>run()</script><style>p {}</style><htmpl-json query="params"></htmpl-json>
+<p><htmpl-insert query="htmpl(nonce)"></htmpl-insert></p>
+"#;
+    let options = EvaluateOptions::new().nonce("r4nd0m");
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<script nonce="r4nd0m">run()</script><style nonce="r4nd0m">p {}</style><script type="application/json" nonce="r4nd0m">[{}]</script>
+<p>r4nd0m</p>
+"#,
+    );
+
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<script>run()</script><style>p {}</style><script type="application/json">[{}]</script>
+<p>null</p>
+"#,
+    );
+}
+
+#[test]
+fn integrity() {
+    let conn = make_test_db();
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("js")).unwrap();
+    std::fs::write(root.path().join("js/app.js"), "alert(1)").unwrap();
+    const TEMPLATE: &str = r#"
+<script src="/js/app.js"></script><script src="https://cdn.example/lib.js"></script>
+<link rel="icon" href="/js/app.js">
+"#;
+    let options = EvaluateOptions::new().asset_root(root.path());
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<script src="/js/app.js" integrity="sha384-HT2E9NfWiuQ/w1PRai+hTyqW16NIoCGA/m8VQDUopfAtcz6YQjtsMmQd5uRbVDpW"></script><script src="https://cdn.example/lib.js"></script>
+<link rel="icon" href="/js/app.js">
+"#,
+    );
+
+    let result = evaluate_template_with(
+        r#"<link rel="stylesheet" href="missing.css">"#,
+        &conn,
+        &options,
+    );
+    assert!(matches!(result.unwrap_err().inner(), Error::Asset(..)));
+}
+
+#[test]
+fn url_attrs() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="link">SELECT 'javascript:alert(1)' AS evil, '/search?q=a b' AS search;</htmpl-query>
+<htmpl-attr select="a.evil" attr="href" query="link(evil)"></htmpl-attr>
+<htmpl-attr select="a.evil" attr="title" query="link(evil)"></htmpl-attr>
+<htmpl-attr select="a.search" attr="href" query="link(search)"></htmpl-attr>
+<a class="evil"></a><a class="search"></a>
+"#;
+    let options = EvaluateOptions::new().reject_unsafe_urls(true);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r#"
+<a class="evil" href="about:invalid#htmpl" title="javascript:alert(1)"></a><a class="search" href="/search?q=a%20b"></a>
+"#,
+    );
+}
+
+#[test]
+fn style() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="theme">SELECT '#336699' AS primary_color, '1.5rem' AS gap, 'red;}&lt;/style>' AS evil, NULL AS unset;</htmpl-query>
+<htmpl-style query="theme"></htmpl-style>
+<htmpl-style query="theme" select="div"></htmpl-style><div style="color: red"></div>
+"#;
+    let options = EvaluateOptions::new().nonce("n");
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert!(result.contains(
+        r#"<style nonce="n">:root { --primary-color: #336699; --gap: 1.5rem; --evil: red\3b \7d \3c /style\3e ; }</style>"#
+    ), "{}", result);
+    assert!(result.contains(
+        r#"<div style="--primary-color: #336699; --gap: 1.5rem; --evil: red\3b \7d \3c /style\3e ;"></div>"#
+    ), "{}", result);
+}
+
+#[test]
+fn scoped() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<p class="card"></p><htmpl-scoped suffix="c1"><style>.card > .title { color: red; }</style><div class="card"><htmpl-foreach query="users"><p class="title"></p></htmpl-foreach></div></htmpl-scoped>"#;
+    let result = evaluate_template(
+        format!(r#"<htmpl-query name="users">SELECT id FROM users;</htmpl-query>{TEMPLATE}"#),
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(
+        result,
+        r#"<p class="card"></p><style>.card-c1 > .title-c1 { color: red; }</style><div class="card-c1"><p class="title-c1"></p><p class="title-c1"></p></div>"#,
+    );
+
+    // By default, the suffix is stable for the same source.
+    let template = r#"<htmpl-scoped><p class="x"></p></htmpl-scoped>"#;
+    let first = evaluate_template(template, &conn).unwrap();
+    assert_eq!(first, evaluate_template(template, &conn).unwrap());
+    assert!(first.starts_with(r#"<p class="x-"#), "{}", first);
+}
+
+#[test]
+fn head() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<title>Site</title><meta name="description" content="A site"><main><htmpl-query name="user">SELECT name FROM users WHERE id = 1;</htmpl-query><htmpl-title><htmpl-insert query="user(name)"></htmpl-insert> - Site</htmpl-title><htmpl-meta name="description" query="user(name)"></htmpl-meta><htmpl-meta property="og:type" content="profile"></htmpl-meta><p>Hi</p></main>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<meta property="og:type" content="profile"><title>cceckman - Site</title><meta name="description" content="cceckman"><main><p>Hi</p></main>"#,
+    );
+
+    let result = evaluate_template(r#"<p>Hi</p><htmpl-title>Page</htmpl-title>"#, &conn).unwrap();
+    assert_html_eq(result, r#"<title>Page</title><p>Hi</p>"#);
+}
+
+#[test]
+fn open_graph() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="page">SELECT name AS title, '/users/' || id AS url, '/img/' || id || '.png' AS image, NULL AS description FROM users WHERE id = 1;</htmpl-query><htmpl-og query="page" base="https://example.com/"></htmpl-og><p>Hi</p>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<meta property="og:title" content="cceckman"><meta property="og:url" content="https://example.com/users/1"><meta property="og:image" content="https://example.com/img/1.png"><meta property="og:type" content="website"><meta name="twitter:title" content="cceckman"><meta name="twitter:image" content="https://example.com/img/1.png"><meta name="twitter:card" content="summary_large_image"><p>Hi</p>"#,
+    );
+}
+
+#[test]
+fn heading_ids() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<h1>Users</h1><p id="intro"></p><h2>Intro</h2><h2 id="custom">Intro</h2><htmpl-foreach query="users"><h3><htmpl-insert query="users(name)"></htmpl-insert>'s <em>Page</em></h3></htmpl-foreach><h3>cceckman's page</h3>"#;
+    let options = EvaluateOptions::new().heading_ids(true);
+    let result = evaluate_template_with(
+        format!(r#"<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>{TEMPLATE}"#),
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_html_eq(
+        result,
+        r#"<h1 id="users">Users</h1><p id="intro"></p><h2 id="intro-1">Intro</h2><h2 id="custom">Intro</h2><h3 id="cceckman-s-page">cceckman's <em>Page</em></h3><h3 id="ddedkman-s-page">ddedkman's <em>Page</em></h3><h3 id="cceckman-s-page-1">cceckman's page</h3>"#,
+    );
+}
+
+#[test]
+fn base_url() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r##"<htmpl-query name="users">SELECT id FROM users WHERE id = 1;</htmpl-query><a href="/"></a><htmpl-attr select="a.user" attr="href" query="users(id)"></htmpl-attr><a class="user" href="unset"></a><img src="img/a.png"><a href="#top"></a><a href="https://other.example/x"></a>"##;
+    let options = EvaluateOptions::new().base_url("/blog/");
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r##"<a href="/blog/"></a><a class="user" href="1"></a><img src="img/a.png"><a href="#top"></a><a href="https://other.example/x"></a>"##,
+    );
+    let options = EvaluateOptions::new().base_url("https://example.com/blog/");
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        r##"<a href="https://example.com/blog/"></a><a class="user" href="https://example.com/blog/1"></a><img src="https://example.com/blog/img/a.png"><a href="#top"></a><a href="https://other.example/x"></a>"##,
+    );
+}
+
+#[test]
+fn post_process() {
+    let conn = make_test_db();
+    let images = scraper::Selector::parse("img").unwrap();
+    let options = EvaluateOptions::new()
+        .heading_ids(true)
+        .post_process(move |html| {
+            let ids: Vec<_> = html.select(&images).map(|e| e.id()).collect();
+            for id in ids {
+                if let Node::Element(img) = html.tree.get_mut(id).unwrap().value() {
+                    let loading = QualName::new(None, ns!(), local_name!("loading"));
+                    img.attrs.insert(loading, "lazy".into());
+                }
+            }
+        })
+        .post_process(|html| {
+            let heading = scraper::Selector::parse("h1").unwrap();
+            let id = html
+                .select(&heading)
+                .next()
+                .unwrap()
+                .attr("id")
+                .map(str::to_owned);
+            assert_eq!(id.as_deref(), Some("users"));
+        });
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="users">SELECT id FROM users;</htmpl-query><h1>Users</h1><htmpl-foreach query="users"><img src="/u.png"></htmpl-foreach>"#,
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_html_eq(
+        result,
+        r#"<h1 id="users">Users</h1><img src="/u.png" loading="lazy"><img src="/u.png" loading="lazy">"#,
+    );
+}
+
+#[test]
+fn strip_comments() {
+    let conn = make_test_db();
+    const TEMPLATE: &str =
+        r#"<!-- TODO: style --><p>Hi<!-- internal --></p><!--[if IE]><p>Upgrade</p><![endif]-->"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(result, TEMPLATE);
+    let options = EvaluateOptions::new().comments(Comments::Strip);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>Hi</p>");
+    let options = EvaluateOptions::new().comments(Comments::KeepConditional);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>Hi</p><!--[if IE]><p>Upgrade</p><![endif]-->");
+}
+
+#[test]
+fn document() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<!-- generated --><!DOCTYPE html><!-- page --><html lang="en"><head><title>Users</title></head><body><htmpl-query name="users">SELECT name FROM users WHERE id = 1;</htmpl-query><p><htmpl-insert query="users(name)"></htmpl-insert></p></body></html><!-- end -->"#;
+    let options = EvaluateOptions::new().document(true);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r#"<!-- generated --><!DOCTYPE html><!-- page --><html lang="en"><head><title>Users</title></head><body><p>cceckman</p></body></html><!-- end -->"#
+    );
+}
+
+#[test]
+fn pretty() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query><div class="users"><h1>Users</h1>
+        <ul><htmpl-foreach query="users">
+            <li><a href="/"> <htmpl-insert query="users(name)"></htmpl-insert></a>, a <em>user</em> </li>
+        </htmpl-foreach></ul><pre>  keep
+  this</pre>
+    <script>if (a < b) { go(); }</script></div><p>Done</p>"#;
+    let options = EvaluateOptions::new().formatting(Formatting::Pretty);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r#"<div class="users">
+  <h1>Users</h1>
+  <ul>
+    <li><a href="/"> cceckman</a>, a <em>user</em></li>
+    <li><a href="/"> ddedkman</a>, a <em>user</em></li>
+  </ul>
+  <pre>  keep
+  this</pre>
+  <script>if (a < b) { go(); }</script>
+</div>
+<p>Done</p>
+"#
+    );
+}
+
+#[test]
+fn minified() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query>
+<div class="users list">
+    <h1 id="users">  Users   list </h1>
+    <ul>
+        <htmpl-foreach query="users">
+        <li><a href="/"> <htmpl-insert query="users(name)"></htmpl-insert></a>,   a <em>user</em> </li>
+        </htmpl-foreach>
+    </ul>
+    <select name="who"><option value="">Anyone</option> <option selected="">Me</option></select>
+    <pre>  keep
+  this</pre>
+</div>"#;
+    let options = EvaluateOptions::new().formatting(Formatting::Minified);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r#"<div class="users list"><h1 id=users>Users list</h1><ul><li><a href=/> cceckman</a>, a <em>user</em><li><a href=/> ddedkman</a>, a <em>user</em></ul><select name=who><option value>Anyone<option selected>Me</select><pre>  keep
+  this</pre></div>"#
+    );
+    // The minified output parses to the same tree, modulo whitespace.
+    let unminified = evaluate_template(TEMPLATE, &conn).unwrap();
+    let options = EvaluateOptions::new().formatting(Formatting::Minified);
+    assert_eq!(
+        evaluate_template_with(&result, &conn, &options).unwrap(),
+        evaluate_template_with(&unminified, &conn, &options).unwrap()
+    );
+}
+
+#[test]
+fn xhtml() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r##"<htmpl-query name="users">SELECT name FROM users WHERE id = 1;</htmpl-query><p class=greeting>Hi, <htmpl-insert query="users(name)"></htmpl-insert> &amp; co.<br></p><input disabled><div></div><svg viewBox="0 0 1 1"><use xlink:href="#a"></use></svg><script>if (a < b) {}</script>"##;
+    let options = EvaluateOptions::new().format(OutputFormat::Xhtml);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r##"<p xmlns="http://www.w3.org/1999/xhtml" class="greeting">Hi, cceckman &amp; co.<br/></p><input xmlns="http://www.w3.org/1999/xhtml" disabled=""/><div xmlns="http://www.w3.org/1999/xhtml"></div><svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 1 1"><use xmlns:xlink="http://www.w3.org/1999/xlink" xlink:href="#a"></use></svg><script xmlns="http://www.w3.org/1999/xhtml"><![CDATA[if (a < b) {}]]></script>"##
+    );
+    // The output is well-formed XML.
+    let options = EvaluateOptions::new().format(OutputFormat::Xml);
+    evaluate_template_with(format!("<root>{result}</root>"), &conn, &options).unwrap();
+}
+
+#[test]
+fn foreign_attrs() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r##"<htmpl-query name="users">SELECT id, name FROM users WHERE id = 1;</htmpl-query><htmpl-attr select="use" attr="xlink:href" query="users(name)"></htmpl-attr><htmpl-attr select="svg" attr="viewbox" query="users(id)"></htmpl-attr><htmpl-attr select="mi" attr="definitionURL" query="users(name)"></htmpl-attr><svg viewBox="0 0 10 10"><use xlink:href="#x"></use></svg><math><mi>x</mi></math>"##;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_eq!(
+        result,
+        r#"<svg viewBox="1"><use xlink:href="cceckman"></use></svg><math><mi definitionURL="cceckman">x</mi></math>"#
+    );
+    let options = EvaluateOptions::new().format(OutputFormat::Xhtml);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="1"><use xmlns:xlink="http://www.w3.org/1999/xlink" xlink:href="cceckman"></use></svg><math xmlns="http://www.w3.org/1998/Math/MathML"><mi definitionURL="cceckman">x</mi></math>"#
+    );
+}
+
+#[test]
+fn escaping() {
+    let conn = make_test_db();
+    const TEMPLATE: &str =
+        r#"<p title="Café &quot;Ünï&quot;">It's "café" — <script>let s = "é";</script></p>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_eq!(
+        result,
+        r#"<p title="Café &quot;Ünï&quot;">It's "café" — <script>let s = "é";</script></p>"#
+    );
+    let options = EvaluateOptions::new().ascii_only(true).escape_quotes(true);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        r#"<p title="Caf&#xE9; &quot;&#xDC;n&#xEF;&quot;">It&#39;s &quot;caf&#xE9;&quot; &#x2014; <script>let s = "é";</script></p>"#
+    );
+}
+
+#[test]
+fn parse_settings() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT name FROM users WHERE id = 1;</htmpl-query><noscript><htmpl-insert query="users(name)"></htmpl-insert></noscript>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<noscript><htmpl-insert query="users(name)"></htmpl-insert></noscript>"#,
+    );
+    let options = EvaluateOptions::new().scripting(false);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, r#"<noscript>cceckman</noscript>"#);
+
+    let options = EvaluateOptions::new().max_template_len(16);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap_err();
+    assert_eq!(
+        result,
+        Error::Limit("template length".into(), TEMPLATE.len(), 16)
+    );
+
+    // The fragment is within an <html> element.
+    const NESTED: &str = "<div><div><div></div></div></div>";
+    let options = EvaluateOptions::new().max_depth(4);
+    evaluate_template_with(NESTED, &conn, &options).unwrap();
+    let options = EvaluateOptions::new().max_depth(3);
+    let result = evaluate_template_with(NESTED, &conn, &options).unwrap_err();
+    assert_eq!(result, Error::Limit("template depth".into(), 4, 3));
+}
+
+#[test]
+fn lenient_parse() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = "<p>One</p>\n<p>Two</span></p>";
+    let Error::HtmlParse(message) = evaluate_template(TEMPLATE, &conn).unwrap_err() else {
+        panic!("expected a parse error");
+    };
+    assert!(message.starts_with("line 2: "), "{}", message);
+    let options = EvaluateOptions::new().lenient(true);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>One</p>\n<p>Two</p>");
+
+    let output = render(TEMPLATE, &conn, &options).unwrap();
+    let [diagnostic] = output.diagnostics() else {
+        panic!("expected one diagnostic: {:?}", output.diagnostics());
+    };
+    assert_eq!(diagnostic.rule, "parse");
+    assert!(
+        diagnostic
+            .message
+            .starts_with("recovered from error in template: line 2: "),
+        "{}",
+        diagnostic
+    );
+}
+
+#[test]
+fn to_dom() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query><ul><htmpl-foreach query="users"><li><htmpl-insert query="users(name)"></htmpl-insert></li></htmpl-foreach></ul>"#;
+    let dom = evaluate_to_dom(TEMPLATE, &conn).unwrap();
+    let items = scraper::Selector::parse("ul > li").unwrap();
+    let names: Vec<String> = dom.select(&items).map(|e| e.text().collect()).collect();
+    assert_eq!(names, ["cceckman", "ddedkman"]);
+}
+
+#[test]
+fn parsed() {
+    let conn = make_test_db();
+    let template = Html::parse_fragment(
+        r#"<htmpl-query name="users">SELECT name FROM users WHERE id = 1;</htmpl-query><p><htmpl-insert query="users(name)"></htmpl-insert></p>"#,
+    );
+    let result = evaluate_parsed(&template, &conn).unwrap();
+    assert_html_eq(result, "<p>cceckman</p>");
+    // The parsed template can be evaluated again.
+    let result = evaluate_parsed(&template, &conn).unwrap();
+    assert_html_eq(result, "<p>cceckman</p>");
+}
+
+#[test]
+fn no_root_element() {
+    let conn = make_test_db();
+    // A hook can leave the output without the fragment's root element.
+    let options = EvaluateOptions::new().post_process(|html| {
+        let root: Vec<_> = html.tree.root().children().map(|n| n.id()).collect();
+        for id in root {
+            html.tree.get_mut(id).unwrap().detach();
+        }
+    });
+    let result = evaluate_template_with("<p>Hi</p>", &conn, &options).unwrap();
+    assert_eq!(result, "");
+}
+
+#[test]
+fn error_location() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT id, name, id > 1 AS later FROM users ORDER BY id;</htmpl-query>
+<div id="main">
+    <h1>Users</h1>
+    <ul class="users list">
+    <htmpl-foreach query="users">
+        <li><htmpl-if true="users(later)"><htmpl-insert query="users(nope)"></htmpl-insert></htmpl-if></li>
+    </htmpl-foreach>
+    </ul>
+</div>
+"#;
+    let err = evaluate_template(TEMPLATE, &conn).unwrap_err();
+    assert_eq!(
+        err.location(),
+        Some(
+            "div#main:nth-child(2) > ul.users.list:nth-child(2) > htmpl-foreach (row 2) > li > htmpl-if > htmpl-insert"
+        )
+    );
+    assert!(matches!(
+        err.inner(),
+        Error::MissingColumn(element, _, _, _) if element == "htmpl-insert"
+    ));
+    assert!(err.to_string().starts_with("at div#main"), "{}", err);
+}
+
+#[test]
+fn error_accessors() {
+    let conn = make_test_db();
+    let mut templates = Templates::new();
+    templates.insert(
+        "index.html",
+        r#"<htmpl-query name="users">SELECT id, name FROM users;</htmpl-query><p><htmpl-insert query="users(name)"></htmpl-insert></p>"#,
+    );
+    let err = templates
+        .evaluate("index.html", &conn, &Default::default())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Cardinality);
+    assert_eq!(err.kind().to_string(), "cardinality");
+    assert_eq!(err.element(), Some("htmpl-insert"));
+    assert_eq!(err.query_name(), Some("users"));
+
+    let err = templates
+        .evaluate("other.html", &conn, &Default::default())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MissingTemplate);
+    assert_eq!(err.element(), None);
+    assert_eq!(err.query_name(), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_error() {
+    let conn = make_test_db();
+    let mut templates = Templates::new();
+    templates.insert(
+        "index.html",
+        r#"<p><htmpl-insert query="q"></htmpl-insert></p>"#,
+    );
+    let err = templates
+        .evaluate("index.html", &conn, &Default::default())
+        .unwrap_err();
+    assert_eq!(
+        serde_json::to_value(&err).unwrap(),
+        serde_json::json!({
+            "kind": "missing_query",
+            "message": err.to_string(),
+            "template": "index.html",
+            "location": "p > htmpl-insert",
+            "element": "htmpl-insert",
+            "query": "q",
+        })
+    );
+}
+
+#[test]
+fn trace() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT id, id > 1 AS later FROM users ORDER BY id;</htmpl-query><htmpl-foreach query="users"><htmpl-if true="users(later)"><b></b></htmpl-if></htmpl-foreach>"#;
+    let output = render(TEMPLATE, &conn, &EvaluateOptions::new()).unwrap();
+    assert_eq!(output.trace(), None);
+
+    let output = render(TEMPLATE, &conn, &EvaluateOptions::new().trace(true)).unwrap();
+    assert_html_eq(output.output(), "<b></b>");
+    let steps: Vec<String> = output
+        .trace()
+        .unwrap()
+        .steps()
+        .iter()
+        .map(|step| match step {
+            TraceStep::Element { path, .. } => format!("element {}", path),
+            TraceStep::Query { name, rows, .. } => format!("query {} {:?}", name, rows),
+            TraceStep::Bind { name, rows } => format!("bind {} {}", name, rows),
+            TraceStep::Row { query, row } => format!("row {} {}", query, row),
+            TraceStep::Branch {
+                condition, taken, ..
+            } => format!("branch {} {}", condition, taken),
+        })
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            "bind params 1",
+            "bind htmpl 1",
+            // The html element around the fragment.
+            "element html",
+            "element htmpl-query:nth-child(1)",
+            "query users Some(2)",
+            "bind users 2",
+            "element htmpl-foreach:nth-child(2)",
+            "row users 0",
+            "element htmpl-foreach:nth-child(2) (row 1) > htmpl-if",
+            "branch users(later) false",
+            "row users 1",
+            "element htmpl-foreach:nth-child(2) (row 2) > htmpl-if",
+            "branch users(later) true",
+            "element htmpl-foreach:nth-child(2) (row 2) > htmpl-if > b",
+        ]
+    );
+    assert!(output.trace().unwrap().to_json().contains(
+        r#"{"step":"branch","element":"htmpl-if","condition":"users(later)","taken":true}"#
+    ));
+}
+
+#[test]
+fn annotate() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT name FROM users ORDER BY id;</htmpl-query><htmpl-foreach query="users"><p><htmpl-insert query="users(name)"></htmpl-insert></p></htmpl-foreach><htmpl-insert query="params(x)"></htmpl-insert>"#;
+    let options = EvaluateOptions::new()
+        .params(Params::new().set("x", 1))
+        .annotate(true);
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(
+        result,
+        "<!-- htmpl: users, row 1 --><p><!-- htmpl: users(name), row 1 -->cceckman<!-- /htmpl --></p><!-- /htmpl -->\
+        <!-- htmpl: users, row 2 --><p><!-- htmpl: users(name), row 2 -->ddedkman<!-- /htmpl --></p><!-- /htmpl -->\
+        <!-- htmpl: params(x) -->1<!-- /htmpl -->"
+    );
+}
+
+#[test]
+fn preview() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users" :id="params(id)">SELECT id, name FROM users WHERE id > :id ORDER BY id;</htmpl-query>
+<htmpl-foreach query="users"><p><htmpl-insert query="users(id)"></htmpl-insert>: <htmpl-insert query="users(name)"></htmpl-insert></p></htmpl-foreach>
+"#;
+    let result = evaluate_template_with(
+        TEMPLATE,
+        &conn,
+        &EvaluateOptions::new().params(Params::new().set("id", 100)),
+    )
+    .unwrap();
+    assert_html_eq(result, "");
+
+    let result =
+        evaluate_template_with(TEMPLATE, &conn, &EvaluateOptions::new().preview(3)).unwrap();
+    assert_html_eq(result, "<p>1: Ada</p><p>2: Grace</p><p>3: Alan</p>");
+}
+
+#[test]
+fn deterministic() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="u">SELECT name, uuid, id FROM users WHERE id = 1;</htmpl-query><htmpl-attr select="a" query="u(name)" attr="title"></htmpl-attr><htmpl-attr select="a" query="u(id)" attr="data-id"></htmpl-attr><a z="1" y="2" x="3" w="4" v="5" href="/"></a>"#;
+    let first = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_eq!(
+        first,
+        r#"<a z="1" y="2" x="3" w="4" v="5" href="/" title="cceckman" data-id="1"></a>"#
+    );
+    for _ in 0..10 {
+        assert_eq!(evaluate_template(TEMPLATE, &conn).unwrap(), first);
+    }
+
+    // Columns are listed in the order they were selected.
+    let err = evaluate_template(
+        r#"<htmpl-query name="u">SELECT name, uuid, id FROM users WHERE id = 1;</htmpl-query><htmpl-insert query="u(nope)"></htmpl-insert>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err.inner(), Error::MissingColumn(_, _, columns, _) if columns == r#""name,uuid,id""#),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn validate() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="u">SELECT name FROM users WHERE id = 1;</htmpl-query><p><htmpl-insert query="u"></htmpl-insert></p>"#;
+    let options = EvaluateOptions::new().validate(true);
+    let output = render(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(output.diagnostics(), []);
+
+    // A block element in a paragraph can't be written out and parsed back the same way.
+    let options = options.post_process(|html| {
+        let p = scraper::Selector::parse("p").unwrap();
+        let id = html.select(&p).next().unwrap().id();
+        let div = Node::Element(scraper::node::Element::new(
+            QualName::new(None, ns!(html), local_name!("div")),
+            Vec::new(),
+        ));
+        html.tree.get_mut(id).unwrap().append(div);
+    });
+    let output = render(TEMPLATE, &conn, &options).unwrap();
+    assert_eq!(output.output(), "<p>cceckman<div></div></p>");
+    assert!(!output.diagnostics().is_empty());
+    assert!(output.diagnostics().iter().all(|d| d.rule == "parse"));
+
+    let output = render(TEMPLATE, &conn, &options.validate(false)).unwrap();
+    assert_eq!(output.diagnostics(), []);
+}
+
+#[test]
+fn lints() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="users">SELECT name FROM users;</htmpl-query><htmpl-foreach query="users"><p id="user"><htmpl-insert query="users(name)"></htmpl-insert></p></htmpl-foreach><img src="/logo.png">"#;
+    let options = EvaluateOptions::new().accessibility_lints().lint(|html| {
+        let p = scraper::Selector::parse("p").unwrap();
+        vec![crate::Diagnostic {
+            rule: "paragraphs",
+            message: html.select(&p).count().to_string(),
+        }]
+    });
+    let output = render(TEMPLATE, &conn, &options).unwrap();
+    let diagnostics: Vec<String> = output
+        .diagnostics()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    assert_eq!(
+        diagnostics,
+        [
+            r#"img-alt: <img src="/logo.png"> has no alt text"#,
+            r#"duplicate-id: id "user" is used by 2 elements"#,
+            "paragraphs: 2",
+        ]
+    );
+}
+
+#[test]
+fn translate() {
+    let conn = make_test_db();
+    let catalog = crate::Catalog::parse(
+        "de",
+        "greeting = Hallo, { $name }!\nusers = { $count } Benutzer",
+    )
+    .unwrap();
+    let options = EvaluateOptions::new().catalog(catalog);
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="u">SELECT name, (SELECT count(*) FROM users) AS n FROM users WHERE id = 1;</htmpl-query><p><htmpl-t key="greeting" :name="u(name)"></htmpl-t></p><p><htmpl-t key="users" :count="u(n)"></htmpl-t></p><p><htmpl-t key="missing">Fallback</htmpl-t></p>"#,
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_html_eq(
+        result,
+        "<p>Hallo, cceckman!</p><p>2 Benutzer</p><p>Fallback</p>",
+    );
+
+    let err = evaluate_template_with(r#"<htmpl-t key="missing"></htmpl-t>"#, &conn, &options)
+        .unwrap_err();
+    assert_eq!(*err.inner(), Error::MissingMessage("missing".to_owned()));
+    let err = evaluate_template_with(r#"<htmpl-t key="greeting"></htmpl-t>"#, &conn, &options)
+        .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("htmpl-t".into(), "$name".to_owned())
+    );
+    let err = evaluate_template(r#"<htmpl-t key="greeting"></htmpl-t>"#, &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingOption("htmpl-t".into(), "catalog".into())
+    );
+}
+
+#[test]
+fn translate_plurals() {
+    let conn = make_test_db();
+    let catalog = crate::Catalog::parse(
+        "en",
+        "users =\n    { $count ->\n        [one] One user\n       *[other] { $count } users\n    }",
+    )
+    .unwrap();
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="n">SELECT count(*) AS n FROM users;</htmpl-query><htmpl-query name="one">SELECT 1 AS n;</htmpl-query><p><htmpl-t key="users" count="n(n)"></htmpl-t></p><p><htmpl-t key="users" count="one"></htmpl-t></p>"#,
+        &conn,
+        &EvaluateOptions::new().catalog(catalog),
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>2 users</p><p>One user</p>");
+}
+
+#[test]
+fn timezone() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="q">SELECT '2024-11-03 05:30:00' AS before, '2024-11-03 06:30:00' AS after;</htmpl-query><p><htmpl-insert query="q(before)" tz></htmpl-insert></p><p><htmpl-insert query="q(after)" tz></htmpl-insert></p><p><htmpl-insert query="q(after)" tz="Asia/Tokyo" tz-format="%H:%M %Z"></htmpl-insert></p>"#;
+    let options =
+        EvaluateOptions::new().timezone(jiff::tz::TimeZone::get("America/New_York").unwrap());
+    let result = evaluate_template_with(TEMPLATE, &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        "<p>2024-11-03 01:30:00 EDT</p><p>2024-11-03 01:30:00 EST</p><p>15:30 JST</p>",
+    );
+
+    let err = evaluate_template(TEMPLATE, &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingOption("htmpl-insert".into(), "timezone".into())
+    );
+}
+
+#[test]
+fn truthiness() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="q">SELECT 'false' AS flag;</htmpl-query><htmpl-if true="q(flag)">yes</htmpl-if><htmpl-if false="q(flag)">no</htmpl-if>"#;
+    assert_eq!(evaluate_template(TEMPLATE, &conn).unwrap(), "yes");
+    let options = EvaluateOptions::new().truthiness(Truthiness::TextFlags);
+    assert_eq!(
+        evaluate_template_with(TEMPLATE, &conn, &options).unwrap(),
+        "no"
+    );
+    let options = EvaluateOptions::new().truthiness(Truthiness::StrictInteger);
+    let err = evaluate_template_with(TEMPLATE, &conn, &options).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::NotABoolean(
+            "htmpl-if".into(),
+            "q(flag)".to_owned(),
+            r#"Text("false")"#.to_owned()
+        )
+    );
+    assert!(
+        err.to_string().contains(
+            r#"not a boolean: in element htmpl-if, q(flag) is Text("false"); with strict truthiness, only integers and null are conditions"#
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn insert_default() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r#"<htmpl-query name="q">SELECT NULL AS nickname, 'cc' AS initials;</htmpl-query><htmpl-query name="none">SELECT name FROM users WHERE id = 100;</htmpl-query><p><htmpl-insert query="q(nickname)" default="(none)"></htmpl-insert></p><p><htmpl-insert query="q(initials)" default="(none)"></htmpl-insert></p><p><htmpl-insert query="none" default="nobody"></htmpl-insert></p>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>(none)</p><p>cc</p><p>nobody</p>");
+
+    // Other errors are still errors.
+    let err = evaluate_template(
+        r#"<htmpl-query name="q">SELECT 1 AS a;</htmpl-query><htmpl-insert query="q(b)" default="x"></htmpl-insert>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MissingColumn);
+}
+
+#[test]
+fn optional() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="none">SELECT name FROM users WHERE id = 100;</htmpl-query><htmpl-attr select="a" query="none" attr="title" optional></htmpl-attr><a href="/">[<htmpl-insert query="none" optional></htmpl-insert>]</a>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(result, r#"<a href="/">[]</a>"#);
+
+    let err = evaluate_template(TEMPLATE.replace(" optional", ""), &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::Cardinality("htmpl-attr".into(), "none".to_owned(), 0, 1)
+    );
+}
+
+#[test]
+fn insert_join() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r#"<htmpl-query name="u">SELECT name, NULL AS missing FROM users ORDER BY id;</htmpl-query><htmpl-query name="none">SELECT name FROM users WHERE id = 100;</htmpl-query><p><htmpl-insert query="u(name)" join=", "></htmpl-insert></p><p><htmpl-insert query="none" join=", " default="nobody"></htmpl-insert></p><p><htmpl-insert query="u(missing)" join=", "></htmpl-insert></p>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>cceckman, ddedkman</p><p>nobody</p><p></p>");
+}
+
+#[test]
+fn row_index() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="u">SELECT name FROM users ORDER BY id;</htmpl-query><h1><htmpl-insert query="u[0](name)"></htmpl-insert></h1><h2><htmpl-insert query="u[1]"></htmpl-insert></h2><htmpl-if true="u[1](name)"><p>more</p></htmpl-if>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(result, "<h1>cceckman</h1><h2>ddedkman</h2><p>more</p>");
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="u">SELECT name FROM users;</htmpl-query><htmpl-insert query="u[2](name)"></htmpl-insert>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::Cardinality("htmpl-insert".into(), "u".to_owned(), 2, 3)
+    );
+    for bad in ["u[x](name)", "u[0", "[0](name)"] {
+        let err = evaluate_template(
+            format!(
+                r#"<htmpl-query name="u">SELECT name FROM users;</htmpl-query><htmpl-insert query="{}"></htmpl-insert>"#,
+                bad
+            ),
+            &conn,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter, "{}", bad);
+    }
+}
+
+#[test]
+fn meta_selectors() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="u">SELECT name FROM users;</htmpl-query><htmpl-query name="none">SELECT name FROM users WHERE id = 100;</htmpl-query><p><htmpl-insert query="u#count"></htmpl-insert> users</p><htmpl-if true="u#exists"><p>some</p></htmpl-if><htmpl-if true="none#empty"><p>none</p></htmpl-if><htmpl-if true="none#count"><p>unreachable</p></htmpl-if>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(result, "<p>2 users</p><p>some</p><p>none</p>");
+
+    for bad in ["u#rows", "u(name)#count", "#count"] {
+        let err = evaluate_template(
+            format!(
+                r#"<htmpl-query name="u">SELECT name FROM users;</htmpl-query><htmpl-insert query="{}"></htmpl-insert>"#,
+                bad
+            ),
+            &conn,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter, "{}", bad);
+    }
+}
+
+#[test]
+fn dotted_selectors() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r#"<htmpl-query name="u">SELECT name, json_object('tags', json_array('a', 'b')) AS meta FROM users ORDER BY id;</htmpl-query><htmpl-query name="one" :name="u[0].name">SELECT :name AS name;</htmpl-query><p><htmpl-insert query="one.name"></htmpl-insert></p><p><htmpl-insert query="u[1].name"></htmpl-insert></p><p><htmpl-insert query="u[0].meta->>'$.tags[1]'"></htmpl-insert></p>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>cceckman</p><p>ddedkman</p><p>b</p>");
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="u">SELECT name FROM users;</htmpl-query><htmpl-insert query="u."></htmpl-insert>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidParameter);
+}
+
+#[test]
+fn literal_params() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        format!(
+            r#"<htmpl-query name="u" :uuid="'{}'" :limit="1" :x="1.5" :quote="'it''s'" :raw="literal:it's">SELECT name, :x AS x, :quote AS quote, :raw AS raw FROM users WHERE uuid = :uuid LIMIT :limit;</htmpl-query><p><htmpl-insert query="u(name)"></htmpl-insert> <htmpl-insert query="u(x)"></htmpl-insert> <htmpl-insert query="u(quote)"></htmpl-insert> <htmpl-insert query="u(raw)"></htmpl-insert></p>"#,
+            CCECKMAN_UUID
+        ),
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>cceckman 1.5 it's it's</p>");
+}
+
+#[test]
+fn positional_params() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        format!(
+            r#"<htmpl-query name="u" :1="'{}'" :2="1">SELECT name FROM users WHERE uuid = ? LIMIT ?;</htmpl-query><htmpl-query name="n" :2="'b'" :1="'a'">SELECT ?2 || ?1 AS ba;</htmpl-query><p><htmpl-insert query="u(name)"></htmpl-insert> <htmpl-insert query="n(ba)"></htmpl-insert></p>"#,
+            CCECKMAN_UUID
+        ),
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>cceckman ba</p>");
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="q" :1="1">SELECT ?, ?;</htmpl-query>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("htmpl-query".into(), ":2".to_owned())
+    );
+}
+
+#[test]
+fn expand_params() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r#"<htmpl-query name="all">SELECT id FROM users;</htmpl-query><htmpl-query name="none">SELECT id FROM users WHERE 0;</htmpl-query>
+        <htmpl-query name="picked" :ids="all(id)" expand=":ids">SELECT group_concat(name, ' ') AS names FROM users WHERE id IN (:ids) AND name != ':ids' -- :ids
+        </htmpl-query><htmpl-query name="unpicked" :ids="none(id)" expand>SELECT count(*) AS n FROM users WHERE id IN (:ids);</htmpl-query><p><htmpl-insert query="picked(names)"></htmpl-insert> <htmpl-insert query="unpicked(n)"></htmpl-insert></p>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>cceckman ddedkman 0</p>");
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="q" :a="1" expand=":b">SELECT :a;</htmpl-query>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("htmpl-query".into(), ":b".to_owned())
+    );
+}
+
+#[test]
+fn param_types() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r#"<htmpl-query name="q" :n="'1'" :n:type="integer" :r="2" :r:type="real" :t="3" :t:type="text">SELECT typeof(:n) || ' ' || typeof(:r) || ' ' || typeof(:t) AS types, (SELECT name FROM users WHERE id = :n) AS name;</htmpl-query><p><htmpl-insert query="q(types)"></htmpl-insert> <htmpl-insert query="q(name)"></htmpl-insert></p>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<p>integer real text cceckman</p>");
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="q" :n="'one'" :n:type="integer">SELECT :n;</htmpl-query>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidParameter);
+    let err = evaluate_template(
+        r#"<htmpl-query name="q" :n="1" :n:type="date">SELECT :n;</htmpl-query>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter("htmpl-query".into(), r#":n:type="date""#.to_owned())
+    );
+
+    // Redacted values aren't in error messages.
+    let options = EvaluateOptions::new()
+        .redact_params(true)
+        .params(Params::new().set("id", "secret@x.com".to_owned()));
+    for template in [
+        r#"<htmpl-query name="q" :id="params(id)" :id:type="integer">SELECT :id;</htmpl-query>"#,
+        r#"<htmpl-if true="params(id)"></htmpl-if>"#,
+        r#"<htmpl-form select="form" query="params"></htmpl-form><form><input type="checkbox" name="id"></form>"#,
+    ] {
+        let options = options.clone().truthiness(Truthiness::StrictInteger);
+        let err = evaluate_template_with(template, &conn, &options).unwrap_err();
+        assert!(
+            matches!(
+                err.kind(),
+                ErrorKind::InvalidParameter | ErrorKind::NotABoolean
+            ),
+            "{}",
+            template
+        );
+        assert!(!err.to_string().contains("secret"), "{}", err);
+        assert!(err.to_string().contains("[redacted]"), "{}", err);
+    }
+}
+
+#[test]
+fn query_src() {
+    let conn = make_test_db();
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("queries")).unwrap();
+    std::fs::write(
+        root.path().join("queries/user.sql"),
+        "SELECT name FROM users WHERE uuid = :uuid;\n",
+    )
+    .unwrap();
+    let template = format!(
+        r#"<htmpl-query name="u" src="/queries/user.sql" :uuid="'{}'"></htmpl-query><p><htmpl-insert query="u(name)"></htmpl-insert></p>"#,
+        CCECKMAN_UUID
+    );
+    let options = EvaluateOptions::new().query_root(root.path());
+    let result = evaluate_template_with(&template, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>cceckman</p>");
+
+    let err = evaluate_template(&template, &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingOption("htmpl-query".into(), "query_root".into())
+    );
+    let err = evaluate_template_with(
+        r#"<htmpl-query name="u" src="../user.sql"></htmpl-query>"#,
+        &conn,
+        &options,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidParameter);
+    let err = evaluate_template_with(
+        r#"<htmpl-query name="u" src="queries/missing.sql"></htmpl-query>"#,
+        &conn,
+        &options,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Asset);
+}
+
+#[test]
+fn named_queries() {
+    let conn = make_test_db();
+    let options = EvaluateOptions::new()
+        .named_query("user_by_uuid", "SELECT name FROM users WHERE uuid = :uuid;")
+        .named_query("broken", "SELECT nothing FROM nowhere;");
+    let template = format!(
+        r#"<htmpl-query name="u" ref="user_by_uuid" :uuid="'{}'"></htmpl-query><p><htmpl-insert query="u(name)"></htmpl-insert></p>"#,
+        CCECKMAN_UUID
+    );
+    let result = evaluate_template_with(&template, &conn, &options).unwrap();
+    assert_html_eq(result, "<p>cceckman</p>");
+
+    let err = options.check_named_queries(&conn).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Sql);
+    assert_eq!(err.query_name(), Some("broken"));
+    let err = evaluate_template_with(
+        r#"<htmpl-query name="u" ref="missing"></htmpl-query>"#,
+        &conn,
+        &options,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter("htmpl-query".into(), r#"ref="missing""#.to_owned())
+    );
+}
+
+#[test]
+fn export_queries() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        r#"<div><div><htmpl-query name="p" export>SELECT 'parent' AS s;</htmpl-query><htmpl-query name="g" export="global">SELECT 'global' AS s;</htmpl-query><htmpl-query name="l">SELECT 'local' AS s;</htmpl-query></div><p><htmpl-insert query="p(s)"></htmpl-insert></p></div><p><htmpl-insert query="g(s)"></htmpl-insert></p><htmpl-if true="g(s)"><htmpl-query name="l">SELECT 'if' AS s;</htmpl-query></htmpl-if>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<div><div></div><p>parent</p></div><p>global</p>");
+
+    let err = evaluate_template(
+        r#"<div><div><htmpl-query name="p" export>SELECT 1 AS s;</htmpl-query></div></div><htmpl-insert query="p(s)"></htmpl-insert>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MissingQuery);
+    let err = evaluate_template(
+        r#"<htmpl-query name="p" export="sideways">SELECT 1 AS s;</htmpl-query>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter("htmpl-query".into(), r#"export="sideways""#.to_owned())
+    );
+}
+
+#[test]
+fn prelude() {
+    let conn = make_test_db();
+    let options = EvaluateOptions::new()
+        .params(Params::new().set("uuid", CCECKMAN_UUID.to_owned()))
+        .prelude_query("me", "SELECT name FROM users WHERE uuid = :uuid")
+        .prelude_rows(
+            "site",
+            vec![[("title".to_owned(), Value::Text("Example".to_owned()))].into()],
+        );
+    let result = evaluate_template_with(
+        r#"<h1><htmpl-insert query="site(title)"></htmpl-insert></h1><div><p><htmpl-insert query="me(name)"></htmpl-insert></p></div>"#,
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_html_eq(result, "<h1>Example</h1><div><p>cceckman</p></div>");
+
+    let options = EvaluateOptions::new().prelude_query("me", "SELECT :missing");
+    let err = evaluate_template_with("<p></p>", &conn, &options).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("".into(), ":missing".to_owned())
+    );
+}
+
+#[test]
+fn let_bindings() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        format!(
+            r#"<htmpl-query name="u" :uuid="'{}'">SELECT name FROM users WHERE uuid = :uuid;</htmpl-query><htmpl-let name="shout" value="u(name)|upper"></htmpl-let><htmpl-let name="n" value="'  My Post  '|trim|slug"></htmpl-let><div><htmpl-let name="len" value="u(name) | length" export></htmpl-let></div><p><htmpl-insert query="shout"></htmpl-insert> <htmpl-insert query="n(n)"></htmpl-insert> <htmpl-insert query="len"></htmpl-insert></p>"#,
+            CCECKMAN_UUID
+        ),
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(result, "<div></div><p>CCECKMAN my-post 8</p>");
+
+    let err = evaluate_template(
+        r#"<htmpl-let name="x" value="'a'|reverse"></htmpl-let>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter("htmpl-let".into(), r#"unknown filter "reverse""#.to_owned())
+    );
+}
+
+#[test]
+fn with_row() {
+    let conn = make_test_db();
+    let result = evaluate_template(
+        format!(
+            r#"<htmpl-query name="u" :uuid="'{}'">SELECT name, uuid FROM users WHERE uuid = :uuid;</htmpl-query><htmpl-query name="one">SELECT 1 AS n;</htmpl-query><htmpl-with query="u"><p><htmpl-insert query="(name)"></htmpl-insert> <htmpl-insert query=".(uuid)"></htmpl-insert></p><htmpl-with query="one"><p><htmpl-insert query=".n"></htmpl-insert> <htmpl-insert query="u(name)"></htmpl-insert></p></htmpl-with></htmpl-with>"#,
+            CCECKMAN_UUID
+        ),
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(
+        result,
+        format!("<p>cceckman {}</p><p>1 cceckman</p>", CCECKMAN_UUID),
+    );
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="all">SELECT name FROM users;</htmpl-query><htmpl-with query="all"></htmpl-with>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::Cardinality("htmpl-with".into(), "all".to_owned(), 2, 1)
+    );
+    let err =
+        evaluate_template(r#"<htmpl-insert query="(name)"></htmpl-insert>"#, &conn).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidParameter);
+}
+
+#[test]
+fn components() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"
+<htmpl-query name="users">SELECT uuid FROM users ORDER BY name;</htmpl-query>
+<ul><htmpl-foreach query="users"><user-card :uuid="users(uuid)" :label="'User'"></user-card></htmpl-foreach></ul>
+<htmpl-define name="user-card" props="uuid label"><htmpl-query name="user" :uuid="uuid">SELECT name FROM users WHERE uuid = :uuid;</htmpl-query><li><htmpl-insert query="label"></htmpl-insert>: <htmpl-insert query="user(name)"></htmpl-insert></li></htmpl-define>
+"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        "<ul><li>User: cceckman</li><li>User: ddedkman</li></ul>",
+    );
+
+    // The component can't see the queries around the instance.
+    let err = evaluate_template(
+        r#"<htmpl-query name="q">SELECT 1 AS n;</htmpl-query><x-leak></x-leak><htmpl-define name="x-leak"><htmpl-insert query="q"></htmpl-insert></htmpl-define>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MissingQuery);
+    let err = evaluate_template(
+        r#"<x-card></x-card><htmpl-define name="x-card" props="title"></htmpl-define>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("".into(), ":title".to_owned())
+    );
+}
+
+#[test]
+fn slots() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-define name="x-card" props="n"><div><h2><htmpl-slot name="title">Untitled</htmpl-slot></h2><htmpl-slot></htmpl-slot><htmpl-insert query="n"></htmpl-insert></div></htmpl-define><htmpl-query name="q">SELECT 'hello' AS s;</htmpl-query><x-card :n="1"><b slot="title"><htmpl-insert query="q"></htmpl-insert></b>Body <i>text</i></x-card><x-card :n="2"></x-card>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<div><h2><b slot="title">hello</b></h2>Body <i>text</i>1</div><div><h2>Untitled</h2>2</div>"#,
+    );
+
+    let err = evaluate_template(r#"<htmpl-slot></htmpl-slot>"#, &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter("htmpl-slot".into(), "outside of a component".to_owned())
+    );
+}
+
+#[test]
+fn layouts() {
+    let conn = make_test_db();
+    let mut templates = Templates::new();
+    templates.insert(
+        "base.html",
+        r#"<header><htmpl-block name="title">Site</htmpl-block></header><main><htmpl-block name="content"></htmpl-block></main>"#,
+    );
+    templates.insert(
+        "section.html",
+        r#"<htmpl-extends template="base.html"><htmpl-block name="content"><aside>Posts</aside><htmpl-block name="post"></htmpl-block></htmpl-block></htmpl-extends>"#,
+    );
+    templates.insert(
+        "page.html",
+        r#"<htmpl-extends template="section.html">
+<htmpl-query name="u">SELECT name FROM users ORDER BY name LIMIT 1;</htmpl-query>
+<htmpl-block name="post"><p><htmpl-insert query="u(name)"></htmpl-insert></p></htmpl-block>
+</htmpl-extends>"#,
+    );
+    templates.insert(
+        "loop.html",
+        r#"<htmpl-extends template="loop.html"></htmpl-extends>"#,
+    );
+    let options = EvaluateOptions::new();
+    let result = templates.evaluate("page.html", &conn, &options).unwrap();
+    assert_html_eq(
+        result,
+        "<header>Site</header><main><aside>Posts</aside><p>cceckman</p></main>",
+    );
+
+    let err = templates
+        .evaluate("loop.html", &conn, &options)
+        .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::Cycle(vec!["loop.html".to_owned(), "loop.html".to_owned()])
+    );
+    let err = evaluate_template(templates.get("page.html").unwrap(), &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingOption("htmpl-extends".into(), "templates".into())
+    );
+}
+
+#[test]
+fn macros() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="u">SELECT name FROM users ORDER BY name LIMIT 1;</htmpl-query><htmpl-macro name="badge" args="label kind"><b><htmpl-insert query="kind"></htmpl-insert>: <htmpl-insert query="label"></htmpl-insert> (<htmpl-insert query="u(name)"></htmpl-insert>)</b></htmpl-macro><htmpl-call macro="badge" label="New" kind="info"></htmpl-call><htmpl-call macro="badge" :label="u(name)" :kind="2"></htmpl-call>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        "<b>info: New (cceckman)</b><b>2: cceckman (cceckman)</b>",
+    );
+
+    let err = evaluate_template(
+        r#"<htmpl-call macro="badge"></htmpl-call><htmpl-macro name="badge"></htmpl-macro>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter("htmpl-call".into(), r#"macro="badge""#.to_owned())
+    );
+    let err = evaluate_template(
+        r#"<htmpl-macro name="badge" args="label"></htmpl-macro><htmpl-call macro="badge"></htmpl-call>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("htmpl-call".into(), "label".to_owned())
+    );
+}
+
+#[test]
+fn cycles() {
+    let conn = make_test_db();
+    let mut templates = Templates::new();
+    templates.insert(
+        "a.html",
+        r#"<htmpl-extends template="b.html"></htmpl-extends>"#,
+    );
+    templates.insert(
+        "b.html",
+        r#"<htmpl-extends template="a.html"></htmpl-extends>"#,
+    );
+    let err = templates
+        .evaluate("a.html", &conn, &EvaluateOptions::new())
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "in template a.html: circular reference: b.html -> a.html -> b.html"
+    );
+
+    let err = evaluate_template(
+        r#"<x-a></x-a><htmpl-define name="x-a"><x-b></x-b></htmpl-define><htmpl-define name="x-b"><div><x-a></x-a></div></htmpl-define>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::Cycle(vec!["x-a".to_owned(), "x-b".to_owned(), "x-a".to_owned()])
+    );
+    let err = evaluate_template(
+        r#"<htmpl-macro name="m"><htmpl-call macro="m"></htmpl-call></htmpl-macro><htmpl-call macro="m"></htmpl-call>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Cycle);
+}
+
+#[cfg(unix)]
+#[test]
+fn templates_sandboxed() {
+    let outside = tempfile::tempdir().unwrap();
+    std::fs::write(outside.path().join("secret.html"), "secret").unwrap();
+    let root = tempfile::tempdir().unwrap();
+    std::fs::create_dir(root.path().join("posts")).unwrap();
+    std::fs::write(root.path().join("posts/index.html"), "<p>Posts</p>").unwrap();
+    // A link back up the tree is not followed forever.
+    std::os::unix::fs::symlink(root.path(), root.path().join("posts/up")).unwrap();
+    let templates = Templates::from_dir(root.path()).unwrap();
+    assert_eq!(templates.get("posts/index.html"), Some("<p>Posts</p>"));
+
+    std::os::unix::fs::symlink(
+        outside.path().join("secret.html"),
+        root.path().join("secret.html"),
+    )
+    .unwrap();
+    let err = Templates::from_dir(root.path()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+}
+
+#[test]
+fn element_prefix() {
+    let conn = Connection::open_in_memory().unwrap();
+    let options = EvaluateOptions::new().prefix("x-");
+    let result = evaluate_template_with(
+        r#"<x-query name="q">SELECT 1 AS n;</x-query><x-if true="q(n)"><p><x-insert query="q(n)"></x-insert></p></x-if><x-widget></x-widget>"#,
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_html_eq(&result, "<p>1</p><x-widget></x-widget>");
+
+    let err =
+        evaluate_template_with("<div><x-insert></x-insert></div>", &conn, &options).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingAttr("x-insert".into(), "query".into())
+    );
+    assert_eq!(err.location(), Some("div > x-insert"));
+
+    let partial = options.clone().partial(true);
+    let result = evaluate_template_with(
+        r#"<x-insert query="q(n)" defer></x-insert><x-later></x-later>"#,
+        &conn,
+        &partial,
+    )
+    .unwrap();
+    assert_html_eq(
+        &result,
+        r#"<x-insert query="q(n)"></x-insert><x-later></x-later>"#,
+    );
+}
+
+#[test]
+fn verbatim() {
+    let conn = Connection::open_in_memory().unwrap();
+    let result = evaluate_template(
+        r#"<htmpl-query name="q">SELECT 1 AS n;</htmpl-query><div><htmpl-verbatim><htmpl-insert query="q(n)"></htmpl-insert> <htmpl-define name="x-a"><p>a</p></htmpl-define><x-a></x-a></htmpl-verbatim></div><x-a></x-a>"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(
+        &result,
+        r#"<div><htmpl-insert query="q(n)"></htmpl-insert> <htmpl-define name="x-a"><p>a</p></htmpl-define><x-a></x-a></div><x-a></x-a>"#,
+    );
+
+    let options = EvaluateOptions::new().prefix("x-");
+    let result = evaluate_template_with(
+        r#"<x-verbatim><x-insert query="q(n)"></x-insert></x-verbatim>"#,
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_html_eq(&result, r#"<x-insert query="q(n)"></x-insert>"#);
+}
+
+#[test]
+fn comment_element() {
+    let conn = Connection::open_in_memory().unwrap();
+    let result = evaluate_template(
+        r#"<p>a<htmpl-comment>secret <htmpl-insert></htmpl-insert><htmpl-define name="x-a"></htmpl-define></htmpl-comment>b</p><!-- kept -->"#,
+        &conn,
+    )
+    .unwrap();
+    assert_html_eq(&result, "<p>ab</p><!-- kept -->");
+}
+
+#[test]
+fn trim_whitespace() {
+    let db = make_test_db();
+    const TEMPLATE: &str = r#"
+        <div>
+            <htmpl-query name="q" trim>
+                SELECT uuid FROM users WHERE name = "cceckman";
+            </htmpl-query>
+            <htmpl-if true="q" trim trim-inner>
+                <b>yes</b> <htmpl-insert query="q"></htmpl-insert>
+            </htmpl-if>
+        </div> <htmpl-verbatim><htmpl-if trim> </htmpl-if> </htmpl-verbatim>"#;
+    let result = evaluate_template(TEMPLATE, &db).unwrap();
+    assert_html_eq(
+        result,
+        format!(
+            "\n        <div><b>yes</b> {}</div> <htmpl-if trim=\"\"> </htmpl-if> ",
+            CCECKMAN_UUID
+        ),
+    );
+}
+
+#[test]
+fn write_mode() {
+    let conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch("CREATE TABLE log (msg TEXT);").unwrap();
+    let count = || -> i64 {
+        conn.query_row("SELECT count(*) FROM log", [], |row| row.get(0))
+            .unwrap()
+    };
+    const INSERT: &str =
+        r#"<htmpl-query name="w" writes>INSERT INTO log (msg) VALUES ('hi');</htmpl-query>"#;
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="w">INSERT INTO log (msg) VALUES ('hi');</htmpl-query>"#,
+        &conn,
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidParameter);
+    let err = evaluate_template(INSERT, &conn).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingOption("htmpl-query".into(), "mode".into())
+    );
+    assert_eq!(count(), 0);
+
+    let options = EvaluateOptions::new().mode(Mode::Write);
+    evaluate_template_with(INSERT, &conn, &options).unwrap();
+    assert_eq!(count(), 1);
+
+    // A failure later in the evaluation rolls back the write.
+    let failing = format!("{}<htmpl-insert query=\"missing\"></htmpl-insert>", INSERT);
+    evaluate_template_with(failing, &conn, &options).unwrap_err();
+    assert_eq!(count(), 1);
+
+    // As does a failed write shown inline.
+    let inline = options.clone().inline_errors(true);
+    let failing = format!(
+        r#"{}<htmpl-query name="w2" writes>INSERT INTO missing (msg) VALUES ('hi');</htmpl-query>"#,
+        INSERT
+    );
+    let result = evaluate_template_with(failing, &conn, &inline).unwrap();
+    assert!(result.contains("htmpl-error"), "{}", result);
+    assert_eq!(count(), 1);
+}
+
+#[test]
+fn snapshot_reads() {
+    let file = NamedTempFile::new().unwrap();
+    let conn = Connection::open(file.path()).unwrap();
+    conn.execute_batch("PRAGMA journal_mode = WAL; CREATE TABLE log (msg TEXT);")
+        .unwrap();
+    // Another connection writes between the two queries.
+    let writer = Mutex::new(Connection::open(file.path()).unwrap());
+    let options = EvaluateOptions::new().before_query(move |info| {
+        if info.name == "after" {
+            writer
+                .lock()
+                .unwrap()
+                .execute("INSERT INTO log (msg) VALUES ('hi')", [])
+                .unwrap();
+        }
+    });
+    let result = evaluate_template_with(
+        r#"<htmpl-query name="before">SELECT count(*) AS n FROM log;</htmpl-query><htmpl-query name="after">SELECT count(*) AS n FROM log;</htmpl-query><htmpl-insert query="before(n)"></htmpl-insert> <htmpl-insert query="after(n)"></htmpl-insert>"#,
+        &conn,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(result, "0 0");
+    let n: i64 = conn
+        .query_row("SELECT count(*) FROM log", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(n, 1);
+}
+
+#[test]
+fn attached_databases() {
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("index.db");
+    let conn = Connection::open(&index).unwrap();
+    conn.execute_batch("CREATE TABLE docs (uuid TEXT, rank INTEGER);")
+        .unwrap();
+    conn.execute("INSERT INTO docs VALUES (?, 1);", [CCECKMAN_UUID])
+        .unwrap();
+    let db = make_test_db();
+    let options = EvaluateOptions::new().attach("search", &index);
+    const TEMPLATE: &str = r#"<htmpl-query name="q">SELECT name FROM users JOIN search.docs USING (uuid) ORDER BY rank;</htmpl-query><htmpl-foreach query="q"><htmpl-insert query="q(name)"></htmpl-insert></htmpl-foreach>"#;
+    let result = evaluate_template_with(TEMPLATE, &db, &options).unwrap();
+    assert_eq!(result, "cceckman");
+    // Evaluating again with the same connection reuses the attachment.
+    assert_eq!(
+        result,
+        evaluate_template_with(TEMPLATE, &db, &options).unwrap()
+    );
+
+    let err = evaluate_template(
+        r#"<htmpl-query name="q">-- Sneaky
+            attach DATABASE ':memory:' AS other;</htmpl-query>"#,
+        &db,
+    )
+    .unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::InvalidParameter(
+            "htmpl-query".into(),
+            "ATTACH is not allowed in templates".to_owned()
+        )
+    );
+    // Nor can templates end the evaluation's transaction.
+    for sql in [
+        "COMMIT",
+        "end transaction",
+        "RELEASE htmpl",
+        "ROLLBACK TO htmpl",
+        "BEGIN",
+    ] {
+        let err = evaluate_template(
+            format!(r#"<htmpl-query name="q">{};</htmpl-query>"#, sql),
+            &db,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter, "{}", sql);
+    }
+    let err = evaluate_template_with(
+        "",
+        &Connection::open_in_memory().unwrap(),
+        &EvaluateOptions::new().attach("missing", dir.path().join("no/such.db")),
+    )
+    .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Open);
+}
+
+#[test]
+fn tenant_routing() {
+    let dir = tempfile::tempdir().unwrap();
+    for tenant in ["acme", "globex"] {
+        let conn = Connection::open(dir.path().join(format!("{}.db", tenant))).unwrap();
+        conn.execute_batch("CREATE TABLE site (name TEXT);")
+            .unwrap();
+        conn.execute("INSERT INTO site VALUES (?)", [tenant])
+            .unwrap();
+    }
+    let mut templates = Templates::new();
+    templates.insert(
+        "index.html",
+        r#"<htmpl-query name="site">SELECT name FROM site;</htmpl-query><htmpl-insert query="site(name)"></htmpl-insert>"#,
+    );
+    let opened = Arc::new(Mutex::new(Vec::new()));
+    let root = dir.path().to_owned();
+    let log = opened.clone();
+    let renderer = Renderer::new(templates, Pool::new(":memory:"), Default::default()).route(
+        "tenant",
+        move |tenant| {
+            log.lock().unwrap().push(tenant.to_owned());
+            Ok(Pool::new(root.join(format!("{}.db", tenant))))
+        },
+    );
+    let render = |tenant: &str| {
+        renderer
+            .render("index.html", Params::new().set("tenant", tenant.to_owned()))
+            .map(RenderOutput::into_output)
+    };
+    assert_eq!(render("acme").unwrap(), "acme");
+    assert_eq!(render("globex").unwrap(), "globex");
+    assert_eq!(render("acme").unwrap(), "acme");
+    // Each tenant's pool is opened once.
+    assert_eq!(*opened.lock().unwrap(), ["acme", "globex"]);
+
+    for tenant in ["../acme", "acme/../globex", ".hidden", ""] {
+        let err = render(tenant).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidParameter, "{:?}", tenant);
+    }
+    assert_eq!(opened.lock().unwrap().len(), 2);
+
+    let err = renderer.render("index.html", Params::new()).unwrap_err();
+    assert_eq!(
+        *err.inner(),
+        Error::MissingParameter("".into(), "tenant".to_owned())
+    );
+}
+
+#[test]
+fn dotted_columns() {
+    let conn = make_test_db();
+    const TEMPLATE: &str = r#"<htmpl-query name="post">SELECT '["a", "b"]' AS tags, 'Hello' AS title;</htmpl-query><ul><htmpl-foreach query="post.tags" as="tag"><li><htmpl-insert query="tag.value"></htmpl-insert></li></htmpl-foreach></ul><htmpl-json query="post.title"></htmpl-json>"#;
+    let result = evaluate_template(TEMPLATE, &conn).unwrap();
+    assert_html_eq(
+        result,
+        r#"<ul><li>a</li><li>b</li></ul><script type="application/json">"Hello"</script>"#,
+    );
 }
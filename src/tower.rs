@@ -0,0 +1,209 @@
+//! A [tower](https://docs.rs/tower) `Service` that renders templates.
+//!
+//! [`HtmplService`] maps each request path to a template, and the query string to
+//! [parameters](crate::Params), so an htmpl site can be mounted in any `tower`- or
+//! `hyper`-based stack:
+//!
+//! - `/` and other paths ending in `/` render the directory's `index.html`
+//! - `/about` renders `about` if there is such a template, or `about.html` otherwise
+//! - `/post.html?id=3` renders `post.html`, with `params(id)` bound to `"3"`
+//!
+//! Only `GET` and `HEAD` requests are accepted.
+//! Responses carry `ETag` and `Last-Modified` headers (see [`RenderOutput`](crate::RenderOutput)),
+//! and conditional requests are answered with "304 Not Modified".
+
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{Method, Request, Response, StatusCode};
+use tower_service::Service;
+
+use crate::{
+    respond::{error_response, respond},
+    EvaluateOptions, Params, Pool, Renderer, Templates,
+};
+
+/// A service that renders the template named by the request path.
+///
+/// Cloning an `HtmplService` is cheap; clones share the same [`Renderer`].
+#[derive(Debug, Clone)]
+pub struct HtmplService(Arc<Renderer>);
+
+impl HtmplService {
+    /// Render the templates against connections from the pool, with the given options.
+    pub fn new(templates: Templates, pool: Pool, options: EvaluateOptions) -> Self {
+        Renderer::new(templates, pool, options).into()
+    }
+
+    fn respond<B>(&self, request: &Request<B>) -> Response<String> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return error_response(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let renderer = &self.0;
+        let template = template_name(renderer.templates(), request.uri().path());
+        let params: Params = form_urlencoded::parse(request.uri().query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+        let options = renderer.options().clone().params(params);
+        respond(renderer, &template, &options, request.headers())
+    }
+}
+
+impl From<Renderer> for HtmplService {
+    fn from(renderer: Renderer) -> Self {
+        HtmplService(Arc::new(renderer))
+    }
+}
+
+impl<B> Service<Request<B>> for HtmplService {
+    type Response = Response<String>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        ready(Ok(self.respond(&request)))
+    }
+}
+
+/// Map a request path to the name of a template.
+fn template_name(templates: &Templates, path: &str) -> String {
+    let name = path.trim_start_matches('/');
+    if name.is_empty() || name.ends_with('/') {
+        format!("{}index.html", name)
+    } else if templates.get(name).is_none() {
+        format!("{}.html", name)
+    } else {
+        name.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Request, StatusCode};
+    use rusqlite::Connection;
+    use tempfile::NamedTempFile;
+    use tower::ServiceExt;
+
+    use super::HtmplService;
+    use crate::{EvaluateOptions, Pool, Templates};
+
+    fn service(db: &NamedTempFile) -> HtmplService {
+        let conn = Connection::open(db.path()).unwrap();
+        conn.execute_batch(
+            r#"
+            CREATE TABLE users (name TEXT NOT NULL, greeting TEXT NOT NULL);
+            INSERT INTO users (name, greeting) VALUES ("cceckman", "hello"), ("ddedkman", "hi");
+            "#,
+        )
+        .unwrap();
+        let mut templates = Templates::new();
+        templates.insert("index.html", "home");
+        templates.insert("users/index.html", "users");
+        templates.insert(
+            "greet.html",
+            r#"<htmpl-query name="q" :name="params(name)">SELECT greeting FROM users WHERE name = :name;</htmpl-query><p><htmpl-insert query="q"></htmpl-insert></p>"#,
+        );
+        let options = EvaluateOptions::new().freshness_query("SELECT 1700000000");
+        HtmplService::new(templates, Pool::new(db.path()), options)
+    }
+
+    async fn get(service: HtmplService, uri: &str) -> (StatusCode, String) {
+        let response = service
+            .oneshot(Request::get(uri).body(()).unwrap())
+            .await
+            .unwrap();
+        (response.status(), response.into_body())
+    }
+
+    #[tokio::test]
+    async fn paths_map_to_templates() {
+        let db = NamedTempFile::new().unwrap();
+        let service = service(&db);
+        assert_eq!(
+            get(service.clone(), "/").await,
+            (StatusCode::OK, "home".to_owned())
+        );
+        assert_eq!(
+            get(service.clone(), "/users/").await,
+            (StatusCode::OK, "users".to_owned())
+        );
+        assert_eq!(
+            get(service.clone(), "/greet?name=cceckman").await,
+            (StatusCode::OK, "<p>hello</p>".to_owned())
+        );
+        assert_eq!(
+            get(service.clone(), "/greet.html?name=ddedkman").await,
+            (StatusCode::OK, "<p>hi</p>".to_owned())
+        );
+        assert_eq!(get(service, "/nope").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_post() {
+        let db = NamedTempFile::new().unwrap();
+        let response = service(&db)
+            .oneshot(Request::post("/").body(()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn conditional_requests() {
+        let db = NamedTempFile::new().unwrap();
+        let service = service(&db);
+        let response = service
+            .clone()
+            .oneshot(Request::get("/").body(()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response.headers()["etag"].clone();
+        let last_modified = response.headers()["last-modified"].clone();
+        assert_eq!(last_modified, "Tue, 14 Nov 2023 22:13:20 GMT");
+
+        let response = service
+            .clone()
+            .oneshot(
+                Request::get("/")
+                    .header("if-none-match", etag.clone())
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], etag);
+
+        let response = service
+            .clone()
+            .oneshot(
+                Request::get("/")
+                    .header("if-modified-since", last_modified)
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = service
+            .oneshot(
+                Request::get("/")
+                    .header("if-modified-since", "Tue, 14 Nov 2023 22:13:19 GMT")
+                    .body(())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
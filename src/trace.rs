@@ -0,0 +1,147 @@
+//! A record of the steps of an evaluation.
+//!
+//! When [tracing is enabled](crate::EvaluateOptions::trace), each element visited, query run,
+//! binding made, and condition checked is recorded, in order, and returned with the
+//! [output of a render](crate::RenderOutput::trace).
+
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use crate::json::json_string;
+
+/// A step in the evaluation of a template.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceStep {
+    /// An element was visited, at the given path in the template
+    /// (as in [`Error::location`](crate::Error::location)).
+    Element { name: String, path: String },
+    /// An `htmpl-query` was run.
+    Query {
+        name: String,
+        sql: String,
+        duration: Duration,
+        /// How many rows the query returned; None if the query failed.
+        rows: Option<usize>,
+    },
+    /// A name was bound to the results of a query, or to one of their rows.
+    Bind { name: String, rows: usize },
+    /// A row of an `htmpl-foreach` was evaluated, counting from 0.
+    Row { query: String, row: usize },
+    /// A condition (e.g. of an `htmpl-if`) was checked.
+    Branch {
+        element: String,
+        condition: String,
+        taken: bool,
+    },
+}
+
+impl TraceStep {
+    /// Serialize the step as a JSON object, with its kind in the `step` field.
+    pub fn to_json(&self) -> String {
+        match self {
+            TraceStep::Element { name, path } => format!(
+                r#"{{"step":"element","name":{},"path":{}}}"#,
+                json_string(name),
+                json_string(path)
+            ),
+            TraceStep::Query {
+                name,
+                sql,
+                duration,
+                rows,
+            } => format!(
+                r#"{{"step":"query","name":{},"sql":{},"duration_us":{},"rows":{}}}"#,
+                json_string(name),
+                json_string(sql),
+                duration.as_micros(),
+                rows.map(|r| r.to_string())
+                    .unwrap_or_else(|| "null".to_owned())
+            ),
+            TraceStep::Bind { name, rows } => format!(
+                r#"{{"step":"bind","name":{},"rows":{}}}"#,
+                json_string(name),
+                rows
+            ),
+            TraceStep::Row { query, row } => format!(
+                r#"{{"step":"row","query":{},"row":{}}}"#,
+                json_string(query),
+                row
+            ),
+            TraceStep::Branch {
+                element,
+                condition,
+                taken,
+            } => format!(
+                r#"{{"step":"branch","element":{},"condition":{},"taken":{}}}"#,
+                json_string(element),
+                json_string(condition),
+                taken
+            ),
+        }
+    }
+}
+
+/// The steps of an evaluation, in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trace {
+    steps: Vec<TraceStep>,
+}
+
+impl Trace {
+    /// The steps of the evaluation.
+    pub fn steps(&self) -> &[TraceStep] {
+        &self.steps
+    }
+
+    /// Serialize the trace as a JSON array of [steps](TraceStep::to_json).
+    pub fn to_json(&self) -> String {
+        let steps: Vec<String> = self.steps.iter().map(TraceStep::to_json).collect();
+        format!("[{}]", steps.join(","))
+    }
+}
+
+/// Where the steps of an evaluation are recorded, shared between scopes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Recorder(Rc<RefCell<Vec<TraceStep>>>);
+
+impl Recorder {
+    pub fn record(&self, step: TraceStep) {
+        self.0.borrow_mut().push(step)
+    }
+
+    /// The steps recorded so far.
+    pub fn trace(&self) -> Trace {
+        Trace {
+            steps: self.0.borrow().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Trace, TraceStep};
+
+    #[test]
+    fn json() {
+        let trace = Trace {
+            steps: vec![
+                TraceStep::Query {
+                    name: "q".to_owned(),
+                    sql: "SELECT \"<\";".to_owned(),
+                    duration: Duration::from_micros(12),
+                    rows: None,
+                },
+                TraceStep::Branch {
+                    element: "htmpl-if".to_owned(),
+                    condition: "q(x)".to_owned(),
+                    taken: true,
+                },
+            ],
+        };
+        assert_eq!(
+            trace.to_json(),
+            r#"[{"step":"query","name":"q","sql":"SELECT \"\u003c\";","duration_us":12,"rows":null},{"step":"branch","element":"htmpl-if","condition":"q(x)","taken":true}]"#
+        );
+    }
+}
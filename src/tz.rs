@@ -0,0 +1,77 @@
+//! Conversion of timestamps from the database into the viewer's time zone.
+
+use jiff::{civil::DateTime, tz::TimeZone, SignedDuration, Timestamp};
+use rusqlite::types::Value;
+
+use crate::Error;
+
+/// The default format of converted timestamps, e.g. `2024-03-10 03:30:00 EDT`.
+pub(crate) const DEFAULT_FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+/// Interpret a value from the database as a point in time.
+///
+/// Text is a date and time as SQLite's date and time functions write it
+/// (`2024-03-10 08:30:00`, in UTC), or with an explicit offset (`2024-03-10T08:30:00-05:00`).
+/// Numbers are Unix timestamps, in seconds.
+fn timestamp(value: &Value) -> Option<Timestamp> {
+    match value {
+        Value::Integer(i) => Timestamp::from_second(*i).ok(),
+        Value::Real(f) => SignedDuration::try_from_secs_f64(*f)
+            .ok()
+            .and_then(|d| Timestamp::from_duration(d).ok()),
+        Value::Text(t) => t.parse::<Timestamp>().ok().or_else(|| {
+            let datetime: DateTime = t.parse().ok()?;
+            Some(datetime.to_zoned(TimeZone::UTC).ok()?.timestamp())
+        }),
+        Value::Null | Value::Blob(_) => None,
+    }
+}
+
+/// Look up a time zone by its name in the tz database, e.g. `America/New_York`.
+pub(crate) fn zone(element: &'static str, name: &str) -> Result<TimeZone, Error> {
+    TimeZone::get(name)
+        .map_err(|_| Error::InvalidParameter(element.into(), format!("tz={:?}", name)))
+}
+
+/// Format the timestamp in the time zone, per the `strftime`-style format.
+pub(crate) fn convert(
+    element: &'static str,
+    value: &Value,
+    tz: &TimeZone,
+    format: &str,
+) -> Result<String, Error> {
+    let timestamp = timestamp(value).ok_or_else(|| {
+        Error::InvalidParameter(element.into(), format!("{:?} is not a timestamp", value))
+    })?;
+    let zoned = timestamp.to_zoned(tz.clone());
+    jiff::fmt::strtime::format(format, &zoned)
+        .map_err(|_| Error::InvalidParameter(element.into(), format!("tz-format={:?}", format)))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+
+    use super::{convert, zone, DEFAULT_FORMAT};
+
+    #[test]
+    fn dst() {
+        let new_york = zone("htmpl-insert", "America/New_York").unwrap();
+        let convert = |value: Value| convert("htmpl-insert", &value, &new_york, DEFAULT_FORMAT);
+        // Before and after the change to daylight saving time, on 2024-03-10.
+        assert_eq!(
+            convert(Value::Text("2024-03-10 06:30:00".to_owned())).unwrap(),
+            "2024-03-10 01:30:00 EST"
+        );
+        assert_eq!(
+            convert(Value::Text("2024-03-10T07:30:00Z".to_owned())).unwrap(),
+            "2024-03-10 03:30:00 EDT"
+        );
+        assert_eq!(
+            convert(Value::Integer(1710055800)).unwrap(),
+            "2024-03-10 03:30:00 EDT"
+        );
+        assert!(convert(Value::Text("yesterday".to_owned())).is_err());
+        assert!(zone("htmpl-insert", "Nowhere/Special").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! Escaping of query values that are used as URLs.
+//!
+//! Query values added to URL-valued attributes (`href`, `src`, and the like) are
+//! percent-encoded where they contain characters that can't appear in a URL,
+//! and, if the [options](crate::EvaluateOptions::reject_unsafe_urls) ask,
+//! URLs with script-bearing schemes are replaced with a harmless one.
+
+/// Attributes whose values are URLs.
+const URL_ATTRS: &[&str] = &[
+    "action",
+    "background",
+    "cite",
+    "data",
+    "formaction",
+    "href",
+    "icon",
+    "manifest",
+    "ping",
+    "poster",
+    "src",
+    "xlink:href",
+    // htmx request attributes
+    "hx-delete",
+    "hx-get",
+    "hx-patch",
+    "hx-post",
+    "hx-push-url",
+    "hx-put",
+    "hx-replace-url",
+];
+
+/// Schemes that run script (or embed arbitrary documents) when navigated to.
+const UNSAFE_SCHEMES: &[&str] = &["javascript", "vbscript", "data"];
+
+/// The replacement for an unsafe URL.
+pub(crate) const INVALID_URL: &str = "about:invalid#htmpl";
+
+/// Returns true if the attribute holds a URL.
+pub(crate) fn is_url_attr(name: &str) -> bool {
+    URL_ATTRS.iter().any(|a| a.eq_ignore_ascii_case(name))
+}
+
+/// Returns true if the URL uses a scheme that can run script.
+fn is_unsafe(url: &str) -> bool {
+    // Browsers ignore leading spaces and control characters, and tabs and newlines anywhere.
+    let url: String = url
+        .trim_start_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .collect();
+    url.split_once(':').is_some_and(|(scheme, _)| {
+        UNSAFE_SCHEMES
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(scheme))
+    })
+}
+
+/// Escape a value for use as a URL: percent-encode the characters that can't appear in a URL,
+/// leaving delimiters (and existing escapes) in place.
+///
+/// If `reject_unsafe` is set, URLs with a script-bearing scheme are replaced with [`INVALID_URL`].
+pub(crate) fn escape(url: &str, reject_unsafe: bool) -> String {
+    if reject_unsafe && is_unsafe(url) {
+        tracing::warn!("replacing unsafe URL {:?}", url);
+        return INVALID_URL.to_owned();
+    }
+    let mut out = String::with_capacity(url.len());
+    for c in url.chars() {
+        if c.is_ascii_alphanumeric() || ";,/?:@&=+$-_.!~*'()#%[]".contains(c) {
+            out.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for b in c.encode_utf8(&mut buf).bytes() {
+                out.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    out
+}
+
+/// Returns true if the URL has a scheme, e.g. `https:`.
+pub(crate) fn has_scheme(url: &str) -> bool {
+    url.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+/// Resolve a URL against an absolute base URL, e.g. `https://example.com/blog/`.
+///
+/// URLs with a scheme are returned as-is; protocol-relative URLs take the base's scheme;
+/// absolute paths are resolved against the base's origin; and other URLs (including
+/// fragments and queries) are appended to the base's path, up to its last `/`.
+pub(crate) fn resolve(base: &str, url: &str) -> String {
+    if has_scheme(url) {
+        return url.to_owned();
+    }
+    let (scheme, rest) = base.split_once("://").unwrap_or(("https", base));
+    if let Some(url) = url.strip_prefix("//") {
+        return format!("{}://{}", scheme, url);
+    }
+    let origin_len = rest.find('/').unwrap_or(rest.len());
+    let origin = &rest[..origin_len];
+    if url.starts_with('/') {
+        return format!("{}://{}{}", scheme, origin, url);
+    }
+    let path = &rest[origin_len..];
+    let dir = match path.rfind('/') {
+        Some(i) => &path[..=i],
+        None => "/",
+    };
+    format!("{}://{}{}{}", scheme, origin, dir, url)
+}
+
+/// Rebase a URL for a site deployed under the base URL, e.g. `https://example.com/blog/` or `/blog/`.
+///
+/// URLs that are absolute paths (e.g. `/img/a.png`) are moved under the base
+/// (`/blog/img/a.png`). If the base is an absolute URL, other relative URLs are resolved
+/// against it. URLs with a scheme or host, and fragment-only URLs, are unchanged.
+pub(crate) fn rebase(base: &str, url: &str) -> Option<String> {
+    if url.is_empty() || url.starts_with('#') || url.starts_with("//") || has_scheme(url) {
+        return None;
+    }
+    if let Some(path) = url.strip_prefix('/') {
+        return Some(format!("{}/{}", base.trim_end_matches('/'), path));
+    }
+    has_scheme(base).then(|| resolve(base, url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, is_url_attr, rebase, resolve, INVALID_URL};
+
+    #[test]
+    fn rebases() {
+        let base = "https://example.com/blog/";
+        assert_eq!(
+            rebase(base, "post.html").unwrap(),
+            "https://example.com/blog/post.html"
+        );
+        assert_eq!(
+            rebase(base, "/img/a.png").unwrap(),
+            "https://example.com/blog/img/a.png"
+        );
+        assert_eq!(rebase(base, "#top"), None);
+        assert_eq!(rebase(base, "mailto:a@example.com"), None);
+        assert_eq!(rebase("/blog/", "/img/a.png").unwrap(), "/blog/img/a.png");
+        assert_eq!(rebase("/blog", "/img/a.png").unwrap(), "/blog/img/a.png");
+        assert_eq!(rebase("/blog/", "post.html"), None);
+    }
+
+    #[test]
+    fn resolves() {
+        let base = "https://example.com/blog/index.html";
+        assert_eq!(
+            resolve(base, "post.html"),
+            "https://example.com/blog/post.html"
+        );
+        assert_eq!(resolve(base, "/img/a.png"), "https://example.com/img/a.png");
+        assert_eq!(
+            resolve(base, "//cdn.example/a.png"),
+            "https://cdn.example/a.png"
+        );
+        assert_eq!(
+            resolve(base, "http://other.example/"),
+            "http://other.example/"
+        );
+        assert_eq!(resolve("https://example.com", "a"), "https://example.com/a");
+    }
+
+    #[test]
+    fn encodes() {
+        assert_eq!(escape("/posts?q=a b", false), "/posts?q=a%20b");
+        assert_eq!(escape("/caf\u{e9}\"", false), "/caf%C3%A9%22");
+        assert_eq!(escape("/a%20b#top", false), "/a%20b#top");
+    }
+
+    #[test]
+    fn rejects() {
+        assert_eq!(escape("javascript:alert(1)", true), INVALID_URL);
+        assert_eq!(escape(" JavaScript:alert(1)", true), INVALID_URL);
+        assert_eq!(escape("java\tscript:alert(1)", true), INVALID_URL);
+        assert_eq!(escape("data:text/html,hi", true), INVALID_URL);
+        assert_eq!(escape("javascript:alert(1)", false), "javascript:alert(1)");
+        assert_eq!(escape("/javascript:x", true), "/javascript:x");
+        assert_eq!(escape("data", true), "data");
+        assert_eq!(escape("https://example.com", true), "https://example.com");
+    }
+
+    #[test]
+    fn attrs() {
+        assert!(is_url_attr("href"));
+        assert!(is_url_attr("SRC"));
+        assert!(is_url_attr("hx-get"));
+        assert!(!is_url_attr("title"));
+    }
+}
@@ -1,18 +1,33 @@
 //! Visitor for an HTML tree.
 
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc, sync::Arc};
 
-use crate::queries::{Attribute, DbTable, Scope};
+use crate::components;
+use crate::css;
+use crate::diagnostics;
+use crate::elements::evaluate_children;
+use crate::filters;
+use crate::functions;
+use crate::head;
+use crate::integrity;
+use crate::json;
+use crate::names;
+use crate::options::{Comments, EvaluateOptions, OutputFormat, Prelude, Truthiness};
+use crate::params::{describe, HTMPL_QUERY, PARAMS_QUERY};
+use crate::parse;
+use crate::postprocess;
+use crate::prefix;
+use crate::queries::{self, row_columns, AttrMode, Attribute, DbTable, Scope, Specifier};
+use crate::serialize::serialize_children;
+use crate::trace::{Trace, TraceStep};
+use crate::tz;
+use crate::urls;
+use crate::RenderOutput;
 use ego_tree::{NodeMut, NodeRef};
-use html5ever::{
-    local_name, ns,
-    serialize::{SerializeOpts, TraversalScope},
-    tokenizer::TokenizerOpts,
-    tree_builder::TreeBuilderOpts,
-    QualName,
-};
+use html5ever::{local_name, namespace_url, ns, QualName};
 use rusqlite::types::{Value, ValueRef};
 use scraper::{selectable::Selectable, ElementRef, Node, Selector};
+use sha2::{Digest, Sha256};
 
 use crate::Error;
 
@@ -27,11 +42,30 @@ fn truthy(v: ValueRef) -> bool {
     }
 }
 
+/// Returns the truthiness of the database value, per the [`Truthiness`] rules;
+/// None if the value can't be a condition under those rules.
+fn truthy_per(truthiness: Truthiness, v: ValueRef) -> Option<bool> {
+    match (truthiness, v) {
+        (_, ValueRef::Null) => Some(false),
+        (Truthiness::StrictInteger, ValueRef::Integer(i)) => Some(i != 0),
+        (Truthiness::StrictInteger, _) => None,
+        (Truthiness::TextFlags, ValueRef::Text(s)) => {
+            let s = String::from_utf8_lossy(s);
+            let s = s.trim();
+            let falsy = ["", "0", "false", "no", "off"]
+                .iter()
+                .any(|flag| s.eq_ignore_ascii_case(flag));
+            Some(!falsy)
+        }
+        (_, v) => Some(truthy(v)),
+    }
+}
+
 /// Recursive "visit" function.
 ///
 /// Evaluates the source node in the provided scope,
 /// adding elements under output_parent as needed.
-fn visit_recurse(
+pub(crate) fn visit_recurse(
     scope: &mut Scope,
     source: NodeRef<Node>,
     output_parent: &mut NodeMut<Node>,
@@ -40,14 +74,61 @@ fn visit_recurse(
         visit_element(scope, eref, output_parent)
     } else {
         let mut new = output_parent.append(source.value().clone());
-        let mut scope = scope.push();
+        let mut inner = scope.push();
         for child in source.children() {
-            visit_recurse(&mut scope, child, &mut new)?;
+            visit_recurse(&mut inner, child, &mut new)?;
         }
+        scope.adopt(inner);
         Ok(())
     }
 }
 
+/// Copy a (non-htmpl) element to the output, with the attributes that apply to it,
+/// and evaluate its children.
+fn copy_element(
+    scope: &mut Scope,
+    source: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let mut new = source.value().clone();
+    let mut content = None;
+    // TODO: Consider constructing the qualified Attribute in the -attr element, and
+    // cloning it here; that should do less string-cloning up-front
+    for new_attr in scope.get_attrs(source.id()) {
+        if new_attr.mode == AttrMode::Content {
+            content = Some(new_attr.value.clone());
+            continue;
+        }
+        let name = names::attr_name(&new.name, &new_attr.name);
+        let value = match (new_attr.mode, new.attrs.get(&name)) {
+            (AttrMode::Remove, _) => {
+                new.attrs.shift_remove(&name);
+                continue;
+            }
+            (AttrMode::Append, Some(old)) => append_tokens(old, &new_attr.value),
+            _ => new_attr.value.clone(),
+        };
+        new.attrs.insert(name, value.into());
+    }
+    // TODO: Actually add the new attributes to the element?
+    stamp_nonce(scope.options(), &mut new);
+    stamp_integrity(scope.options(), &mut new).and_then(|()| {
+        let mut new = output_parent.append(Node::Element(new));
+        if let Some(text) = content {
+            new.append(Node::Text(scraper::node::Text { text: text.into() }));
+            return Ok(());
+        }
+        // Patch attributes.
+        // Insert self, then recurse in a new scope.
+        let mut inner = scope.push();
+        for child in source.children() {
+            visit_recurse(&mut inner, child, &mut new)?;
+        }
+        scope.adopt(inner);
+        Ok(())
+    })
+}
+
 /// Visit an element node in the tree.
 /// Delegates to specialized functions for htmpl-* elements.
 fn visit_element(
@@ -55,40 +136,312 @@ fn visit_element(
     source: ElementRef,
     output_parent: &mut NodeMut<Node>,
 ) -> Result<(), Error> {
-    tracing::debug!("element: {}", source.value().name.local.as_ref());
-    match source.value().name.local.as_ref() {
-        "htmpl-foreach" => visit_foreach(scope, source, output_parent),
-        "htmpl-insert" => {
-            let content = visit_insert(scope, source)?;
-            output_parent.append(Node::Text(scraper::node::Text {
-                text: content.into(),
-            }));
-            Ok(())
-        }
-        "htmpl-query" => scope.do_query(source),
-        "htmpl-if" => visit_if(scope, source, output_parent),
-        "htmpl-attr" => visit_attr(scope, source),
-        _ => {
-            let mut new = source.value().clone();
-            // TODO: Consider constructing the qualified Attribute in the -attr element, and
-            // cloning it here; that should do less string-cloning up-front
-            for new_attr in scope.get_attrs(source.id()) {
-                new.attrs.insert(
-                    QualName::new(None, "".into(), new_attr.name.clone().into()),
-                    new_attr.value.clone().into(),
-                );
-            }
-            // TODO: Actually add the new attributes to the element?
-
-            let mut new = output_parent.append(Node::Element(new));
-            // Patch attributes.
-            // Insert self, then recurse in a new scope.
-            let mut scope = scope.push();
-            for child in source.children() {
-                visit_recurse(&mut scope, child, &mut new)?;
+    let name = source.value().name.local.as_ref();
+    tracing::debug!("element: {}", name);
+    scope.record(|| TraceStep::Element {
+        name: prefix::display(scope.options(), name),
+        path: breadcrumbs(scope, source),
+    });
+    if scope.options().partial && is_deferred(scope.options(), source) {
+        copy_deferred(scope.options(), source, output_parent);
+        return Ok(());
+    }
+    let result =
+        match name {
+            "htmpl-foreach" => visit_foreach(scope, source, output_parent),
+            "htmpl-insert" => visit_insert(scope, source).map(|content| {
+                let query = source.value().attr("query").unwrap_or_default();
+                let annotated = scope.options().annotate;
+                if annotated {
+                    append_annotation(output_parent, &provenance(scope, query));
+                }
+                match source.value().attr("highlight") {
+                    Some(wrapper) => {
+                        append_highlighted(scope.options(), output_parent, &content, wrapper)
+                    }
+                    None if source.value().attr("html").is_some() => {
+                        append_html(output_parent, &content)
+                    }
+                    None => {
+                        output_parent.append(Node::Text(scraper::node::Text {
+                            text: content.into(),
+                        }));
+                    }
+                }
+                if annotated {
+                    append_annotation(output_parent, "/htmpl");
+                }
+            }),
+            "htmpl-query" => scope.do_query(source),
+            "htmpl-if" => visit_if(scope, source, output_parent),
+            "htmpl-attr" => visit_attr(scope, source)
+                .and_then(|()| evaluate_children(source, scope, output_parent)),
+            "htmpl-block" => visit_block(scope, source, output_parent),
+            "htmpl-hx" => visit_hx(scope, source)
+                .and_then(|()| evaluate_children(source, scope, output_parent)),
+            "htmpl-json" => visit_json(scope, source, output_parent),
+            "htmpl-dataset" => visit_dataset(scope, source)
+                .and_then(|()| evaluate_children(source, scope, output_parent)),
+            "htmpl-table" => visit_table(scope, source, output_parent),
+            "htmpl-options" => visit_options(scope, source, output_parent),
+            "htmpl-form" => visit_form(scope, source),
+            "htmpl-csrf" => visit_csrf(scope, source, output_parent),
+            "htmpl-style" => visit_style(scope, source, output_parent),
+            "htmpl-scoped" => visit_scoped(scope, source, output_parent),
+            "htmpl-title" | "htmpl-meta" => visit_head(scope, source, output_parent),
+            "htmpl-og" => visit_og(scope, source, output_parent),
+            "htmpl-t" => visit_t(scope, source, output_parent),
+            "htmpl-let" => visit_let(scope, source),
+            "htmpl-with" => visit_with(scope, source, output_parent),
+            "htmpl-class" => visit_class(scope, source)
+                .and_then(|()| evaluate_children(source, scope, output_parent)),
+            "htmpl-define" | "htmpl-comment" => Ok(()),
+            "htmpl-slot" => components::slot(scope, source, output_parent),
+            "htmpl-macro" => visit_macro(scope, source),
+            "htmpl-call" => components::call(scope, source, output_parent),
+            "htmpl-verbatim" => {
+                for child in source.children() {
+                    copy_subtree(child, output_parent);
+                }
+                Ok(())
             }
+            _ => match scope.options().elements.get(name) {
+                Some(handler) => handler.handle(source, scope, output_parent),
+                None => match scope
+                    .component(name)
+                    .and_then(|id| ElementRef::wrap(source.tree().get(id)?))
+                {
+                    Some(define) => components::instantiate(scope, define, source, output_parent),
+                    None => copy_element(scope, source, output_parent),
+                },
+            },
+        };
+    match result.map_err(|e| prefix::error(scope.options(), e)) {
+        // Errors in writing output can't be shown in the output.
+        Err(e) if scope.options().inline_errors && !matches!(e, Error::Serialize(_)) => {
+            let name = prefix::display(scope.options(), name);
+            tracing::error!("error evaluating {}: {}", name, e);
+            append_error(output_parent, &name, &e);
+            scope.note_inline_error();
             Ok(())
         }
+        Err(e @ Error::At(_, _)) => Err(e),
+        Err(e) => Err(Error::At(breadcrumbs(scope, source), Box::new(e))),
+        result => result,
+    }
+}
+
+/// The path to the element in the template, e.g. `body > div.card:nth-child(2) > htmpl-insert`,
+/// including the row of each `htmpl-foreach` that is being evaluated.
+fn breadcrumbs(scope: &Scope, element: ElementRef) -> String {
+    let mut path: Vec<String> = element
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        // Skip the synthetic html element that wraps a fragment.
+        .filter(|e| {
+            !e.parent()
+                .is_some_and(|p| matches!(p.value(), Node::Fragment))
+        })
+        .map(|e| breadcrumb(scope, e))
+        .collect();
+    path.reverse();
+    path.push(breadcrumb(scope, element));
+    path.join(" > ")
+}
+
+/// A single step in the [`breadcrumbs`] to an element.
+fn breadcrumb(scope: &Scope, element: ElementRef) -> String {
+    let mut step = prefix::display(scope.options(), element.value().name());
+    if let Some(id) = element.value().id() {
+        step += &format!("#{}", id);
+    }
+    for class in element
+        .value()
+        .attr("class")
+        .unwrap_or_default()
+        .split_ascii_whitespace()
+    {
+        step += &format!(".{}", class);
+    }
+    let siblings = element
+        .parent()
+        .map(|p| p.children().filter(|c| c.value().is_element()).count())
+        .unwrap_or(0);
+    if siblings > 1 {
+        let n = element
+            .prev_siblings()
+            .filter(|c| c.value().is_element())
+            .count()
+            + 1;
+        step += &format!(":nth-child({})", n);
+    }
+    if let Some(row) = scope.row(element.id()) {
+        step += &format!(" (row {})", row + 1);
+    }
+    step
+}
+
+/// The htmpl elements that are evaluated.
+pub(crate) const ELEMENTS: &[&str] = &[
+    "htmpl-foreach",
+    "htmpl-insert",
+    "htmpl-query",
+    "htmpl-if",
+    "htmpl-attr",
+    "htmpl-block",
+    "htmpl-hx",
+    "htmpl-json",
+    "htmpl-dataset",
+    "htmpl-table",
+    "htmpl-options",
+    "htmpl-form",
+    "htmpl-csrf",
+    "htmpl-class",
+    "htmpl-style",
+    "htmpl-scoped",
+    "htmpl-title",
+    "htmpl-meta",
+    "htmpl-og",
+    "htmpl-t",
+    "htmpl-let",
+    "htmpl-with",
+    "htmpl-define",
+    "htmpl-slot",
+    "htmpl-macro",
+    "htmpl-call",
+    "htmpl-verbatim",
+    "htmpl-comment",
+];
+
+/// Returns true if the node is within an `htmpl-verbatim` or `htmpl-comment` element,
+/// so isn't evaluated.
+pub(crate) fn is_inert(node: NodeRef<Node>) -> bool {
+    node.ancestors().any(|a| {
+        a.value()
+            .as_element()
+            .is_some_and(|e| matches!(e.name(), "htmpl-verbatim" | "htmpl-comment"))
+    })
+}
+
+/// Returns true if the element should be left for a later pass, in a partial evaluation:
+/// if it is marked with the `defer` attribute, or is an htmpl element we don't know about.
+fn is_deferred(options: &EvaluateOptions, element: ElementRef) -> bool {
+    let name = element.value().name.local.as_ref();
+    let known = ELEMENTS.contains(&name) || options.elements.get(name).is_some();
+    let htmpl = name.starts_with("htmpl-")
+        || options
+            .prefix
+            .as_ref()
+            .is_some_and(|prefix| name.starts_with(prefix.as_str()));
+    (htmpl && !known) || (known && element.value().attr("defer").is_some())
+}
+
+/// Copy a deferred element and its descendants to the output, as written.
+fn copy_deferred(
+    options: &EvaluateOptions,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) {
+    let mut new = element.value().clone();
+    new.name.local = prefix::display(options, element.value().name()).into();
+    new.attrs.retain(|name, _| name.local.as_ref() != "defer");
+    let mut new = output_parent.append(Node::Element(new));
+    for child in element.children() {
+        copy_subtree(child, &mut new);
+    }
+}
+
+/// Copy the node and its descendants to the output, without evaluating them.
+fn copy_subtree(source: NodeRef<Node>, output_parent: &mut NodeMut<Node>) {
+    let mut new = output_parent.append(source.value().clone());
+    for child in source.children() {
+        copy_subtree(child, &mut new);
+    }
+}
+
+/// Append a description of the error to the output, in place of the element that failed.
+fn append_error(output_parent: &mut NodeMut<Node>, element: &str, error: &Error) {
+    let class = html5ever::Attribute {
+        name: QualName::new(None, "".into(), "class".into()),
+        value: "htmpl-error".into(),
+    };
+    let div = scraper::node::Element::new(
+        QualName::new(None, ns!(html), local_name!("div")),
+        vec![class],
+    );
+    output_parent
+        .append(Node::Element(div))
+        .append(Node::Text(scraper::node::Text {
+            text: format!("{}: {}", element, error).into(),
+        }));
+}
+
+/// Describe where a value comes from, for an [annotation](EvaluateOptions::annotate):
+/// the query specifier, and the row of the query being iterated over, if any.
+fn provenance(scope: &Scope, specifier: &str) -> String {
+    let query = Specifier::parse(specifier).map_or(specifier, |s| s.query);
+    match scope.query_row(query) {
+        Some(row) => format!("htmpl: {}, row {}", specifier, row + 1),
+        None => format!("htmpl: {}", specifier),
+    }
+}
+
+/// Append a comment annotating the output.
+fn append_annotation(output_parent: &mut NodeMut<Node>, text: &str) {
+    // "--" would end the comment early.
+    let text = text.replace("--", "- -");
+    output_parent.append(Node::Comment(scraper::node::Comment {
+        comment: format!(" {} ", text).into(),
+    }));
+}
+
+/// Append text with highlighted spans, e.g. from an FTS5 `snippet()` or `highlight()`.
+///
+/// The spans between the highlight delimiters are wrapped in the named element
+/// (`mark`, if empty); everything else, including unmatched delimiters, is inserted as text.
+fn append_highlighted(
+    options: &EvaluateOptions,
+    output_parent: &mut NodeMut<Node>,
+    content: &str,
+    wrapper: &str,
+) {
+    let wrapper = if wrapper.is_empty() { "mark" } else { wrapper };
+    let (start, end) = match &options.highlight_delimiters {
+        Some((start, end)) => (start.clone(), end.clone()),
+        None => (format!("<{}>", wrapper), format!("</{}>", wrapper)),
+    };
+    let ns = match options.format {
+        OutputFormat::Html | OutputFormat::Xhtml => ns!(html),
+        OutputFormat::Xml => ns!(),
+    };
+    let text = |s: &str| Node::Text(scraper::node::Text { text: s.into() });
+
+    let mut rest = content;
+    while let Some((before, after)) = rest.split_once(start.as_str()) {
+        let Some((highlighted, after)) = after.split_once(end.as_str()) else {
+            break;
+        };
+        if !before.is_empty() {
+            output_parent.append(text(before));
+        }
+        let element =
+            scraper::node::Element::new(QualName::new(None, ns.clone(), wrapper.into()), vec![]);
+        output_parent
+            .append(Node::Element(element))
+            .append(text(highlighted));
+        rest = after;
+    }
+    if !rest.is_empty() {
+        output_parent.append(text(rest));
+    }
+}
+
+/// Append the nodes of the HTML fragment, for an htmpl-insert element with the `html` attribute.
+fn append_html(output_parent: &mut NodeMut<Node>, content: &str) {
+    let fragment = scraper::Html::parse_fragment(content);
+    // The content of the fragment is within an `html` element.
+    for child in fragment.root_element().children() {
+        copy_subtree(child, output_parent);
     }
 }
 
@@ -98,11 +451,57 @@ fn visit_insert(scope: &Scope, element: ElementRef) -> Result<String, Error> {
     let query = element
         .value()
         .attr("query")
-        .ok_or(Error::MissingAttr("htmpl-insert", "query"))?;
-    let value = scope
-        .get_single(query)
-        .map_err(|e| e.set_element("htmpl-insert"))?;
-    Ok(format_value(value))
+        .ok_or(Error::MissingAttr("htmpl-insert".into(), "query".into()))?;
+    let default = element.value().attr("default");
+    if let Some(separator) = element.value().attr("join") {
+        let values = scope
+            .get_each(query)
+            .map_err(|e| e.set_element("htmpl-insert"))?;
+        let values = values
+            .iter()
+            .filter(|value| ***value != Value::Null)
+            .map(|value| format_insert(scope, element, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(match default {
+            Some(default) if values.is_empty() => default.to_owned(),
+            _ => values.join(separator),
+        });
+    }
+    // With a default, a missing value (a null, or no row) is not an error;
+    // nor is no row, for an optional insert.
+    let optional = element.value().attr("optional").is_some();
+    let value = match (scope.get_single(query), default) {
+        (Err(Error::Cardinality(_, _, 0, _)), Some(default)) => return Ok(default.to_owned()),
+        (Err(Error::Cardinality(_, _, 0, _)), None) if optional => return Ok(String::new()),
+        (Ok(value), Some(default)) if *value == Value::Null => return Ok(default.to_owned()),
+        (result, _) => result.map_err(|e| e.set_element("htmpl-insert"))?,
+    };
+    format_insert(scope, element, &value)
+}
+
+/// Format a value for an htmpl-insert element, converting it to a time zone per its `tz` attribute.
+fn format_insert(scope: &Scope, element: ElementRef, value: &Value) -> Result<String, Error> {
+    match element.value().attr("tz") {
+        Some(name) if *value != Value::Null => {
+            let zone = match name {
+                "" => scope
+                    .options()
+                    .timezone
+                    .clone()
+                    .ok_or(Error::MissingOption(
+                        "htmpl-insert".into(),
+                        "timezone".into(),
+                    ))?,
+                name => tz::zone("htmpl-insert", name)?,
+            };
+            let format = element
+                .value()
+                .attr("tz-format")
+                .unwrap_or(tz::DEFAULT_FORMAT);
+            tz::convert("htmpl-insert", value, &zone, format)
+        }
+        _ => Ok(format_value(value)),
+    }
 }
 
 /// Visit an htmpl-foreach node.
@@ -115,17 +514,63 @@ fn visit_foreach(
     let query = element
         .value()
         .attr("query")
-        .ok_or(Error::MissingAttr("htmpl-foreach", "query"))?;
-    let it = scope
-        .for_each_row(query)
-        .ok_or(Error::MissingQuery("htmpl-foreach", query.to_owned()))?
-        .enumerate();
-    for (i, mut scope) in it {
+        .ok_or(Error::MissingAttr("htmpl-foreach".into(), "query".into()))?;
+    let column = Specifier::parse(query)
+        .map_err(|e| e.set_element("htmpl-foreach"))?
+        .column;
+    let it = if column.is_some() {
+        // A column: iterate over its JSON contents.
+        scope
+            .for_each_json(query, element.value().attr("as"))
+            .map_err(|e| e.set_element("htmpl-foreach"))?
+    } else {
+        scope.for_each_row(query).ok_or(Error::MissingQuery(
+            "htmpl-foreach".into(),
+            query.to_owned(),
+        ))?
+    }
+    .enumerate();
+    let sink = scope.sink();
+    let annotate = scope.options().annotate;
+    for (i, mut inner) in it {
+        inner.enter_row(element.id(), i);
+        inner.record(|| TraceStep::Row {
+            query: query.to_owned(),
+            row: i,
+        });
         let _iteration = tracing::debug_span!("foreach", "i={}", i).entered();
+        if let Some(sink) = sink {
+            // Streaming: render the iteration on its own, and send it out.
+            // Nested loops are part of this iteration.
+            inner.set_sink(None);
+            let mut iteration = scraper::Html::new_fragment();
+            if annotate {
+                let note = format!("htmpl: {}, row {}", query, i + 1);
+                append_annotation(&mut iteration.tree.root_mut(), &note);
+            }
+            for child in element.children() {
+                visit_recurse(&mut inner, child, &mut iteration.tree.root_mut())?;
+            }
+            if annotate {
+                append_annotation(&mut iteration.tree.root_mut(), "/htmpl");
+            }
+            let content =
+                serialize_children(iteration.tree.root(), sink.output_format(), inner.options());
+            sink.emit(element.value().attr("event"), &content)?;
+            scope.adopt(inner);
+            continue;
+        }
         // rows * children:
+        if annotate {
+            append_annotation(output_parent, &format!("htmpl: {}, row {}", query, i + 1));
+        }
         for child in element.children() {
-            visit_recurse(&mut scope, child, output_parent)?;
+            visit_recurse(&mut inner, child, output_parent)?;
+        }
+        if annotate {
+            append_annotation(output_parent, "/htmpl");
         }
+        scope.adopt(inner);
     }
     Ok(())
 }
@@ -143,63 +588,1191 @@ fn visit_if(
         return Err(Error::MultipleConditions(format!("{:?}", element)));
     }
 
-    let specifier = t
-        .or(f)
-        .ok_or(Error::MissingAttr("htmpl-if", "true= or false="))?;
+    let specifier = t.or(f).ok_or(Error::MissingAttr(
+        "htmpl-if".into(),
+        "true= or false=".into(),
+    ))?;
 
+    let truthiness = condition(scope, specifier, "htmpl-if")?;
+
+    if t.is_some() && truthiness || f.is_some() && !truthiness {
+        let mut inner = scope.push();
+        for child in element.children() {
+            visit_recurse(&mut inner, child, output_parent)?;
+        }
+        scope.adopt(inner);
+    }
+
+    Ok(())
+}
+
+/// Returns the truthiness of the value of the specifier.
+fn condition(scope: &Scope, specifier: &str, element: &'static str) -> Result<bool, Error> {
     let maybe = scope
         .get_single(specifier)
-        .map_err(|e| e.set_element("htmpl-if"));
-    let truthiness = match maybe {
+        .map_err(|e| e.set_element(element));
+    let taken = match maybe {
         // A cardinality of 0 is not an error, it's just false.
         Err(Error::Cardinality(_, _, 0, _)) => false,
         Err(e) => return Err(e),
-        Ok(v) => truthy(v.into()),
+        Ok(v) => truthy_per(scope.options().truthiness, (&*v).into()).ok_or_else(|| {
+            Error::NotABoolean(
+                element.into(),
+                specifier.to_owned(),
+                describe(&v, scope.options().redact_params),
+            )
+        })?,
     };
+    scope.record(|| TraceStep::Branch {
+        element: element.to_owned(),
+        condition: specifier.to_owned(),
+        taken,
+    });
+    Ok(taken)
+}
 
-    if t.is_some() && truthiness || f.is_some() && !truthiness {
-        let mut scope = scope.push();
-        for child in element.children() {
-            visit_recurse(&mut scope, child, output_parent)?;
-        }
+/// Returns true if the element's `if=` specifier is truthy, or its `unless=` specifier is falsy,
+/// or it has neither.
+fn conditions_hold(
+    scope: &Scope,
+    element: ElementRef,
+    element_name: &'static str,
+) -> Result<bool, Error> {
+    match (element.value().attr("if"), element.value().attr("unless")) {
+        (Some(_), Some(_)) => Err(Error::MultipleConditions(format!("{:?}", element))),
+        (Some(specifier), None) => condition(scope, specifier, element_name),
+        (None, Some(specifier)) => condition(scope, specifier, element_name).map(|c| !c),
+        (None, None) => Ok(true),
     }
+}
 
+/// Evaluate an htmpl-class element, adding classes to the selected elements.
+///
+/// The `class` attribute lists the classes to add to the elements' existing classes;
+/// the `if=` and `unless=` conditions are as for htmpl-attr.
+fn visit_class(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    let selector = selector(scope.options(), element, "htmpl-class")?;
+    let class = element
+        .value()
+        .attr("class")
+        .ok_or(Error::MissingAttr("htmpl-class".into(), "class".into()))?;
+    if !conditions_hold(scope, element, "htmpl-class")? {
+        return Ok(());
+    }
+    let attr = Rc::new(Attribute {
+        name: "class".to_owned(),
+        value: class.to_owned(),
+        mode: AttrMode::Append,
+    });
+    add_attrs(scope, element, "htmpl-class", &selector, &[attr])?;
     Ok(())
 }
 
 /// Evaluate an htmpl-attr element.
+///
+/// With the `remove` attribute, the attribute is removed from the selected elements
+/// (and no query is needed). With the `boolean` attribute, the attribute is added with no value
+/// if the query value is truthy, and removed if it is falsy.
+/// If the element has an `if=` or `unless=` specifier, the attribute is only added
+/// if the specified value is truthy, or falsy, respectively.
+/// With the `optional` attribute, a query with no rows adds nothing, rather than failing.
 fn visit_attr(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    if !conditions_hold(scope, element, "htmpl-attr")? {
+        return Ok(());
+    }
+    let selector = selector(scope.options(), element, "htmpl-attr")?;
+    let attr = element
+        .value()
+        .attr("attr")
+        .ok_or(Error::MissingAttr("htmpl-attr".into(), "attr".into()))?;
+    let (mode, value) = if element.value().attr("remove").is_some() {
+        (AttrMode::Remove, String::new())
+    } else if element.value().attr("boolean").is_some() {
+        let query = element
+            .value()
+            .attr("query")
+            .ok_or(Error::MissingAttr("htmpl-attr".into(), "query".into()))?;
+        if condition(scope, query, "htmpl-attr")? {
+            (AttrMode::Set, String::new())
+        } else {
+            (AttrMode::Remove, String::new())
+        }
+    } else {
+        let query = element
+            .value()
+            .attr("query")
+            .ok_or(Error::MissingAttr("htmpl-attr".into(), "query".into()))?;
+        let value = match scope.get_single(query) {
+            Err(Error::Cardinality(_, _, 0, _)) if element.value().attr("optional").is_some() => {
+                return Ok(())
+            }
+            result => result.map_err(|e| e.set_element("htmpl-attr"))?,
+        };
+        let mode = if element.value().attr("append").is_some() {
+            AttrMode::Append
+        } else {
+            AttrMode::Set
+        };
+        (mode, attr_value(scope.options(), attr, &value))
+    };
+    let attr = Rc::new(Attribute {
+        name: attr.to_owned(),
+        value,
+        mode,
+    });
+    add_attrs(scope, element, "htmpl-attr", &selector, &[attr])?;
+    Ok(())
+}
+
+/// Add the space-separated tokens that aren't already in the existing value.
+fn append_tokens(existing: &str, tokens: &str) -> String {
+    let mut value = existing.trim().to_owned();
+    for token in tokens.split_ascii_whitespace() {
+        if !value.split_ascii_whitespace().any(|t| t == token) {
+            if !value.is_empty() {
+                value.push(' ');
+            }
+            value.push_str(token);
+        }
+    }
+    value
+}
+
+/// Evaluate an htmpl-hx element, adding htmx attributes to the selected elements.
+///
+/// Each attribute other than `select=` and `scope=` is added with an `hx-` prefix.
+/// Attributes named with a leading `:` are specifiers, and are replaced with the query value;
+/// others are added verbatim.
+fn visit_hx(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    let selector = selector(scope.options(), element, "htmpl-hx")?;
+    let mut attrs = Vec::new();
+    for (name, value) in element.value().attrs() {
+        if name == "select" || name == "scope" {
+            continue;
+        }
+        let attr = match name.strip_prefix(':') {
+            Some(name) => {
+                let value = scope
+                    .get_single(value)
+                    .map_err(|e| e.set_element("htmpl-hx"))?;
+                let name = format!("hx-{}", name);
+                let value = attr_value(scope.options(), &name, &value);
+                Attribute {
+                    name,
+                    value,
+                    mode: AttrMode::Set,
+                }
+            }
+            None => Attribute {
+                name: format!("hx-{}", name),
+                value: value.to_owned(),
+                mode: AttrMode::Set,
+            },
+        };
+        attrs.push(Rc::new(attr));
+    }
+    add_attrs(scope, element, "htmpl-hx", &selector, &attrs)?;
+    Ok(())
+}
+
+/// Evaluate an htmpl-dataset element,
+/// adding a `data-*` attribute to the selected elements for each column of a single-row query.
+///
+/// Attribute names are the lowercased column names, in the order of the query's columns;
+/// null values are skipped.
+fn visit_dataset(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    let query = element
+        .value()
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-dataset".into(), "query".into()))?;
+    let selector = selector(scope.options(), element, "htmpl-dataset")?;
+    let rows = scope
+        .get(query)
+        .map_err(|e| e.set_element("htmpl-dataset"))?;
+    let row = match rows.as_slice() {
+        [row] => row,
+        _ => {
+            return Err(Error::Cardinality(
+                "htmpl-dataset".into(),
+                query.to_owned(),
+                rows.len(),
+                1,
+            ))
+        }
+    };
+    let attrs: Vec<_> = row_columns(row, scope.columns(query).unwrap_or_default())
+        .into_iter()
+        .filter(|(_, value)| **value != Value::Null)
+        .map(|(name, value)| {
+            Rc::new(Attribute {
+                name: format!("data-{}", name.to_lowercase()),
+                value: format_value(value),
+                mode: AttrMode::Set,
+            })
+        })
+        .collect();
+    add_attrs(scope, element, "htmpl-dataset", &selector, &attrs)?;
+    Ok(())
+}
+
+/// Evaluate an htmpl-form element, filling the controls of the selected forms from a single-row query.
+///
+/// Each `input`, `select`, or `textarea` with a `name` matching a column of the query is filled in:
+/// - checkboxes and radio buttons are `checked` if their `value` matches the column
+///   (or, without a `value`, if the column is truthy);
+/// - the `option`s of a `select` are `selected` if their value matches the column;
+/// - a `textarea`'s text is replaced with the column;
+/// - other inputs, except passwords and files, have their `value` set to the column.
+///
+/// Null columns are skipped.
+fn visit_form(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    let query = element
+        .value()
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-form".into(), "query".into()))?;
+    let selector = selector(scope.options(), element, "htmpl-form")?;
+    let rows = scope.get(query).map_err(|e| e.set_element("htmpl-form"))?;
+    let row = match rows.as_slice() {
+        [row] => row,
+        _ => {
+            return Err(Error::Cardinality(
+                "htmpl-form".into(),
+                query.to_owned(),
+                rows.len(),
+                1,
+            ))
+        }
+    };
+    let root = select_root(element, "htmpl-form")?;
+
+    let selectors = &scope.options().selectors;
+    let controls = selectors
+        .parse("input[name], select[name], textarea[name]")
+        .expect("invalid selector");
+    let options = selectors.parse("option").expect("invalid selector");
+    let attr = |name: &str, value: String| {
+        Rc::new(Attribute {
+            name: name.to_owned(),
+            value,
+            mode: AttrMode::Set,
+        })
+    };
+    let mut attrs = Vec::new();
+    for form in root.select(&selector) {
+        for control in form.select(&controls) {
+            let name = control.value().attr("name").unwrap_or_default();
+            let column = row.get(name);
+            let Some(value) = column.filter(|v| **v != Value::Null) else {
+                continue;
+            };
+            let text = format_value(value);
+            if control.value().name() == "textarea" {
+                let content = Attribute {
+                    name: String::new(),
+                    value: text,
+                    mode: AttrMode::Content,
+                };
+                attrs.push((control, Rc::new(content)));
+                continue;
+            }
+            if control.value().name() == "select" {
+                for option in control.select(&options) {
+                    let option_value = match option.value().attr("value") {
+                        Some(v) => v.to_owned(),
+                        None => option.text().collect::<String>().trim().to_owned(),
+                    };
+                    if option_value == text {
+                        attrs.push((option, attr("selected", String::new())));
+                    }
+                }
+                continue;
+            }
+            let kind = control
+                .value()
+                .attr("type")
+                .unwrap_or("text")
+                .to_ascii_lowercase();
+            match kind.as_str() {
+                "checkbox" | "radio" => {
+                    let checked = match control.value().attr("value") {
+                        Some(v) => v == text,
+                        None => truthy_per(scope.options().truthiness, value.into()).ok_or_else(
+                            || {
+                                Error::NotABoolean(
+                                    "htmpl-form".into(),
+                                    format!("{}({})", query, name),
+                                    describe(value, scope.options().redact_params),
+                                )
+                            },
+                        )?,
+                    };
+                    if checked {
+                        attrs.push((control, attr("checked", String::new())));
+                    }
+                }
+                "password" | "file" => {}
+                _ => attrs.push((control, attr("value", text))),
+            }
+        }
+    }
+    for (target, attr) in attrs {
+        bind_attr(scope, element, target, attr);
+    }
+    Ok(())
+}
+
+/// Evaluate an htmpl-csrf element, rendering the CSRF token from the options.
+///
+/// By default, the token is the value of a hidden input, named per the `name` attribute
+/// (default `csrf_token`), for use in a form. With the `meta` attribute, the token is the content
+/// of a `meta` element (default name `csrf-token`), for scripts to use.
+fn visit_csrf(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let token = scope
+        .options()
+        .csrf_token
+        .as_ref()
+        .ok_or(Error::MissingOption(
+            "htmpl-csrf".into(),
+            "csrf_token".into(),
+        ))?
+        .get()();
+    let meta = element.value().attr("meta").is_some();
+    let name =
+        element
+            .value()
+            .attr("name")
+            .unwrap_or(if meta { "csrf-token" } else { "csrf_token" });
+    let attr = |name: &str, value: &str| html5ever::Attribute {
+        name: QualName::new(None, "".into(), name.into()),
+        value: value.into(),
+    };
+    let (tag, attrs) = if meta {
+        (
+            local_name!("meta"),
+            vec![attr("name", name), attr("content", &token)],
+        )
+    } else {
+        (
+            local_name!("input"),
+            vec![
+                attr("type", "hidden"),
+                attr("name", name),
+                attr("value", &token),
+            ],
+        )
+    };
+    let ns = element.value().name.ns.clone();
+    output_parent.append(Node::Element(scraper::node::Element::new(
+        QualName::new(None, ns, tag),
+        attrs,
+    )));
+    Ok(())
+}
+
+/// Evaluate an htmpl-style element, setting CSS custom properties from a single-row query.
+///
+/// Each non-null column becomes a custom property named per [`css::property_name`].
+/// With a `select=` attribute, the properties are set in the `style` attribute of the
+/// selected elements (replacing any existing style); otherwise, they are output in a `style`
+/// element, in a rule for the `rule=` selector (default `:root`).
+fn visit_style(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let query = element
+        .value()
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-style".into(), "query".into()))?;
+    let rows = scope.get(query).map_err(|e| e.set_element("htmpl-style"))?;
+    let row = match rows.as_slice() {
+        [row] => row,
+        _ => {
+            return Err(Error::Cardinality(
+                "htmpl-style".into(),
+                query.to_owned(),
+                rows.len(),
+                1,
+            ))
+        }
+    };
+    let columns = scope.columns(query).unwrap_or_default();
+    let declarations: Vec<String> = columns
+        .iter()
+        .filter_map(|column| match row.get(column) {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(format!(
+                "{}: {};",
+                css::property_name(column),
+                css::escape_value(&format_value(value))
+            )),
+        })
+        .collect();
+
+    if element.value().attr("select").is_some() {
+        let selector = selector(scope.options(), element, "htmpl-style")?;
+        let attr = Rc::new(Attribute {
+            name: "style".to_owned(),
+            value: declarations.join(" "),
+            mode: AttrMode::Set,
+        });
+        return add_attrs(scope, element, "htmpl-style", &selector, &[attr]);
+    }
+
+    let rule = element.value().attr("rule").unwrap_or(":root");
+    let mut style = scraper::node::Element::new(
+        QualName::new(None, element.value().name.ns.clone(), local_name!("style")),
+        vec![],
+    );
+    stamp_nonce(scope.options(), &mut style);
+    output_parent
+        .append(Node::Element(style))
+        .append(Node::Text(scraper::node::Text {
+            text: format!("{} {{ {} }}", rule, declarations.join(" ")).into(),
+        }));
+    Ok(())
+}
+
+/// Evaluate an htmpl-scoped element, suffixing the class names in its contents.
+///
+/// The children are evaluated in place; then each class name in the output, and each class
+/// selector in `style` elements in the output, gets a suffix: the `suffix=` attribute, or by
+/// default, a hash of the element's source. The styles within the element then apply only to
+/// its contents, even if other templates on the page use the same class names.
+fn visit_scoped(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let suffix = match element.value().attr("suffix") {
+        Some(suffix) => suffix.to_owned(),
+        None => {
+            let hash = Sha256::digest(element.html().as_bytes());
+            hash[..3].iter().map(|b| format!("{:02x}", b)).collect()
+        }
+    };
+    let last = output_parent.last_child().map(|n| n.id());
+    evaluate_children(element, scope, output_parent)?;
+
+    let parent = output_parent.id();
+    let tree = output_parent.tree();
+    let new: Vec<_> = tree
+        .get(parent)
+        .into_iter()
+        .flat_map(|parent| parent.children())
+        .skip_while(|child| last.is_some_and(|last| child.id() != last))
+        .skip(usize::from(last.is_some()))
+        .flat_map(|child| child.descendants())
+        .map(|node| node.id())
+        .collect();
+    for id in new {
+        let Some(mut node) = tree.get_mut(id) else {
+            continue;
+        };
+        let in_style = node
+            .parent()
+            .is_some_and(|mut p| matches!(p.value(), Node::Element(e) if e.name() == "style"));
+        match node.value() {
+            Node::Element(e) => {
+                let class = QualName::new(None, "".into(), local_name!("class"));
+                if let Some(classes) = e.attrs.get_mut(&class) {
+                    let suffixed: Vec<_> = classes
+                        .split_ascii_whitespace()
+                        .map(|class| format!("{}-{}", class, suffix))
+                        .collect();
+                    *classes = suffixed.join(" ").into();
+                }
+            }
+            Node::Text(text) if in_style => {
+                text.text = css::suffix_classes(&text.text, &suffix).into();
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate an htmpl-table element, rendering a query's results as a table.
+///
+/// The table has a column for each name in the comma-separated `columns` attribute
+/// (by default, each column of the query, in order), headed by the corresponding name in the
+/// comma-separated `labels` attribute (by default, the column name); and a row for each row
+/// of the query. Null values are empty cells.
+/// Other attributes of the `htmpl-table` element are copied to the `table` element.
+fn visit_table(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let query = element
+        .value()
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-table".into(), "query".into()))?;
+    let rows = scope.get(query).map_err(|e| e.set_element("htmpl-table"))?;
+    let split = |s: &str| -> Vec<String> { s.split(',').map(|s| s.trim().to_owned()).collect() };
+    let columns = match element.value().attr("columns") {
+        Some(columns) => split(columns),
+        None => scope.columns(query).map(<[_]>::to_vec).unwrap_or_default(),
+    };
+    let labels = match element.value().attr("labels") {
+        Some(labels) => split(labels),
+        None => columns.clone(),
+    };
+    if labels.len() != columns.len() {
+        return Err(Error::InvalidParameter(
+            "htmpl-table".into(),
+            "labels".to_owned(),
+        ));
+    }
+    check_columns("htmpl-table", query, rows, &columns)?;
+
+    let ns = &element.value().name.ns;
+    let new = |name: &str| {
+        Node::Element(scraper::node::Element::new(
+            QualName::new(None, ns.clone(), name.into()),
+            vec![],
+        ))
+    };
+    let text = |s: String| Node::Text(scraper::node::Text { text: s.into() });
+
+    let mut table = element.value().clone();
+    table.name = QualName::new(None, ns.clone(), local_name!("table"));
+    table
+        .attrs
+        .retain(|name, _| !["query", "columns", "labels"].contains(&name.local.as_ref()));
+    let mut table = output_parent.append(Node::Element(table));
+    {
+        let mut thead = table.append(new("thead"));
+        let mut tr = thead.append(new("tr"));
+        for label in labels {
+            tr.append(new("th")).append(text(label));
+        }
+    }
+    let mut tbody = table.append(new("tbody"));
+    for row in rows {
+        let mut tr = tbody.append(new("tr"));
+        for column in &columns {
+            let mut td = tr.append(new("td"));
+            match get_column("htmpl-table", query, row, column)? {
+                Value::Null => {}
+                value => {
+                    td.append(text(format_value(value)));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Evaluate an htmpl-options element, rendering a `select` with an `option` for each row of a query.
+///
+/// Each option's value is from the column named in the `value` attribute,
+/// and its text from the column named in the `label` attribute (by default, the value column).
+/// If the `selected` attribute is present, it is a selector; the options whose value matches
+/// the selected value are marked `selected`.
+///
+/// The HTML parser drops unknown elements inside a `select`, so the `htmpl-options` element
+/// stands in for the `select`: its other attributes are copied to the `select` element, and its
+/// children (e.g. a placeholder `option`) are evaluated ahead of the query's options.
+fn visit_options(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
     let query = element
         .value()
         .attr("query")
-        .ok_or(Error::MissingAttr("htmpl-attr", "query"))?;
+        .ok_or(Error::MissingAttr("htmpl-options".into(), "query".into()))?;
+    let value_column = element
+        .value()
+        .attr("value")
+        .ok_or(Error::MissingAttr("htmpl-options".into(), "value".into()))?;
+    let label_column = element.value().attr("label").unwrap_or(value_column);
+    let selected = match element.value().attr("selected") {
+        Some(selector) => {
+            let selected = scope
+                .get_single(selector)
+                .map_err(|e| e.set_element("htmpl-options"))?;
+            Some(format_value(&selected))
+        }
+        None => None,
+    };
+    let rows = scope
+        .get(query)
+        .map_err(|e| e.set_element("htmpl-options"))?;
+    check_columns(
+        "htmpl-options",
+        query,
+        rows,
+        &[value_column.to_owned(), label_column.to_owned()],
+    )?;
+
+    let ns = &element.value().name.ns;
+    let mut select = element.value().clone();
+    select.name = QualName::new(None, ns.clone(), local_name!("select"));
+    select
+        .attrs
+        .retain(|name, _| !["query", "value", "label", "selected"].contains(&name.local.as_ref()));
+    let mut select = output_parent.append(Node::Element(select));
+    let mut inner = scope.push();
+    for child in element.children() {
+        visit_recurse(&mut inner, child, &mut select)?;
+    }
+
+    let name = QualName::new(None, ns.clone(), local_name!("option"));
+    for row in rows {
+        let value = format_value(get_column("htmpl-options", query, row, value_column)?);
+        let mut attrs = vec![html5ever::Attribute {
+            name: QualName::new(None, "".into(), local_name!("value")),
+            value: value.as_str().into(),
+        }];
+        if selected.as_ref() == Some(&value) {
+            attrs.push(html5ever::Attribute {
+                name: QualName::new(None, "".into(), local_name!("selected")),
+                value: "".into(),
+            });
+        }
+        let label = format_value(get_column("htmpl-options", query, row, label_column)?);
+        select
+            .append(Node::Element(scraper::node::Element::new(
+                name.clone(),
+                attrs,
+            )))
+            .append(Node::Text(scraper::node::Text { text: label.into() }));
+    }
+    scope.adopt(inner);
+    Ok(())
+}
+
+/// Check that the query's results have each of the columns.
+fn check_columns(
+    element: &'static str,
+    query: &str,
+    rows: &[HashMap<String, Value>],
+    columns: &[String],
+) -> Result<(), Error> {
+    let Some(row) = rows.first() else {
+        return Ok(());
+    };
+    match columns.iter().find(|c| !row.contains_key(*c)) {
+        Some(missing) => Err(missing_column(element, query, row, missing)),
+        None => Ok(()),
+    }
+}
+
+/// The value of the column in a row of the query's results.
+///
+/// Rows can differ in their columns (e.g. per [`EvaluateOptions::transform_row`]),
+/// so this checks each row, not just the first.
+fn get_column<'r>(
+    element: &'static str,
+    query: &str,
+    row: &'r HashMap<String, Value>,
+    column: &str,
+) -> Result<&'r Value, Error> {
+    row.get(column)
+        .ok_or_else(|| missing_column(element, query, row, column))
+}
+
+/// The error for a row of the query's results that doesn't have the column.
+fn missing_column(
+    element: &'static str,
+    query: &str,
+    row: &HashMap<String, Value>,
+    column: &str,
+) -> Error {
+    let mut have: Vec<_> = row.keys().map(String::as_str).collect();
+    have.sort();
+    Error::MissingColumn(
+        element.into(),
+        query.to_owned(),
+        format!("\"{}\"", have.join(",")),
+        column.to_owned(),
+    )
+}
+
+/// Evaluate an htmpl-json element, emitting query results as JSON for scripts on the page.
+///
+/// If the `query` attribute is a query name, the output is an array of its rows,
+/// each an object keyed by column name; if it is a selector of a single value,
+/// the output is that value.
+/// The JSON is output in a `<script type="application/json">` element,
+/// with the other attributes of the `htmpl-json` element (e.g. `id`).
+fn visit_json(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let query = element
+        .value()
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-json".into(), "query".into()))?;
+    let column = Specifier::parse(query)
+        .map_err(|e| e.set_element("htmpl-json"))?
+        .column;
+    let content = if column.is_some() {
+        let value = scope
+            .get_single(query)
+            .map_err(|e| e.set_element("htmpl-json"))?;
+        json::json_value(&value)
+    } else {
+        let rows = scope.get(query).map_err(|e| e.set_element("htmpl-json"))?;
+        json::json_rows(rows, scope.columns(query).unwrap_or_default())
+    };
+
+    let mut script = element.value().clone();
+    script.name = QualName::new(None, script.name.ns.clone(), local_name!("script"));
+    script
+        .attrs
+        .retain(|name, _| name.local.as_ref() != "query");
+    script.attrs.insert(
+        QualName::new(None, "".into(), local_name!("type")),
+        "application/json".into(),
+    );
+    stamp_nonce(scope.options(), &mut script);
+    output_parent
+        .append(Node::Element(script))
+        .append(Node::Text(scraper::node::Text {
+            text: content.into(),
+        }));
+    Ok(())
+}
+
+/// Format a query value for an attribute, escaping it if the attribute is a URL.
+fn attr_value(options: &EvaluateOptions, name: &str, value: &Value) -> String {
+    let value = format_value(value);
+    if urls::is_url_attr(name) {
+        urls::escape(&value, options.reject_unsafe_urls)
+    } else {
+        value
+    }
+}
+
+/// Add the CSP nonce, if any, to a `script` or `style` element.
+fn stamp_nonce(options: &EvaluateOptions, element: &mut scraper::node::Element) {
+    if let Some(nonce) = &options.nonce {
+        if matches!(element.name(), "script" | "style") {
+            element.attrs.insert(
+                QualName::new(None, "".into(), local_name!("nonce")),
+                nonce.as_str().into(),
+            );
+        }
+    }
+}
+
+/// Add the subresource integrity hash of a local script or stylesheet, if there is an asset root.
+fn stamp_integrity(
+    options: &EvaluateOptions,
+    element: &mut scraper::node::Element,
+) -> Result<(), Error> {
+    let Some(root) = &options.asset_root else {
+        return Ok(());
+    };
+    if element.attr("integrity").is_some() {
+        return Ok(());
+    }
+    let url = match element.name() {
+        "script" => element.attr("src"),
+        "link" => element
+            .attr("rel")
+            .filter(|rel| {
+                rel.split_ascii_whitespace().any(|rel| {
+                    ["stylesheet", "preload", "modulepreload"]
+                        .iter()
+                        .any(|r| rel.eq_ignore_ascii_case(r))
+                })
+            })
+            .and(element.attr("href")),
+        _ => None,
+    };
+    if let Some(hash) = url
+        .map(|url| integrity::integrity(root, url))
+        .transpose()?
+        .flatten()
+    {
+        element.attrs.insert(
+            QualName::new(None, "".into(), local_name!("integrity")),
+            hash.into(),
+        );
+    }
+    Ok(())
+}
+
+/// Bind the queries available to every template: `params`, and `htmpl` (from the options).
+pub(crate) fn bind_builtins(scope: &mut Scope, options: &EvaluateOptions) -> Result<(), Error> {
+    scope.bind(PARAMS_QUERY, vec![options.params.row()]);
+    let nonce = options.nonce.clone().map_or(Value::Null, Value::Text);
+    scope.bind(HTMPL_QUERY, vec![[("nonce".to_owned(), nonce)].into()]);
+    for (name, prelude) in options.prelude.iter() {
+        match prelude {
+            Prelude::Query(sql) => scope.run_prelude(name, sql)?,
+            Prelude::Rows(rows) => scope.bind(name.as_str(), rows.to_vec()),
+        }
+    }
+    scope.mark_root();
+    Ok(())
+}
+
+/// The compiled `select=` selector of the element.
+fn selector(
+    options: &EvaluateOptions,
+    element: ElementRef,
+    element_name: &'static str,
+) -> Result<Arc<Selector>, Error> {
     let select = element
         .value()
         .attr("select")
-        .ok_or(Error::MissingAttr("htmpl-attr", "select"))?;
-    let selector: Selector = Selector::parse(select)
-        .map_err(|_| Error::InvalidParameter("htmpl-attr", "select".to_owned()))?;
-    let attr = element
+        .ok_or(Error::MissingAttr(element_name.into(), "select".into()))?;
+    options
+        .selectors
+        .parse(select)
+        .ok_or_else(|| Error::InvalidParameter(element_name.into(), "select".to_owned()))
+}
+
+/// The element whose descendants the element's `select=` selector applies to,
+/// per its `scope=` attribute:
+/// - `siblings` (the default): the parent, i.e. the siblings of the element and their descendants
+/// - `descendants`: the element itself
+/// - `document`: the outermost element
+/// - a number: the ancestor that many levels up (`1` is the parent)
+///
+/// If there are fewer ancestors than requested, the outermost element is used.
+fn select_root<'a>(
+    element: ElementRef<'a>,
+    element_name: &'static str,
+) -> Result<ElementRef<'a>, Error> {
+    let hops = match element.value().attr("scope") {
+        None | Some("siblings") => 1,
+        Some("descendants") => 0,
+        Some("document") => usize::MAX,
+        Some(hops) => hops
+            .parse()
+            .map_err(|_| Error::InvalidParameter(element_name.into(), "scope".to_owned()))?,
+    };
+    let mut root = element;
+    for _ in 0..hops {
+        match root.parent().and_then(ElementRef::wrap) {
+            Some(parent) => root = parent,
+            None => break,
+        }
+    }
+    Ok(root)
+}
+
+/// Add the attribute to the target element: in the current scope if the target is among the
+/// element's siblings (and their descendants), or for the rest of the evaluation if not.
+fn bind_attr(scope: &mut Scope, element: ElementRef, target: ElementRef, attr: Rc<Attribute>) {
+    let local = element
+        .parent()
+        .is_some_and(|parent| target.ancestors().any(|a| a.id() == parent.id()));
+    if local {
+        scope.add_attr(target.id(), attr)
+    } else {
+        scope.add_outer_attr(target.id(), attr)
+    }
+}
+
+/// Add the attributes to the elements that match the selector,
+/// within the element's [scope](select_root); it is an error if none match.
+fn add_attrs(
+    scope: &mut Scope,
+    element: ElementRef,
+    element_name: &'static str,
+    selector: &Selector,
+    attrs: &[Rc<Attribute>],
+) -> Result<(), Error> {
+    let root = select_root(element, element_name)?;
+    let mut matched = false;
+    for selected in root.select(selector) {
+        tracing::debug!("add_attr {:?}", selected);
+        matched = true;
+        for attr in attrs {
+            bind_attr(scope, element, selected, attr.clone());
+        }
+    }
+    if !matched {
+        return Err(Error::NoMatch(
+            element_name.into(),
+            element
+                .value()
+                .attr("select")
+                .unwrap_or_default()
+                .to_owned(),
+            element
+                .value()
+                .attr("scope")
+                .unwrap_or("siblings")
+                .to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Evaluate an htmpl-title or htmpl-meta element, into a marker for the document head.
+///
+/// An htmpl-meta element's `content` is the value of its `query=` selector, if it has one.
+/// After evaluation, [`head::hoist`] moves the markers into the head of the document.
+fn visit_head(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let mut marker = element.value().clone();
+    if let Some(query) = element.value().attr("query") {
+        let value = scope
+            .get_single(query)
+            .map_err(|e| e.set_element("htmpl-meta"))?;
+        let content = format_value(&value);
+        marker
+            .attrs
+            .retain(|name, _| name.local.as_ref() != "query");
+        marker.attrs.insert(
+            QualName::new(None, "".into(), local_name!("content")),
+            content.into(),
+        );
+    }
+    let mut new = output_parent.append(Node::Element(marker));
+    evaluate_children(element, scope, &mut new)
+}
+
+/// The Open Graph properties set by htmpl-og, by column name, with their Twitter equivalents.
+const OG_PROPERTIES: &[(&str, &str, Option<&str>)] = &[
+    ("title", "og:title", Some("twitter:title")),
+    ("description", "og:description", Some("twitter:description")),
+    ("url", "og:url", None),
+    ("image", "og:image", Some("twitter:image")),
+    ("image_alt", "og:image:alt", Some("twitter:image:alt")),
+    ("type", "og:type", None),
+    ("site_name", "og:site_name", None),
+    ("locale", "og:locale", None),
+];
+
+/// Evaluate an htmpl-og element, adding Open Graph and Twitter `meta` tags for a page
+/// from a single-row query.
+///
+/// Columns are named per [`OG_PROPERTIES`]; null or missing columns are skipped.
+/// The `url` and `image` columns are resolved against the `base=` attribute, if present,
+/// since Open Graph requires absolute URLs.
+/// The tags are output as htmpl-meta markers, so they replace any existing tags in the head.
+fn visit_og(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let query = element
         .value()
-        .attr("attr")
-        .ok_or(Error::MissingAttr("htmpl-attr", "attr"))?;
-    let value = scope
-        .get_single(query)
-        .map_err(|e| e.set_element("htmpl-attr"))?;
-    let attr = Rc::new(Attribute {
-        name: attr.to_owned(),
-        value: format_value(value),
-    });
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-og".into(), "query".into()))?;
+    let rows = scope.get(query).map_err(|e| e.set_element("htmpl-og"))?;
+    let row = match rows.as_slice() {
+        [row] => row,
+        _ => {
+            return Err(Error::Cardinality(
+                "htmpl-og".into(),
+                query.to_owned(),
+                rows.len(),
+                1,
+            ))
+        }
+    };
+    let base = element.value().attr("base");
+    let get = |column: &str| match row.get(column) {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(format_value(value)),
+    };
 
-    if let Some(parent) = element.parent().and_then(ElementRef::wrap) {
-        for selected in parent.select(&selector) {
-            tracing::debug!("add_attr {:?}", selected);
-            scope.add_attr(selected.id(), attr.clone())
+    let mut tags: Vec<(&str, &str, String)> = Vec::new();
+    for (column, og, twitter) in OG_PROPERTIES {
+        let value = match (get(column), *column) {
+            (Some(url), "url" | "image") => {
+                let url = match base {
+                    Some(base) => urls::resolve(base, &url),
+                    None => url,
+                };
+                urls::escape(&url, scope.options().reject_unsafe_urls)
+            }
+            (Some(value), _) => value,
+            (None, "type") => "website".to_owned(),
+            (None, _) => continue,
+        };
+        if let Some(twitter) = twitter {
+            tags.push(("name", twitter, value.clone()));
         }
+        tags.push(("property", og, value));
+    }
+    let card = if get("image").is_some() {
+        "summary_large_image"
     } else {
-        tracing::error!("htmpl-attr with no parent: {:?}", element);
+        "summary"
+    };
+    tags.push(("name", "twitter:card", card.to_owned()));
+    tags.sort_by_key(|(key, _, _)| *key == "name");
+
+    let ns = &element.value().name.ns;
+    for (key, name, content) in tags {
+        let attrs = vec![
+            html5ever::Attribute {
+                name: QualName::new(None, "".into(), key.into()),
+                value: name.into(),
+            },
+            html5ever::Attribute {
+                name: QualName::new(None, "".into(), local_name!("content")),
+                value: content.into(),
+            },
+        ];
+        output_parent.append(Node::Element(scraper::node::Element::new(
+            QualName::new(None, ns.clone(), "htmpl-meta".into()),
+            attrs,
+        )));
     }
+    Ok(())
+}
 
+/// Visit an htmpl-macro node: define the macro for the rest of the scope, without evaluating it.
+fn visit_macro(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    let name = element
+        .value()
+        .attr("name")
+        .ok_or(Error::MissingAttr("htmpl-macro".into(), "name".into()))?;
+    scope.define_macro(name, element.id());
+    Ok(())
+}
+
+/// Visit an htmpl-with node: check that the `query` has a single row,
+/// and evaluate the body with selectors like `(column)` referring to it.
+fn visit_with(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let query = element
+        .value()
+        .attr("query")
+        .ok_or(Error::MissingAttr("htmpl-with".into(), "query".into()))?;
+    let rows = scope
+        .get(query)
+        .map_err(|e| e.set_element("htmpl-with"))?
+        .len();
+    if rows != 1 {
+        return Err(Error::Cardinality(
+            "htmpl-with".into(),
+            query.to_owned(),
+            rows,
+            1,
+        ));
+    }
+    let mut inner = scope.push();
+    inner.set_with(query);
+    for child in element.children() {
+        visit_recurse(&mut inner, child, output_parent)?;
+    }
+    scope.adopt(inner);
+    Ok(())
+}
+
+/// Evaluate an htmpl-let element: bind the `name` to a single row, with a single column
+/// of the same name, holding the `value`: a literal or a selector, then any filters.
+fn visit_let(scope: &mut Scope, element: ElementRef) -> Result<(), Error> {
+    let name = element
+        .value()
+        .attr("name")
+        .ok_or(Error::MissingAttr("htmpl-let".into(), "name".into()))?;
+    let value = element
+        .value()
+        .attr("value")
+        .ok_or(Error::MissingAttr("htmpl-let".into(), "value".into()))?;
+    let (value, filters) = filters::split(value);
+    let mut value = match queries::literal(value) {
+        Some(value) => value,
+        None => scope
+            .get_single(value)
+            .map_err(|e| e.set_element("htmpl-let"))?
+            .into_owned(),
+    };
+    for filter in filters {
+        value = filters::apply("htmpl-let", filter, value)?;
+    }
+    scope.bind_columns(
+        name,
+        vec![name.to_owned()],
+        vec![[(name.to_owned(), value)].into()],
+    );
+    scope
+        .export(element, name)
+        .map_err(|e| e.set_element("htmpl-let"))
+}
+
+/// Evaluate an htmpl-t element: look up the message with the `key` in the
+/// [catalog](EvaluateOptions::catalog), and insert it as text.
+///
+/// Each `:name` attribute is a selector, whose value is the message's `$name` variable;
+/// the `count` attribute is a selector for the `$count` variable, for plurals.
+/// If the catalog has no such message, the element's content is evaluated instead.
+fn visit_t(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let key = element
+        .value()
+        .attr("key")
+        .ok_or(Error::MissingAttr("htmpl-t".into(), "key".into()))?;
+    let catalog = scope
+        .options()
+        .catalog
+        .clone()
+        .ok_or(Error::MissingOption("htmpl-t".into(), "catalog".into()))?;
+    let mut variables = HashMap::new();
+    for (name, selector) in element.value().attrs() {
+        if let Some(name) = name.strip_prefix(':').or((name == "count").then_some(name)) {
+            let value = scope
+                .get_single(selector)
+                .map_err(|e| e.set_element("htmpl-t"))?;
+            variables.insert(name.to_owned(), value.into_owned());
+        }
+    }
+    match catalog.format(key, &variables, format_value) {
+        Some(message) => {
+            output_parent.append(Node::Text(scraper::node::Text {
+                text: message?.into(),
+            }));
+            Ok(())
+        }
+        None if element.has_children() => evaluate_children(element, scope, output_parent),
+        None => Err(Error::MissingMessage(key.to_owned())),
+    }
+}
+
+/// Visit an htmpl-block node.
+///
+/// A block is evaluated in place, like its contents were written without it;
+/// when rendering only a block (per [`EvaluateOptions::block`]),
+/// the output of the named block is collected under an `htmpl-block` element.
+fn visit_block(
+    scope: &mut Scope,
+    element: ElementRef,
+    output_parent: &mut NodeMut<Node>,
+) -> Result<(), Error> {
+    let name = element
+        .value()
+        .attr("name")
+        .ok_or(Error::MissingAttr("htmpl-block".into(), "name".into()))?;
+    let mut inner = scope.push();
+    if scope.options().block.as_deref() == Some(name) {
+        let mut new = output_parent.append(Node::Element(element.value().clone()));
+        for child in element.children() {
+            visit_recurse(&mut inner, child, &mut new)?;
+        }
+    } else {
+        for child in element.children() {
+            visit_recurse(&mut inner, child, output_parent)?;
+        }
+    }
+    scope.adopt(inner);
     Ok(())
 }
 
@@ -221,71 +1794,176 @@ fn format_value(v: &Value) -> String {
 
 /// Parse the HTML tree, replacing htmpl elements and attributes.
 pub fn evaluate_template(s: impl AsRef<str>, dbs: &DbTable) -> Result<String, Error> {
-    // scraper::parse_fragment impugns an <html> element into the root, which isn't necessarily
-    // true for us.
-    // Try to parse without adding an <html>.
-    // ...doesn't work.
-    use html5ever::namespace_url;
-    use html5ever::tendril::TendrilSink;
-    let h = html5ever::driver::parse_fragment(
-        scraper::Html::new_fragment(),
-        html5ever::ParseOpts {
-            tokenizer: TokenizerOpts {
-                exact_errors: true,
-                ..TokenizerOpts::default()
-            },
-            tree_builder: TreeBuilderOpts {
-                exact_errors: true,
-                // Enable "scripting" since we have custom elements
-                scripting_enabled: true,
-                ..TreeBuilderOpts::default()
-            },
-        },
-        QualName::new(None, ns!(html), local_name!("body")),
-        Vec::new(),
-    )
-    .one(s.as_ref());
-    if !h.errors.is_empty() {
-        return Err(Error::HtmlParse(h.errors.join("; ")));
-    }
-    tracing::debug!("parse errors: {:?}", h.errors);
-    tracing::debug!("quirks: {:?}", h.quirks_mode);
-    // let mut h = Html::parse_fragment(s.as_ref());
-
-    let mut scope = Scope::new(dbs);
-    let mut output = scraper::Html::new_fragment();
-    visit_recurse(&mut scope, h.tree.root(), &mut output.tree.root_mut())?;
-
-    // Scraper appears to synthesize an <html> wrapping element.
-    // TODO: Make "this is a fragment" vs. "this is a whole-document" explicit,
-    // so we do/don't strip the <html> element depending.
-    // (Why does scraper add a root element?)
-    // For now, we remove it here:
-    if let Some(root) = output.select(&Selector::parse("html").unwrap()).next() {
-        // Lifted from scraper::Html::serialize(), but with different options.
-        let mut buf = Vec::new();
-        // TODO: Derferred (I/O) serialization?
-        html5ever::serialize(
-            &mut buf,
-            &root,
-            SerializeOpts {
-                scripting_enabled: false, // What does this do?
-                traversal_scope: TraversalScope::ChildrenOnly(None),
-                // traversal_scope: TraversalScope::IncludeNode,
-                create_missing_parent: false,
-            },
-        )
-        .map_err(Error::Serialize)?;
-        return Ok(String::from_utf8(buf).unwrap());
+    evaluate_template_with(s, dbs, &EvaluateOptions::default())
+}
+
+/// Evaluate the template, per the provided options.
+pub fn evaluate_template_with(
+    s: impl AsRef<str>,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+) -> Result<String, Error> {
+    let h = parse::parse(s.as_ref(), options)?;
+    let (dom, _) = evaluate_parsed_to_dom(&h, dbs, options)?;
+    let output = serialize(&dom, options)?;
+    diagnostics::check(&h.errors, &dom, &output, options);
+    Ok(output)
+}
+
+/// Evaluate the template, per the provided options, and report the [trace](EvaluateOptions::trace)
+/// and [diagnostics](crate::Diagnostic) of the evaluation with the output.
+pub(crate) fn evaluate_render(
+    s: impl AsRef<str>,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+    last_modified: Option<i64>,
+) -> Result<RenderOutput, Error> {
+    let h = parse::parse(s.as_ref(), options)?;
+    let (dom, trace) = evaluate_parsed_to_dom(&h, dbs, options)?;
+    let output = serialize(&dom, options)?;
+    let diagnostics = diagnostics::check(&h.errors, &dom, &output, options);
+    Ok(RenderOutput::new(output, last_modified)
+        .with_trace(trace)
+        .with_diagnostics(diagnostics))
+}
+
+/// Evaluate the template into a tree, rather than serializing it,
+/// e.g. to select nodes from the output.
+pub fn evaluate_to_dom(s: impl AsRef<str>, dbs: &DbTable) -> Result<scraper::Html, Error> {
+    evaluate_to_dom_with(s, dbs, &EvaluateOptions::default())
+}
+
+/// Evaluate the template into a tree, per the provided options.
+///
+/// As from [`scraper::Html::parse_fragment`], the content of an HTML fragment is within
+/// an `html` element. The [block](EvaluateOptions::block) option doesn't apply: the tree
+/// holds the whole template, with each evaluation of the block in an `htmpl-block` element.
+pub fn evaluate_to_dom_with(
+    s: impl AsRef<str>,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+) -> Result<scraper::Html, Error> {
+    let h = parse::parse(s.as_ref(), options)?;
+    let (output, _) = evaluate_parsed_to_dom(&h, dbs, options)?;
+    Ok(output)
+}
+
+/// Evaluate a template that has already been parsed, e.g. by [`scraper::Html::parse_fragment`].
+pub fn evaluate_parsed(h: &scraper::Html, dbs: &DbTable) -> Result<String, Error> {
+    evaluate_parsed_with(h, dbs, &EvaluateOptions::default())
+}
+
+/// Evaluate a template that has already been parsed, per the provided options.
+///
+/// The template should be parsed per the options: as an HTML fragment, by default,
+/// or as a document if the options set [`document`](EvaluateOptions::document)
+/// or an XML [`format`](EvaluateOptions::format). Parse settings and limits don't apply.
+pub fn evaluate_parsed_with(
+    h: &scraper::Html,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+) -> Result<String, Error> {
+    let (dom, _) = evaluate_parsed_to_dom(h, dbs, options)?;
+    let output = serialize(&dom, options)?;
+    diagnostics::check(&[], &dom, &output, options);
+    Ok(output)
+}
+
+/// Evaluate the parsed template into a tree.
+fn evaluate_parsed_to_dom(
+    h: &scraper::Html,
+    dbs: &DbTable,
+    options: &EvaluateOptions,
+) -> Result<(scraper::Html, Option<Trace>), Error> {
+    functions::setup(dbs, options)?;
+
+    if let Some(block) = &options.block {
+        let selector = options
+            .selectors
+            .parse("htmpl-block[name]")
+            .expect("invalid selector");
+        if !h
+            .select(&selector)
+            .any(|e| e.attr("name") == Some(block) && !is_inert(*e))
+        {
+            return Err(Error::MissingBlock(block.clone()));
+        }
+    }
+
+    let mut scope = Scope::new(dbs, options);
+    scope.set_components(components::definitions(h).map_err(|e| prefix::error(options, e))?);
+    let mut output = if options.format.is_html() && !options.document {
+        scraper::Html::new_fragment()
+    } else {
+        scraper::Html::new_document()
+    };
+    queries::transaction(dbs, || {
+        bind_builtins(&mut scope, options)?;
+        visit_recurse(&mut scope, h.tree.root(), &mut output.tree.root_mut())?;
+        // Don't commit part of the writes, if some failed.
+        Ok(!scope.has_inline_errors())
+    })?;
+    head::hoist(&mut output.tree);
+    if options.heading_ids {
+        postprocess::heading_ids(&mut output.tree);
+    }
+    if let Some(base) = &options.base_url {
+        postprocess::rebase_urls(&mut output.tree, base);
+    }
+    if options.comments != Comments::Keep {
+        postprocess::strip_comments(&mut output.tree, options.comments);
+    }
+    for hook in options.post_process.iter() {
+        hook(&mut output);
     }
-    panic!("unexpected end of function: no root element");
+    Ok((output, scope.trace()))
+}
+
+/// Serialize the evaluated tree, per the options.
+fn serialize(output: &scraper::Html, options: &EvaluateOptions) -> Result<String, Error> {
+    if options.block.is_some() {
+        // Only the named block is output with an htmpl-block element.
+        // Blocks nested within another evaluation of the same block are part of the outer one.
+        let is_block = |n: &NodeRef<Node>| {
+            n.value()
+                .as_element()
+                .is_some_and(|e| e.name() == "htmpl-block")
+        };
+        return Ok(output
+            .tree
+            .root()
+            .descendants()
+            .filter(|n| is_block(n) && !n.ancestors().any(|a| is_block(&a)))
+            .map(|n| serialize_children(n, options.format, options))
+            .collect());
+    }
+
+    if options.format == OutputFormat::Xml || options.document {
+        return Ok(serialize_children(
+            output.tree.root(),
+            options.format,
+            options,
+        ));
+    }
+
+    // Scraper synthesizes an <html> element around the content of a fragment,
+    // which isn't part of the output.
+    // A post-processing hook may have removed it; if so, output what is left.
+    let root = output.tree.root();
+    let html = root.descendants().find(|n| n.value().is_element());
+    Ok(serialize_children(
+        html.unwrap_or(root),
+        options.format,
+        options,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
     use rusqlite::types::ValueRef;
 
-    use crate::visit::truthy;
+    use crate::visit::{truthy, truthy_per};
+    use crate::Truthiness;
 
     #[test]
     fn null_falsy() {
@@ -346,4 +2024,36 @@ mod tests {
     fn nonempty_blob_truthy() {
         assert!(truthy(ValueRef::Blob(b"hello world")))
     }
+
+    #[test]
+    fn text_flags_falsy() {
+        for flag in ["0", "false", " No ", "OFF", ""] {
+            assert_eq!(
+                truthy_per(Truthiness::TextFlags, ValueRef::Text(flag.as_bytes())),
+                Some(false),
+                "{:?}",
+                flag
+            );
+        }
+        assert_eq!(
+            truthy_per(Truthiness::TextFlags, ValueRef::Text(b"yes")),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn strict_integer() {
+        assert_eq!(
+            truthy_per(Truthiness::StrictInteger, ValueRef::Integer(2)),
+            Some(true)
+        );
+        assert_eq!(
+            truthy_per(Truthiness::StrictInteger, ValueRef::Null),
+            Some(false)
+        );
+        assert_eq!(
+            truthy_per(Truthiness::StrictInteger, ValueRef::Text(b"1")),
+            None
+        );
+    }
 }